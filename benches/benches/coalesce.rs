@@ -0,0 +1,70 @@
+use arigato::{raw::R, server::RWriter};
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::io::AsyncWrite;
+
+/// Sink which throws away everything written to it, counting the number of
+/// writes (each of which would be a syscall on a real socket).
+struct CountingSink(Arc<AtomicUsize>);
+
+impl AsyncWrite for CountingSink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+const REPLIES: u16 = 1000;
+
+async fn send_replies(rw: &mut RWriter) {
+    for tag in 0..REPLIES {
+        rw.send(R::Clunk(tag)).await.unwrap();
+    }
+    rw.flush().await.unwrap();
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("coalesce");
+
+    for (name, limit) in [("uncoalesced", None), ("coalesced-8k", Some(8192))] {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let mut rw = RWriter::new(Box::pin(CountingSink(writes.clone())), 8192);
+        rw.set_coalescing(limit);
+
+        rt.block_on(send_replies(&mut rw));
+        println!(
+            "{name}: {} writes for {REPLIES} replies",
+            writes.swap(0, Ordering::Relaxed)
+        );
+
+        group.bench_function(name, |b| {
+            b.iter(|| rt.block_on(send_replies(&mut rw)));
+        });
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,109 @@
+use arigato::{raw::R, server::RWriter};
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::{
+    io::{self, IoSlice},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::io::AsyncWrite;
+
+/// Sink which throws away everything written to it, counting the number of
+/// writes (each of which would be a syscall on a real socket). If it's
+/// vectored, it takes a whole writev at a time, like a socket would.
+struct CountingSink(Arc<AtomicUsize>, bool);
+
+impl AsyncWrite for CountingSink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        if !self.1 {
+            let buf = bufs.iter().find(|b| !b.is_empty()).map_or(&[][..], |b| b);
+            return self.poll_write(cx, buf);
+        }
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Poll::Ready(Ok(bufs.iter().map(|b| b.len()).sum()))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.1
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+const REPLIES: u16 = 1000;
+
+fn replies(name: &str) -> Vec<R> {
+    (0..REPLIES)
+        .map(|tag| match name {
+            "clunk" => R::Clunk(tag),
+            "error" => R::Error(tag, "ENOENT".to_owned(), 2),
+            _ => R::Read(tag, vec![0xAB; 4096]),
+        })
+        .collect()
+}
+
+async fn send_replies(rw: &mut RWriter, replies: Vec<R>) {
+    for r in replies {
+        rw.send(r).await.unwrap();
+    }
+    rw.flush().await.unwrap();
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("framing");
+
+    for vectored in [true, false] {
+        for message in ["clunk", "error", "read-4k"] {
+            let name = match vectored {
+                true => format!("{message}-writev"),
+                false => format!("{message}-write"),
+            };
+            let writes = Arc::new(AtomicUsize::new(0));
+            let mut rw = RWriter::new(Box::pin(CountingSink(writes.clone(), vectored)), 8192 + 24);
+
+            // every frame, size prefix and all, should be one write, be it
+            // a writev or a copy of the data of an Rread onto the header.
+            rt.block_on(send_replies(&mut rw, replies(message)));
+            println!(
+                "{name}: {} writes for {REPLIES} replies",
+                writes.swap(0, Ordering::Relaxed)
+            );
+
+            group.bench_function(&name, |b| {
+                b.iter_batched(
+                    || replies(message),
+                    |replies| rt.block_on(send_replies(&mut rw, replies)),
+                    criterion::BatchSize::SmallInput,
+                );
+            });
+        }
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
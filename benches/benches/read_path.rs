@@ -0,0 +1,102 @@
+use arigato::{
+    raw::{Dehydrate, R},
+    server::{BufferPool, RWriter},
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::{
+    io::{self, Cursor},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// AsyncWrite that throws everything away, supporting vectored writes.
+struct Sink;
+
+impl AsyncWrite for Sink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(bufs.iter().map(|buf| buf.len()).sum()))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+const CHUNK: usize = 1024 * 1024;
+const CHUNKS: u16 = 64;
+const MSIZE: u32 = 24 + CHUNK as u32;
+
+/// Stand-in for OpenFile::read_at over a large sequential file.
+fn read_at(buf: &mut [u8], offset: u64) {
+    buf.fill(offset as u8);
+}
+
+/// Read path as it was: a fresh buffer per read, which is then copied
+/// into the frame by dehydrate.
+async fn copied(w: &mut Pin<Box<dyn AsyncWrite + Send>>) {
+    for tag in 0..CHUNKS {
+        let mut buf = vec![0u8; CHUNK];
+        read_at(&mut buf, tag as u64);
+        let mut frame = Cursor::new(vec![]);
+        R::Read(tag, buf).dehydrate(&mut frame).unwrap();
+        let frame = frame.into_inner();
+        w.write_all(&((frame.len() + 4) as u32).to_le_bytes())
+            .await
+            .unwrap();
+        w.write_all(&frame).await.unwrap();
+    }
+}
+
+/// Read path as the server does it: a pooled buffer, written out after the
+/// frame header without being copied.
+async fn framed(rw: &mut RWriter, pool: &BufferPool) {
+    for tag in 0..CHUNKS {
+        let mut buf = pool.take(CHUNK);
+        read_at(&mut buf, tag as u64);
+        rw.send(R::Read(tag, buf)).await.unwrap();
+    }
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("read_path");
+    group.throughput(Throughput::Bytes(CHUNK as u64 * CHUNKS as u64));
+
+    let mut w: Pin<Box<dyn AsyncWrite + Send>> = Box::pin(Sink);
+    group.bench_function("copied", |b| {
+        b.iter(|| rt.block_on(copied(&mut w)));
+    });
+
+    let pool = BufferPool::default();
+    let mut rw = RWriter::new(Box::pin(Sink), MSIZE);
+    rw.set_pool(Some(pool.clone()));
+    group.bench_function("framed", |b| {
+        b.iter(|| rt.block_on(framed(&mut rw, &pool)));
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,117 @@
+use arigato::{
+    fs::MemFilesystem,
+    raw::{R, T},
+    server::{AsyncServer, RReader, TWriter},
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio::net::UnixStream;
+
+const SMALL: usize = 4096;
+const LARGE: usize = 1024 * 1024;
+const MSIZE: u32 = 24 + LARGE as u32;
+
+/// Client end of a connection to the server being benchmarked.
+struct Client {
+    tw: TWriter,
+    rr: RReader,
+}
+
+impl Client {
+    async fn rpc(&mut self, t: T) -> R {
+        self.tw.send(t).await.unwrap();
+        self.rr.next().await.unwrap()
+    }
+
+    /// Attach, walk to `name`, read all `size` bytes of it, and clunk
+    /// everything again.
+    async fn cycle(&mut self, name: &str, size: usize) {
+        let r = self
+            .rpc(T::Attach(1, 1, !0, "bench".to_owned(), "".to_owned(), 0))
+            .await;
+        assert!(matches!(r, R::Attach(1, _)), "{:?}", r);
+        let r = self.rpc(T::Walk(2, 1, 2, vec![name.to_owned()])).await;
+        assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+        let r = self.rpc(T::Open(3, 2, 0.into())).await;
+        assert!(matches!(r, R::Open(3, _, _)), "{:?}", r);
+
+        let mut offset = 0;
+        while offset < size {
+            match self.rpc(T::Read(4, 2, offset as u64, LARGE as u32)).await {
+                R::Read(4, data) if !data.is_empty() => offset += data.len(),
+                r => panic!("{:?}", r),
+            }
+        }
+
+        assert_eq!(R::Clunk(5), self.rpc(T::Clunk(5, 2)).await);
+        assert_eq!(R::Clunk(6), self.rpc(T::Clunk(6, 1)).await);
+    }
+}
+
+/// Start a server over a MemFilesystem holding a `small` and a `large` file
+/// on a UNIX socket, and connect to it.
+async fn setup() -> Client {
+    let dir = std::env::temp_dir().join(format!("arigato-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("rpc.sock");
+    let _ = std::fs::remove_file(&path);
+
+    let srv = AsyncServer::builder()
+        .with_unix_listen_address(&path)
+        .with_msize(MSIZE)
+        .with_filesystem("", MemFilesystem::new())
+        .build()
+        .await
+        .unwrap();
+    let handle = srv.handle();
+    tokio::spawn(async move { srv.serve().await });
+    handle.ready().await;
+
+    let (read, write) = UnixStream::connect(&path).await.unwrap().into_split();
+    let _ = std::fs::remove_file(&path);
+    let mut client = Client {
+        tw: TWriter::new(Box::pin(write), MSIZE),
+        rr: RReader::new(Box::pin(read), MSIZE),
+    };
+    let r = client
+        .rpc(T::Version(0xFFFF, MSIZE, "9P2000.u".parse().unwrap()))
+        .await;
+    assert!(matches!(r, R::Version(_, MSIZE, _)), "{:?}", r);
+
+    let r = client
+        .rpc(T::Attach(1, 1, !0, "bench".to_owned(), "".to_owned(), 0))
+        .await;
+    assert!(matches!(r, R::Attach(1, _)), "{:?}", r);
+    for (name, size) in [("small", SMALL), ("large", LARGE)] {
+        let r = client.rpc(T::Walk(2, 1, 2, vec![])).await;
+        assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+        let r = client
+            .rpc(T::Create(3, 2, name.to_owned(), 0o644, 1, "".to_owned()))
+            .await;
+        assert!(matches!(r, R::Create(3, _, _)), "{:?}", r);
+        let r = client.rpc(T::Write(4, 2, 0, vec![0x55; size])).await;
+        assert_eq!(R::Write(4, size as u32), r);
+        assert_eq!(R::Clunk(5), client.rpc(T::Clunk(5, 2)).await);
+    }
+    assert_eq!(R::Clunk(6), client.rpc(T::Clunk(6, 1)).await);
+
+    client
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut client = rt.block_on(setup());
+    let mut group = c.benchmark_group("rpc");
+    group.throughput(Throughput::Elements(1));
+
+    for (name, size) in [("small", SMALL), ("large", LARGE)] {
+        group.bench_function(format!("attach-walk-open-read-clunk/{name}"), |b| {
+            b.iter(|| rt.block_on(client.cycle(name, size)));
+        });
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,106 @@
+use arigato::{
+    fs::MemFilesystem,
+    raw::{R, T},
+    server::{AsyncServer, RReader, TWriter},
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio::net::TcpStream;
+
+const SIZE: usize = 16 * 1024 * 1024;
+const CHUNK: usize = 1024 * 1024;
+const MSIZE: u32 = 24 + CHUNK as u32;
+
+/// Client end of a connection to the server being benchmarked.
+struct Client {
+    tw: TWriter,
+    rr: RReader,
+}
+
+impl Client {
+    async fn rpc(&mut self, t: T) -> R {
+        self.tw.send(t).await.unwrap();
+        self.rr.next().await.unwrap()
+    }
+
+    /// Read all of the open fid 2, one msize-sized chunk at a time.
+    async fn read_all(&mut self) {
+        let mut offset = 0;
+        while offset < SIZE {
+            match self.rpc(T::Read(4, 2, offset as u64, CHUNK as u32)).await {
+                R::Read(4, data) if !data.is_empty() => offset += data.len(),
+                r => panic!("{:?}", r),
+            }
+        }
+    }
+}
+
+/// Start a server on a localhost TCP port with the given socket buffer
+/// sizes, create a large file on it, and leave it open for reading on fid 2.
+async fn setup(buffer: Option<u32>) -> Client {
+    let mut builder = AsyncServer::builder()
+        .with_tcp_listen_address("127.0.0.1:0")
+        .with_msize(MSIZE)
+        .with_filesystem("", MemFilesystem::new());
+    if let Some(size) = buffer {
+        builder = builder
+            .with_recv_buffer_size(size)
+            .with_send_buffer_size(size);
+    }
+    let srv = builder.build().await.unwrap();
+    let addr = srv.local_addr().unwrap();
+    let handle = srv.handle();
+    tokio::spawn(async move { srv.serve().await });
+    handle.ready().await;
+
+    let (read, write) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut client = Client {
+        tw: TWriter::new(Box::pin(write), MSIZE),
+        rr: RReader::new(Box::pin(read), MSIZE),
+    };
+    let r = client
+        .rpc(T::Version(0xFFFF, MSIZE, "9P2000.u".parse().unwrap()))
+        .await;
+    assert!(matches!(r, R::Version(_, MSIZE, _)), "{:?}", r);
+
+    let r = client
+        .rpc(T::Attach(1, 1, !0, "bench".to_owned(), "".to_owned(), 0))
+        .await;
+    assert!(matches!(r, R::Attach(1, _)), "{:?}", r);
+    let r = client.rpc(T::Walk(2, 1, 2, vec![])).await;
+    assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+    let r = client
+        .rpc(T::Create(3, 2, "large".to_owned(), 0o644, 2, "".to_owned()))
+        .await;
+    assert!(matches!(r, R::Create(3, _, _)), "{:?}", r);
+    for offset in (0..SIZE).step_by(CHUNK) {
+        let r = client
+            .rpc(T::Write(4, 2, offset as u64, vec![0x55; CHUNK]))
+            .await;
+        assert_eq!(R::Write(4, CHUNK as u32), r);
+    }
+
+    client
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("socket_buffers");
+    group.throughput(Throughput::Bytes(SIZE as u64));
+
+    for (name, buffer) in [
+        ("default", None),
+        ("64k", Some(64 * 1024)),
+        ("4m", Some(4 * 1024 * 1024)),
+    ] {
+        let mut client = rt.block_on(setup(buffer));
+        group.bench_function(format!("read/{name}"), |b| {
+            b.iter(|| rt.block_on(client.read_all()));
+        });
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
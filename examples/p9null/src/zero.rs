@@ -19,9 +19,9 @@
 // THE SOFTWARE. }}}
 
 use arigato::{
-    raw::{Dehydrate, FileType, IoDirection, OpenMode, Qid, Stat},
+    raw::{Dialect, FileType, IoDirection, OpenMode, Qid, Stat},
     server::{
-        File as FileTrait, FileError, FileResult, Filesystem as FilesystemTrait,
+        File as FileTrait, FileError, FileResult, Filesystem as FilesystemTrait, OpContext,
         OpenFile as OpenFileTrait,
     },
 };
@@ -74,6 +74,29 @@ impl File {
             Self::HundredGig => "100gig",
         }
     }
+
+    async fn open_as(&mut self, om: OpenMode, dialect: Dialect) -> FileResult<OpenFile> {
+        match self {
+            Self::Directory => {
+                match om.direction() {
+                    IoDirection::Read => {}
+                    _ => return Err(FileError(1, "EPERM".to_owned())),
+                }
+
+                let mut ent = Cursor::new(vec![]);
+
+                for f in [Self::Zero, Self::Gig, Self::TenGig, Self::HundredGig] {
+                    f.stat().await?.dehydrate_as(&mut ent, dialect).unwrap();
+                }
+
+                Ok(OpenFile::Cursor(ent))
+            }
+            Self::Zero => Ok(OpenFile::Zero),
+            Self::Gig => Ok(OpenFile::Gig),
+            Self::TenGig => Ok(OpenFile::TenGig),
+            Self::HundredGig => Ok(OpenFile::HundredGig),
+        }
+    }
 }
 
 impl FileTrait for File {
@@ -112,9 +135,9 @@ impl FileTrait for File {
         Ok(())
     }
 
-    async fn walk(&self, path: &[&str]) -> FileResult<(Option<Self>, Vec<Self>)> {
+    async fn walk(&self, path: &[&str]) -> FileResult<(FileResult<Self>, Vec<Self>)> {
         if path.is_empty() {
-            return Ok((Some(self.clone()), vec![]));
+            return Ok((Ok(self.clone()), vec![]));
         }
 
         match self {
@@ -125,10 +148,10 @@ impl FileTrait for File {
 
                 let path = path[0];
                 match path {
-                    "zero" => return Ok((Some(Self::Zero), vec![self.clone()])),
-                    "1gig" => return Ok((Some(Self::Gig), vec![self.clone()])),
-                    "10gig" => return Ok((Some(Self::TenGig), vec![self.clone()])),
-                    "100gig" => return Ok((Some(Self::HundredGig), vec![self.clone()])),
+                    "zero" => return Ok((Ok(Self::Zero), vec![self.clone()])),
+                    "1gig" => return Ok((Ok(Self::Gig), vec![self.clone()])),
+                    "10gig" => return Ok((Ok(Self::TenGig), vec![self.clone()])),
+                    "100gig" => return Ok((Ok(Self::HundredGig), vec![self.clone()])),
                     _ => {}
                 }
             }
@@ -154,27 +177,15 @@ impl FileTrait for File {
     }
 
     async fn open(&mut self, om: OpenMode) -> FileResult<OpenFile> {
-        match self {
-            Self::Directory => {
-                match om.direction() {
-                    IoDirection::Read => {}
-                    _ => return Err(FileError(1, "EPERM".to_owned())),
-                }
-
-                let mut ent = Cursor::new(vec![]);
-
-                Self::Zero.stat().await?.dehydrate(&mut ent).unwrap();
-                Self::Gig.stat().await?.dehydrate(&mut ent).unwrap();
-                Self::TenGig.stat().await?.dehydrate(&mut ent).unwrap();
-                Self::HundredGig.stat().await?.dehydrate(&mut ent).unwrap();
+        self.open_as(om, Dialect::Unix).await
+    }
 
-                Ok(OpenFile::Cursor(ent))
-            }
-            Self::Zero => Ok(OpenFile::Zero),
-            Self::Gig => Ok(OpenFile::Gig),
-            Self::TenGig => Ok(OpenFile::TenGig),
-            Self::HundredGig => Ok(OpenFile::HundredGig),
-        }
+    async fn open_with_context(
+        &mut self,
+        ctx: &OpContext<'_>,
+        om: OpenMode,
+    ) -> FileResult<OpenFile> {
+        self.open_as(om, ctx.dialect).await
     }
 }
 
@@ -20,9 +20,9 @@
 
 use super::clean;
 use arigato::{
-    raw::{Dehydrate, FileType, IoDirection, OpenMode, Qid, Stat},
+    raw::{Dialect, FileType, IoDirection, OpenMode, Qid, Stat},
     server::{
-        File as FileTrait, FileError, FileResult, Filesystem as FilesystemTrait,
+        File as FileTrait, FileError, FileResult, Filesystem as FilesystemTrait, OpContext,
         OpenFile as OpenFileTrait,
     },
 };
@@ -38,39 +38,74 @@ use std::{
 #[derive(Clone, Debug)]
 pub struct FileServer {
     root: PathBuf,
-    follow_symlinks: bool,
+    follow_on_open: bool,
+    follow_on_stat: bool,
+    max_dir_size: Option<usize>,
 }
 
 ///
 pub struct FileServerBuilder {
     root: PathBuf,
-    follow_symlinks: bool,
+    follow_on_open: bool,
+    follow_on_stat: bool,
+    max_dir_size: Option<usize>,
 }
 
 impl FileServer {
     pub fn builder(root: &Path) -> FileServerBuilder {
         FileServerBuilder {
             root: root.to_owned(),
-            follow_symlinks: false,
+            follow_on_open: false,
+            follow_on_stat: false,
+            max_dir_size: None,
         }
     }
 }
 
 impl FileServerBuilder {
-    pub fn follow_symlinks(mut self, follow: bool) -> Self {
-        self.follow_symlinks = follow;
+    /// Resolve symlinks everywhere, or nowhere. This sets both
+    /// [FileServerBuilder::follow_on_open] and
+    /// [FileServerBuilder::follow_on_stat].
+    pub fn follow_symlinks(self, follow: bool) -> Self {
+        self.follow_on_open(follow).follow_on_stat(follow)
+    }
+
+    /// Resolve symlinks when walking to and opening a file, so a symlink to
+    /// a regular file can be read through.
+    pub fn follow_on_open(mut self, follow: bool) -> Self {
+        self.follow_on_open = follow;
+        self
+    }
+
+    /// Resolve symlinks when answering a Tstat. When this is off, stat is
+    /// an lstat, and symlinks show up as links with their target as the
+    /// extension.
+    pub fn follow_on_stat(mut self, follow: bool) -> Self {
+        self.follow_on_stat = follow;
+        self
+    }
+
+    /// Refuse to open a directory whose listing would take more than
+    /// `max_dir_size` bytes, with EFBIG, rather than holding all of it in
+    /// memory. By default, listings can be any size.
+    pub fn max_dir_size(mut self, max_dir_size: Option<usize>) -> Self {
+        self.max_dir_size = max_dir_size;
         self
     }
 
     pub fn build(self) -> FileServer {
         let Self {
             root,
-            follow_symlinks,
+            follow_on_open,
+            follow_on_stat,
+            max_dir_size,
         } = self;
 
         FileServer {
             root,
-            follow_symlinks,
+            follow_on_open,
+            follow_on_stat,
+            max_dir_size,
         }
     }
 }
@@ -146,7 +181,7 @@ impl File {
             return Err(FileError(18, "EXDEV".to_owned()));
         }
 
-        let meta = fs.meta(&path)?;
+        let meta = fs.meta(&path, fs.follow_on_open)?;
         let qid = Self::qid_for_file(&meta);
         Ok(Self {
             path: path.to_owned(),
@@ -155,38 +190,60 @@ impl File {
         })
     }
 
-    async fn open_dir(&mut self, om: OpenMode) -> FileResult<OpenFile> {
+    async fn open_dir(&mut self, om: OpenMode, dialect: Dialect) -> FileResult<OpenFile> {
         match om.direction() {
             IoDirection::Read => {}
             _ => return Err(FileError(1, "EPERM".to_owned())),
         }
 
+        // go through the directory an entry at a time, so that a listing
+        // over the cap is given up on before it's all been read in.
         let mut ent = Cursor::new(vec![]);
-        for dirent in std::fs::read_dir(&self.path)?.into_iter() {
-            let stat = Self::new(self.filesystem.clone(), &dirent?.path())?
-                .stat()
-                .await?;
-            match stat.dehydrate(&mut ent) {
+        for dirent in std::fs::read_dir(&self.path)? {
+            let stat = self.entry(&dirent?)?.stat().await?;
+            match stat.dehydrate_as(&mut ent, dialect) {
                 Ok(_) => {}
                 Err(_) => return Err(FileError(22, "EINVAL".to_owned())),
             }
+            match self.filesystem.max_dir_size {
+                Some(max) if ent.get_ref().len() > max => {
+                    return Err(FileError(27, "EFBIG".to_owned()));
+                }
+                _ => {}
+            }
         }
         Ok(OpenFile::Cursor(true, ent))
     }
 
+    /// File for an entry of this directory.
+    fn entry(&self, dirent: &std::fs::DirEntry) -> FileResult<Self> {
+        Self::new(self.filesystem.clone(), &dirent.path())
+    }
+
+    async fn open_as(&mut self, om: OpenMode, dialect: Dialect) -> FileResult<OpenFile> {
+        match self.qid.ty {
+            FileType::File => self.open_file(om).await,
+            FileType::Dir => self.open_dir(om, dialect).await,
+            _ => Err(FileError(1, "EPERM".to_owned())),
+        }
+    }
+
     async fn open_file(&mut self, om: OpenMode) -> FileResult<OpenFile> {
         match om.direction() {
             IoDirection::Read => {}
             _ => return Err(FileError(1, "EPERM".to_owned())),
         }
 
-        Ok(OpenFile::File(std::fs::File::open(&self.path)?))
+        let oo = om
+            .to_open_options()
+            .map_err(|_| FileError(22, "EINVAL".to_owned()))?;
+        Ok(OpenFile::File(oo.open(&self.path)?))
     }
 }
 
 impl FileServer {
-    fn meta(&self, path: &Path) -> FileResult<Metadata> {
-        Ok(if self.follow_symlinks {
+    fn meta(&self, path: &Path, follow: bool) -> FileResult<Metadata> {
+        Ok(if follow {
             std::fs::metadata(path)?
         } else {
             std::fs::symlink_metadata(path)?
@@ -198,13 +255,19 @@ impl FileTrait for File {
     type OpenFile = OpenFile;
 
     async fn stat(&self) -> FileResult<Stat> {
-        // use the cached qid here rather than reworking the qid based on
-        // the filesystem again; this may be a mistake.
+        let fs = &self.filesystem;
+        let meta = fs.meta(&self.path, fs.follow_on_stat)?;
 
-        let qid = self.qid.clone();
+        // use the cached qid here rather than reworking the qid based on
+        // the filesystem again; this may be a mistake. If walk and stat
+        // disagree on following symlinks the cached qid is of the wrong
+        // file, so that case does get reworked.
+        let qid = if fs.follow_on_open == fs.follow_on_stat {
+            self.qid.clone()
+        } else {
+            Self::qid_for_file(&meta)
+        };
         let ty = qid.ty;
-
-        let meta = self.filesystem.meta(&self.path)?;
         let mut sb = Stat::builder(
             self.path
                 .file_name()
@@ -238,13 +301,21 @@ impl FileTrait for File {
         Ok(sb.build())
     }
 
+    async fn readdir(&self) -> FileResult<Vec<Stat>> {
+        let mut stats = vec![];
+        for dirent in std::fs::read_dir(&self.path)? {
+            stats.push(self.entry(&dirent?)?.stat().await?);
+        }
+        Ok(stats)
+    }
+
     async fn wstat(&mut self, _s: &Stat) -> FileResult<()> {
         Err(FileError(1, "EPERM".to_owned()))
     }
 
-    async fn walk(&self, path: &[&str]) -> FileResult<(Option<Self>, Vec<Self>)> {
+    async fn walk(&self, path: &[&str]) -> FileResult<(FileResult<Self>, Vec<Self>)> {
         if path.is_empty() {
-            return Ok((Some(self.clone()), vec![]));
+            return Ok((Ok(self.clone()), vec![]));
         }
 
         let mut my_path = self.path.clone();
@@ -254,16 +325,13 @@ impl FileTrait for File {
             my_path.push(part);
             walked_path.push(match Self::new(self.filesystem.clone(), &my_path) {
                 Ok(v) => v,
-                Err(_) => {
-                    return Ok((None, walked_path));
+                Err(err) => {
+                    return Ok((Err(err), walked_path));
                 }
             });
         }
 
-        Ok((
-            Self::new(self.filesystem.clone(), &my_path).ok(),
-            walked_path,
-        ))
+        Ok((Self::new(self.filesystem.clone(), &my_path), walked_path))
     }
 
     async fn unlink(&mut self) -> FileResult<()> {
@@ -282,11 +350,15 @@ impl FileTrait for File {
     }
 
     async fn open(&mut self, om: OpenMode) -> FileResult<Self::OpenFile> {
-        match self.qid.ty {
-            FileType::File => self.open_file(om).await,
-            FileType::Dir => self.open_dir(om).await,
-            _ => Err(FileError(1, "EPERM".to_owned())),
-        }
+        self.open_as(om, Dialect::Unix).await
+    }
+
+    async fn open_with_context(
+        &mut self,
+        ctx: &OpContext<'_>,
+        om: OpenMode,
+    ) -> FileResult<Self::OpenFile> {
+        self.open_as(om, ctx.dialect).await
     }
 
     fn qid(&self) -> Qid {
@@ -302,4 +374,98 @@ impl FilesystemTrait for FileServer {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    /// Make a root holding `file` (containing "hello") and `link`, a
+    /// symlink to it.
+    fn root(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("p9srv-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), b"hello").unwrap();
+        std::os::unix::fs::symlink(dir.join("file"), dir.join("link")).unwrap();
+        clean(&dir)
+    }
+
+    fn link(fs: FileServer) -> File {
+        block_on(async {
+            let root = fs.attach("", "", 0).await.unwrap();
+            let (link, _) = root.walk(&["link"]).await.unwrap();
+            link.unwrap()
+        })
+    }
+
+    #[test]
+    fn follow_on_open() {
+        let dir = root("open");
+        let mut link = link(FileServer::builder(&dir).follow_on_open(true).build());
+        assert!(matches!(link.qid().ty, FileType::File));
+
+        block_on(async {
+            let mut of = link.open(OpenMode::from(0)).await.unwrap();
+            let mut buf = [0u8; 16];
+            let n = of.read_at(&mut buf, 0).await.unwrap();
+            assert_eq!(&buf[..n as usize], b"hello");
+
+            // stat was left as an lstat, so this is still a link.
+            let stat = link.stat().await.unwrap();
+            assert!(matches!(stat.qid.ty, FileType::Link));
+            assert_eq!(stat.extension, dir.join("file").to_str().unwrap());
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn follow_on_stat() {
+        let dir = root("stat");
+        let mut link = link(FileServer::builder(&dir).follow_on_stat(true).build());
+        assert!(matches!(link.qid().ty, FileType::Link));
+
+        block_on(async {
+            assert!(link.open(OpenMode::from(0)).await.is_err());
+
+            let stat = link.stat().await.unwrap();
+            assert!(matches!(stat.qid.ty, FileType::File));
+            assert_eq!(stat.length, 5);
+            assert_eq!(stat.extension, "");
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn max_dir_size() {
+        let dir = root("max-dir-size");
+        for name in 0..100 {
+            std::fs::write(dir.join(format!("file-{name}")), b"").unwrap();
+        }
+
+        block_on(async {
+            let fs = FileServer::builder(&dir).max_dir_size(Some(1024)).build();
+            let mut root = fs.attach("", "", 0).await.unwrap();
+            match root.open(OpenMode::from(0)).await {
+                Err(err) => assert_eq!(err.errno, 27),
+                Ok(_) => panic!("opened a listing over the cap"),
+            }
+
+            // with room for it, the same listing opens just fine.
+            let fs = FileServer::builder(&dir)
+                .max_dir_size(Some(1 << 20))
+                .build();
+            let mut root = fs.attach("", "", 0).await.unwrap();
+            assert!(root.open(OpenMode::from(0)).await.is_ok());
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
 // vim: foldmethod=marker
@@ -31,6 +31,9 @@ mod file_server;
 use clean::clean;
 use file_server::FileServer;
 
+/// Largest directory listing we'll hold in memory, in bytes.
+const MAX_DIR_SIZE: usize = 16 * 1024 * 1024;
+
 #[tokio::main]
 async fn main() {
     let log_level = "info";
@@ -57,7 +60,10 @@ async fn main() {
 
         srv = srv.with_filesystem(
             &chunk[0],
-            FileServer::builder(&path).follow_symlinks(true).build(),
+            FileServer::builder(&path)
+                .follow_symlinks(true)
+                .max_dir_size(Some(MAX_DIR_SIZE))
+                .build(),
         );
     }
 
@@ -0,0 +1,264 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use super::{Client, ReadDir, Result};
+use crate::{
+    raw::{Fid, OpenMode, Stat},
+    server::FileError,
+};
+use std::sync::Arc;
+
+/// Largest read or write to send in one message to a file open with
+/// `iounit`.
+fn chunk_size(client: &Client, iounit: u32) -> u32 {
+    let io_header_size = client.dialect().limits().io_header_size;
+    let max = client.msize().saturating_sub(io_header_size).max(1);
+    match iounit {
+        0 => max,
+        iounit => iounit.min(max),
+    }
+}
+
+/// Split a `/`-separated path into the elements to walk.
+fn elements(path: &str) -> Vec<&str> {
+    path.split('/').filter(|e| !e.is_empty()).collect()
+}
+
+/// Directory on a 9P server, held open by a fid. Paths given to a Dir are
+/// relative to it, with `/` between elements.
+///
+/// As there is no way to clunk a fid on drop, call [Dir::close] once done
+/// with it, or the fid stays in use for as long as the connection is up.
+pub struct Dir {
+    client: Arc<Client>,
+    fid: Fid,
+}
+
+impl Dir {
+    /// Attach to the filesystem `aname` as `uname`, returning its root.
+    pub async fn attach(client: Arc<Client>, uname: &str, aname: &str) -> Result<Self> {
+        let (fid, _) = client.attach(uname, aname, !0).await?;
+        Ok(Self { client, fid })
+    }
+
+    /// The directory at `path`.
+    pub async fn open_dir(&self, path: &str) -> Result<Dir> {
+        let (fid, _) = self.client.walk(self.fid, &elements(path)).await?;
+        Ok(Dir {
+            client: self.client.clone(),
+            fid,
+        })
+    }
+
+    /// Open the file at `path`.
+    pub async fn open(&self, path: &str, mode: OpenMode) -> Result<RemoteFile> {
+        let (fid, _) = self.client.walk(self.fid, &elements(path)).await?;
+        match self.client.open(fid, mode).await {
+            Ok((_, iounit)) => Ok(RemoteFile {
+                client: self.client.clone(),
+                fid,
+                iounit,
+                offset: 0,
+            }),
+            Err(e) => {
+                let _ = self.client.clunk(fid).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Every entry of this directory.
+    pub async fn read_dir(&self) -> Result<Vec<Stat>> {
+        let listing = self.open("", OpenMode::read()).await?;
+        let count = chunk_size(&listing.client, listing.iounit);
+        let mut entries = ReadDir::new(&listing.client, listing.fid, count);
+        let mut stats = vec![];
+        let stats = loop {
+            match entries.next().await {
+                Some(Ok(stat)) => stats.push(stat),
+                Some(Err(e)) => break Err(e),
+                None => break Ok(stats),
+            }
+        };
+        drop(entries);
+        listing.close().await?;
+        stats
+    }
+
+    /// Metadata about this directory.
+    pub async fn metadata(&self) -> Result<Stat> {
+        self.client.stat(self.fid).await
+    }
+
+    /// Clunk the fid held by this Dir.
+    pub async fn close(self) -> Result<()> {
+        self.client.clunk(self.fid).await
+    }
+}
+
+/// Open file on a 9P server, with a current offset which reads and writes
+/// pick up from, like a [std::fs::File]. Reads and writes are split up into
+/// as many messages as it takes, going by the iounit of the file and the
+/// msize of the connection.
+///
+/// As with [Dir], call [RemoteFile::close] once done with it.
+pub struct RemoteFile {
+    client: Arc<Client>,
+    fid: Fid,
+    iounit: u32,
+    offset: u64,
+}
+
+impl RemoteFile {
+    /// Read into `buf` from the current offset, in a single message,
+    /// returning how many bytes were read. 0 is the end of the file.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let count = chunk_size(&self.client, self.iounit).min(buf.len() as u32);
+        let data = self.client.read(self.fid, self.offset, count).await?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    /// Read from the current offset until the end of the file.
+    pub async fn read_to_end(&mut self) -> Result<Vec<u8>> {
+        let count = chunk_size(&self.client, self.iounit);
+        let mut out = vec![];
+        loop {
+            let data = self.client.read(self.fid, self.offset, count).await?;
+            if data.is_empty() {
+                return Ok(out);
+            }
+            self.offset += data.len() as u64;
+            out.extend_from_slice(&data);
+        }
+    }
+
+    /// Write all of `data` at the current offset.
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        let count = chunk_size(&self.client, self.iounit) as usize;
+        let mut data = data;
+        while !data.is_empty() {
+            let chunk = &data[..count.min(data.len())];
+            let n = self.client.write(self.fid, self.offset, chunk).await? as usize;
+            if n == 0 {
+                return Err(FileError(5, "EIO".to_owned()).into());
+            }
+            self.offset += n as u64;
+            data = &data[n.min(data.len())..];
+        }
+        Ok(())
+    }
+
+    /// Move the current offset to `offset` bytes from the start of the
+    /// file.
+    pub fn seek(&mut self, offset: u64) {
+        self.offset = offset;
+    }
+
+    /// Metadata about this file.
+    pub async fn metadata(&self) -> Result<Stat> {
+        self.client.stat(self.fid).await
+    }
+
+    /// Clunk the fid held by this RemoteFile.
+    pub async fn close(self) -> Result<()> {
+        self.client.clunk(self.fid).await
+    }
+}
+
+// the tests talk over UNIX sockets.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{Client, Dir};
+    use crate::{
+        client::ClientError,
+        fs::{create_dir_all, MemFilesystem},
+        raw::{FileType, OpenMode},
+        server::{
+            testing::{block_on, socket_path},
+            AsyncServer, File, FileError, Filesystem, OpenFile,
+        },
+    };
+    use std::sync::Arc;
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn files_and_dirs() {
+        block_on(async {
+            let fs = MemFilesystem::new();
+            let root = fs.attach("", "", 0).await.unwrap();
+            let (mut docs, _) = create_dir_all(&root, &["docs"], 0o755).await.unwrap();
+            let readme: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+            let mut file = docs
+                .create("readme", 0o644, FileType::File, OpenMode::from(1), "")
+                .await
+                .unwrap();
+            let mut of = file.open(OpenMode::from(1)).await.unwrap();
+            of.write_at(&mut readme.clone(), 0).await.unwrap();
+
+            let path = socket_path("client.sock");
+            let srv = AsyncServer::builder()
+                .with_unix_listen_address(&path)
+                .with_filesystem("", fs)
+                .build()
+                .await
+                .unwrap();
+            tokio::spawn(async move { srv.serve().await });
+
+            let (read, write) = UnixStream::connect(&path).await.unwrap().into_split();
+            // small enough that the readme takes several reads.
+            let client = Arc::new(Client::new(read, write, 256).await.unwrap());
+            assert_eq!(256, client.msize());
+            let root = Dir::attach(client, "user", "").await.unwrap();
+
+            let mut file = root.open("docs/readme", OpenMode::from(0)).await.unwrap();
+            assert_eq!(readme, file.read_to_end().await.unwrap());
+            assert_eq!(1000, file.metadata().await.unwrap().length);
+            file.close().await.unwrap();
+
+            let mut file = root.open("/docs/readme", OpenMode::from(2)).await.unwrap();
+            file.seek(998);
+            file.write(&[0xAA; 300]).await.unwrap();
+            file.seek(0);
+            let mut buf = [0; 4];
+            assert_eq!(4, file.read(&mut buf).await.unwrap());
+            assert_eq!([0, 1, 2, 3], buf);
+            file.close().await.unwrap();
+
+            let docs = root.open_dir("docs").await.unwrap();
+            let stats = docs.read_dir().await.unwrap();
+            assert_eq!(1, stats.len());
+            assert_eq!(("readme", 1298), (stats[0].name.as_str(), stats[0].length));
+            assert_eq!("docs", docs.metadata().await.unwrap().name);
+            docs.close().await.unwrap();
+
+            assert!(matches!(
+                root.open("missing", OpenMode::from(0)).await,
+                Err(ClientError::FileError(FileError { errno: 2, .. }))
+            ));
+            root.close().await.unwrap();
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+}
+
+// vim: foldmethod=marker
@@ -0,0 +1,97 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Client side of the 9P protocol: a [Client] which speaks raw T and R
+//! messages to a server, and the [Dir] and [RemoteFile] wrappers for
+//! treating what the server exports like a filesystem.
+
+mod file;
+mod read_dir;
+mod rpc;
+
+pub use file::{Dir, RemoteFile};
+pub use read_dir::ReadDir;
+pub use rpc::Client;
+
+use crate::{
+    raw::{RError, TError, R},
+    server::FileError,
+};
+
+/// Result type returned by the client.
+pub type Result<RetT> = std::result::Result<RetT, ClientError>;
+
+/// Possible Errors that may be returned by the client.
+#[derive(Debug)]
+pub enum ClientError {
+    /// Failed to come to an agreement with the server about the 9P
+    /// protocol to use.
+    FailedToNegotiate,
+
+    /// The server replied with something other than what was asked for.
+    UnexpectedReply(R),
+
+    /// Something happened below us.
+    IoError(std::io::Error),
+
+    /// 9p T Error type
+    TError(TError),
+
+    /// 9p R Error type
+    RError(RError),
+
+    /// The server replied with an Rerror.
+    FileError(FileError),
+
+    /// Every fid (or tag) is already in use.
+    Exhausted,
+}
+
+impl From<FileError> for ClientError {
+    fn from(fe: FileError) -> Self {
+        Self::FileError(fe)
+    }
+}
+
+impl From<TError> for ClientError {
+    fn from(te: TError) -> Self {
+        match te {
+            TError::IoError(ioe) => ioe.into(),
+            _ => Self::TError(te),
+        }
+    }
+}
+
+impl From<RError> for ClientError {
+    fn from(re: RError) -> Self {
+        match re {
+            RError::IoError(ioe) => ioe.into(),
+            _ => Self::RError(re),
+        }
+    }
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(ioe: std::io::Error) -> Self {
+        Self::IoError(ioe)
+    }
+}
+
+// vim: foldmethod=marker
@@ -0,0 +1,210 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use super::{Client, ClientError, Result};
+use crate::raw::{Fid, RError, Stat};
+use futures_core::Stream;
+use std::{
+    future::Future,
+    io::Cursor,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+/// Read of the next chunk of the listing, in flight.
+type Read<'client> = Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'client>>;
+
+/// [Stream] of the entries of a directory on a 9P server, parsed out of the
+/// reads of its open fid as they come in, as returned by
+/// [Client::read_dir]. A Stat which is cut off at the end of one read is
+/// picked up again in the next.
+pub struct ReadDir<'client> {
+    client: &'client Client,
+    fid: Fid,
+    count: u32,
+
+    /// Offset of the next read.
+    offset: u64,
+
+    /// Bytes read, but not yet parsed into a Stat.
+    buf: Vec<u8>,
+
+    /// Set once the server has no more to read, or something went wrong.
+    done: bool,
+
+    /// Read of the next chunk, if one has been started.
+    read: Option<Read<'client>>,
+}
+
+impl<'client> ReadDir<'client> {
+    /// Read the entries of the open directory `fid`, `count` bytes at a
+    /// time.
+    pub(super) fn new(client: &'client Client, fid: Fid, count: u32) -> Self {
+        Self {
+            client,
+            fid,
+            count,
+            offset: 0,
+            buf: vec![],
+            done: false,
+            read: None,
+        }
+    }
+
+    /// Size of the Stat at the start of what has been read, its size
+    /// prefix included, if all of it has been read.
+    fn complete(&self) -> Option<usize> {
+        let prefix = self.buf.get(..2)?;
+        let size = 2 + u16::from_le_bytes([prefix[0], prefix[1]]) as usize;
+        (self.buf.len() >= size).then_some(size)
+    }
+
+    /// Wait for the next entry, returning None once there are no more.
+    /// This is the same as `StreamExt::next`, for callers without it.
+    pub async fn next(&mut self) -> Option<Result<Stat>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for ReadDir<'_> {
+    type Item = Result<Stat>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Stat>>> {
+        let this = &mut *self;
+        while !this.done {
+            if let Some(size) = this.complete() {
+                let mut c = Cursor::new(&this.buf[..size]);
+                let stat = Stat::hydrate_as(&mut c, this.client.dialect());
+                this.buf.drain(..size);
+                return Poll::Ready(Some(stat.map_err(|e| RError::from(e).into())));
+            }
+
+            let (client, fid, offset, count) = (this.client, this.fid, this.offset, this.count);
+            let read = this
+                .read
+                .get_or_insert_with(|| Box::pin(client.read(fid, offset, count)));
+            let data = ready!(read.as_mut().poll(cx));
+            this.read = None;
+            match data {
+                Ok(data) if data.is_empty() => {
+                    this.done = true;
+                    if !this.buf.is_empty() {
+                        // the listing ended partway through a Stat.
+                        let eof = std::io::ErrorKind::UnexpectedEof;
+                        return Poll::Ready(Some(Err(ClientError::IoError(eof.into()))));
+                    }
+                }
+                Ok(data) => {
+                    this.offset += data.len() as u64;
+                    this.buf.extend_from_slice(&data);
+                }
+                Err(e) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        client::{Client, ClientError},
+        raw::{Dehydrate, FileType, OpenMode, Qid, Stat, IOHDRSZ},
+        server::{testing::block_on, testing::TestFs, AsyncServer},
+    };
+    use futures_util::StreamExt;
+    use std::io::{Cursor, ErrorKind};
+    use tokio::net::TcpStream;
+
+    // usize::is_multiple_of is too new to lean on.
+    #[allow(clippy::manual_is_multiple_of)]
+    #[test]
+    fn stats_across_reads() {
+        block_on(async {
+            const MSIZE: u32 = 256;
+            let stats: Vec<Stat> = (0..10)
+                .map(|i| {
+                    let name = format!("{i}-{}", "x".repeat(40));
+                    Stat::builder(&name, Qid::new(FileType::File, 0, i)).build()
+                })
+                .collect();
+            let mut listing = Cursor::new(vec![]);
+            for stat in &stats {
+                stat.dehydrate(&mut listing).unwrap();
+            }
+            let listing = listing.into_inner();
+
+            // the stats don't line up with the reads.
+            let count = (MSIZE - IOHDRSZ) as usize;
+            let size = listing.len() / stats.len();
+            assert!(listing.len() > count && count % size != 0);
+
+            let fs = TestFs::new(&[("listing", &listing), ("short", &listing[..count + 10])]);
+            let srv = AsyncServer::builder()
+                .with_tcp_listen_address("127.0.0.1:0")
+                .with_filesystem("", fs)
+                .build()
+                .await
+                .unwrap();
+            let addr = srv.local_addr().unwrap();
+            tokio::spawn(async move { srv.serve().await });
+
+            let (read, write) = TcpStream::connect(addr).await.unwrap().into_split();
+            let client = Client::new(read, write, MSIZE).await.unwrap();
+            let (root, _) = client.attach("user", "", !0).await.unwrap();
+
+            let (fid, _) = client.walk(root, &["listing"]).await.unwrap();
+            client.open(fid, OpenMode::read()).await.unwrap();
+            let mut entries = client.read_dir(fid);
+            for stat in &stats {
+                assert_eq!(stat, &entries.next().await.unwrap().unwrap());
+            }
+            assert!(entries.next().await.is_none());
+            assert!(entries.next().await.is_none());
+
+            // it's a Stream like any other.
+            let listed: Vec<Stat> = client
+                .read_dir(fid)
+                .map(|stat| stat.unwrap())
+                .collect()
+                .await;
+            assert_eq!(stats, listed);
+            client.clunk(fid).await.unwrap();
+
+            // a listing which stops partway through a stat is an error.
+            let (fid, _) = client.walk(root, &["short"]).await.unwrap();
+            client.open(fid, OpenMode::read()).await.unwrap();
+            let mut entries = client.read_dir(fid);
+            for stat in &stats[..count / size] {
+                assert_eq!(stat, &entries.next().await.unwrap().unwrap());
+            }
+            assert!(matches!(
+                entries.next().await,
+                Some(Err(ClientError::IoError(e))) if e.kind() == ErrorKind::UnexpectedEof
+            ));
+            assert!(entries.next().await.is_none());
+        });
+    }
+}
+
+// vim: foldmethod=marker
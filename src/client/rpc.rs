@@ -0,0 +1,459 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use super::{ClientError, ReadDir, Result};
+use crate::{
+    raw::{Dialect, Fid, OpenMode, Qid, Stat, Tag, Version, GETATTR_BASIC, NOFID, NOTAG, R, T},
+    server::{errno_name, FileError, RReader, TWriter},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Mutex,
+};
+
+/// Version of the protocol the Client asks for, unless told otherwise.
+const VERSION: &str = "9P2000.u";
+
+/// Allocator of fids or tags, below `limit` (which is reserved to mean
+/// "none"). Ids given back are handed out again before any new ones.
+struct Ids {
+    next: u32,
+    limit: u32,
+    free: Vec<u32>,
+}
+
+impl Ids {
+    fn new(limit: u32) -> Self {
+        Self {
+            next: 0,
+            limit,
+            free: vec![],
+        }
+    }
+
+    fn take(&mut self) -> Option<u32> {
+        if let Some(id) = self.free.pop() {
+            return Some(id);
+        }
+        if self.next == self.limit {
+            return None;
+        }
+        self.next += 1;
+        Some(self.next - 1)
+    }
+
+    fn give(&mut self, id: u32) {
+        self.free.push(id);
+    }
+}
+
+/// Connection state, only touched by one request at a time.
+struct Conn {
+    tw: TWriter,
+    rr: RReader,
+    tags: Ids,
+}
+
+/// Connection to a 9P server, speaking raw T and R messages. Each method
+/// is one request, with the tag chosen by the Client; fids are picked by
+/// the Client too, and are the caller's to clunk. Once clunked, a fid may
+/// be handed out again.
+///
+/// Requests are sent one at a time: each waits for its reply before the
+/// next one goes out.
+pub struct Client {
+    conn: Mutex<Conn>,
+    fids: std::sync::Mutex<Ids>,
+    msize: u32,
+    version: Version,
+    dialect: Dialect,
+}
+
+impl Client {
+    /// Negotiate a version with the server on the other end of `read` and
+    /// `write`, asking for an msize of `msize`, and 9P2000.u.
+    pub async fn new<ReadT, WriteT>(read: ReadT, write: WriteT, msize: u32) -> Result<Self>
+    where
+        ReadT: AsyncRead + Send + 'static,
+        WriteT: AsyncWrite + Send + 'static,
+    {
+        Self::new_with_version(read, write, msize, &VERSION.parse().unwrap()).await
+    }
+
+    /// Negotiate a version with the server on the other end of `read` and
+    /// `write`, asking for an msize of `msize`, and `version`. The server
+    /// may settle on another dialect than the one asked for, which is then
+    /// used for the rest of the connection; see [Client::dialect].
+    pub async fn new_with_version<ReadT, WriteT>(
+        read: ReadT,
+        write: WriteT,
+        msize: u32,
+        version: &Version,
+    ) -> Result<Self>
+    where
+        ReadT: AsyncRead + Send + 'static,
+        WriteT: AsyncWrite + Send + 'static,
+    {
+        let mut tw = TWriter::new(Box::pin(write), msize);
+        let mut rr = RReader::new(Box::pin(read), msize);
+
+        tw.send(T::Version(NOTAG, msize, version.clone())).await?;
+        let (msize, version) = match rr.next().await? {
+            R::Version(NOTAG, server_msize, version) if server_msize <= msize => {
+                (server_msize, version)
+            }
+            R::Version(_, _, _) => return Err(ClientError::FailedToNegotiate),
+            r => return Err(ClientError::UnexpectedReply(r)),
+        };
+        let dialect = Dialect::of(&version).ok_or(ClientError::FailedToNegotiate)?;
+        tw.set_msize(msize);
+        rr.set_msize(msize);
+        tw.set_dialect(dialect);
+        rr.set_dialect(dialect);
+
+        Ok(Self {
+            conn: Mutex::new(Conn {
+                tw,
+                rr,
+                tags: Ids::new(NOTAG as u32),
+            }),
+            fids: std::sync::Mutex::new(Ids::new(NOFID)),
+            msize,
+            version,
+            dialect,
+        })
+    }
+
+    /// msize agreed on with the server.
+    pub fn msize(&self) -> u32 {
+        self.msize
+    }
+
+    /// Version agreed on with the server.
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    /// [Dialect] of the version agreed on with the server, which decides
+    /// how a Stat is laid out, and which messages the server understands.
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    fn fid(&self) -> Option<Fid> {
+        self.fids.lock().unwrap().take()
+    }
+
+    fn free_fid(&self, fid: Fid) {
+        self.fids.lock().unwrap().give(fid)
+    }
+
+    /// Send the T built by `t` with a fresh tag, and wait for its reply.
+    /// An Rerror (or Rlerror) comes back as a [ClientError::FileError].
+    async fn rpc<F>(&self, t: F) -> Result<R>
+    where
+        F: FnOnce(Tag) -> T,
+    {
+        let mut conn = self.conn.lock().await;
+        let tag = conn.tags.take().ok_or(ClientError::Exhausted)? as Tag;
+        let r = Self::round_trip(&mut conn, t(tag)).await;
+        conn.tags.give(tag as u32);
+        match r? {
+            R::Error(rtag, desc, errno) if rtag == tag => Err(FileError(errno, desc).into()),
+            R::LError(rtag, ecode) if rtag == tag => {
+                let desc = errno_name(ecode).unwrap_or_default().to_owned();
+                Err(FileError(ecode, desc).into())
+            }
+            r if r.tag() == tag => Ok(r),
+            r => Err(ClientError::UnexpectedReply(r)),
+        }
+    }
+
+    async fn round_trip(conn: &mut Conn, t: T) -> Result<R> {
+        conn.tw.send(t).await?;
+        Ok(conn.rr.next().await?)
+    }
+
+    /// Run a request which brings `fid` into use, giving `fid` back if it
+    /// fails.
+    async fn claiming<RetT>(
+        &self,
+        fid: Fid,
+        f: impl std::future::Future<Output = Result<RetT>>,
+    ) -> Result<RetT> {
+        let r = f.await;
+        if r.is_err() {
+            self.free_fid(fid);
+        }
+        r
+    }
+
+    /// Attach to the filesystem `aname` as `uname` (and `n_uname`),
+    /// returning the fid of its root.
+    pub async fn attach(&self, uname: &str, aname: &str, n_uname: u32) -> Result<(Fid, Qid)> {
+        let fid = self.fid().ok_or(ClientError::Exhausted)?;
+        let t = |tag| T::Attach(tag, fid, NOFID, uname.to_owned(), aname.to_owned(), n_uname);
+        self.claiming(fid, async {
+            match self.rpc(t).await? {
+                R::Attach(_, qid) => Ok((fid, qid)),
+                r => Err(ClientError::UnexpectedReply(r)),
+            }
+        })
+        .await
+    }
+
+    /// Walk `path` from `fid`, returning the new fid and the qid of every
+    /// element walked. A walk which does not make it all the way is
+    /// ENOENT, and leaves no new fid behind.
+    pub async fn walk(&self, fid: Fid, path: &[&str]) -> Result<(Fid, Vec<Qid>)> {
+        let newfid = self.fid().ok_or(ClientError::Exhausted)?;
+        let path: Vec<String> = path.iter().map(|p| p.to_string()).collect();
+        let wanted = path.len();
+        self.claiming(newfid, async {
+            match self.rpc(|tag| T::Walk(tag, fid, newfid, path)).await? {
+                R::Walk(_, qids) if qids.len() == wanted => Ok((newfid, qids)),
+                R::Walk(_, _) => Err(FileError(2, "ENOENT".to_owned()).into()),
+                r => Err(ClientError::UnexpectedReply(r)),
+            }
+        })
+        .await
+    }
+
+    /// Open `fid`, returning its qid and iounit.
+    pub async fn open(&self, fid: Fid, mode: OpenMode) -> Result<(Qid, u32)> {
+        match self.rpc(|tag| T::Open(tag, fid, mode)).await? {
+            R::Open(_, qid, iounit) => Ok((qid, iounit)),
+            r => Err(ClientError::UnexpectedReply(r)),
+        }
+    }
+
+    /// Read up to `count` bytes from the open `fid`, at `offset`.
+    pub async fn read(&self, fid: Fid, offset: u64, count: u32) -> Result<Vec<u8>> {
+        match self.rpc(|tag| T::Read(tag, fid, offset, count)).await? {
+            R::Read(_, data) => Ok(data),
+            r => Err(ClientError::UnexpectedReply(r)),
+        }
+    }
+
+    /// Write `data` to the open `fid`, at `offset`, returning how much of
+    /// it was written.
+    pub async fn write(&self, fid: Fid, offset: u64, data: &[u8]) -> Result<u32> {
+        match self
+            .rpc(|tag| T::Write(tag, fid, offset, data.to_vec()))
+            .await?
+        {
+            R::Write(_, n) => Ok(n),
+            r => Err(ClientError::UnexpectedReply(r)),
+        }
+    }
+
+    /// Read the entries of the directory open as `fid`, from the start,
+    /// as many as fit in the msize at a time.
+    pub fn read_dir(&self, fid: Fid) -> ReadDir<'_> {
+        let io_header_size = self.dialect().limits().io_header_size;
+        let count = self.msize().saturating_sub(io_header_size).max(1);
+        ReadDir::new(self, fid, count)
+    }
+
+    /// Stat `fid`. Over 9P2000.L, this is a Tgetattr, whose reply carries
+    /// no name or string uid/gid, so those come back empty.
+    pub async fn stat(&self, fid: Fid) -> Result<Stat> {
+        if self.dialect == Dialect::Linux {
+            return match self.rpc(|tag| T::GetAttr(tag, fid, GETATTR_BASIC)).await? {
+                R::GetAttr(_, attr) => Ok(attr.to_stat("")),
+                r => Err(ClientError::UnexpectedReply(r)),
+            };
+        }
+        match self.rpc(|tag| T::Stat(tag, fid)).await? {
+            R::Stat(_, stat) => Ok(stat),
+            r => Err(ClientError::UnexpectedReply(r)),
+        }
+    }
+
+    /// Clunk `fid`, which may not be used again. Even if the server replies
+    /// with an error, the fid is no longer in use.
+    pub async fn clunk(&self, fid: Fid) -> Result<()> {
+        let r = self.rpc(|tag| T::Clunk(tag, fid)).await;
+        self.free_fid(fid);
+        match r? {
+            R::Clunk(_) => Ok(()),
+            r => Err(ClientError::UnexpectedReply(r)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Client, ClientError, Ids};
+    use crate::{
+        raw::{Dialect, FileType, OpenMode, Perm, NOTAG},
+        server::{testing::block_on, testing::TestFs, AsyncServer, FileError},
+    };
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn ids_reused() {
+        let mut ids = Ids::new(2);
+        assert_eq!(Some(0), ids.take());
+        assert_eq!(Some(1), ids.take());
+        assert_eq!(None, ids.take());
+
+        ids.give(0);
+        assert_eq!(Some(0), ids.take());
+        assert_eq!(None, ids.take());
+
+        // the limit itself is never handed out.
+        let mut tags = Ids::new(NOTAG as u32);
+        tags.next = NOTAG as u32 - 1;
+        assert_eq!(Some(NOTAG as u32 - 1), tags.take());
+        assert_eq!(None, tags.take());
+    }
+
+    #[test]
+    fn negotiated_dialect() {
+        block_on(async {
+            let srv = AsyncServer::builder()
+                .with_tcp_listen_address("127.0.0.1:0")
+                .with_filesystem("", TestFs::new(&[("motd", b"hello")]))
+                .build()
+                .await
+                .unwrap();
+            let addr = srv.local_addr().unwrap();
+            tokio::spawn(async move { srv.serve().await });
+
+            // the server only speaks 9P2000.u (and 9P2000), so asking for
+            // 9P2000.L gets us 9P2000.u; asking for 9P2000 gets us that.
+            for (asked, agreed, dialect) in [
+                ("9P2000.L", "9P2000.u", Dialect::Unix),
+                ("9P2000", "9P2000", Dialect::Base),
+            ] {
+                let (read, write) = TcpStream::connect(addr).await.unwrap().into_split();
+                let client = Client::new_with_version(read, write, 8192, &asked.parse().unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(agreed, client.version().to_string());
+                assert_eq!(dialect, client.dialect());
+
+                // and everything after it is laid out to match: the
+                // Tattach, Stats, directory listings and Rerrors, which
+                // carry no errno in 9P2000.
+                let (root, _) = client.attach("user", "", !0).await.unwrap();
+                let (fid, _) = client.walk(root, &["motd"]).await.unwrap();
+                assert_eq!("motd", client.stat(fid).await.unwrap().name);
+
+                let (dir, _) = client.walk(root, &[]).await.unwrap();
+                client.open(dir, OpenMode::from(0)).await.unwrap();
+                let mut entries = client.read_dir(dir);
+                assert_eq!("motd", entries.next().await.unwrap().unwrap().name);
+                assert!(entries.next().await.is_none());
+
+                let errno = if dialect == Dialect::Base { 0 } else { 2 };
+                match client.walk(root, &["missing"]).await {
+                    Err(ClientError::FileError(e)) => {
+                        assert_eq!((errno, "ENOENT"), (e.errno, e.description.as_str()))
+                    }
+                    v => panic!("unexpected {:?}", v.map(|_| ())),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn linux_stat() {
+        block_on(async {
+            let srv = AsyncServer::builder()
+                .with_tcp_listen_address("127.0.0.1:0")
+                .with_linux_dialect(true)
+                .with_filesystem("", TestFs::new(&[("motd", b"hello")]))
+                .build()
+                .await
+                .unwrap();
+            let addr = srv.local_addr().unwrap();
+            tokio::spawn(async move { srv.serve().await });
+
+            let (read, write) = TcpStream::connect(addr).await.unwrap().into_split();
+            let client = Client::new_with_version(read, write, 8192, &"9P2000.L".parse().unwrap())
+                .await
+                .unwrap();
+            assert_eq!(Dialect::Linux, client.dialect());
+
+            // a 9P2000.L server won't answer a Tstat, so this is a Tgetattr.
+            let (root, _) = client.attach("user", "", !0).await.unwrap();
+            let (fid, qids) = client.walk(root, &["motd"]).await.unwrap();
+            let stat = client.stat(fid).await.unwrap();
+            assert_eq!(qids[0], stat.qid);
+            assert_eq!(5, stat.length);
+            assert_eq!(0o644, Perm::from(stat.mode).permissions());
+            assert_eq!(FileType::File, Perm::from(stat.mode).file_type());
+            assert_eq!("", stat.name);
+
+            let stat = client.stat(root).await.unwrap();
+            assert_eq!(FileType::Dir, Perm::from(stat.mode).file_type());
+        });
+    }
+
+    #[test]
+    fn tcp_round_trip() {
+        block_on(async {
+            let srv = AsyncServer::builder()
+                .with_tcp_listen_address("127.0.0.1:0")
+                .with_filesystem("", TestFs::new(&[("motd", b"hello")]))
+                .build()
+                .await
+                .unwrap();
+            let addr = srv.local_addr().unwrap();
+            tokio::spawn(async move { srv.serve().await });
+
+            let (read, write) = TcpStream::connect(addr).await.unwrap().into_split();
+            let client = Client::new(read, write, 8192).await.unwrap();
+            assert_eq!("9P2000.u", client.version().to_string());
+
+            let (root, _) = client.attach("user", "", !0).await.unwrap();
+            for _ in 0..3 {
+                let (fid, qids) = client.walk(root, &["motd"]).await.unwrap();
+                assert_eq!(1, qids.len());
+                // clunked fids are handed out again.
+                assert_eq!(root + 1, fid);
+
+                client.open(fid, OpenMode::from(0)).await.unwrap();
+                assert_eq!(b"hello".to_vec(), client.read(fid, 0, 64).await.unwrap());
+                assert_eq!(b"lo".to_vec(), client.read(fid, 3, 64).await.unwrap());
+                assert_eq!("motd", client.stat(fid).await.unwrap().name);
+                client.clunk(fid).await.unwrap();
+            }
+
+            // as are the fids of failed walks.
+            assert!(matches!(
+                client.walk(root, &["missing"]).await,
+                Err(ClientError::FileError(FileError { errno: 2, .. }))
+            ));
+            let (fid, _) = client.walk(root, &["motd"]).await.unwrap();
+            assert_eq!(root + 1, fid);
+
+            client.open(fid, OpenMode::from(1)).await.unwrap();
+            assert_eq!(3, client.write(fid, 5, b"!!!").await.unwrap());
+            assert_eq!(b"hello!!!".to_vec(), client.read(fid, 0, 64).await.unwrap());
+            client.clunk(fid).await.unwrap();
+            client.clunk(root).await.unwrap();
+        });
+    }
+}
+
+// vim: foldmethod=marker
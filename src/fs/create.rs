@@ -0,0 +1,122 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use crate::{
+    raw::{FileType, OpenMode, Qid},
+    server::{File, FileError, FileResult},
+};
+
+/// Walk `path` from `dir`, creating any missing directories along the way
+/// (like `mkdir -p`), with the provided permissions. This returns the final
+/// directory, along with the qid of every element of the path, whether it
+/// already existed or not.
+///
+/// This is built entirely out of [File::walk] and [File::create], so plain
+/// Tcreate stays single-level; it's for Filesystems (or their users) that
+/// want to populate a tree.
+pub async fn create_dir_all<FileT>(
+    dir: &FileT,
+    path: &[&str],
+    perm: u16,
+) -> FileResult<(FileT, Vec<Qid>)>
+where
+    FileT: File,
+{
+    let enotdir = || FileError(20, "ENOTDIR".to_owned());
+
+    let (file, walked) = dir.walk(path).await?;
+    let mut qids: Vec<Qid> = walked.iter().map(|f| f.qid()).collect();
+    match file {
+        Ok(file) if file.qid().ty != FileType::Dir => return Err(enotdir()),
+        Ok(file) => return Ok((file, qids)),
+        // only missing directories are ours to create.
+        Err(err) if err.errno != 2 && err.errno != 20 => return Err(err),
+        Err(_) => {}
+    }
+
+    let mut dir = match walked.into_iter().last() {
+        Some(dir) => dir,
+        None => dir.walk(&[]).await?.0?,
+    };
+    if dir.qid().ty != FileType::Dir {
+        return Err(enotdir());
+    }
+    for name in &path[qids.len()..] {
+        dir = dir
+            .create(name, perm, FileType::Dir, OpenMode::from(0), "")
+            .await?;
+        qids.push(dir.qid());
+    }
+    Ok((dir, qids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fs::MemFilesystem,
+        server::{testing::block_on, Filesystem},
+    };
+
+    #[test]
+    fn create_nested() {
+        block_on(async {
+            let mut root = MemFilesystem::new().attach("", "user", 0).await.unwrap();
+            root.create("a", 0o755, FileType::Dir, OpenMode::from(0), "")
+                .await
+                .unwrap();
+
+            let (c, qids) = create_dir_all(&root, &["a", "b", "c"], 0o750)
+                .await
+                .unwrap();
+            assert_eq!(3, qids.len());
+            assert!(qids.iter().all(|qid| qid.ty == FileType::Dir));
+            assert_eq!(c.qid(), qids[2]);
+
+            // every intermediate directory is really there.
+            let (b, walked) = root.walk(&["a", "b"]).await.unwrap();
+            assert_eq!(
+                qids[..2],
+                walked.iter().map(|f| f.qid()).collect::<Vec<_>>()
+            );
+            assert_eq!(0o750, b.unwrap().stat().await.unwrap().mode & 0o777);
+
+            // doing it again is a no-op.
+            let (_, again) = create_dir_all(&root, &["a", "b", "c"], 0o750)
+                .await
+                .unwrap();
+            assert_eq!(qids, again);
+        });
+    }
+
+    #[test]
+    fn create_through_file() {
+        block_on(async {
+            let mut root = MemFilesystem::new().attach("", "user", 0).await.unwrap();
+            root.create("f", 0o644, FileType::File, OpenMode::from(0), "")
+                .await
+                .unwrap();
+            assert!(create_dir_all(&root, &["f", "b"], 0o755).await.is_err());
+            assert!(create_dir_all(&root, &["f"], 0o755).await.is_err());
+        });
+    }
+}
+
+// vim: foldmethod=marker
@@ -0,0 +1,361 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Directory listing plumbing shared by the Filesystems in this module.
+
+use crate::{
+    raw::{Dialect, Stat},
+    server::{next_entry, DirEntries, File, FileError, FileResult, OpenFile},
+};
+use std::io::Cursor;
+
+/// Serialize a single Stat as a directory entry, laid out for `dialect`.
+fn serialize_one(stat: Stat, dialect: Dialect) -> FileResult<Vec<u8>> {
+    let toolong = |_| FileError(36, "ENAMETOOLONG".to_owned());
+    stat.validate_as(dialect).map_err(toolong)?;
+    let mut ent = Cursor::new(Vec::with_capacity(stat.encoded_size() + 2));
+    stat.dehydrate_as(&mut ent, dialect).map_err(toolong)?;
+    Ok(ent.into_inner())
+}
+
+/// Serialize each Stat on its own, ready to be handed to [read_entries].
+pub(crate) fn serialize(
+    stats: impl IntoIterator<Item = Stat>,
+    dialect: Dialect,
+) -> FileResult<Vec<Vec<u8>>> {
+    stats
+        .into_iter()
+        .map(|stat| serialize_one(stat, dialect))
+        .collect()
+}
+
+/// Serialized listing of a directory, as read back once it is opened, built
+/// from [File::readdir].
+pub(crate) async fn open<FileT: File>(dir: &FileT, dialect: Dialect) -> FileResult<Vec<Vec<u8>>> {
+    serialize(dir.readdir().await?, dialect)
+}
+
+/// Fill `buf` with as many whole serialized entries as fit, starting from
+/// the entry at byte `offset` of the listing. Directory reads must start on
+/// an entry boundary, and may not return partial entries.
+pub(crate) fn read_entries(entries: &[Vec<u8>], buf: &mut [u8], offset: u64) -> FileResult<u32> {
+    let mut entries = entries.iter();
+    let mut pos = 0;
+    while pos < offset {
+        match entries.next() {
+            Some(entry) => pos += entry.len() as u64,
+            None => return Ok(0),
+        }
+    }
+    if pos != offset {
+        return Err(FileError(22, "EINVAL".to_owned()));
+    }
+
+    let mut n = 0;
+    for entry in entries {
+        if n + entry.len() > buf.len() {
+            break;
+        }
+        buf[n..n + entry.len()].copy_from_slice(entry);
+        n += entry.len();
+    }
+    Ok(n as u32)
+}
+
+/// Find the last entry boundary at or before byte `offset` of `listing`, a
+/// serialized directory (each entry a Stat, behind its u16 size), as read
+/// back from a directory in 9P2000 and 9P2000.u. Offsets at or past the end
+/// of the listing come back as its length. Servers holding a listing as one
+/// blob can use this to make sure a read never starts partway through an
+/// entry; a listing which is cut off partway through an entry is EIO.
+pub fn entry_boundary(listing: &[u8], offset: u64) -> FileResult<u64> {
+    let mut pos = 0usize;
+    while pos < listing.len() {
+        let size = match listing.get(pos..pos + 2) {
+            Some(size) => u16::from_le_bytes([size[0], size[1]]) as usize,
+            None => return Err(FileError(5, "EIO".to_owned())),
+        };
+        let next = pos + 2 + size;
+        if next > listing.len() {
+            return Err(FileError(5, "EIO".to_owned()));
+        }
+        if next as u64 > offset {
+            break;
+        }
+        pos = next;
+    }
+    Ok(pos as u64)
+}
+
+/// Check that `offset` falls on an entry boundary of `listing` (see
+/// [entry_boundary]), as a directory read is required to.
+pub fn is_entry_boundary(listing: &[u8], offset: u64) -> FileResult<bool> {
+    Ok(offset <= listing.len() as u64 && entry_boundary(listing, offset)? == offset)
+}
+
+/// [OpenFile] for a directory, which pulls entries from
+/// [File::readdir_stream] as they are read, serializing only enough of them
+/// to fill each read. Reads must pick up where the last one left off, or
+/// start over from offset 0.
+pub struct DirReader<FileT> {
+    dir: FileT,
+    entries: DirEntries,
+
+    /// Offset the next read is expected at.
+    offset: u64,
+
+    /// Serialized entry which did not fit in the last read.
+    pending: Option<Vec<u8>>,
+
+    /// Dialect the entries are laid out for.
+    dialect: Dialect,
+}
+
+impl<FileT> DirReader<FileT>
+where
+    FileT: File + Send + Sync,
+{
+    /// Start reading the entries of `dir`, laid out for 9P2000.u.
+    pub async fn new(dir: FileT) -> FileResult<Self> {
+        Self::new_as(dir, Dialect::Unix).await
+    }
+
+    /// Start reading the entries of `dir`, laid out for the provided
+    /// [Dialect], as found in [crate::server::OpContext::dialect].
+    pub async fn new_as(dir: FileT, dialect: Dialect) -> FileResult<Self> {
+        let entries = dir.readdir_stream().await?;
+        Ok(Self {
+            dir,
+            entries,
+            offset: 0,
+            pending: None,
+            dialect,
+        })
+    }
+}
+
+impl<FileT> OpenFile for DirReader<FileT>
+where
+    FileT: File + Send + Sync,
+{
+    fn iounit(&self) -> u32 {
+        0
+    }
+
+    async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> FileResult<u32> {
+        if offset == 0 && self.offset != 0 {
+            self.entries = self.dir.readdir_stream().await?;
+            self.offset = 0;
+            self.pending = None;
+        } else if offset != self.offset {
+            return Err(FileError(22, "EINVAL".to_owned()));
+        }
+
+        let mut n = 0;
+        loop {
+            let entry = match self.pending.take() {
+                Some(entry) => entry,
+                None => match next_entry(&mut self.entries).await {
+                    Some(stat) => serialize_one(stat?, self.dialect)?,
+                    None => break,
+                },
+            };
+            if n + entry.len() > buf.len() {
+                if n == 0 {
+                    // no amount of retrying is going to get this one out.
+                    return Err(FileError(90, "EMSGSIZE".to_owned()));
+                }
+                self.pending = Some(entry);
+                break;
+            }
+            buf[n..n + entry.len()].copy_from_slice(&entry);
+            n += entry.len();
+        }
+        self.offset += n as u64;
+        Ok(n as u32)
+    }
+
+    async fn write_at(&mut self, _: &mut [u8], _: u64) -> FileResult<u32> {
+        Err(FileError(21, "EISDIR".to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        raw::{FileType, Hydrate, OpenMode, Qid},
+        server::{testing::block_on, DirStream},
+    };
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+    };
+
+    /// Directory of `n` made-up entries, counting how many have been
+    /// produced.
+    #[derive(Clone)]
+    struct Generated(usize, Arc<AtomicUsize>);
+
+    struct Generator(usize, Arc<AtomicUsize>);
+
+    impl DirStream for Generator {
+        fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<FileResult<Stat>>> {
+            let i = self.1.load(Ordering::Relaxed);
+            if i == self.0 {
+                return Poll::Ready(None);
+            }
+            self.1.fetch_add(1, Ordering::Relaxed);
+            let qid = Qid::new(FileType::File, 0, i as u64);
+            Poll::Ready(Some(Ok(Stat::regular_file(&format!("{i:08}"), qid, 0))))
+        }
+    }
+
+    impl File for Generated {
+        type OpenFile = DirReader<Self>;
+
+        async fn stat(&self) -> FileResult<Stat> {
+            Ok(Stat::directory("/", self.qid()))
+        }
+
+        async fn wstat(&mut self, _: &Stat) -> FileResult<()> {
+            Err(FileError(1, "EPERM".to_owned()))
+        }
+
+        async fn walk(&self, _: &[&str]) -> FileResult<(FileResult<Self>, Vec<Self>)> {
+            Ok((Err(FileError(2, "ENOENT".to_owned())), vec![]))
+        }
+
+        async fn unlink(&mut self) -> FileResult<()> {
+            Err(FileError(1, "EPERM".to_owned()))
+        }
+
+        async fn create(
+            &mut self,
+            _: &str,
+            _: u16,
+            _: FileType,
+            _: OpenMode,
+            _: &str,
+        ) -> FileResult<Self> {
+            Err(FileError(1, "EPERM".to_owned()))
+        }
+
+        async fn open(&mut self, _: OpenMode) -> FileResult<DirReader<Self>> {
+            DirReader::new(self.clone()).await
+        }
+
+        async fn readdir_stream(&self) -> FileResult<DirEntries> {
+            self.1.store(0, Ordering::Relaxed);
+            Ok(Box::pin(Generator(self.0, self.1.clone())))
+        }
+
+        fn qid(&self) -> Qid {
+            Qid::new(FileType::Dir, 0, 0)
+        }
+    }
+
+    #[test]
+    fn streamed_listing() {
+        block_on(async {
+            const ENTRIES: usize = 100_000;
+            let produced = Arc::new(AtomicUsize::new(0));
+            let mut dir = Generated(ENTRIES, produced.clone());
+            let mut of = dir.open(OpenMode::from(0)).await.unwrap();
+
+            let mut buf = vec![0; 8192];
+            let n = of.read_at(&mut buf, 0).await.unwrap() as usize;
+            let qid = Qid::new(FileType::File, 0, 0);
+            let entry_len = serialize_one(Stat::regular_file("00000000", qid, 0), Dialect::Unix)
+                .unwrap()
+                .len();
+            assert_eq!(0, n % entry_len);
+            // what fit, plus the one that didn't.
+            assert_eq!(n / entry_len + 1, produced.load(Ordering::Relaxed));
+
+            let mut offset = n as u64;
+            let mut seen = n / entry_len;
+            loop {
+                let n = of.read_at(&mut buf, offset).await.unwrap() as usize;
+                if n == 0 {
+                    break;
+                }
+                let mut c = Cursor::new(&buf[..n]);
+                while (c.position() as usize) < n {
+                    let stat = Stat::hydrate(&mut c).unwrap();
+                    assert_eq!(format!("{seen:08}"), stat.name);
+                    seen += 1;
+                }
+                // never more than a read's worth ahead of the client.
+                assert!(produced.load(Ordering::Relaxed) <= seen + 1);
+                offset += n as u64;
+            }
+            assert_eq!(ENTRIES, seen);
+
+            // a read at some other offset is refused, but 0 starts over.
+            assert!(of.read_at(&mut buf, 10).await.is_err());
+            let n = of.read_at(&mut buf, 0).await.unwrap() as usize;
+            let stat = Stat::hydrate(&mut Cursor::new(&buf[..n])).unwrap();
+            assert_eq!("00000000", stat.name);
+        });
+    }
+
+    #[test]
+    fn boundaries() {
+        let stats = ["a", "bb", "ccc"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Stat::builder(name, Qid::new(FileType::File, 0, i as u64)).build());
+        let entries = serialize(stats, Dialect::Unix).unwrap();
+        let ends: Vec<u64> = entries
+            .iter()
+            .scan(0, |end, entry| {
+                *end += entry.len() as u64;
+                Some(*end)
+            })
+            .collect();
+        let listing = entries.concat();
+
+        assert_eq!(0, entry_boundary(&listing, 0).unwrap());
+        assert_eq!(ends[0], entry_boundary(&listing, ends[0]).unwrap());
+        // partway through the second entry.
+        assert_eq!(ends[0], entry_boundary(&listing, ends[0] + 3).unwrap());
+        assert_eq!(ends[1], entry_boundary(&listing, ends[2] - 1).unwrap());
+        assert_eq!(ends[2], entry_boundary(&listing, ends[2] + 100).unwrap());
+
+        assert!(is_entry_boundary(&listing, ends[1]).unwrap());
+        assert!(!is_entry_boundary(&listing, ends[1] + 1).unwrap());
+        assert!(is_entry_boundary(&listing, ends[2]).unwrap());
+        assert!(!is_entry_boundary(&listing, ends[2] + 1).unwrap());
+
+        // cut off partway through the last entry.
+        let short = &listing[..listing.len() - 1];
+        assert!(matches!(
+            entry_boundary(short, ends[2]),
+            Err(FileError { errno: 5, .. })
+        ));
+    }
+}
+
+// vim: foldmethod=marker
@@ -0,0 +1,165 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Mapping of qids onto inode numbers, for bridging to FUSE.
+
+use crate::raw::Qid;
+use std::collections::HashMap;
+
+/// Inode number FUSE reserves for the root of the mount.
+const FUSE_ROOT_ID: u64 = 1;
+
+/// Inode number and generation a qid is known by, as filled in to a FUSE
+/// `fuse_entry_param`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InodeEntry {
+    /// Inode number, stable for as long as the qid path is mapped.
+    pub inode: u64,
+
+    /// Generation of the inode, which is the qid version.
+    pub generation: u64,
+}
+
+/// Stable, two-way, mapping between the qids of a 9P tree and the inode
+/// numbers of a FUSE mount.
+///
+/// qid paths would make fine inode numbers as they are, except that FUSE
+/// reserves inode 1 for the root of the mount, and a Filesystem is free to
+/// use path 1 for anything (or for its root, while leaving path 0 to
+/// something else). Instead, the root gets inode 1 and every other path is
+/// handed the next inode number the first time it is seen. Inode numbers
+/// are never reused, even once forgotten.
+///
+/// The generation of each inode follows the qid version, so FUSE will see a
+/// file whose version has changed as a new file behind the same inode.
+#[derive(Debug, Clone)]
+pub struct QidInodeMap {
+    inodes: HashMap<u64, u64>,
+    qids: HashMap<u64, Qid>,
+    next: u64,
+}
+
+impl QidInodeMap {
+    /// Create a new QidInodeMap, with `root` as inode 1.
+    pub fn new(root: Qid) -> Self {
+        Self {
+            inodes: HashMap::from([(root.path, FUSE_ROOT_ID)]),
+            qids: HashMap::from([(FUSE_ROOT_ID, root)]),
+            next: FUSE_ROOT_ID + 1,
+        }
+    }
+
+    /// Look up the inode for `qid`, assigning it one if its path has not
+    /// been seen before (or was forgotten). The qid is remembered as the
+    /// latest version of the file.
+    pub fn entry(&mut self, qid: Qid) -> InodeEntry {
+        let inode = match self.inodes.get(&qid.path) {
+            Some(inode) => *inode,
+            None => {
+                let inode = self.next;
+                self.next += 1;
+                self.inodes.insert(qid.path, inode);
+                inode
+            }
+        };
+        let generation = qid.version as u64;
+        self.qids.insert(inode, qid);
+        InodeEntry { inode, generation }
+    }
+
+    /// Latest qid seen for `inode`, if it is mapped.
+    pub fn qid(&self, inode: u64) -> Option<&Qid> {
+        self.qids.get(&inode)
+    }
+
+    /// Drop the mapping for `inode`, once FUSE has forgotten it, returning
+    /// the qid it was mapped to. The root is never forgotten.
+    pub fn forget(&mut self, inode: u64) -> Option<Qid> {
+        if inode == FUSE_ROOT_ID {
+            return None;
+        }
+        let qid = self.qids.remove(&inode)?;
+        self.inodes.remove(&qid.path);
+        Some(qid)
+    }
+
+    /// Number of mapped inodes, including the root.
+    pub fn len(&self) -> usize {
+        self.qids.len()
+    }
+
+    /// Check if only the root is mapped. A QidInodeMap always has a root,
+    /// so it is never empty as such.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InodeEntry, QidInodeMap};
+    use crate::raw::{FileType, Qid};
+
+    #[test]
+    fn inodes() {
+        // path 1 is some regular file, and the root is path 0.
+        let root = Qid::new(FileType::Dir, 0, 0);
+        let mut map = QidInodeMap::new(root.clone());
+        assert_eq!(
+            InodeEntry {
+                inode: 1,
+                generation: 0
+            },
+            map.entry(root.clone())
+        );
+
+        let file = Qid::new(FileType::File, 0, 1);
+        let entry = map.entry(file.clone());
+        assert_eq!(2, entry.inode);
+        assert_eq!(Some(&file), map.qid(2));
+        assert_eq!(entry, map.entry(file.clone()));
+        let other = map.entry(Qid::new(FileType::File, 0, 7));
+        assert_eq!(3, other.inode);
+        assert_eq!(3, map.len());
+        assert_eq!(None, map.qid(4));
+    }
+
+    #[test]
+    fn generations() {
+        let mut map = QidInodeMap::new(Qid::new(FileType::Dir, 0, 1));
+        let entry = map.entry(Qid::new(FileType::File, 3, 2));
+        assert_eq!(3, entry.generation);
+
+        // same file, new version: same inode, next generation.
+        let changed = Qid::new(FileType::File, 4, 2);
+        let next = map.entry(changed.clone());
+        assert_eq!((entry.inode, 4), (next.inode, next.generation));
+        assert_eq!(Some(&changed), map.qid(entry.inode));
+
+        // once forgotten, the path comes back as a new inode.
+        assert_eq!(Some(changed.clone()), map.forget(entry.inode));
+        assert_eq!(None, map.qid(entry.inode));
+        assert_ne!(entry.inode, map.entry(changed).inode);
+        assert_eq!(None, map.forget(1));
+        assert!(!map.is_empty());
+    }
+}
+
+// vim: foldmethod=marker
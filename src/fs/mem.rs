@@ -0,0 +1,696 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use super::dir;
+use crate::{
+    raw::{Dialect, FileType, IoDirection, OpenMode, Qid, Stat},
+    server::{File, FileError, FileResult, Filesystem, FilesystemResult, OpContext, OpenFile},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// qid path of the root directory.
+const ROOT: u64 = 1;
+
+/// Tunlinkat flag to remove a directory rather than a file.
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// Largest a file may grow to, by a write or a wstat of its length.
+const MAX_FILE_SIZE: u64 = 1 << 30;
+
+/// Check that a file may grow to `len` bytes, which is None if working out
+/// the length overflowed.
+fn file_size(len: Option<u64>) -> FileResult<usize> {
+    match len {
+        Some(len) if len <= MAX_FILE_SIZE => Ok(len as usize),
+        _ => Err(FileError(27, "EFBIG".to_owned())),
+    }
+}
+
+enum Kind {
+    Dir(BTreeMap<String, u64>),
+    File(Vec<u8>),
+    Symlink(String),
+}
+
+struct Node {
+    kind: Kind,
+    mode: u16,
+    version: u32,
+
+    /// Directory this node was created in; the root is its own parent.
+    parent: u64,
+
+    /// Number of names this node is known by; it goes away with the last.
+    links: usize,
+}
+
+/// Every node in the tree, by qid path.
+struct Nodes {
+    nodes: HashMap<u64, Node>,
+    next: u64,
+}
+
+type Tree = Arc<Mutex<Nodes>>;
+
+impl Nodes {
+    fn get(&self, path: u64) -> FileResult<&Node> {
+        self.nodes
+            .get(&path)
+            .ok_or_else(|| FileError(2, "ENOENT".to_owned()))
+    }
+
+    fn get_mut(&mut self, path: u64) -> FileResult<&mut Node> {
+        self.nodes
+            .get_mut(&path)
+            .ok_or_else(|| FileError(2, "ENOENT".to_owned()))
+    }
+
+    fn children(&self, path: u64) -> FileResult<&BTreeMap<String, u64>> {
+        match &self.get(path)?.kind {
+            Kind::Dir(children) => Ok(children),
+            _ => Err(FileError(20, "ENOTDIR".to_owned())),
+        }
+    }
+
+    fn children_mut(&mut self, path: u64) -> FileResult<&mut BTreeMap<String, u64>> {
+        match &mut self.get_mut(path)?.kind {
+            Kind::Dir(children) => Ok(children),
+            _ => Err(FileError(20, "ENOTDIR".to_owned())),
+        }
+    }
+
+    /// Check that name in the directory parent still refers to path, rather
+    /// than having been unlinked (and perhaps created anew) since.
+    fn check_name(&self, parent: u64, name: &str, path: u64) -> FileResult<()> {
+        match self.children(parent)?.get(name) {
+            Some(p) if *p == path => Ok(()),
+            _ => Err(FileError(2, "ENOENT".to_owned())),
+        }
+    }
+}
+
+/// Read-write Filesystem held entirely in memory, and shared by everyone
+/// attached to it. This is mostly useful for tests, benchmarks and
+/// scratch space. No one file may grow past 1 GiB; writes past that are
+/// refused with EFBIG.
+#[derive(Clone)]
+pub struct MemFilesystem {
+    tree: Tree,
+}
+
+impl Default for MemFilesystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemFilesystem {
+    /// Create a new MemFilesystem, containing an empty root directory.
+    pub fn new() -> Self {
+        let root = Node {
+            kind: Kind::Dir(BTreeMap::new()),
+            mode: 0o755,
+            version: 0,
+            parent: ROOT,
+            links: 1,
+        };
+        Self {
+            tree: Arc::new(Mutex::new(Nodes {
+                nodes: HashMap::from([(ROOT, root)]),
+                next: ROOT + 1,
+            })),
+        }
+    }
+}
+
+impl Filesystem for MemFilesystem {
+    type File = MemFile;
+
+    async fn attach(&self, _: &str, _: &str, _: u32) -> FilesystemResult<MemFile> {
+        Ok(MemFile {
+            tree: self.tree.clone(),
+            parent: ROOT,
+            name: "/".to_owned(),
+            path: ROOT,
+        })
+    }
+}
+
+/// File (or directory, or symlink) within a [MemFilesystem], as reached by
+/// some name in some directory.
+#[derive(Clone)]
+pub struct MemFile {
+    tree: Tree,
+    parent: u64,
+    name: String,
+    path: u64,
+}
+
+impl MemFile {
+    fn lock(&self) -> MutexGuard<'_, Nodes> {
+        self.tree.lock().unwrap()
+    }
+
+    fn child(&self, name: &str, path: u64) -> Self {
+        Self {
+            tree: self.tree.clone(),
+            parent: self.path,
+            name: name.to_owned(),
+            path,
+        }
+    }
+
+    fn qid_of(node: &Node, path: u64) -> Qid {
+        let ty = match node.kind {
+            Kind::Dir(_) => FileType::Dir,
+            Kind::File(_) => FileType::File,
+            Kind::Symlink(_) => FileType::Link,
+        };
+        Qid::new(ty, node.version, path)
+    }
+
+    /// Open the file, listing directories in the provided [Dialect].
+    async fn open_as(&mut self, mode: OpenMode, dialect: Dialect) -> FileResult<MemOpenFile> {
+        {
+            let mut nodes = self.lock();
+            let node = nodes.get_mut(self.path)?;
+            match &mut node.kind {
+                Kind::Dir(_) => {
+                    if !matches!(mode.direction(), IoDirection::Read) {
+                        return Err(FileError(21, "EISDIR".to_owned()));
+                    }
+                }
+                Kind::File(data) => {
+                    if mode.truncate() && !matches!(mode.direction(), IoDirection::Read) {
+                        data.clear();
+                        node.version += 1;
+                    }
+                    return Ok(MemOpenFile::File(self.clone()));
+                }
+                Kind::Symlink(_) => return Err(FileError(40, "ELOOP".to_owned())),
+            }
+        }
+        Ok(MemOpenFile::Dir(dir::open(self, dialect).await?))
+    }
+}
+
+impl File for MemFile {
+    type OpenFile = MemOpenFile;
+
+    async fn stat(&self) -> FileResult<Stat> {
+        let nodes = self.lock();
+        let node = nodes.get(self.path)?;
+        let builder =
+            Stat::builder(&self.name, Self::qid_of(node, self.path)).with_mode(node.mode as u32);
+        Ok(match &node.kind {
+            Kind::Dir(_) => builder.build(),
+            Kind::File(data) => builder.with_size(data.len() as u64).build(),
+            Kind::Symlink(target) => builder
+                .with_size(target.len() as u64)
+                .with_extension(target)
+                .build(),
+        })
+    }
+
+    async fn wstat(&mut self, stat: &Stat) -> FileResult<()> {
+        let tree = self.tree.clone();
+        let mut nodes = tree.lock().unwrap();
+
+        if !stat.name.is_empty() && stat.name != self.name {
+            if self.path == ROOT {
+                return Err(FileError(16, "EBUSY".to_owned()));
+            }
+            nodes.check_name(self.parent, &self.name, self.path)?;
+            let siblings = nodes.children_mut(self.parent)?;
+            if siblings.contains_key(&stat.name) {
+                return Err(FileError(17, "EEXIST".to_owned()));
+            }
+            siblings.remove(&self.name);
+            siblings.insert(stat.name.clone(), self.path);
+            self.name = stat.name.clone();
+        }
+
+        let node = nodes.get_mut(self.path)?;
+        if stat.length != !0 {
+            match &mut node.kind {
+                Kind::File(data) => data.resize(file_size(Some(stat.length))?, 0),
+                _ => return Err(FileError(21, "EISDIR".to_owned())),
+            }
+            node.version += 1;
+        }
+        if stat.mode != !0 {
+            node.mode = stat.permissions();
+        }
+        Ok(())
+    }
+
+    async fn walk(&self, path: &[&str]) -> FileResult<(FileResult<Self>, Vec<Self>)> {
+        let nodes = self.lock();
+        let mut file = self.clone();
+        let mut files = vec![];
+        for name in path {
+            let children = match &nodes.get(file.path)?.kind {
+                Kind::Dir(children) => children,
+                _ => return Ok((Err(FileError(20, "ENOTDIR".to_owned())), files)),
+            };
+            let next = match *name {
+                ".." => {
+                    let parent = nodes.get(file.path)?.parent;
+                    let grandparent = nodes.get(parent)?.parent;
+                    let name = match nodes
+                        .children(grandparent)?
+                        .iter()
+                        .find(|(_, p)| **p == parent)
+                    {
+                        Some((name, _)) => name.clone(),
+                        None => "/".to_owned(),
+                    };
+                    Some(Self {
+                        tree: self.tree.clone(),
+                        parent: grandparent,
+                        name,
+                        path: parent,
+                    })
+                }
+                name => children.get(name).map(|path| file.child(name, *path)),
+            };
+            match next {
+                Some(next) => {
+                    file = next;
+                    files.push(file.clone());
+                }
+                None => return Ok((Err(FileError(2, "ENOENT".to_owned())), files)),
+            }
+        }
+        Ok((Ok(file), files))
+    }
+
+    async fn unlink(&mut self) -> FileResult<()> {
+        if self.path == ROOT {
+            return Err(FileError(16, "EBUSY".to_owned()));
+        }
+        let mut nodes = self.lock();
+        nodes.check_name(self.parent, &self.name, self.path)?;
+        if let Kind::Dir(children) = &nodes.get(self.path)?.kind {
+            if !children.is_empty() {
+                return Err(FileError(39, "ENOTEMPTY".to_owned()));
+            }
+        }
+        nodes.children_mut(self.parent)?.remove(&self.name);
+        let node = nodes.get_mut(self.path)?;
+        node.links -= 1;
+        if node.links == 0 {
+            nodes.nodes.remove(&self.path);
+        }
+        Ok(())
+    }
+
+    async fn create(
+        &mut self,
+        name: &str,
+        perm: u16,
+        ty: FileType,
+        _: OpenMode,
+        extension: &str,
+    ) -> FileResult<Self> {
+        let mut nodes = self.lock();
+        if nodes.children(self.path)?.contains_key(name) {
+            return Err(FileError(17, "EEXIST".to_owned()));
+        }
+        let kind = match ty {
+            FileType::Dir => Kind::Dir(BTreeMap::new()),
+            FileType::Link => Kind::Symlink(extension.to_owned()),
+            _ => Kind::File(vec![]),
+        };
+
+        let path = nodes.next;
+        nodes.next += 1;
+        nodes.nodes.insert(
+            path,
+            Node {
+                kind,
+                mode: perm & 0o777,
+                version: 0,
+                parent: self.path,
+                links: 1,
+            },
+        );
+        nodes.children_mut(self.path)?.insert(name.to_owned(), path);
+        Ok(self.child(name, path))
+    }
+
+    async fn open(&mut self, mode: OpenMode) -> FileResult<MemOpenFile> {
+        self.open_as(mode, Dialect::Unix).await
+    }
+
+    async fn open_with_context(
+        &mut self,
+        ctx: &OpContext<'_>,
+        mode: OpenMode,
+    ) -> FileResult<MemOpenFile> {
+        self.open_as(mode, ctx.dialect).await
+    }
+
+    async fn readdir(&self) -> FileResult<Vec<Stat>> {
+        let children: Vec<Self> = self
+            .lock()
+            .children(self.path)?
+            .iter()
+            .map(|(name, path)| self.child(name, *path))
+            .collect();
+
+        let mut stats = vec![];
+        for child in children {
+            stats.push(child.stat().await?);
+        }
+        Ok(stats)
+    }
+
+    async fn mkdir(&mut self, name: &str, mode: u32, _: u32) -> FileResult<Qid> {
+        let mode = (mode & 0o777) as u16;
+        let dir = self
+            .create(name, mode, FileType::Dir, OpenMode::from(0), "")
+            .await?;
+        Ok(dir.qid())
+    }
+
+    async fn unlink_at(&mut self, name: &str, flags: u32) -> FileResult<()> {
+        let mut child = {
+            let nodes = self.lock();
+            let path = match nodes.children(self.path)?.get(name) {
+                Some(path) => *path,
+                None => return Err(FileError(2, "ENOENT".to_owned())),
+            };
+            let is_dir = matches!(nodes.get(path)?.kind, Kind::Dir(_));
+            match (is_dir, flags & AT_REMOVEDIR != 0) {
+                (true, false) => return Err(FileError(21, "EISDIR".to_owned())),
+                (false, true) => return Err(FileError(20, "ENOTDIR".to_owned())),
+                _ => self.child(name, path),
+            }
+        };
+        child.unlink().await
+    }
+
+    async fn symlink(&mut self, name: &str, target: &str, _: u32) -> FileResult<Qid> {
+        let link = self
+            .create(name, 0o777, FileType::Link, OpenMode::from(0), target)
+            .await?;
+        Ok(link.qid())
+    }
+
+    async fn link(&mut self, target: &Self, name: &str) -> FileResult<()> {
+        if !Arc::ptr_eq(&self.tree, &target.tree) {
+            return Err(FileError(18, "EXDEV".to_owned()));
+        }
+        let mut nodes = self.lock();
+        if nodes.children(self.path)?.contains_key(name) {
+            return Err(FileError(17, "EEXIST".to_owned()));
+        }
+        let node = nodes.get_mut(target.path)?;
+        if let Kind::Dir(_) = node.kind {
+            return Err(FileError(1, "EPERM".to_owned()));
+        }
+        node.links += 1;
+        nodes
+            .children_mut(self.path)?
+            .insert(name.to_owned(), target.path);
+        Ok(())
+    }
+
+    fn qid(&self) -> Qid {
+        let nodes = self.lock();
+        match nodes.get(self.path) {
+            Ok(node) => Self::qid_of(node, self.path),
+            // unlinked out from under us; the path is still ours.
+            Err(_) => Qid::new(FileType::File, 0, self.path),
+        }
+    }
+}
+
+/// Open handle to a [MemFile].
+pub enum MemOpenFile {
+    /// Serialized Stat of every entry in the directory, as of when it was
+    /// opened.
+    Dir(Vec<Vec<u8>>),
+
+    /// Regular file.
+    File(MemFile),
+}
+
+impl OpenFile for MemOpenFile {
+    fn iounit(&self) -> u32 {
+        0
+    }
+
+    async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> FileResult<u32> {
+        match self {
+            Self::Dir(entries) => dir::read_entries(entries, buf, offset),
+            Self::File(file) => {
+                let nodes = file.lock();
+                let Kind::File(data) = &nodes.get(file.path)?.kind else {
+                    return Err(FileError(21, "EISDIR".to_owned()));
+                };
+                let offset = (offset as usize).min(data.len());
+                let n = buf.len().min(data.len() - offset);
+                buf[..n].copy_from_slice(&data[offset..offset + n]);
+                Ok(n as u32)
+            }
+        }
+    }
+
+    async fn write_at(&mut self, buf: &mut [u8], offset: u64) -> FileResult<u32> {
+        match self {
+            Self::Dir(_) => Err(FileError(21, "EISDIR".to_owned())),
+            Self::File(file) => {
+                let mut nodes = file.lock();
+                let node = nodes.get_mut(file.path)?;
+                let Kind::File(data) = &mut node.kind else {
+                    return Err(FileError(21, "EISDIR".to_owned()));
+                };
+                let end = file_size(offset.checked_add(buf.len() as u64))?;
+                let offset = offset as usize;
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[offset..end].copy_from_slice(buf);
+                node.version += 1;
+                Ok(buf.len() as u32)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::block_on;
+
+    async fn write(file: &mut MemFile, data: &[u8]) {
+        let mut of = file.open(OpenMode::from(1)).await.unwrap();
+        let mut data = data.to_vec();
+        of.write_at(&mut data, 0).await.unwrap();
+    }
+
+    async fn read(file: &mut MemFile) -> Vec<u8> {
+        let mut of = file.open(OpenMode::from(0)).await.unwrap();
+        let mut buf = vec![0; 1024];
+        let n = of.read_at(&mut buf, 0).await.unwrap();
+        buf.truncate(n as usize);
+        buf
+    }
+
+    #[test]
+    fn create_write_read() {
+        block_on(async {
+            let fs = MemFilesystem::new();
+            let mut root = fs.attach("", "user", 0).await.unwrap();
+            let mut dir = root
+                .create("dir", 0o755, FileType::Dir, OpenMode::from(0), "")
+                .await
+                .unwrap();
+            let mut file = dir
+                .create("file", 0o644, FileType::File, OpenMode::from(1), "")
+                .await
+                .unwrap();
+            write(&mut file, b"hello").await;
+
+            // a second attach sees the same tree.
+            let root = fs.attach("", "user", 0).await.unwrap();
+            let (file, files) = root.walk(&["dir", "file"]).await.unwrap();
+            let mut file = file.unwrap();
+            assert_eq!(FileType::Dir, files[0].qid().ty);
+            assert_eq!(b"hello".to_vec(), read(&mut file).await);
+            let stat = file.stat().await.unwrap();
+            assert_eq!(("file", 5), (stat.name.as_str(), stat.length));
+
+            let (dir, _) = root.walk(&["dir"]).await.unwrap();
+            let (up, _) = dir.unwrap().walk(&["..", "dir", "file"]).await.unwrap();
+            assert_eq!(file.qid(), up.unwrap().qid());
+            let (up, _) = root.walk(&["dir", "..", ".."]).await.unwrap();
+            assert_eq!("/", up.unwrap().stat().await.unwrap().name);
+        });
+    }
+
+    #[test]
+    fn unlink() {
+        block_on(async {
+            let mut root = MemFilesystem::new().attach("", "user", 0).await.unwrap();
+            let mut dir = root
+                .create("dir", 0o755, FileType::Dir, OpenMode::from(0), "")
+                .await
+                .unwrap();
+            let mut file = dir
+                .create("file", 0o644, FileType::File, OpenMode::from(1), "")
+                .await
+                .unwrap();
+
+            assert!(dir.unlink().await.is_err());
+            file.unlink().await.unwrap();
+            dir.unlink().await.unwrap();
+            let (dir, _) = root.walk(&["dir"]).await.unwrap();
+            assert_eq!(Some(2), dir.err().map(|err| err.errno));
+        });
+    }
+
+    #[test]
+    fn stale_name() {
+        block_on(async {
+            let mut root = MemFilesystem::new().attach("", "user", 0).await.unwrap();
+            let mut stale = root
+                .create("file", 0o644, FileType::File, OpenMode::from(1), "")
+                .await
+                .unwrap();
+            root.link(&stale, "hardlink").await.unwrap();
+            let (file, _) = root.walk(&["file"]).await.unwrap();
+            file.unwrap().unlink().await.unwrap();
+            let mut file = root
+                .create("file", 0o644, FileType::File, OpenMode::from(1), "")
+                .await
+                .unwrap();
+            write(&mut file, b"new").await;
+
+            // "file" is no longer the name stale was reached by.
+            assert!(matches!(
+                stale.unlink().await,
+                Err(FileError { errno: 2, .. })
+            ));
+            let mut rename = Stat::builder("renamed", stale.qid()).build();
+            rename.length = !0;
+            rename.mode = !0;
+            assert!(matches!(
+                stale.wstat(&rename).await,
+                Err(FileError { errno: 2, .. })
+            ));
+
+            let (file, _) = root.walk(&["file"]).await.unwrap();
+            assert_eq!(b"new".to_vec(), read(&mut file.unwrap()).await);
+            let (link, _) = root.walk(&["hardlink"]).await.unwrap();
+            let mut link = link.unwrap();
+            assert_eq!(stale.qid(), link.qid());
+            link.unlink().await.unwrap();
+            assert!(stale.stat().await.is_err());
+        });
+    }
+
+    #[test]
+    fn file_too_big() {
+        block_on(async {
+            let mut root = MemFilesystem::new().attach("", "user", 0).await.unwrap();
+            let mut file = root
+                .create("file", 0o644, FileType::File, OpenMode::from(1), "")
+                .await
+                .unwrap();
+            let mut of = file.open(OpenMode::from(1)).await.unwrap();
+            for offset in [1 << 62, u64::MAX - 1, MAX_FILE_SIZE - 1] {
+                assert!(matches!(
+                    of.write_at(&mut b"hi".to_vec(), offset).await,
+                    Err(FileError { errno: 27, .. })
+                ));
+            }
+            let mut stat = Stat::builder("", file.qid()).build();
+            stat.mode = !0;
+            stat.length = 1 << 62;
+            assert!(matches!(
+                file.wstat(&stat).await,
+                Err(FileError { errno: 27, .. })
+            ));
+            assert_eq!(0, file.stat().await.unwrap().length);
+        });
+    }
+
+    #[test]
+    fn link() {
+        block_on(async {
+            let mut root = MemFilesystem::new().attach("", "user", 0).await.unwrap();
+            let mut file = root
+                .create("file", 0o644, FileType::File, OpenMode::from(1), "")
+                .await
+                .unwrap();
+            write(&mut file, b"hello").await;
+
+            root.link(&file, "hardlink").await.unwrap();
+            let (link, _) = root.walk(&["hardlink"]).await.unwrap();
+            let mut link = link.unwrap();
+            assert_eq!(file.qid(), link.qid());
+            assert_eq!(b"hello".to_vec(), read(&mut link).await);
+            assert!(matches!(
+                root.link(&file, "hardlink").await,
+                Err(FileError { errno: 17, .. })
+            ));
+
+            // the file outlives either one of its names.
+            file.unlink().await.unwrap();
+            assert_eq!(b"hello".to_vec(), read(&mut link).await);
+            let (dir, _) = root.walk(&[]).await.unwrap();
+            assert!(matches!(
+                root.link(&dir.unwrap(), "root").await,
+                Err(FileError { errno: 1, .. })
+            ));
+        });
+    }
+
+    #[test]
+    fn readdir() {
+        block_on(async {
+            let mut root = MemFilesystem::new().attach("", "user", 0).await.unwrap();
+            for (name, ty) in [("b", FileType::File), ("a", FileType::Dir)] {
+                root.create(name, 0o755, ty, OpenMode::from(0), "")
+                    .await
+                    .unwrap();
+            }
+
+            let stats = root.readdir().await.unwrap();
+            let names: Vec<_> = stats.iter().map(|s| s.name.as_str()).collect();
+            assert_eq!(vec!["a", "b"], names);
+            assert_eq!(FileType::Dir, stats[0].qid.ty);
+
+            let (file, _) = root.walk(&["b"]).await.unwrap();
+            assert!(matches!(
+                file.unwrap().readdir().await,
+                Err(FileError { errno: 20, .. })
+            ));
+        });
+    }
+}
+
+// vim: foldmethod=marker
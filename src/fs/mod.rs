@@ -0,0 +1,38 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Ready-made Filesystem implementations, for the common cases that don't
+//! warrant writing the traits out by hand.
+
+mod create;
+mod dir;
+mod inode_map;
+mod mem;
+mod qid_space;
+mod static_tree;
+
+pub use create::create_dir_all;
+pub use dir::{entry_boundary, is_entry_boundary, DirReader};
+pub use inode_map::{InodeEntry, QidInodeMap};
+pub use mem::{MemFile, MemFilesystem, MemOpenFile};
+pub use qid_space::QidSpace;
+pub use static_tree::{StaticEntry, StaticFile, StaticOpenFile, StaticTree};
+
+// vim: foldmethod=marker
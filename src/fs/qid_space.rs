@@ -0,0 +1,149 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Namespacing of qid paths, for Filesystems built out of other
+//! Filesystems.
+
+use crate::{
+    raw::Qid,
+    server::{FileError, FileResult},
+};
+
+/// Slice of the qid path space handed to one of several Filesystems being
+/// composed into one (an overlay or union, say).
+///
+/// qid paths only need to be unique within a single Filesystem, so two
+/// trees may well both use small, hardcoded, paths; the root of each being
+/// path 1 is common. A Filesystem which serves files from several trees at
+/// once has to keep those apart, which it can do by giving each tree its
+/// own QidSpace: the top `bits` of the path identify the tree, and the rest
+/// are the path as the tree sees it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QidSpace {
+    index: u64,
+    bits: u32,
+}
+
+impl QidSpace {
+    /// Create the `index`th QidSpace out of `1 << bits` of them. This panics
+    /// if `bits` is not between 1 and 63, or if `index` does not fit in
+    /// `bits` bits.
+    pub fn new(index: u64, bits: u32) -> Self {
+        assert!((1..64).contains(&bits), "QidSpace bits must be in 1..64");
+        assert!(index < (1 << bits), "QidSpace index does not fit in bits");
+        Self { index, bits }
+    }
+
+    fn shift(&self) -> u32 {
+        64 - self.bits
+    }
+
+    fn mask(&self) -> u64 {
+        (1 << self.shift()) - 1
+    }
+
+    /// Move a qid from the tree's own path space into this QidSpace.
+    /// Paths too large to fit alongside the index are refused with
+    /// EOVERFLOW, rather than being allowed to collide.
+    pub fn map(&self, qid: Qid) -> FileResult<Qid> {
+        if qid.path & !self.mask() != 0 {
+            return Err(FileError(75, "EOVERFLOW".to_owned()));
+        }
+        Ok(Qid {
+            path: (self.index << self.shift()) | qid.path,
+            ..qid
+        })
+    }
+
+    /// Move a qid from this QidSpace back into the tree's own path space,
+    /// if it belongs to this QidSpace at all.
+    pub fn unmap(&self, qid: Qid) -> Option<Qid> {
+        if qid.path >> self.shift() != self.index {
+            return None;
+        }
+        Some(Qid {
+            path: qid.path & self.mask(),
+            ..qid
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QidSpace;
+    use crate::{
+        fs::{StaticEntry, StaticTree},
+        raw::{FileType, Qid},
+        server::{testing::block_on, File, Filesystem},
+    };
+    use std::collections::{BTreeMap, HashSet};
+
+    #[test]
+    fn composed_trees() {
+        block_on(async {
+            let tree = || {
+                StaticTree::new(BTreeMap::from([
+                    ("a".to_owned(), StaticEntry::file(b"a")),
+                    ("b".to_owned(), StaticEntry::file(b"b")),
+                ]))
+            };
+            let trees = [tree(), tree()];
+
+            let mut raw = vec![];
+            let mut mapped = HashSet::new();
+            for (index, tree) in trees.iter().enumerate() {
+                let space = QidSpace::new(index as u64, 8);
+                let root = tree.attach("", "user", 0).await.unwrap();
+                let (_, files) = root.walk(&["a"]).await.unwrap();
+                let (_, more) = root.walk(&["b"]).await.unwrap();
+                for qid in [root.qid()]
+                    .into_iter()
+                    .chain(files.iter().chain(more.iter()).map(|f| f.qid()))
+                {
+                    raw.push(qid.path);
+                    let mapped_qid = space.map(qid.clone()).unwrap();
+                    assert!(mapped.insert(mapped_qid.path), "collision on {:?}", qid);
+                    assert_eq!(Some(qid), space.unmap(mapped_qid.clone()));
+                    let other = QidSpace::new(1 - index as u64, 8);
+                    assert_eq!(None, other.unmap(mapped_qid));
+                }
+            }
+
+            assert_eq!(6, mapped.len());
+            // whereas the trees on their own do collide.
+            assert_eq!(3, raw.iter().collect::<HashSet<_>>().len());
+        });
+    }
+
+    #[test]
+    fn overflow() {
+        let space = QidSpace::new(3, 8);
+        let qid = Qid::new(FileType::File, 0, 1 << 56);
+        assert!(space.map(qid).is_err());
+
+        let qid = Qid::new(FileType::File, 7, (1 << 56) - 1);
+        let mapped = space.map(qid.clone()).unwrap();
+        assert_eq!(0x03FF_FFFF_FFFF_FFFF, mapped.path);
+        assert_eq!(Some(qid), space.unmap(mapped.clone()));
+        assert_eq!(None, QidSpace::new(2, 8).unmap(mapped));
+    }
+}
+
+// vim: foldmethod=marker
@@ -0,0 +1,379 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use super::dir;
+use crate::{
+    raw::{Dialect, FileType, IoDirection, OpenMode, Qid, Stat},
+    server::{File, FileError, FileResult, Filesystem, FilesystemResult, OpContext, OpenFile},
+};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// Entry in a [StaticTree], as declared by the user.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StaticEntry {
+    /// Regular file, with the provided contents.
+    File(Vec<u8>),
+
+    /// Directory, with the provided children by name.
+    Dir(BTreeMap<String, StaticEntry>),
+}
+
+impl StaticEntry {
+    /// Regular file with the provided contents.
+    pub fn file(contents: &[u8]) -> Self {
+        Self::File(contents.to_vec())
+    }
+
+    /// Directory containing the provided (name, entry) pairs.
+    pub fn dir<'a>(children: impl IntoIterator<Item = (&'a str, StaticEntry)>) -> Self {
+        Self::Dir(
+            children
+                .into_iter()
+                .map(|(name, entry)| (name.to_owned(), entry))
+                .collect(),
+        )
+    }
+}
+
+/// Node of the flattened tree; nodes are known by their index, which is also
+/// the path of their qid.
+struct Node {
+    name: String,
+    parent: usize,
+    kind: NodeKind,
+}
+
+enum NodeKind {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, usize>),
+}
+
+type Nodes = Arc<Vec<Node>>;
+
+/// Read-only Filesystem with a tree that is declared once, up front. Walk,
+/// stat and qids all come for free; attempts to modify the tree are refused
+/// with EROFS.
+#[derive(Clone)]
+pub struct StaticTree {
+    nodes: Nodes,
+}
+
+impl StaticTree {
+    /// Create a new StaticTree, the root directory of which contains the
+    /// provided `root` entries.
+    pub fn new(root: BTreeMap<String, StaticEntry>) -> Self {
+        let mut nodes = vec![];
+        flatten(&mut nodes, 0, "/", StaticEntry::Dir(root));
+        Self {
+            nodes: Arc::new(nodes),
+        }
+    }
+}
+
+/// Push `entry` (and everything below it) onto `nodes`, returning its index.
+fn flatten(nodes: &mut Vec<Node>, parent: usize, name: &str, entry: StaticEntry) -> usize {
+    let idx = nodes.len();
+    match entry {
+        StaticEntry::File(contents) => nodes.push(Node {
+            name: name.to_owned(),
+            parent,
+            kind: NodeKind::File(contents),
+        }),
+        StaticEntry::Dir(children) => {
+            nodes.push(Node {
+                name: name.to_owned(),
+                parent,
+                kind: NodeKind::Dir(BTreeMap::new()),
+            });
+            let children: BTreeMap<String, usize> = children
+                .into_iter()
+                .map(|(name, entry)| {
+                    let child = flatten(nodes, idx, &name, entry);
+                    (name, child)
+                })
+                .collect();
+            nodes[idx].kind = NodeKind::Dir(children);
+        }
+    }
+    idx
+}
+
+fn erofs() -> FileError {
+    FileError(30, "EROFS".to_owned())
+}
+
+impl Filesystem for StaticTree {
+    type File = StaticFile;
+
+    async fn attach(&self, _: &str, _: &str, _: u32) -> FilesystemResult<StaticFile> {
+        Ok(StaticFile {
+            nodes: self.nodes.clone(),
+            idx: 0,
+        })
+    }
+}
+
+/// File (or directory) within a [StaticTree].
+#[derive(Clone)]
+pub struct StaticFile {
+    nodes: Nodes,
+    idx: usize,
+}
+
+impl StaticFile {
+    fn node(&self) -> &Node {
+        &self.nodes[self.idx]
+    }
+
+    fn at(&self, idx: usize) -> Self {
+        Self {
+            nodes: self.nodes.clone(),
+            idx,
+        }
+    }
+
+    /// Open the file, listing directories in the provided [Dialect].
+    async fn open_as(&mut self, mode: OpenMode, dialect: Dialect) -> FileResult<StaticOpenFile> {
+        if !matches!(mode.direction(), IoDirection::Read) || mode.truncate() {
+            return Err(erofs());
+        }
+        match &self.node().kind {
+            NodeKind::File(_) => Ok(StaticOpenFile::File(self.clone())),
+            NodeKind::Dir(_) => Ok(StaticOpenFile::Dir(dir::open(self, dialect).await?)),
+        }
+    }
+}
+
+impl File for StaticFile {
+    type OpenFile = StaticOpenFile;
+
+    async fn stat(&self) -> FileResult<Stat> {
+        let node = self.node();
+        Ok(match &node.kind {
+            NodeKind::Dir(_) => Stat::directory(&node.name, self.qid()),
+            NodeKind::File(contents) => {
+                Stat::regular_file(&node.name, self.qid(), contents.len() as u64)
+            }
+        })
+    }
+
+    async fn wstat(&mut self, _: &Stat) -> FileResult<()> {
+        Err(erofs())
+    }
+
+    async fn walk(&self, path: &[&str]) -> FileResult<(FileResult<Self>, Vec<Self>)> {
+        let mut idx = self.idx;
+        let mut files = vec![];
+        for name in path {
+            let child = match &self.nodes[idx].kind {
+                NodeKind::Dir(children) => match *name {
+                    // the parent of the root is the root.
+                    ".." => Some(self.nodes[idx].parent),
+                    name => children.get(name).copied(),
+                },
+                NodeKind::File(_) => return Ok((Err(FileError(20, "ENOTDIR".to_owned())), files)),
+            };
+            match child {
+                Some(child) => {
+                    idx = child;
+                    files.push(self.at(idx));
+                }
+                None => return Ok((Err(FileError(2, "ENOENT".to_owned())), files)),
+            }
+        }
+        Ok((Ok(self.at(idx)), files))
+    }
+
+    async fn unlink(&mut self) -> FileResult<()> {
+        Err(erofs())
+    }
+
+    async fn create(
+        &mut self,
+        _: &str,
+        _: u16,
+        _: FileType,
+        _: OpenMode,
+        _: &str,
+    ) -> FileResult<Self> {
+        Err(erofs())
+    }
+
+    async fn open(&mut self, mode: OpenMode) -> FileResult<StaticOpenFile> {
+        self.open_as(mode, Dialect::Unix).await
+    }
+
+    async fn open_with_context(
+        &mut self,
+        ctx: &OpContext<'_>,
+        mode: OpenMode,
+    ) -> FileResult<StaticOpenFile> {
+        self.open_as(mode, ctx.dialect).await
+    }
+
+    async fn readdir(&self) -> FileResult<Vec<Stat>> {
+        match &self.node().kind {
+            NodeKind::File(_) => Err(FileError(20, "ENOTDIR".to_owned())),
+            NodeKind::Dir(children) => {
+                let mut stats = vec![];
+                for child in children.values() {
+                    stats.push(self.at(*child).stat().await?);
+                }
+                Ok(stats)
+            }
+        }
+    }
+
+    fn qid(&self) -> Qid {
+        let ty = match self.node().kind {
+            NodeKind::Dir(_) => FileType::Dir,
+            NodeKind::File(_) => FileType::File,
+        };
+        Qid::new(ty, 0, self.idx as u64)
+    }
+}
+
+/// Open handle to a [StaticFile].
+pub enum StaticOpenFile {
+    /// Serialized Stat of every entry in the directory.
+    Dir(Vec<Vec<u8>>),
+
+    /// Regular file.
+    File(StaticFile),
+}
+
+impl OpenFile for StaticOpenFile {
+    fn iounit(&self) -> u32 {
+        0
+    }
+
+    async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> FileResult<u32> {
+        match self {
+            Self::File(file) => {
+                let NodeKind::File(contents) = &file.node().kind else {
+                    unreachable!();
+                };
+                let offset = (offset as usize).min(contents.len());
+                let n = buf.len().min(contents.len() - offset);
+                buf[..n].copy_from_slice(&contents[offset..offset + n]);
+                Ok(n as u32)
+            }
+            Self::Dir(entries) => dir::read_entries(entries, buf, offset),
+        }
+    }
+
+    async fn write_at(&mut self, _: &mut [u8], _: u64) -> FileResult<u32> {
+        Err(erofs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{raw::Hydrate, server::testing::block_on};
+    use std::io::Cursor;
+
+    fn tree() -> StaticTree {
+        StaticTree::new(BTreeMap::from([
+            (
+                "etc".to_owned(),
+                StaticEntry::dir([(
+                    "arigato",
+                    StaticEntry::dir([
+                        ("motd", StaticEntry::file(b"domo")),
+                        ("version", StaticEntry::file(b"0.2.0")),
+                    ]),
+                )]),
+            ),
+            ("README".to_owned(), StaticEntry::file(b"hello")),
+        ]))
+    }
+
+    async fn read_all(file: &mut StaticFile) -> Vec<u8> {
+        let mut of = file.open(OpenMode::from(0)).await.unwrap();
+        let mut buf = vec![0; 1024];
+        let n = of.read_at(&mut buf, 0).await.unwrap();
+        buf.truncate(n as usize);
+        buf
+    }
+
+    #[test]
+    fn walk_and_read() {
+        block_on(async {
+            let root = tree().attach("", "user", 0).await.unwrap();
+            assert_eq!(FileType::Dir, root.qid().ty);
+
+            let (file, files) = root.walk(&["etc", "arigato", "motd"]).await.unwrap();
+            let mut file = file.unwrap();
+            assert_eq!(3, files.len());
+            assert_eq!(FileType::Dir, files[0].qid().ty);
+            assert_eq!(FileType::Dir, files[1].qid().ty);
+            assert_eq!(file.qid(), files[2].qid());
+            assert_eq!(b"domo".to_vec(), read_all(&mut file).await);
+
+            let stat = file.stat().await.unwrap();
+            assert_eq!("motd", stat.name);
+            assert_eq!(4, stat.length);
+
+            // a missing last component walks as far as it can.
+            let (file, files) = root.walk(&["etc", "arigato", "nope"]).await.unwrap();
+            assert_eq!(Some(2), file.err().map(|err| err.errno));
+            assert_eq!(2, files.len());
+
+            let (file, _) = root.walk(&["etc", "..", "..", "README"]).await.unwrap();
+            assert_eq!(b"hello".to_vec(), read_all(&mut file.unwrap()).await);
+
+            // and nothing can be walked through a file.
+            let (file, files) = root.walk(&["README", "etc"]).await.unwrap();
+            assert_eq!(Some(20), file.err().map(|err| err.errno));
+            assert_eq!(1, files.len());
+        });
+    }
+
+    #[test]
+    fn read_dir() {
+        block_on(async {
+            let root = tree().attach("", "user", 0).await.unwrap();
+            let (dir, _) = root.walk(&["etc", "arigato"]).await.unwrap();
+            let listing = read_all(&mut dir.unwrap()).await;
+
+            let mut c = Cursor::new(listing.as_slice());
+            let mut names = vec![];
+            while (c.position() as usize) < listing.len() {
+                names.push(Stat::hydrate(&mut c).unwrap().name);
+            }
+            assert_eq!(vec!["motd", "version"], names);
+        });
+    }
+
+    #[test]
+    fn read_only() {
+        block_on(async {
+            let mut root = tree().attach("", "user", 0).await.unwrap();
+            assert!(root.open(OpenMode::from(1)).await.is_err());
+            assert!(root
+                .create("new", 0o644, FileType::File, OpenMode::from(0), "")
+                .await
+                .is_err());
+        });
+    }
+}
+
+// vim: foldmethod=marker
@@ -25,6 +25,8 @@
 //! For those not yet in on the bit, "Mr. Roboto" is a song by Styx. Styx is
 //! also the name of the 9P protocol.
 
+pub mod client;
+pub mod fs;
 pub mod raw;
 pub mod server;
 
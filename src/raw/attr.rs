@@ -0,0 +1,282 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use super::{dehydrate, Dehydrate, FileType, Hydrate, Perm, Qid, Stat};
+use std::io::Cursor;
+
+/// Bits of a Tgetattr request mask (and of the valid mask of an Rgetattr)
+/// covering the fields of a `stat(2)`: mode, nlink, uid, gid, rdev, atime,
+/// mtime, ctime, ino, size and blocks.
+pub const GETATTR_BASIC: u64 = 0x000007ff;
+
+/// Bits of a Tgetattr request mask covering every field of an Rgetattr,
+/// including btime, gen and data_version.
+pub const GETATTR_ALL: u64 = 0x00003fff;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFIFO: u32 = 0o010000;
+
+/// Attr is the attributes of a file as returned by a 9P2000.L Rgetattr,
+/// which takes the place of an Rstat in that dialect. Only the fields with
+/// a bit set in `valid` are meaningful.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attr {
+    /// mask of `GETATTR_*` bits saying which fields were filled in.
+    pub valid: u64,
+
+    /// qid of the file.
+    pub qid: Qid,
+
+    /// unix mode of the file, `S_IFMT` type bits included.
+    pub mode: u32,
+
+    /// numeric uid of the file's owner.
+    pub uid: u32,
+
+    /// numeric gid of the file's group.
+    pub gid: u32,
+
+    /// number of hard links to the file.
+    pub nlink: u64,
+
+    /// device number, if this is a device.
+    pub rdev: u64,
+
+    /// size of the file, in bytes.
+    pub size: u64,
+
+    /// preferred block size for i/o, in bytes.
+    pub blksize: u64,
+
+    /// number of 512 byte blocks allocated to the file.
+    pub blocks: u64,
+
+    /// last access time, in seconds since the epoch.
+    pub atime_sec: u64,
+
+    /// nanoseconds part of the last access time.
+    pub atime_nsec: u64,
+
+    /// last modification time, in seconds since the epoch.
+    pub mtime_sec: u64,
+
+    /// nanoseconds part of the last modification time.
+    pub mtime_nsec: u64,
+
+    /// last status change time, in seconds since the epoch.
+    pub ctime_sec: u64,
+
+    /// nanoseconds part of the last status change time.
+    pub ctime_nsec: u64,
+
+    /// creation time, in seconds since the epoch.
+    pub btime_sec: u64,
+
+    /// nanoseconds part of the creation time.
+    pub btime_nsec: u64,
+
+    /// inode generation number.
+    pub gen: u64,
+
+    /// data version of the file.
+    pub data_version: u64,
+}
+
+impl Attr {
+    /// Convert these attributes into a [Stat] of the file called `name`,
+    /// since an Rgetattr doesn't carry the name of the file. The uid and
+    /// gid only make it over as the numeric `nuid` and `ngid`.
+    pub fn to_stat(&self, name: &str) -> Stat {
+        let ty = match self.mode & S_IFMT {
+            S_IFDIR => FileType::Dir,
+            S_IFLNK => FileType::Link,
+            S_IFCHR | S_IFBLK => FileType::Device,
+            S_IFIFO => FileType::NamedPipe,
+            S_IFSOCK => FileType::Socket,
+            // append-only, exclusive and the like only show in the qid.
+            _ => self.qid.ty,
+        };
+        let mode = Perm::from_unix(self.mode).with_file_type(ty);
+
+        Stat::builder(name, self.qid.clone())
+            .with_mode(mode.into())
+            .with_exact_mode(true)
+            .with_size(self.size)
+            .with_atime(self.atime_sec as u32)
+            .with_mtime(self.mtime_sec as u32)
+            .with_nuid(self.uid)
+            .with_ngid(self.gid)
+            .build()
+    }
+}
+
+impl From<&Stat> for Attr {
+    /// The [GETATTR_BASIC] attributes of the file the Stat describes. A
+    /// Stat has no ctime, so the mtime stands in for it.
+    fn from(stat: &Stat) -> Self {
+        let perm = Perm::from(stat.mode);
+        let ifmt = match perm.file_type() {
+            FileType::Dir => S_IFDIR,
+            FileType::Link => S_IFLNK,
+            FileType::Device => S_IFCHR,
+            FileType::NamedPipe => S_IFIFO,
+            FileType::Socket => S_IFSOCK,
+            _ => S_IFREG,
+        };
+
+        Attr {
+            valid: GETATTR_BASIC,
+            qid: stat.qid.clone(),
+            mode: ifmt | perm.to_unix(),
+            uid: stat.nuid,
+            gid: stat.ngid,
+            nlink: 1,
+            rdev: 0,
+            size: stat.length,
+            blksize: 4096,
+            blocks: stat.length.div_ceil(512),
+            atime_sec: stat.atime as u64,
+            atime_nsec: 0,
+            mtime_sec: stat.mtime as u64,
+            mtime_nsec: 0,
+            ctime_sec: stat.mtime as u64,
+            ctime_nsec: 0,
+            btime_sec: 0,
+            btime_nsec: 0,
+            gen: 0,
+            data_version: 0,
+        }
+    }
+}
+
+impl<T> Hydrate<T> for Attr
+where
+    Self: Sized,
+    T: AsRef<[u8]>,
+{
+    type Error = std::io::Error;
+
+    fn hydrate(b: &mut Cursor<T>) -> Result<Self, Self::Error> {
+        Ok(Attr {
+            valid: u64::hydrate(b)?,
+            qid: Qid::hydrate(b)?,
+            mode: u32::hydrate(b)?,
+            uid: u32::hydrate(b)?,
+            gid: u32::hydrate(b)?,
+            nlink: u64::hydrate(b)?,
+            rdev: u64::hydrate(b)?,
+            size: u64::hydrate(b)?,
+            blksize: u64::hydrate(b)?,
+            blocks: u64::hydrate(b)?,
+            atime_sec: u64::hydrate(b)?,
+            atime_nsec: u64::hydrate(b)?,
+            mtime_sec: u64::hydrate(b)?,
+            mtime_nsec: u64::hydrate(b)?,
+            ctime_sec: u64::hydrate(b)?,
+            ctime_nsec: u64::hydrate(b)?,
+            btime_sec: u64::hydrate(b)?,
+            btime_nsec: u64::hydrate(b)?,
+            gen: u64::hydrate(b)?,
+            data_version: u64::hydrate(b)?,
+        })
+    }
+}
+
+impl Dehydrate for Attr
+where
+    Self: Sized,
+{
+    type Error = std::io::Error;
+
+    fn dehydrate(&self, b: &mut Cursor<Vec<u8>>) -> Result<(), Self::Error> {
+        dehydrate!(
+            b,
+            self.valid,
+            self.qid,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.nlink,
+            self.rdev,
+            self.size,
+            self.blksize,
+            self.blocks,
+            self.atime_sec,
+            self.atime_nsec,
+            self.mtime_sec,
+            self.mtime_nsec,
+            self.ctime_sec,
+            self.ctime_nsec,
+            self.btime_sec,
+            self.btime_nsec,
+            self.gen,
+            self.data_version
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Attr, Dehydrate, FileType, Hydrate, Perm, Qid, Stat};
+    use crate::raw::test_round_trip;
+    use std::io::Cursor;
+
+    test_round_trip!(
+        round_trip_attr,
+        Attr,
+        Attr,
+        (Attr::from(
+            &Stat::builder("motd", Qid::new(FileType::File, 1, 2))
+                .with_mode(0o644)
+                .with_size(1000)
+                .build()
+        ))
+    );
+
+    #[test]
+    fn stat_round_trip() {
+        for (ty, mode, unix) in [
+            (FileType::File, 0o4755, 0o104755),
+            (FileType::Dir, 0o755, 0o040755),
+            (FileType::Link, 0o777, 0o120777),
+            (FileType::Append, 0o644, 0o100644),
+        ] {
+            let stat = Stat::builder("name", Qid::new(ty, 3, 4))
+                .with_mode(Perm::from_unix(mode).into())
+                .with_size(1000)
+                .with_atime(5)
+                .with_mtime(6)
+                .with_nuid(1000)
+                .with_ngid(100)
+                .build();
+            let attr = Attr::from(&stat);
+            assert_eq!(unix, attr.mode, "{:?}", ty);
+            assert_eq!(2, attr.blocks);
+            assert_eq!(stat, attr.to_stat("name"), "{:?}", ty);
+        }
+    }
+}
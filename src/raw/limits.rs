@@ -0,0 +1,153 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Field widths and conventions that vary (or don't) between the 9P
+//! dialects, in one place.
+
+use super::{Version, IOHDRSZ};
+
+/// Flavor of 9P2000 spoken over a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// Plain 9P2000, as spoken by Plan 9.
+    Base,
+
+    /// 9P2000.u, which carries UNIX numeric ids, errnos and special files.
+    Unix,
+
+    /// 9P2000.L, which replaces much of the protocol with Linux-shaped
+    /// messages.
+    Linux,
+}
+
+impl Dialect {
+    /// Dialect of the provided Version, if it's one we know about.
+    pub fn of(version: &Version) -> Option<Self> {
+        match version.to_string().as_str() {
+            "9P2000" => Some(Self::Base),
+            "9P2000.u" => Some(Self::Unix),
+            "9P2000.L" => Some(Self::Linux),
+            _ => None,
+        }
+    }
+
+    /// Limits and conventions of this dialect.
+    pub const fn limits(self) -> Limits {
+        const COMMON: Limits = Limits {
+            max_string_len: u16::MAX as usize,
+            max_walk_elements: 16,
+            header_size: 7,
+            io_header_size: IOHDRSZ,
+            max_stat_size: Some(u16::MAX as usize),
+            stat_extensions: false,
+            n_uname: false,
+            errno: false,
+            error_string: true,
+        };
+
+        match self {
+            Self::Base => COMMON,
+            Self::Unix => Limits {
+                stat_extensions: true,
+                n_uname: true,
+                errno: true,
+                ..COMMON
+            },
+            Self::Linux => Limits {
+                max_stat_size: None,
+                n_uname: true,
+                errno: true,
+                error_string: false,
+                ..COMMON
+            },
+        }
+    }
+}
+
+/// Limits and conventions of a single [Dialect].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest string (name, uid, ...) which fits in its u16 length prefix.
+    pub max_string_len: usize,
+
+    /// Most path elements allowed in a single Twalk (MAXWELEM).
+    pub max_walk_elements: usize,
+
+    /// Bytes of framing in every message: size[4] type[1] tag[2].
+    pub header_size: u32,
+
+    /// Bytes to set aside from the msize for the framing of an Rread or
+    /// Twrite when picking an iounit (IOHDRSZ).
+    pub io_header_size: u32,
+
+    /// Largest encoded Stat, which is preceded by a u16 size. None if Stats
+    /// are not sent over the wire in this dialect (9P2000.L uses getattr).
+    pub max_stat_size: Option<usize>,
+
+    /// Whether Stats carry the extension and numeric uid/gid/muid fields.
+    pub stat_extensions: bool,
+
+    /// Whether Tauth and Tattach carry a numeric n_uname after the aname.
+    pub n_uname: bool,
+
+    /// Whether errors carry a numeric errno.
+    pub errno: bool,
+
+    /// Whether errors carry a descriptive string.
+    pub error_string: bool,
+}
+
+impl Version {
+    /// Limits and conventions of this Version, if its dialect is known.
+    pub fn limits(&self) -> Option<Limits> {
+        Dialect::of(self).map(Dialect::limits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(v: &str) -> Limits {
+        v.parse::<Version>().unwrap().limits().unwrap()
+    }
+
+    #[test]
+    fn dialects() {
+        assert_eq!(None, "9P2001".parse::<Version>().unwrap().limits());
+        assert_eq!(None, "9P2000.x".parse::<Version>().unwrap().limits());
+
+        let (base, unix, linux) = (limits("9P2000"), limits("9P2000.u"), limits("9P2000.L"));
+        for l in [&base, &unix, &linux] {
+            assert_eq!(16, l.max_walk_elements);
+            assert_eq!(65535, l.max_string_len);
+            assert_eq!(7, l.header_size);
+        }
+
+        assert!(!base.stat_extensions && unix.stat_extensions && !linux.stat_extensions);
+        assert!(!base.n_uname && unix.n_uname && linux.n_uname);
+        assert!(!base.errno && unix.errno && linux.errno);
+        assert!(base.error_string && unix.error_string && !linux.error_string);
+        assert_eq!(Some(65535), unix.max_stat_size);
+        assert_eq!(None, linux.max_stat_size);
+    }
+}
+
+// vim: foldmethod=marker
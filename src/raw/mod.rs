@@ -21,18 +21,27 @@
 //! This module contains raw protocol level primitives. This is to be used by
 //! something doing i/o between client and server.
 
+mod attr;
+mod limits;
 mod messages_r;
-mod messages_t;
+pub(crate) mod messages_t;
 mod numbers;
+mod perm;
 mod protocol;
 mod stat;
 mod string;
 mod vec;
 mod version;
 
+pub use attr::{Attr, GETATTR_ALL, GETATTR_BASIC};
+pub use limits::{Dialect, Limits};
 pub use messages_r::{RError, R};
 pub use messages_t::{TError, T};
-pub use protocol::{Fid, FileType, IoDirection, OpenMode, Qid, Tag, Type};
+pub use perm::{CreatePerm, Perm, Rwx};
+pub use protocol::{
+    Fid, FileType, IoDirection, OpenMode, Qid, StatFs, Tag, Type, DMAPPEND, DMAUTH, DMDEVICE,
+    DMDIR, DMEXCL, DMNAMEDPIPE, DMSOCKET, DMSYMLINK, DMTMP, IOHDRSZ, MAXWELEM, NOFID, NOTAG,
+};
 pub use stat::{Stat, StatError};
 pub use string::StringError;
 pub use vec::SliceError;
@@ -51,6 +60,16 @@ where
 
     /// Read bytes from the Cursor to create a new object.
     fn hydrate(b: &mut Cursor<T>) -> Result<Self, Self::Error>;
+
+    /// Like [Hydrate::hydrate], but also return the number of bytes the
+    /// object took up, which is how far the Cursor's position moved. This is
+    /// for checking decoded objects against any size the protocol declared
+    /// for them.
+    fn hydrate_counted(b: &mut Cursor<T>) -> Result<(Self, u64), Self::Error> {
+        let start = b.position();
+        let v = Self::hydrate(b)?;
+        Ok((v, b.position() - start))
+    }
 }
 
 /// Dehydrate is used to take an object and turn it into bytes.
@@ -64,6 +64,26 @@ mod tests {
     test_round_trip!(round_trip_u16, u16, u16, (0, 1, 0xFFFF));
     test_round_trip!(round_trip_u32, u32, u32, (0, 1, 0xFFFFFFFF));
     test_round_trip!(round_trip_u64, u64, u64, (0, 1, 0xFFFFFFFFFFFFFFFF));
+
+    fn bytes<T: Dehydrate>(v: T) -> Vec<u8> {
+        let mut b = Cursor::new(vec![]);
+        v.dehydrate(&mut b).ok().unwrap();
+        b.into_inner()
+    }
+
+    #[test]
+    fn little_endian() {
+        // 9P is little-endian on the wire, no matter the host.
+        assert_eq!(vec![0x02, 0x01], bytes(0x0102u16));
+        assert_eq!(vec![0x04, 0x03, 0x02, 0x01], bytes(0x01020304u32));
+        assert_eq!(
+            vec![0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01],
+            bytes(0x0102030405060708u64)
+        );
+
+        let v = u32::hydrate(&mut Cursor::new([0x04, 0x03, 0x02, 0x01])).unwrap();
+        assert_eq!(0x01020304, v);
+    }
 }
 
 // vim: foldmethod=marker
@@ -0,0 +1,262 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use super::{FileType, DMDEVICE, DMNAMEDPIPE, DMSOCKET};
+
+const DMSETUID: u32 = 0x00080000;
+const DMSETGID: u32 = 0x00040000;
+const DMSETVTX: u32 = 0x00010000;
+
+/// Mask of the bits in a mode word which carry the type of the file, rather
+/// than its permissions.
+const TYPE_MASK: u32 = 0xFF000000 | DMDEVICE | DMNAMEDPIPE | DMSOCKET;
+
+const UNIX_SETUID: u32 = 0o4000;
+const UNIX_SETGID: u32 = 0o2000;
+const UNIX_STICKY: u32 = 0o1000;
+
+/// Read, write and execute bits for one class of user (owner, group or
+/// other).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rwx {
+    /// Permission to read.
+    pub read: bool,
+
+    /// Permission to write.
+    pub write: bool,
+
+    /// Permission to execute (or search, for a directory).
+    pub execute: bool,
+}
+
+impl Rwx {
+    const fn from_bits(bits: u32) -> Self {
+        Self {
+            read: bits & 0o4 == 0o4,
+            write: bits & 0o2 == 0o2,
+            execute: bits & 0o1 == 0o1,
+        }
+    }
+
+    /// Return the three permission bits as an octal digit.
+    pub const fn bits(&self) -> u32 {
+        (self.read as u32) << 2 | (self.write as u32) << 1 | (self.execute as u32)
+    }
+}
+
+impl std::fmt::Display for Rwx {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            if self.read { 'r' } else { '-' },
+            if self.write { 'w' } else { '-' },
+            if self.execute { 'x' } else { '-' },
+        )
+    }
+}
+
+/// Perm is the 9P mode word of a file, as found in a Stat or a Tcreate:
+/// the file type in the high bits, the 9P2000.u setuid/setgid/sticky bits,
+/// and the unix permission bits in the low 9 bits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Perm(u32);
+
+impl From<u32> for Perm {
+    fn from(v: u32) -> Self {
+        Perm(v)
+    }
+}
+
+impl From<Perm> for u32 {
+    fn from(v: Perm) -> Self {
+        v.0
+    }
+}
+
+impl Perm {
+    /// Create a Perm from a unix-style mode (such as `0o4755`), where
+    /// setuid, setgid and sticky are 0o4000, 0o2000 and 0o1000. Any unix file
+    /// type bits are ignored.
+    pub const fn from_unix(mode: u32) -> Self {
+        let mut v = mode & 0o777;
+        if mode & UNIX_SETUID == UNIX_SETUID {
+            v |= DMSETUID;
+        }
+        if mode & UNIX_SETGID == UNIX_SETGID {
+            v |= DMSETGID;
+        }
+        if mode & UNIX_STICKY == UNIX_STICKY {
+            v |= DMSETVTX;
+        }
+        Perm(v)
+    }
+
+    /// Return the unix-style mode (such as `0o4755`) of this Perm, without
+    /// any file type information.
+    pub const fn to_unix(&self) -> u32 {
+        let mut v = self.0 & 0o777;
+        if self.setuid() {
+            v |= UNIX_SETUID;
+        }
+        if self.setgid() {
+            v |= UNIX_SETGID;
+        }
+        if self.sticky() {
+            v |= UNIX_STICKY;
+        }
+        v
+    }
+
+    /// Permissions of the file's owner.
+    pub const fn user(&self) -> Rwx {
+        Rwx::from_bits(self.0 >> 6)
+    }
+
+    /// Permissions of the file's group.
+    pub const fn group(&self) -> Rwx {
+        Rwx::from_bits(self.0 >> 3)
+    }
+
+    /// Permissions of everyone else.
+    pub const fn other(&self) -> Rwx {
+        Rwx::from_bits(self.0)
+    }
+
+    /// The low 9 permission bits (such as `0o755`).
+    pub const fn permissions(&self) -> u16 {
+        (self.0 & 0o777) as u16
+    }
+
+    /// check for setuid
+    pub const fn setuid(&self) -> bool {
+        self.0 & DMSETUID == DMSETUID
+    }
+
+    /// check for setgid
+    pub const fn setgid(&self) -> bool {
+        self.0 & DMSETGID == DMSETGID
+    }
+
+    /// check for the sticky bit
+    pub const fn sticky(&self) -> bool {
+        self.0 & DMSETVTX == DMSETVTX
+    }
+
+    /// Type of the file, from the DM* bits.
+    pub fn file_type(&self) -> FileType {
+        (self.0 & TYPE_MASK).into()
+    }
+
+    /// Replace the type bits of this Perm with the provided FileType.
+    pub fn with_file_type(self, ty: FileType) -> Self {
+        let ty: u32 = ty.into();
+        Perm(self.0 & !TYPE_MASK | ty)
+    }
+}
+
+/// CreatePerm is the perm word of a Tcreate, which packs the type of the
+/// file to be created alongside its unix permission bits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CreatePerm(Perm);
+
+impl From<u32> for CreatePerm {
+    fn from(v: u32) -> Self {
+        CreatePerm(Perm(v))
+    }
+}
+
+impl From<CreatePerm> for u32 {
+    fn from(v: CreatePerm) -> Self {
+        v.0 .0
+    }
+}
+
+impl CreatePerm {
+    /// Type of the file to be created, from the DM* bits.
+    pub fn file_type(&self) -> FileType {
+        self.0.file_type()
+    }
+
+    /// The low 9 unix permission bits (such as `0o755`) of the file to be
+    /// created.
+    pub const fn unix_mode(&self) -> u16 {
+        self.0.permissions()
+    }
+
+    /// The full mode word, as a [Perm].
+    pub const fn perm(&self) -> Perm {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CreatePerm, FileType, Perm, Rwx};
+    use crate::raw::{DMAPPEND, DMDIR};
+
+    #[test]
+    fn setuid_rwxr_xr_x() {
+        let perm = Perm::from_unix(0o4755);
+        assert!(perm.setuid());
+        assert!(!perm.setgid());
+        assert!(!perm.sticky());
+        assert_eq!("rwx", perm.user().to_string());
+        assert_eq!("r-x", perm.group().to_string());
+        assert_eq!(
+            Rwx {
+                read: true,
+                write: false,
+                execute: true,
+            },
+            perm.other()
+        );
+        assert_eq!(0o755, perm.permissions());
+        assert_eq!(0o4755, perm.to_unix());
+    }
+
+    #[test]
+    fn create_perm() {
+        let perm = CreatePerm::from(DMDIR | 0o755);
+        assert_eq!(FileType::Dir, perm.file_type());
+        assert_eq!(0o755, perm.unix_mode());
+        assert_eq!(DMDIR | 0o755, u32::from(perm));
+
+        let perm = CreatePerm::from(DMAPPEND | 0o644);
+        assert_eq!(FileType::Append, perm.file_type());
+        assert_eq!(0o644, perm.unix_mode());
+        assert_eq!(0o644, perm.perm().to_unix());
+    }
+
+    #[test]
+    fn type_bits() {
+        let perm = Perm::from_unix(0o2644).with_file_type(FileType::Dir);
+        assert_eq!(FileType::Dir, perm.file_type());
+        assert!(perm.setgid());
+        assert_eq!(0x80040000 | 0o644, u32::from(perm));
+
+        let perm: Perm = 0x80000000u32.into();
+        let perm = perm.with_file_type(FileType::Device);
+        assert_eq!(FileType::Device, perm.file_type());
+        assert_eq!(0o2644, Perm::from(0x80040000 | 0o644).to_unix());
+    }
+}
+
+// vim: foldmethod=marker
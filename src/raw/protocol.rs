@@ -29,6 +29,21 @@ pub type Type = u8;
 /// Tag is the message request/response unique identifier.
 pub type Tag = u16;
 
+/// Tag used by Tversion (and only Tversion), which is never otherwise a
+/// valid tag for a request.
+pub const NOTAG: Tag = 0xFFFF;
+
+/// Fid meaning "no fid", as sent for the afid of a Tattach which did not
+/// authenticate.
+pub const NOFID: Fid = !0;
+
+/// Largest number of path elements a single Twalk may carry (see walk(5)).
+pub const MAXWELEM: usize = 16;
+
+/// Bytes to set aside from the msize for the header of a Twrite (23 bytes)
+/// or Rread when picking an iounit, as with IOHDRSZ in Plan 9.
+pub const IOHDRSZ: u32 = 24;
+
 /// Client-defined file descriptor.
 pub type Fid = u32;
 
@@ -74,6 +89,7 @@ where
 }
 
 /// Direction for I/O operations -- Read/Write/ReadWrite.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum IoDirection {
     /// Read from the specified file.
     Read,
@@ -86,9 +102,40 @@ pub enum IoDirection {
 }
 
 impl OpenMode {
-    /// File direction (read, write, etc).
+    /// Open for reading (OREAD).
+    pub const fn read() -> Self {
+        OpenMode(0x00)
+    }
+
+    /// Open for writing (OWRITE).
+    pub const fn write() -> Self {
+        OpenMode(0x01)
+    }
+
+    /// Open for reading and writing (ORDWR).
+    pub const fn rdwr() -> Self {
+        OpenMode(0x02)
+    }
+
+    /// Open for execution (OEXEC), which reads the file.
+    pub const fn exec() -> Self {
+        OpenMode(0x03)
+    }
+
+    /// Also truncate the file as it is opened (OTRUNC).
+    pub const fn with_truncate(self) -> Self {
+        OpenMode(self.0 | 0x10)
+    }
+
+    /// Also remove the file once the fid is clunked (ORCLOSE).
+    pub const fn with_remove(self) -> Self {
+        OpenMode(self.0 | 0x40)
+    }
+
+    /// File direction (read, write, etc), from the low two bits of the
+    /// mode; an OEXEC reads the file.
     pub const fn direction(&self) -> IoDirection {
-        match self.0 % 0x04 {
+        match self.0 & 0x03 {
             0 => IoDirection::Read,
             1 => IoDirection::Write,
             2 => IoDirection::ReadWrite,
@@ -111,8 +158,64 @@ impl OpenMode {
     pub const fn remove(&self) -> bool {
         self.0 & 0x40 == 0x40
     }
+
+    /// append on write (9P2000.u)
+    pub const fn append(&self) -> bool {
+        self.0 & 0x80 == 0x80
+    }
+
+    /// Build the [std::fs::OpenOptions] that matches this mode, for
+    /// filesystems that are backed by real files. Truncating needs the file
+    /// to be open for writing, and not for appending (which std can't
+    /// express), so those modes are refused with
+    /// [std::io::ErrorKind::InvalidInput] rather than failing the open.
+    pub fn to_open_options(&self) -> std::io::Result<std::fs::OpenOptions> {
+        let direction = self.direction();
+        if self.truncate() && (direction == IoDirection::Read || self.append()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "OTRUNC needs a mode open for writing, without OAPPEND",
+            ));
+        }
+
+        let mut oo = std::fs::OpenOptions::new();
+        match direction {
+            IoDirection::Read => oo.read(true),
+            IoDirection::Write => oo.write(true),
+            IoDirection::ReadWrite => oo.read(true).write(true),
+        };
+        oo.truncate(self.truncate()).append(self.append());
+        Ok(oo)
+    }
 }
 
+/// Mode bit of a directory.
+pub const DMDIR: u32 = 0x80000000;
+
+/// Mode bit of an append-only file.
+pub const DMAPPEND: u32 = 0x40000000;
+
+/// Mode bit of an exclusive-use file.
+pub const DMEXCL: u32 = 0x20000000;
+
+/// Mode bit of an authentication file.
+pub const DMAUTH: u32 = 0x08000000;
+
+/// Mode bit of a temporary file, which need not be backed up.
+pub const DMTMP: u32 = 0x04000000;
+
+/// Mode bit of a symlink (9P2000.u).
+pub const DMSYMLINK: u32 = 0x02000000;
+
+/// Mode bit of a device node (9P2000.u).
+pub const DMDEVICE: u32 = 0x00800000;
+
+/// Mode bit of a named pipe (9P2000.u).
+pub const DMNAMEDPIPE: u32 = 0x00200000;
+
+/// Mode bit of a UNIX socket (9P2000.u).
+pub const DMSOCKET: u32 = 0x00100000;
+
 /// Type of file.
 #[non_exhaustive]
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -195,9 +298,9 @@ impl From<FileType> for u32 {
         let v = v << (32 - 8);
 
         v | match qt {
-            FileType::Device => 0x00800000,
-            FileType::NamedPipe => 0x00200000,
-            FileType::Socket => 0x00100000,
+            FileType::Device => DMDEVICE,
+            FileType::NamedPipe => DMNAMEDPIPE,
+            FileType::Socket => DMSOCKET,
             _ => 0,
         }
     }
@@ -209,9 +312,9 @@ impl From<u32> for FileType {
         let v = v & 0xfffffe00;
 
         match v {
-            0x00800000 => FileType::Device,
-            0x00200000 => FileType::NamedPipe,
-            0x00100000 => FileType::Socket,
+            DMDEVICE => FileType::Device,
+            DMNAMEDPIPE => FileType::NamedPipe,
+            DMSOCKET => FileType::Socket,
             _ => {
                 let v: u8 = (v >> (32 - 8)).try_into().unwrap();
                 v.into()
@@ -265,6 +368,9 @@ where
 
 /// Qid is a unique file identifier. Two files are the same iff they have the
 /// same qid.
+///
+/// qid paths are only unique within a single Filesystem (tree); see
+/// [crate::fs::QidSpace] for serving several trees as one.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Qid {
     /// the type of the file (directory, etc.), represented as a bit vector corresponding to the
@@ -286,6 +392,27 @@ impl Qid {
     }
 }
 
+impl std::fmt::Display for Qid {
+    /// Plan 9 style, as in `(d 0x5 v0)`: the type, the path, and the
+    /// version.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ty = match self.ty {
+            FileType::Dir => "d",
+            FileType::Append => "a",
+            FileType::Excl => "l",
+            FileType::Auth => "A",
+            FileType::Tmp => "t",
+            FileType::Link => "L",
+            FileType::Device => "D",
+            FileType::NamedPipe => "p",
+            FileType::Socket => "s",
+            FileType::File => "-",
+            FileType::Unknown(_) => "?",
+        };
+        write!(f, "({ty} {:#x} v{})", self.path, self.version)
+    }
+}
+
 impl<T> Hydrate<T> for Qid
 where
     Self: Sized,
@@ -314,10 +441,109 @@ where
     }
 }
 
+/// StatFs is information about the filesystem as a whole, rather than any
+/// one file in it, as returned by a 9P2000.L Rstatfs (and so, `statfs(2)` or
+/// `df` on a mount).
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatFs {
+    /// type of the filesystem, as a `statfs(2)` f_type magic number.
+    pub ty: u32,
+
+    /// size of a block, in bytes.
+    pub bsize: u32,
+
+    /// total number of blocks in the filesystem.
+    pub blocks: u64,
+
+    /// number of free blocks.
+    pub bfree: u64,
+
+    /// number of free blocks available to an unprivileged user.
+    pub bavail: u64,
+
+    /// total number of files (inodes) in the filesystem.
+    pub files: u64,
+
+    /// number of free files (inodes).
+    pub ffree: u64,
+
+    /// filesystem id.
+    pub fsid: u64,
+
+    /// maximum length of a filename, in bytes.
+    pub namelen: u32,
+}
+
+impl Default for StatFs {
+    /// A synthetic filesystem, with plenty of room left, of the 9P type
+    /// (`V9FS_MAGIC`).
+    fn default() -> Self {
+        StatFs {
+            ty: 0x01021997,
+            bsize: 4096,
+            blocks: 1 << 20,
+            bfree: 1 << 20,
+            bavail: 1 << 20,
+            files: 1 << 20,
+            ffree: 1 << 20,
+            fsid: 0,
+            namelen: 255,
+        }
+    }
+}
+
+impl<T> Hydrate<T> for StatFs
+where
+    Self: Sized,
+    T: AsRef<[u8]>,
+{
+    type Error = std::io::Error;
+
+    fn hydrate(b: &mut Cursor<T>) -> Result<Self, Self::Error> {
+        Ok(StatFs {
+            ty: u32::hydrate(b)?,
+            bsize: u32::hydrate(b)?,
+            blocks: u64::hydrate(b)?,
+            bfree: u64::hydrate(b)?,
+            bavail: u64::hydrate(b)?,
+            files: u64::hydrate(b)?,
+            ffree: u64::hydrate(b)?,
+            fsid: u64::hydrate(b)?,
+            namelen: u32::hydrate(b)?,
+        })
+    }
+}
+
+impl Dehydrate for StatFs
+where
+    Self: Sized,
+{
+    type Error = std::io::Error;
+
+    fn dehydrate(&self, b: &mut Cursor<Vec<u8>>) -> Result<(), Self::Error> {
+        dehydrate!(
+            b,
+            self.ty,
+            self.bsize,
+            self.blocks,
+            self.bfree,
+            self.bavail,
+            self.files,
+            self.ffree,
+            self.fsid,
+            self.namelen
+        );
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{super::test_round_trip, Dehydrate, FileType, Hydrate, Qid};
-    use std::io::Cursor;
+    use super::{
+        super::test_round_trip, Dehydrate, FileType, Hydrate, IoDirection, OpenMode, Qid, StatFs,
+        DMAPPEND, DMAUTH, DMDEVICE, DMDIR, DMEXCL, DMNAMEDPIPE, DMSOCKET, DMSYMLINK, DMTMP,
+    };
+    use std::io::{Cursor, Read, Write};
 
     test_round_trip!(
         round_trip_qid,
@@ -326,6 +552,26 @@ mod tests {
         (Qid::new(FileType::File, 10, 0xF00CAFE))
     );
 
+    test_round_trip!(
+        round_trip_statfs,
+        StatFs,
+        StatFs,
+        (
+            StatFs::default(),
+            StatFs {
+                ty: 1,
+                bsize: 2,
+                blocks: 3,
+                bfree: 4,
+                bavail: 5,
+                files: 6,
+                ffree: 7,
+                fsid: 8,
+                namelen: 9,
+            }
+        )
+    );
+
     #[test]
     fn test_filetype() {
         for (ft, check) in [
@@ -362,6 +608,108 @@ mod tests {
             assert_eq!(ft, ftu.into());
         }
     }
+
+    #[test]
+    fn dm_constants() {
+        for (ft, dm) in [
+            (FileType::Dir, DMDIR),
+            (FileType::Append, DMAPPEND),
+            (FileType::Excl, DMEXCL),
+            (FileType::Auth, DMAUTH),
+            (FileType::Tmp, DMTMP),
+            (FileType::Link, DMSYMLINK),
+            (FileType::Device, DMDEVICE),
+            (FileType::NamedPipe, DMNAMEDPIPE),
+            (FileType::Socket, DMSOCKET),
+        ] {
+            assert_eq!(dm, ft.into());
+            assert_eq!(ft, (dm | 0o755).into());
+        }
+    }
+
+    #[test]
+    fn open_mode() {
+        for (mode, raw, direction) in [
+            (OpenMode::read(), 0, IoDirection::Read),
+            (OpenMode::write(), 1, IoDirection::Write),
+            (OpenMode::rdwr(), 2, IoDirection::ReadWrite),
+            (OpenMode::exec(), 3, IoDirection::Read),
+        ] {
+            assert_eq!(raw, u8::from(mode));
+            assert_eq!(direction, mode.direction());
+            assert_eq!(raw == 3, mode.execute());
+
+            // each flag stands on its own, and leaves the direction be.
+            let flagged = [
+                mode.with_truncate(),
+                mode.with_remove(),
+                OpenMode::from(raw | 0x80),
+                OpenMode::from(raw | 0x2C),
+            ];
+            for (idx, flagged) in flagged.into_iter().enumerate() {
+                assert_eq!(direction, flagged.direction());
+                assert_eq!(raw == 3, flagged.execute());
+                assert_eq!(idx == 0, flagged.truncate());
+                assert_eq!(idx == 1, flagged.remove());
+                assert_eq!(idx == 2, flagged.append());
+            }
+
+            let all = mode.with_truncate().with_remove();
+            assert_eq!(raw | 0x50, u8::from(all));
+            assert!(all.truncate() && all.remove() && !all.append());
+        }
+    }
+
+    #[test]
+    fn test_open_options() {
+        let path = std::env::temp_dir().join(format!("arigato-oo-{}", std::process::id()));
+        let reset = || std::fs::write(&path, b"hello").unwrap();
+        let contents = || std::fs::read(&path).unwrap();
+
+        // OREAD
+        reset();
+        let mut f = OpenMode::from(0x00)
+            .to_open_options()
+            .unwrap()
+            .open(&path)
+            .unwrap();
+        let mut buf = vec![];
+        f.read_to_end(&mut buf).unwrap();
+        assert_eq!(b"hello", buf.as_slice());
+        assert!(f.write_all(b"nope").is_err());
+        assert_eq!(b"hello", contents().as_slice());
+
+        // OWRITE | OTRUNC
+        reset();
+        let mut f = OpenMode::from(0x11)
+            .to_open_options()
+            .unwrap()
+            .open(&path)
+            .unwrap();
+        assert_eq!(b"", contents().as_slice());
+        f.write_all(b"bye").unwrap();
+        assert!(f.read_to_end(&mut vec![]).is_err());
+        assert_eq!(b"bye", contents().as_slice());
+
+        // ORDWR | OAPPEND
+        reset();
+        let mut f = OpenMode::from(0x82)
+            .to_open_options()
+            .unwrap()
+            .open(&path)
+            .unwrap();
+        f.write_all(b" world").unwrap();
+        assert_eq!(b"hello world", contents().as_slice());
+
+        // OREAD | OTRUNC, OEXEC | OTRUNC and ORDWR | OTRUNC | OAPPEND
+        for raw in [0x10, 0x13, 0x92] {
+            let err = OpenMode::from(raw).to_open_options().unwrap_err();
+            assert_eq!(std::io::ErrorKind::InvalidInput, err.kind(), "{raw:#x}");
+        }
+        assert_eq!(b"hello world", contents().as_slice());
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
 
 // vim: foldmethod=marker
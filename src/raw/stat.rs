@@ -18,7 +18,10 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE. }}}
 
-use super::{dehydrate, Dehydrate, Hydrate, Qid, SliceError, StringError};
+use super::{
+    dehydrate, Dehydrate, Dialect, FileType, Hydrate, Qid, SliceError, StringError, DMDIR,
+    DMSYMLINK,
+};
 use std::{
     io::{Cursor, Read},
     num::TryFromIntError,
@@ -87,6 +90,13 @@ pub enum StatError {
 
     /// Error slicing.
     SliceError(SliceError<std::io::Error>),
+
+    /// The named string field is too long to be encoded.
+    FieldTooLong(&'static str),
+
+    /// The size declared for a stat disagrees with the number of bytes it
+    /// took to decode it.
+    SizeMismatch,
 }
 
 impl From<SliceError<std::io::Error>> for StatError {
@@ -135,6 +145,7 @@ pub struct StatBuilder {
     nuid: u32,
     ngid: u32,
     nmuid: u32,
+    exact_mode: bool,
 }
 
 impl StatBuilder {
@@ -156,6 +167,7 @@ impl StatBuilder {
             nuid: 0,
             ngid: 0,
             nmuid: 0,
+            exact_mode: false,
         }
     }
 
@@ -165,6 +177,15 @@ impl StatBuilder {
         self
     }
 
+    /// Use the mode set with [StatBuilder::with_mode] exactly as given,
+    /// rather than replacing its type bits (the high byte, and the device,
+    /// pipe and socket bits) with those of the qid's type. This is off by
+    /// default.
+    pub fn with_exact_mode(mut self, exact: bool) -> Self {
+        self.exact_mode = exact;
+        self
+    }
+
     /// Set the atime of the file.
     pub fn with_atime(mut self, atime: u32) -> Self {
         self.atime = atime;
@@ -243,11 +264,16 @@ impl StatBuilder {
             nuid,
             ngid,
             nmuid,
+            exact_mode,
         } = self;
 
-        // override the provided mode.
-        let qid_mode: u32 = qid.ty.into();
-        let mode = mode & 0x00FFFFFF | qid_mode;
+        // override the provided mode, unless asked not to.
+        let mode = if exact_mode {
+            mode
+        } else {
+            let qid_mode: u32 = qid.ty.into();
+            mode & 0x00FFFFFF | qid_mode
+        };
 
         Stat::new(
             ty, dev, qid, mode, atime, mtime, length, name, uid, gid, muid, extension, nuid, ngid,
@@ -262,6 +288,135 @@ impl Stat {
         StatBuilder::new(name, qid)
     }
 
+    /// Stat of a read-only (0o444) regular file of `size` bytes. The type
+    /// of the provided qid is set to [FileType::File].
+    pub fn regular_file(name: &str, qid: Qid, size: u64) -> Stat {
+        let qid = Qid::new(FileType::File, qid.version, qid.path);
+        Self::builder(name, qid)
+            .with_mode(0o444)
+            .with_size(size)
+            .build()
+    }
+
+    /// Stat of a read-only (0o555) directory. The type of the provided qid
+    /// is set to [FileType::Dir].
+    pub fn directory(name: &str, qid: Qid) -> Stat {
+        let qid = Qid::new(FileType::Dir, qid.version, qid.path);
+        Self::builder(name, qid).with_mode(0o555).build()
+    }
+
+    /// Stat with every field set to "don't touch": all ones for integers
+    /// and empty for strings. Sent in a Twstat, this changes nothing, and
+    /// instead asks the server to commit the file to stable storage before
+    /// replying (see stat(5)).
+    pub fn dont_touch() -> Stat {
+        Stat::new(
+            !0,
+            !0,
+            Qid::new(FileType::Unknown(!0), !0, !0),
+            !0,
+            !0,
+            !0,
+            !0,
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            !0,
+            !0,
+            !0,
+        )
+    }
+
+    /// Check if every field of this Stat is "don't touch"; see
+    /// [Stat::dont_touch].
+    pub fn is_dont_touch(&self) -> bool {
+        *self == Self::dont_touch()
+    }
+
+    /// Type of the file, from the type bits of the mode, or from the qid
+    /// when the mode carries none. Only the mode can tell a device, named
+    /// pipe or socket apart from a regular file.
+    pub fn file_type(&self) -> FileType {
+        match FileType::from(self.mode) {
+            FileType::File => self.qid.ty,
+            ty => ty,
+        }
+    }
+
+    /// Check if this is the Stat of a directory, by either the mode or the
+    /// qid.
+    pub fn is_dir(&self) -> bool {
+        self.mode & DMDIR != 0 || u8::from(self.qid.ty) & u8::from(FileType::Dir) != 0
+    }
+
+    /// Check if this is the Stat of a symlink (9P2000.u), by either the mode
+    /// or the qid.
+    pub fn is_symlink(&self) -> bool {
+        self.mode & DMSYMLINK != 0 || u8::from(self.qid.ty) & u8::from(FileType::Link) != 0
+    }
+
+    /// Permission bits of the file, the low 9 bits of the mode.
+    pub fn permissions(&self) -> u16 {
+        (self.mode & 0o777) as u16
+    }
+
+    /// Size of this Stat once encoded, not counting its own u16 size prefix.
+    pub fn encoded_size(&self) -> usize {
+        // type[2] dev[4] qid[13] mode[4] atime[4] mtime[4] length[8] plus
+        // n_uid[4] n_gid[4] n_muid[4], and then the strings.
+        const FIXED: usize = 2 + 4 + 13 + 4 + 4 + 4 + 8 + 4 + 4 + 4;
+        FIXED
+            + [
+                &self.name,
+                &self.uid,
+                &self.gid,
+                &self.muid,
+                &self.extension,
+            ]
+            .iter()
+            .map(|s| 2 + s.len())
+            .sum::<usize>()
+    }
+
+    /// Check that this Stat can be encoded, before handing it off to be
+    /// sent: every string field must fit behind its u16 length, and the
+    /// Stat as a whole behind its u16 size. Builders of directory listings
+    /// may want to call this per entry, rather than finding out deep inside
+    /// a reply.
+    pub fn validate(&self) -> Result<(), StatError> {
+        self.validate_as(Dialect::Unix)
+    }
+
+    /// Like [Stat::validate], for the Stat as laid out in the provided
+    /// [Dialect], which only checks the fields it sends.
+    pub fn validate_as(&self, dialect: Dialect) -> Result<(), StatError> {
+        let limits = dialect.limits();
+        let mut fields = vec![
+            ("name", &self.name),
+            ("uid", &self.uid),
+            ("gid", &self.gid),
+            ("muid", &self.muid),
+        ];
+        let mut size = self.encoded_size();
+        if limits.stat_extensions {
+            fields.push(("extension", &self.extension));
+        } else {
+            // extension[s] n_uid[4] n_gid[4] n_muid[4]
+            size -= 2 + self.extension.len() + 12;
+        }
+        for (field, value) in fields {
+            if value.len() > limits.max_string_len {
+                return Err(StatError::FieldTooLong(field));
+            }
+        }
+        match limits.max_stat_size {
+            Some(max) if size > max => Err(StatError::TooLarge),
+            _ => Ok(()),
+        }
+    }
+
     /// Create a new Stat object
     ///
     /// This is an internal method only used by the [StatBuilder].
@@ -303,6 +458,31 @@ impl Stat {
     }
 }
 
+impl std::fmt::Display for Stat {
+    /// One line, starting with the quoted name, such as
+    /// `'file' qid=(- 0x2 v0) mode=0o644 length=5 uid=glenda gid=glenda
+    /// muid=glenda atime=0 mtime=0`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "'{}' qid={} mode={:#o} length={} uid={} gid={} muid={} atime={} mtime={}",
+            self.name,
+            self.qid,
+            self.mode,
+            self.length,
+            self.uid,
+            self.gid,
+            self.muid,
+            self.atime,
+            self.mtime
+        )?;
+        if !self.extension.is_empty() {
+            write!(f, " extension={}", self.extension)?;
+        }
+        Ok(())
+    }
+}
+
 impl<T> Hydrate<T> for Stat
 where
     Self: Sized,
@@ -311,11 +491,37 @@ where
     type Error = StatError;
 
     fn hydrate(b: &mut Cursor<T>) -> Result<Self, Self::Error> {
+        Self::hydrate_as(b, Dialect::Unix)
+    }
+}
+
+impl Dehydrate for Stat
+where
+    Self: Sized,
+{
+    type Error = StatError;
+
+    fn dehydrate(&self, b: &mut Cursor<Vec<u8>>) -> Result<(), Self::Error> {
+        self.dehydrate_as(b, Dialect::Unix)
+    }
+}
+
+impl Stat {
+    /// Decode a Stat laid out as in the provided [Dialect]. Dialects without
+    /// the 9P2000.u fields leave the extension empty, and the numeric ids
+    /// as `!0` ("none", or "don't touch" in a Twstat).
+    pub fn hydrate_as<T>(b: &mut Cursor<T>, dialect: Dialect) -> Result<Self, StatError>
+    where
+        T: AsRef<[u8]>,
+    {
+        // the fields are read out of just this record, so a stat which is
+        // short can't read on into whatever follows it.
         let size = u16::hydrate(b)? as usize;
-        let mut buf = Vec::with_capacity(size);
+        let mut buf = vec![0u8; size];
         b.read_exact(&mut buf)?;
+        let b = &mut Cursor::new(buf);
 
-        Ok(Stat::new(
+        let mut stat = Stat::new(
             // f
             u16::hydrate(b)?,
             u32::hydrate(b)?,
@@ -328,21 +534,26 @@ where
             String::hydrate(b)?,
             String::hydrate(b)?,
             String::hydrate(b)?,
-            String::hydrate(b)?,
-            u32::hydrate(b)?,
-            u32::hydrate(b)?,
-            u32::hydrate(b)?,
-        ))
+            String::new(),
+            !0,
+            !0,
+            !0,
+        );
+        if dialect.limits().stat_extensions {
+            stat.extension = String::hydrate(b)?;
+            stat.nuid = u32::hydrate(b)?;
+            stat.ngid = u32::hydrate(b)?;
+            stat.nmuid = u32::hydrate(b)?;
+        }
+        if b.position() != size as u64 {
+            return Err(StatError::SizeMismatch);
+        }
+        Ok(stat)
     }
-}
-
-impl Dehydrate for Stat
-where
-    Self: Sized,
-{
-    type Error = StatError;
 
-    fn dehydrate(&self, b: &mut Cursor<Vec<u8>>) -> Result<(), Self::Error> {
+    /// Encode this Stat laid out as in the provided [Dialect], which leaves
+    /// out the 9P2000.u fields unless the dialect has them.
+    pub fn dehydrate_as(&self, b: &mut Cursor<Vec<u8>>, dialect: Dialect) -> Result<(), StatError> {
         // first pass is to write the Stat into a buffer, we size it up
         // and then send it along.
 
@@ -359,12 +570,17 @@ where
             self.name.as_str(),
             self.uid.as_str(),
             self.gid.as_str(),
-            self.muid.as_str(),
-            self.extension.as_str(),
-            self.nuid,
-            self.ngid,
-            self.nmuid
+            self.muid.as_str()
         );
+        if dialect.limits().stat_extensions {
+            dehydrate!(
+                &mut out,
+                self.extension.as_str(),
+                self.nuid,
+                self.ngid,
+                self.nmuid
+            );
+        }
         dehydrate!(b, out.into_inner().as_slice());
         Ok(())
     }
@@ -373,10 +589,54 @@ where
 #[cfg(test)]
 mod tests {
     use super::{
-        super::{test_round_trip, FileType},
-        Dehydrate, Hydrate, Qid, Stat,
+        super::{test_round_trip, FileType, DMDEVICE},
+        Dehydrate, Dialect, Hydrate, Qid, Stat, StatError, DMDIR,
     };
     use std::io::Cursor;
+
+    #[test]
+    fn file_type() {
+        let dir = Stat::builder("dir", Qid::new(FileType::Dir, 0, 1))
+            .with_mode(0o750)
+            .build();
+        assert_eq!(FileType::Dir, dir.file_type());
+        assert!(dir.is_dir() && !dir.is_symlink());
+        assert_eq!(0o750, dir.permissions());
+
+        let file = Stat::regular_file("file", Qid::new(FileType::File, 0, 2), 10);
+        assert_eq!(FileType::File, file.file_type());
+        assert!(!file.is_dir() && !file.is_symlink());
+        assert_eq!(0o444, file.permissions());
+
+        let link = Stat::builder("link", Qid::new(FileType::Link, 0, 3))
+            .with_mode(0o777)
+            .with_extension("file")
+            .build();
+        assert_eq!(FileType::Link, link.file_type());
+        assert!(!link.is_dir() && link.is_symlink());
+        assert_eq!(0o777, link.permissions());
+
+        // the mode and the qid are each enough on their own, and only the
+        // mode can speak for a device.
+        let dir = Stat::builder("dir", Qid::new(FileType::File, 0, 1))
+            .with_mode(DMDIR | 0o755)
+            .with_exact_mode(true)
+            .build();
+        assert!(dir.is_dir());
+        assert_eq!(FileType::Dir, dir.file_type());
+        let dir = Stat::builder("dir", Qid::new(FileType::Dir, 0, 1))
+            .with_mode(0o755)
+            .with_exact_mode(true)
+            .build();
+        assert!(dir.is_dir());
+        assert_eq!(FileType::Dir, dir.file_type());
+        let dev = Stat::builder("null", Qid::new(FileType::File, 0, 4))
+            .with_mode(DMDEVICE | 0o666)
+            .with_exact_mode(true)
+            .build();
+        assert_eq!(FileType::Device, dev.file_type());
+        assert_eq!(0o666, dev.permissions());
+    }
     test_round_trip!(
         round_trip_qid,
         Stat,
@@ -394,6 +654,229 @@ mod tests {
             .with_extension("something")
             .build())
     );
+
+    #[test]
+    fn encoded_size() {
+        let stat = Stat::builder("name", Qid::new(FileType::File, 0, 1))
+            .with_uid("uid")
+            .with_extension("ext")
+            .build();
+        let mut buf = Cursor::new(vec![]);
+        stat.dehydrate(&mut buf).unwrap();
+        assert_eq!(buf.into_inner().len(), stat.encoded_size() + 2);
+    }
+
+    #[test]
+    fn validate() {
+        let qid = Qid::new(FileType::File, 0, 1);
+        assert!(Stat::builder("name", qid.clone())
+            .build()
+            .validate()
+            .is_ok());
+
+        let long = "x".repeat(70000);
+        let stat = Stat::builder("name", qid.clone()).with_muid(&long).build();
+        assert!(matches!(
+            stat.validate(),
+            Err(StatError::FieldTooLong("muid"))
+        ));
+
+        // each field fits, but all of them together do not.
+        let long = "x".repeat(20000);
+        let stat = Stat::builder(&long, qid)
+            .with_uid(&long)
+            .with_gid(&long)
+            .with_extension(&long)
+            .build();
+        assert!(matches!(stat.validate(), Err(StatError::TooLarge)));
+        assert!(stat.dehydrate(&mut Cursor::new(vec![])).is_err());
+
+        // 9P2000 leaves the extension out, and with it, enough to fit.
+        assert!(stat.validate_as(Dialect::Base).is_ok());
+        assert!(stat
+            .dehydrate_as(&mut Cursor::new(vec![]), Dialect::Base)
+            .is_ok());
+    }
+
+    #[test]
+    fn regular_file() {
+        let stat = Stat::regular_file("motd", Qid::new(FileType::Dir, 1, 2), 10);
+        assert_eq!(Qid::new(FileType::File, 1, 2), stat.qid);
+        assert_eq!(0o444, stat.mode);
+        assert_eq!(10, stat.length);
+        assert_eq!("motd", stat.name);
+    }
+
+    #[test]
+    fn directory() {
+        let stat = Stat::directory("etc", Qid::new(FileType::File, 1, 2));
+        assert_eq!(Qid::new(FileType::Dir, 1, 2), stat.qid);
+        assert_eq!(0x80000000 | 0o555, stat.mode);
+        assert_eq!(0, stat.length);
+    }
+
+    #[test]
+    fn exact_mode() {
+        let qid = Qid::new(FileType::Device, 0, 1);
+        let stat = Stat::builder("tty", qid.clone()).with_mode(0o620).build();
+        assert_eq!(0x00800000 | 0o620, stat.mode);
+
+        // nothing is added, and nothing is taken away.
+        let mode = 0x04000000 | 0o620;
+        let stat = Stat::builder("tty", qid.clone())
+            .with_mode(mode)
+            .with_exact_mode(true)
+            .build();
+        assert_eq!(mode, stat.mode);
+        assert_eq!(qid, stat.qid);
+    }
+
+    #[test]
+    fn dont_touch() {
+        let stat = Stat::dont_touch();
+        assert!(stat.is_dont_touch());
+        assert_eq!(!0, stat.mode);
+        assert!(!Stat::builder("", stat.qid).build().is_dont_touch());
+    }
+
+    test_round_trip!(
+        round_trip_sentinels,
+        Stat,
+        Stat,
+        (
+            Stat::dont_touch(),
+            // only the length is being changed (a truncate).
+            Stat {
+                length: 0,
+                ..Stat::dont_touch()
+            },
+            // everything but the name is left alone.
+            Stat {
+                name: "renamed".to_owned(),
+                ..Stat::dont_touch()
+            }
+        )
+    );
+
+    #[test]
+    fn dont_touch_encoding() {
+        let mut b = Cursor::new(vec![]);
+        Stat::dont_touch().dehydrate(&mut b).unwrap();
+        let encoded = b.into_inner();
+
+        // every integer is all ones, and every string is empty.
+        let mut expected = vec![];
+        expected.extend_from_slice(&[0xFF; 2 + 4 + 13 + 4 + 4 + 4 + 8]);
+        expected.extend_from_slice(&[0x00; 5 * 2]);
+        expected.extend_from_slice(&[0xFF; 3 * 4]);
+        let size = (expected.len() as u16).to_le_bytes();
+        assert_eq!([&size[..], &expected].concat(), encoded);
+        assert_eq!(Stat::dont_touch().encoded_size(), expected.len());
+
+        let stat = Stat::hydrate(&mut Cursor::new(&encoded)).unwrap();
+        assert!(stat.is_dont_touch());
+        assert_eq!(u64::MAX, stat.length);
+        assert_eq!(u32::MAX, stat.nmuid);
+        assert_eq!("", stat.name);
+    }
+
+    #[test]
+    fn consumed_size() {
+        let stat = Stat::builder("name", Qid::new(FileType::File, 0, 1))
+            .with_uid("uid")
+            .with_extension("ext")
+            .build();
+        let mut b = Cursor::new(vec![]);
+        stat.dehydrate(&mut b).unwrap();
+        let mut encoded = b.into_inner();
+        let declared = u16::from_le_bytes([encoded[0], encoded[1]]) as u64;
+
+        let (hydrated, consumed) = Stat::hydrate_counted(&mut Cursor::new(&encoded)).unwrap();
+        assert_eq!(stat, hydrated);
+        assert_eq!(declared + 2, consumed);
+
+        // a stat which claims to be any longer than its fields is refused.
+        encoded[0] += 1;
+        encoded.push(0);
+        assert!(matches!(
+            Stat::hydrate(&mut Cursor::new(&encoded)),
+            Err(StatError::SizeMismatch)
+        ));
+    }
+
+    #[test]
+    fn bounded_record() {
+        let stat = Stat::builder("name", Qid::new(FileType::File, 0, 1))
+            .with_uid("uid")
+            .build();
+        let mut b = Cursor::new(vec![]);
+        stat.dehydrate(&mut b).unwrap();
+        let encoded = b.into_inner();
+
+        // whatever follows the record is left alone.
+        let mut trailing = encoded.clone();
+        trailing.extend_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        let mut b = Cursor::new(&trailing);
+        assert_eq!(stat, Stat::hydrate(&mut b).unwrap());
+        assert_eq!(encoded.len() as u64, b.position());
+
+        // a record too short for its fields is refused, even if there are
+        // enough bytes after it to make up the difference.
+        let mut short = encoded.clone();
+        short[0] -= 4;
+        assert!(Stat::hydrate(&mut Cursor::new(&short)).is_err());
+
+        // as is one which runs off the end of the buffer.
+        let truncated = &encoded[..encoded.len() - 1];
+        assert!(matches!(
+            Stat::hydrate(&mut Cursor::new(truncated)),
+            Err(StatError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn dialect_round_trip() {
+        let stat = Stat::builder("name", Qid::new(FileType::File, 0, 1))
+            .with_uid("uid")
+            .with_nuid(500)
+            .with_ngid(501)
+            .with_nmuid(502)
+            .with_extension("ext")
+            .build();
+
+        let mut b = Cursor::new(vec![]);
+        stat.dehydrate_as(&mut b, Dialect::Unix).unwrap();
+        let unix = b.into_inner();
+        assert_eq!(
+            stat,
+            Stat::hydrate_as(&mut Cursor::new(&unix), Dialect::Unix).unwrap()
+        );
+
+        // the base dialect has no extension[s] or n*id[4] fields at all.
+        let mut b = Cursor::new(vec![]);
+        stat.dehydrate_as(&mut b, Dialect::Base).unwrap();
+        let base = b.into_inner();
+        assert_eq!(unix.len() - (2 + 3) - 3 * 4, base.len());
+        assert_eq!(
+            base.len() - 2,
+            u16::from_le_bytes([base[0], base[1]]) as usize
+        );
+
+        let hydrated = Stat::hydrate_as(&mut Cursor::new(&base), Dialect::Base).unwrap();
+        assert_eq!(stat.name, hydrated.name);
+        assert_eq!(stat.uid, hydrated.uid);
+        assert_eq!("", hydrated.extension);
+        assert_eq!(u32::MAX, hydrated.nuid);
+        assert_eq!(u32::MAX, hydrated.ngid);
+        assert_eq!(u32::MAX, hydrated.nmuid);
+
+        // and a stat in one dialect doesn't parse as the other.
+        assert!(Stat::hydrate_as(&mut Cursor::new(&base), Dialect::Unix).is_err());
+        assert!(matches!(
+            Stat::hydrate_as(&mut Cursor::new(&unix), Dialect::Base),
+            Err(StatError::SizeMismatch)
+        ));
+    }
 }
 
 // vim: foldmethod=marker
@@ -80,21 +80,32 @@ impl FromStr for Version {
 }
 
 impl Version {
-    /// try to negotiate with the peer on a 9p protocol.
+    /// Try to agree on a 9P protocol with a peer speaking `other`, returning
+    /// the version both sides will speak. In order of precedence:
+    ///
+    /// | self       | other      | agreed                           |
+    /// |------------|------------|----------------------------------|
+    /// | 9P2000.u   | 9P2000.u   | 9P2000.u (an exact match)        |
+    /// | 9P2000.u   | 9P2000     | 9P2000 (the common base)         |
+    /// | 9P2000     | 9P2000.L   | 9P2000 (the common base)         |
+    /// | 9P2000.L   | 9P2000.u   | [VersionError::MismatchedVariant] |
+    /// | 9P2000     | 9P2001     | [VersionError::MismatchedId]     |
     pub fn try_negotiate(&self, other: &Version) -> Result<Version, VersionError> {
         if self.id != other.id {
             return Err(VersionError::MismatchedId);
         }
 
-        if self.variant == other.variant || self.variant.is_none() {
-            return Ok(self.clone());
-        }
-
-        // TODO: behavior if we want 9P2000.FOO but the peer wants 9P2000;
-        // we should negotiate down to 9P2000, but I don't think we actually
-        // want to here? This should likely change.
-
-        Err(VersionError::MismatchedVariant)
+        let variant = match (&self.variant, &other.variant) {
+            (ours, theirs) if ours == theirs => ours.clone(),
+            // one side speaks only the base protocol, which the other side
+            // speaks too.
+            (None, _) | (_, None) => None,
+            (Some(_), Some(_)) => return Err(VersionError::MismatchedVariant),
+        };
+        Ok(Version {
+            id: self.id.clone(),
+            variant,
+        })
     }
 }
 
@@ -120,7 +131,7 @@ impl Dehydrate for Version {
 
 #[cfg(test)]
 mod tests {
-    use super::{Dehydrate, Hydrate, Version};
+    use super::{Dehydrate, Hydrate, Version, VersionError};
     use crate::raw::test_round_trip;
     use std::io::Cursor;
 
@@ -137,22 +148,50 @@ mod tests {
 
     #[test]
     fn negotiate_matched() {
-        let v: Version = "9P2000".parse().unwrap();
-        let v1: Version = "9P2000.L".parse().unwrap();
-
-        assert_eq!(v.try_negotiate(&v).unwrap(), v, "9P2000 + 9P2000 = 9P2000");
-        assert_eq!(
-            v.try_negotiate(&v1).unwrap(),
-            v,
-            "9P2000 + 9P2000.L = 9P2000"
-        );
-        assert!(v1.try_negotiate(&v).is_err(), "9P2000.L + 9P2000 = Error");
-
-        let v2: Version = "9P2001.L".parse().unwrap();
-        assert!(v.try_negotiate(&v2).is_err());
-        assert!(v1.try_negotiate(&v2).is_err());
-        assert!(v2.try_negotiate(&v1).is_err());
-        assert!(v2.try_negotiate(&v).is_err());
+        let negotiate = |ours: &str, theirs: &str| {
+            let ours: Version = ours.parse().unwrap();
+            ours.try_negotiate(&theirs.parse().unwrap())
+                .map(|v| v.to_string())
+        };
+
+        for (ours, theirs, agreed) in [
+            ("9P2000", "9P2000", "9P2000"),
+            ("9P2000.u", "9P2000.u", "9P2000.u"),
+            ("9P2000.L", "9P2000.L", "9P2000.L"),
+            ("9P2000.u", "9P2000", "9P2000"),
+            ("9P2000", "9P2000.u", "9P2000"),
+            ("9P2000.L", "9P2000", "9P2000"),
+            ("9P2000", "9P2000.L", "9P2000"),
+        ] {
+            assert_eq!(
+                agreed,
+                negotiate(ours, theirs).unwrap(),
+                "{ours} + {theirs} = {agreed}"
+            );
+        }
+
+        for (ours, theirs) in [("9P2000.L", "9P2000.u"), ("9P2000.u", "9P2000.L")] {
+            assert!(
+                matches!(
+                    negotiate(ours, theirs),
+                    Err(VersionError::MismatchedVariant)
+                ),
+                "{ours} + {theirs}"
+            );
+        }
+
+        for (ours, theirs) in [
+            ("9P2000", "9P2001"),
+            ("9P2000.L", "9P2001.L"),
+            ("9P2001.L", "9P2000.L"),
+            ("9P2001.L", "9P2000"),
+            ("9P2000.u", "unknown"),
+        ] {
+            assert!(
+                matches!(negotiate(ours, theirs), Err(VersionError::MismatchedId)),
+                "{ours} + {theirs}"
+            );
+        }
     }
 
     test_round_trip!(
@@ -0,0 +1,318 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Operator-facing control over live connections.
+
+use super::{Peer, Result, ServerError};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// Server-assigned identifier of a single connection, unique for the life of
+/// the [ServerHandle] it was registered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Request from a [ServerHandle] to a connection handler, which is handled
+/// in between 9P requests.
+pub(crate) enum AdminRequest {
+    /// Clunk every fid, replying with how many there were.
+    ResetSession(oneshot::Sender<usize>),
+}
+
+type Connections = Arc<Mutex<HashMap<ConnectionId, (Peer, mpsc::Sender<AdminRequest>)>>>;
+
+/// Cloneable handle to a running server, used to inspect and manage its
+/// connections.
+#[derive(Clone)]
+pub struct ServerHandle {
+    next_id: Arc<AtomicU64>,
+    connections: Connections,
+    ready: Arc<watch::Sender<bool>>,
+    shutdown: Arc<watch::Sender<bool>>,
+    tasks: Arc<AtomicUsize>,
+    panics: Arc<AtomicUsize>,
+}
+
+impl Default for ServerHandle {
+    fn default() -> Self {
+        // watch::Sender is only Default as of tokio 1.43.
+        Self {
+            next_id: Default::default(),
+            connections: Default::default(),
+            ready: Arc::new(watch::channel(false).0),
+            shutdown: Arc::new(watch::channel(false).0),
+            tasks: Default::default(),
+            panics: Default::default(),
+        }
+    }
+}
+
+impl ServerHandle {
+    /// Register a new connection, which will be known to this handle until
+    /// the returned Registration is dropped.
+    pub(crate) fn register(&self, peer: Peer) -> Registration {
+        let id = ConnectionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = mpsc::channel(1);
+        self.connections.lock().unwrap().insert(id, (peer, tx));
+        Registration {
+            id,
+            rx,
+            connections: self.connections.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    /// Mark the server as accepting connections.
+    pub(crate) fn set_ready(&self) {
+        self.ready.send_replace(true);
+    }
+
+    /// Record the number of connection tasks the server is holding on to,
+    /// finished or not.
+    pub(crate) fn set_tasks(&self, tasks: usize) {
+        self.tasks.store(tasks, Ordering::Relaxed);
+    }
+
+    /// Record that a connection task panicked.
+    pub(crate) fn count_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of connections whose task panicked. Only the connection is
+    /// lost; the server carries on serving everyone else.
+    pub fn panics(&self) -> usize {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    /// Number of connection tasks the server is holding on to.
+    #[cfg(all(test, unix))]
+    pub(crate) fn tasks(&self) -> usize {
+        self.tasks.load(Ordering::Relaxed)
+    }
+
+    /// Wait until the server is accepting connections. The listening socket
+    /// is bound by [crate::server::AsyncServerBuilder::build], so clients
+    /// connecting before this resolves will be queued up by the kernel
+    /// rather than refused; this is for readiness probes that want to know
+    /// connections will actually be handled.
+    pub async fn ready(&self) {
+        let mut rx = self.ready.subscribe();
+        // the Sender is held by self, so this can't fail.
+        let _ = rx.wait_for(|ready| *ready).await;
+    }
+
+    /// Stop the server: [crate::server::AsyncServer::serve] stops accepting
+    /// connections, hangs up on every connected peer, and returns.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Wait until [ServerHandle::shutdown] has been called.
+    pub(crate) async fn shutdown_requested(&self) {
+        let mut rx = self.shutdown.subscribe();
+        let _ = rx.wait_for(|shutdown| *shutdown).await;
+    }
+
+    /// Number of currently connected peers.
+    pub fn connection_count(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    /// All currently connected peers.
+    pub fn connections(&self) -> Vec<(ConnectionId, Peer)> {
+        let mut connections: Vec<_> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, (peer, _))| (*id, peer.clone()))
+            .collect();
+        connections.sort_by_key(|(id, _)| *id);
+        connections
+    }
+
+    /// Clunk every fid held by the connection, without disconnecting it.
+    /// This is meant for long-lived connections from clients that leak fids.
+    /// Returns the number of fids that were clunked. Requests in flight
+    /// aren't waited on: they run to the end, but are then answered with
+    /// EBADF, as any fid they used (or were to create) is gone.
+    pub async fn reset_session(&self, id: ConnectionId) -> Result<usize> {
+        let tx = match self.connections.lock().unwrap().get(&id) {
+            Some((_, tx)) => tx.clone(),
+            None => return Err(ServerError::NoSuchConnection),
+        };
+        let (reply, clunked) = oneshot::channel();
+        tx.send(AdminRequest::ResetSession(reply))
+            .await
+            .map_err(|_| ServerError::NoSuchConnection)?;
+        clunked.await.map_err(|_| ServerError::NoSuchConnection)
+    }
+}
+
+/// A connection's membership in a [ServerHandle].
+pub(crate) struct Registration {
+    pub(crate) id: ConnectionId,
+    pub(crate) rx: mpsc::Receiver<AdminRequest>,
+    connections: Connections,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl Registration {
+    /// Shut down the server this connection belongs to; see
+    /// [ServerHandle::shutdown].
+    pub(crate) fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        raw::{R, T},
+        server::{
+            async_server::Options,
+            testing::{block_on, mount, mounts, TestConnection, TestFs},
+            Context,
+        },
+    };
+    use tokio::sync::Semaphore;
+
+    #[test]
+    fn reset_session() {
+        block_on(async {
+            let handle = ServerHandle::default();
+            let peer = Peer::Tcp("127.0.0.1:564".parse().unwrap());
+            let ctx = Context::new(
+                peer.clone(),
+                8192,
+                mounts(vec![("", mount(TestFs::new(&[("a", b"a")])))]),
+                Options::default(),
+            )
+            .with_admin(handle.register(peer));
+            let mut conn = TestConnection::new(ctx);
+
+            conn.attach(8192, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["a".to_owned()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+            let r = conn.rpc(T::Open(3, 2, 0.into())).await;
+            assert!(matches!(r, R::Open(3, _, _)), "{:?}", r);
+
+            let connections = handle.connections();
+            assert_eq!(1, connections.len());
+            assert_eq!(1, handle.connection_count());
+            let (id, _) = connections[0];
+            assert_eq!(2, handle.reset_session(id).await.unwrap());
+
+            for (tag, fid) in [(4, 1), (5, 2)] {
+                let r = conn.rpc(T::Stat(tag, fid)).await;
+                assert_eq!(R::Error(tag, "EBADF".to_owned(), 9), r);
+            }
+
+            // the connection is still good for a fresh attach.
+            let r = conn
+                .rpc(T::Attach(6, 1, !0, "user".to_owned(), "".to_owned(), 0))
+                .await;
+            assert!(matches!(r, R::Attach(6, _)), "{:?}", r);
+
+            // and is forgotten once it goes away.
+            let TestConnection { tw, rr, task } = conn;
+            drop((tw, rr));
+            let _ = task.await;
+            assert!(handle.connections().is_empty());
+            assert_eq!(0, handle.connection_count());
+            assert!(matches!(
+                handle.reset_session(id).await,
+                Err(ServerError::NoSuchConnection)
+            ));
+        });
+    }
+
+    #[test]
+    fn reset_session_counts_only_fids() {
+        block_on(async {
+            let release = Arc::new(Semaphore::new(0));
+            let fs = {
+                let release = release.clone();
+                TestFs::new(&[("a", b"a"), ("slow", b"")]).with_walk_hook(move |name| {
+                    let release = release.clone();
+                    let slow = name == "slow";
+                    async move {
+                        if slow {
+                            release.acquire().await.unwrap().forget()
+                        }
+                    }
+                })
+            };
+            let handle = ServerHandle::default();
+            let peer = Peer::Tcp("127.0.0.1:564".parse().unwrap());
+            let mounts = mounts(vec![("", mount(fs.clone()))]);
+            let ctx = Context::new(peer.clone(), 8192, mounts, Options::default())
+                .with_admin(handle.register(peer));
+            let mut conn = TestConnection::new(ctx);
+
+            conn.attach(8192, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["a".to_owned()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+
+            // fid 1 is off with the walk, which has fid 3 locked, but fid 3
+            // doesn't exist yet.
+            conn.tw
+                .send(T::Walk(3, 1, 3, vec!["slow".to_owned()]))
+                .await
+                .unwrap();
+            while fs.walks() < 2 {
+                tokio::task::yield_now().await;
+            }
+            let (id, _) = handle.connections()[0];
+            assert_eq!(2, handle.reset_session(id).await.unwrap());
+
+            // the walk made it, but fid 3 is gone with the rest.
+            release.add_permits(1);
+            let r = conn.rr.next().await.unwrap();
+            assert_eq!(R::Error(3, "EBADF".to_owned(), 9), r);
+            for (tag, fid) in [(4, 1), (5, 2), (6, 3)] {
+                let r = conn.rpc(T::Stat(tag, fid)).await;
+                assert_eq!(R::Error(tag, "EBADF".to_owned(), 9), r);
+            }
+        });
+    }
+}
+
+// vim: foldmethod=marker
@@ -20,8 +20,12 @@
 
 //! Async i/o
 
-use crate::raw::{Dehydrate, Hydrate, RError, TError, R, T};
-use std::{io::Cursor, pin::Pin};
+use super::BufferPool;
+use crate::raw::{Dialect, RError, TError, Type, R, T};
+use std::{
+    io::{Cursor, IoSlice},
+    pin::Pin,
+};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 /// Wrapper around tokio's AsyncRead, which is boxed and pinned for use by
@@ -32,17 +36,40 @@ pub type AsyncRead = Pin<Box<dyn tokio::io::AsyncRead + Send>>;
 /// futures.
 pub type AsyncWrite = Pin<Box<dyn tokio::io::AsyncWrite + Send>>;
 
+/// Callback invoked with the size (including the size prefix) and message
+/// type of every frame read, before the frame is hydrated.
+pub type FrameObserver = Box<dyn FnMut(u32, Type) + Send>;
+
 macro_rules! async_reader {
     ($name:ident -> <$ty:ty, $err:ty>, $overlong:expr) => {
         /// Read messages from the underlying [AsyncRead].
-        pub struct $name(AsyncRead, u32);
+        pub struct $name(AsyncRead, u32, u32, Option<FrameObserver>, Dialect);
 
         unsafe impl Send for $name {}
 
         impl $name {
             /// Create a new Reader, taking ownership of the [AsyncRead] object.
             pub fn new(r: AsyncRead, msize: u32) -> Self {
-                Self(r, msize)
+                Self(r, msize, 0, None, Dialect::Unix)
+            }
+
+            /// Set the [Dialect] messages are read in; this defaults to
+            /// 9P2000.u.
+            pub fn set_dialect(&mut self, dialect: Dialect) {
+                self.4 = dialect;
+            }
+
+            /// Invoke `observer` with the size and type of every frame as it
+            /// comes off the wire, before it is hydrated. This allows for
+            /// bandwidth accounting without re-encoding messages.
+            pub fn set_observer(&mut self, observer: Option<FrameObserver>) {
+                self.3 = observer;
+            }
+
+            /// Size, as claimed by the size prefix, of the last frame read.
+            /// This is set even if the frame turned out to be over msize.
+            pub fn last_frame_size(&self) -> u32 {
+                self.2
             }
 
             /// Set the limiting msize.
@@ -55,30 +82,114 @@ macro_rules! async_reader {
                 let mut size = [0, 0, 0, 0];
                 self.0.read_exact(&mut size).await?;
                 let size = u32::from_le_bytes(size);
+                self.2 = size;
+                if size < self.4.limits().header_size {
+                    return Err(<$err>::from(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "frame too short for its header",
+                    )));
+                }
                 if size > self.1 {
                     return Err($overlong);
                 }
-                let size = size as usize;
-                let mut buf = vec![0u8; size - 4];
+                let mut buf = vec![0u8; size as usize - 4];
                 self.0.read_exact(&mut buf).await?;
+                if let (Some(observer), Some(ty)) = (self.3.as_mut(), buf.first()) {
+                    observer(size, *ty);
+                }
                 let mut c = Cursor::new(buf);
-                <$ty>::hydrate(&mut c)
+                <$ty>::hydrate_as(&mut c, self.4)
             }
         }
     };
 }
 
+/// Largest body [write_all_vectored] will copy in after the head, rather
+/// than write out on its own, when the writer can't do vectored writes.
+const MAX_BODY_COPY: usize = 64 * 1024;
+
+/// Write out `head` followed by `body`, ideally in a single (vectored)
+/// write, without first copying them into one buffer. If the writer can't do
+/// vectored writes, a small enough body is copied onto the end of `head`
+/// instead, so that the frame still goes out in one write.
+async fn write_all_vectored(
+    w: &mut AsyncWrite,
+    head: &mut Vec<u8>,
+    mut body: &[u8],
+) -> std::io::Result<()> {
+    if !body.is_empty() && !w.is_write_vectored() && body.len() <= MAX_BODY_COPY {
+        head.extend_from_slice(body);
+        return w.write_all(head).await;
+    }
+
+    let mut head = &head[..];
+    while !head.is_empty() {
+        let n = w
+            .write_vectored(&[IoSlice::new(head), IoSlice::new(body)])
+            .await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        if n < head.len() {
+            head = &head[n..];
+        } else {
+            body = &body[n - head.len()..];
+            head = &[];
+        }
+    }
+    w.write_all(body).await
+}
+
 macro_rules! async_writer {
     ($name:ident -> <$ty:ty, $err:ty>, $overlong:expr) => {
         /// Write messages to the underlying [AsyncWrite].
-        pub struct $name(AsyncWrite, u32);
+        pub struct $name(
+            AsyncWrite,
+            u32,
+            Option<usize>,
+            Vec<u8>,
+            Vec<u8>,
+            Option<BufferPool>,
+            Dialect,
+        );
 
         unsafe impl Send for $name {}
 
         impl $name {
             /// Create a new Writer, taking ownership of the [AsyncWrite] object.
             pub fn new(w: AsyncWrite, msize: u32) -> Self {
-                Self(w, msize)
+                Self(w, msize, None, vec![], vec![], None, Dialect::Unix)
+            }
+
+            /// Set the [Dialect] messages are sent in; this defaults to
+            /// 9P2000.u.
+            pub fn set_dialect(&mut self, dialect: Dialect) {
+                self.6 = dialect;
+            }
+
+            /// Once the bulk data of a message (the data of a read or write)
+            /// has been sent, give its buffer back to `pool` to be reused.
+            pub fn set_pool(&mut self, pool: Option<BufferPool>) {
+                self.5 = pool;
+            }
+
+            /// Rather than writing each message out as it is sent, hold them
+            /// (in order) until `limit` bytes are pending or `flush` is
+            /// called, and then write them all out at once. A `limit` of
+            /// None writes each message as it is sent, which is the default.
+            pub fn set_coalescing(&mut self, limit: Option<usize>) {
+                self.2 = limit;
+            }
+
+            /// Write out any pending messages, and flush the underlying
+            /// stream.
+            pub async fn flush(&mut self) -> Result<(), $err> {
+                if !self.3.is_empty() {
+                    self.0.write_all(&self.3).await?;
+                    self.3.clear();
+                }
+                self.0.flush().await?;
+                Ok(())
             }
 
             /// Set the limiting msize.
@@ -87,19 +198,61 @@ macro_rules! async_writer {
             }
 
             /// Write a message to the underlying stream.
+            ///
+            /// The bulk data of a message is written straight out of the
+            /// message's own buffer, following the rest of the frame, rather
+            /// than being copied in with it. The one exception is when
+            /// coalescing, and the frame is smaller than the coalescing
+            /// limit.
             pub async fn send(&mut self, msg: $ty) -> Result<(), $err> {
-                let mut buf = Cursor::new(vec![0; self.1 as usize]);
-                msg.dehydrate(&mut buf)?;
-                let pos = buf.position() as usize;
-                let size = pos + 4;
+                let mut head = Cursor::new(std::mem::take(&mut self.4));
+                head.get_mut().clear();
+                head.get_mut().extend_from_slice(&[0; 4]);
+                head.set_position(4);
+
+                let result = self.send_frame(&msg, &mut head).await;
+                self.4 = head.into_inner();
+                if let (Some(pool), Some(buf)) = (&self.5, msg.into_payload()) {
+                    pool.give(buf);
+                }
+                result
+            }
+
+            async fn send_frame(
+                &mut self,
+                msg: &$ty,
+                head: &mut Cursor<Vec<u8>>,
+            ) -> Result<(), $err> {
+                let body = msg.dehydrate_frame(head, self.6)?;
+                let head = head.get_mut();
+                let size = head.len() + body.len();
 
+                // the size counts its own four bytes, just like the msize
+                // does; a frame of exactly msize bytes is fine.
                 if size > (self.1 as usize) {
                     return Err($overlong);
                 }
+                head[..4].copy_from_slice(&(size as u32).to_le_bytes());
 
-                self.0.write_all(&(size as u32).to_le_bytes()).await?;
-                let buf = buf.into_inner();
-                self.0.write_all(&buf[..pos]).await?;
+                match self.2 {
+                    Some(limit) if size < limit || body.is_empty() => {
+                        self.3.extend_from_slice(head);
+                        self.3.extend_from_slice(body);
+                        if self.3.len() >= limit {
+                            self.flush().await?;
+                        }
+                    }
+                    Some(_) => {
+                        // too big to be worth the copy; get whatever is
+                        // pending out of the way.
+                        if !self.3.is_empty() {
+                            self.0.write_all(&self.3).await?;
+                            self.3.clear();
+                        }
+                        write_all_vectored(&mut self.0, head, body).await?;
+                    }
+                    None => write_all_vectored(&mut self.0, head, body).await?,
+                }
                 Ok(())
             }
         }
@@ -112,4 +265,250 @@ async_reader!(TReader -> <T, TError>, TError::TooLong);
 async_writer!(RWriter -> <R, RError>, RError::TooLong);
 async_writer!(TWriter -> <T, TError>, TError::TooLong);
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::block_on;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn observed_frame_size() {
+        block_on(async {
+            let (client, server) = tokio::io::duplex(4096);
+            let mut tw = TWriter::new(Box::pin(client), 1024);
+            let mut tr = TReader::new(Box::pin(server), 1024);
+
+            let seen = Arc::new(Mutex::new(vec![]));
+            tr.set_observer(Some(Box::new({
+                let seen = seen.clone();
+                move |size, ty| seen.lock().unwrap().push((size, ty))
+            })));
+
+            // size[4] Tclunk[1] tag[2] fid[4]
+            tw.send(T::Clunk(1, 2)).await.unwrap();
+            assert_eq!(T::Clunk(1, 2), tr.next().await.unwrap());
+            assert_eq!(11, tr.last_frame_size());
+            assert_eq!(vec![(11, 120)], *seen.lock().unwrap());
+        });
+    }
+
+    #[test]
+    fn short_frame() {
+        block_on(async {
+            let (mut client, server) = tokio::io::duplex(4096);
+            let mut tr = TReader::new(Box::pin(server), 1024);
+
+            // a size which doesn't even cover size[4] type[1] tag[2].
+            for size in [0u32, 3, 6] {
+                client.write_all(&size.to_le_bytes()).await.unwrap();
+                assert!(matches!(tr.next().await, Err(TError::IoError(_))), "{size}");
+            }
+        });
+    }
+
+    #[test]
+    fn version_frame_layout() {
+        block_on(async {
+            let (client, server) = tokio::io::duplex(4096);
+            let mut tw = TWriter::new(Box::pin(client), 1024);
+            let mut server = server;
+
+            tw.send(T::Version(0xFFFF, 8192, "9P2000".parse().unwrap()))
+                .await
+                .unwrap();
+            drop(tw);
+
+            let mut frame = vec![];
+            server.read_to_end(&mut frame).await.unwrap();
+            #[rustfmt::skip]
+            assert_eq!(
+                vec![
+                    19, 0, 0, 0,           // size[4]
+                    100,                   // Tversion[1]
+                    0xFF, 0xFF,            // tag[2]
+                    0x00, 0x20, 0x00, 0x00, // msize[4]
+                    6, 0,                  // version[s]
+                    b'9', b'P', b'2', b'0', b'0', b'0',
+                ],
+                frame
+            );
+        });
+    }
+
+    #[test]
+    fn coalesced_writes() {
+        block_on(async {
+            let (client, server) = tokio::io::duplex(4096);
+            let mut rw = RWriter::new(Box::pin(server), 1024);
+            let mut rr = RReader::new(Box::pin(client), 1024);
+            rw.set_coalescing(Some(64));
+
+            // 7 bytes apiece; nothing goes out until we reach 64 bytes.
+            for tag in 0..9 {
+                rw.send(R::Clunk(tag)).await.unwrap();
+            }
+            assert_eq!(63, rw.3.len());
+            rw.send(R::Clunk(9)).await.unwrap();
+            assert!(rw.3.is_empty());
+
+            rw.send(R::Clunk(10)).await.unwrap();
+            assert_eq!(7, rw.3.len());
+
+            // big enough to skip the copy, but still sent in order.
+            rw.send(R::Read(11, vec![0xAB; 100])).await.unwrap();
+            assert!(rw.3.is_empty());
+            rw.send(R::Clunk(12)).await.unwrap();
+            rw.flush().await.unwrap();
+
+            for tag in 0..11 {
+                assert_eq!(R::Clunk(tag), rr.next().await.unwrap());
+            }
+            assert_eq!(R::Read(11, vec![0xAB; 100]), rr.next().await.unwrap());
+            assert_eq!(R::Clunk(12), rr.next().await.unwrap());
+        });
+    }
+
+    #[test]
+    fn frame_at_msize() {
+        block_on(async {
+            const MSIZE: u32 = 1024;
+            for coalescing in [None, Some(4096)] {
+                let (client, server) = tokio::io::duplex(8192);
+                let mut rw = RWriter::new(Box::pin(server), MSIZE);
+                let mut rr = RReader::new(Box::pin(client), MSIZE);
+                rw.set_coalescing(coalescing);
+
+                // size[4] Rread[1] tag[2] count[4] data[count]
+                for size in [MSIZE - 1, MSIZE] {
+                    let data = vec![0xAB; size as usize - 11];
+                    rw.send(R::Read(1, data.clone())).await.unwrap();
+                    rw.flush().await.unwrap();
+                    assert_eq!(R::Read(1, data), rr.next().await.unwrap());
+                    assert_eq!(size, rr.last_frame_size());
+                }
+                let r = rw.send(R::Read(2, vec![0xAB; MSIZE as usize - 10])).await;
+                assert!(matches!(r, Err(RError::TooLong)), "{coalescing:?}");
+
+                // size[4] Twrite[1] tag[2] fid[4] offset[8] count[4] data[count]
+                let (client, server) = tokio::io::duplex(8192);
+                let mut tw = TWriter::new(Box::pin(client), MSIZE);
+                let mut tr = TReader::new(Box::pin(server), MSIZE);
+                tw.set_coalescing(coalescing);
+                for size in [MSIZE - 1, MSIZE] {
+                    let data = vec![0xCD; size as usize - 23];
+                    tw.send(T::Write(1, 2, 0, data.clone())).await.unwrap();
+                    tw.flush().await.unwrap();
+                    assert_eq!(T::Write(1, 2, 0, data), tr.next().await.unwrap());
+                    assert_eq!(size, tr.last_frame_size());
+                }
+                let r = tw
+                    .send(T::Write(2, 2, 0, vec![0xCD; MSIZE as usize - 22]))
+                    .await;
+                assert!(matches!(r, Err(TError::TooLong)), "{coalescing:?}");
+
+                // nor will a reader take a frame one byte over.
+                tw.set_msize(MSIZE + 1);
+                tw.send(T::Write(3, 2, 0, vec![0xCD; MSIZE as usize - 22]))
+                    .await
+                    .unwrap();
+                tw.flush().await.unwrap();
+                assert!(matches!(tr.next().await, Err(TError::TooLong)));
+                assert_eq!(MSIZE + 1, tr.last_frame_size());
+            }
+        });
+    }
+
+    #[test]
+    fn read_buffer_returned_to_pool() {
+        block_on(async {
+            let (client, server) = tokio::io::duplex(4096);
+            let mut rw = RWriter::new(Box::pin(server), 1024);
+            let mut rr = RReader::new(Box::pin(client), 1024);
+            let pool = BufferPool::new(4);
+            rw.set_pool(Some(pool.clone()));
+
+            let mut buf = pool.take(512);
+            buf[..5].copy_from_slice(b"hello");
+            buf.truncate(5);
+            let ptr = buf.as_ptr();
+            rw.send(R::Read(1, buf)).await.unwrap();
+            assert_eq!(R::Read(1, b"hello".to_vec()), rr.next().await.unwrap());
+
+            assert_eq!(1, pool.len());
+            let reused = pool.take(512);
+            assert_eq!(ptr, reused.as_ptr());
+            pool.give(reused);
+
+            // an oversized frame is still an error, and the buffer is
+            // still given back.
+            let r = rw.send(R::Read(2, pool.take(2048))).await;
+            assert!(matches!(r, Err(RError::TooLong)));
+            assert_eq!(1, pool.len());
+        });
+    }
+
+    /// Writer which keeps every write made to it, one by one, and can't do
+    /// vectored writes.
+    struct Writes(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl tokio::io::AsyncWrite for Writes {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn one_write_per_frame() {
+        block_on(async {
+            let writes = Arc::new(Mutex::new(vec![]));
+            let mut rw = RWriter::new(Box::pin(Writes(writes.clone())), 1 << 20);
+            let sent = || {
+                vec![
+                    R::Clunk(1),
+                    R::Error(2, "ENOENT".to_owned(), 2),
+                    R::Read(3, vec![0xAB; 4096]),
+                    R::Read(4, vec![0xCD; MAX_BODY_COPY + 1]),
+                ]
+            };
+
+            // the size prefix goes out with the rest of the frame, as does
+            // the data of a read, unless it's too big to be worth copying.
+            let mut counts = vec![];
+            for r in sent() {
+                rw.send(r).await.unwrap();
+                counts.push(std::mem::take(&mut *writes.lock().unwrap()));
+            }
+            assert_eq!(
+                vec![1, 1, 1, 2],
+                counts.iter().map(|w| w.len()).collect::<Vec<_>>()
+            );
+
+            let stream: Vec<u8> = counts.into_iter().flatten().flatten().collect();
+            let mut rr = RReader::new(Box::pin(std::io::Cursor::new(stream)), 1 << 20);
+            for r in sent() {
+                assert_eq!(r, rr.next().await.unwrap());
+            }
+        });
+    }
+}
+
 // vim: foldmethod=marker
@@ -19,15 +19,162 @@
 // THE SOFTWARE. }}}
 
 use super::{
-    aio::{RWriter, TReader},
-    connection_handler, JoinSet, Result,
+    admin::{Registration, ServerHandle},
+    aio::{AsyncRead, AsyncWrite, RWriter, TReader},
+    connection_handler,
+    connection_limit::ConnectionLimit,
+    message_handler::{linux_only, SUPPORTED_MESSAGES},
+    select::{select, Either},
+    Authenticator, Clock, ConnectionLimitPolicy, JoinSet, NoopObserver, Observer, PathPolicy, Peer,
+    RateLimit, RateLimitPolicy, Result, SystemClock,
 };
 use crate::{
-    raw::Version,
+    raw::{messages_t::TYPE_TAUTH, Type},
     server::{FileHandles, Filesystem, Requests},
 };
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::{net::TcpListener, sync::Mutex};
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpSocket},
+    sync::{Mutex, OwnedSemaphorePermit},
+};
+
+/// A Filesystem registered with the server under some name (aname), along
+/// with any per-filesystem tuning.
+pub(crate) struct Mount<FilesystemT> {
+    pub(crate) filesystem: FilesystemT,
+
+    /// Upper bound on the number of bytes returned by a single read against
+    /// any file on this filesystem. This is applied on top of the msize.
+    pub(crate) max_read: Option<u32>,
+}
+
+/// All Filesystems known to the server, by name (aname).
+pub(crate) type Mounts<FilesystemT> = Arc<Mutex<HashMap<String, Mount<FilesystemT>>>>;
+
+/// Picks the Filesystem to attach to given the (uname, aname) of a Tattach,
+/// or None to look the aname up in the [Mounts] as usual.
+pub(crate) type Router<FilesystemT> = Arc<dyn Fn(&str, &str) -> Option<FilesystemT> + Send + Sync>;
+
+/// Number of requests a connection may have running or waiting to run
+/// before the server stops reading from it, unless told otherwise by
+/// [AsyncServerBuilder::with_max_requests].
+pub(crate) const DEFAULT_MAX_REQUESTS: usize = 64;
+
+/// Per-connection tunables, copied from the [AsyncServer] into the [Context]
+/// of each new connection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Options {
+    /// Limit on how quickly a connection may issue requests.
+    pub(crate) rate_limit: Option<RateLimit>,
+
+    /// Check that the qids returned by a Filesystem's walk make sense before
+    /// sending them along to the client.
+    pub(crate) validate_walk: bool,
+
+    /// Reply ENOENT to a walk that could not resolve the full path, rather
+    /// than returning the partial chain.
+    pub(crate) strict_walk: bool,
+
+    /// Offer 9P2000.L alongside 9P2000.u.
+    pub(crate) linux: bool,
+
+    /// Refuse, with EROFS, anything that would change a Filesystem.
+    pub(crate) read_only: bool,
+
+    /// Replace the client-provided uname and n_uname with the kernel-reported
+    /// uid of the peer, when we have one.
+    pub(crate) peer_cred_identity: bool,
+
+    /// Hold replies until this many bytes are pending or the client has
+    /// nothing else queued up, rather than writing each one out on its own.
+    pub(crate) coalesce_writes: Option<usize>,
+
+    /// Hang up on connections that have not sent a request in this long.
+    pub(crate) idle_timeout: Option<Duration>,
+
+    /// Give up on requests which have not been answered in this long,
+    /// replying ETIMEDOUT.
+    pub(crate) request_timeout: Option<Duration>,
+
+    /// Stop reading requests from a connection with this many running or
+    /// waiting to run; None is [DEFAULT_MAX_REQUESTS].
+    pub(crate) max_requests: Option<usize>,
+
+    /// Hang up on connections that send more than this many bytes without
+    /// successfully negotiating a version.
+    pub(crate) handshake_byte_budget: Option<u32>,
+
+    /// Source of time for timeouts; None is the [SystemClock].
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+
+    /// Rewrite or refuse walks before they reach the Filesystem.
+    pub(crate) path_policy: Option<Arc<dyn PathPolicy>>,
+
+    /// Require clients to authenticate before they attach.
+    pub(crate) authenticator: Option<Arc<dyn Authenticator>>,
+
+    /// Told about every request and reply, for keeping metrics; None is the
+    /// [NoopObserver].
+    pub(crate) observer: Option<Arc<dyn Observer>>,
+}
+
+impl Options {
+    /// Clock to use for this connection.
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock))
+    }
+
+    /// Number of requests a connection may have running or waiting to run.
+    pub(crate) fn max_requests(&self) -> usize {
+        self.max_requests.unwrap_or(DEFAULT_MAX_REQUESTS)
+    }
+
+    /// Observer to tell about this connection.
+    pub(crate) fn observer(&self) -> Arc<dyn Observer> {
+        self.observer
+            .clone()
+            .unwrap_or_else(|| Arc::new(NoopObserver))
+    }
+}
+
+/// Socket the [AsyncServer] is accepting new connections on.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /// Accept the next connection, returning both halves of the stream, as
+    /// well as who is on the other end.
+    pub(crate) async fn accept(&self) -> std::io::Result<(AsyncRead, AsyncWrite, Peer)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                socket.set_nodelay(true)?;
+                let (read, write) = socket.into_split();
+                Ok((Box::pin(read), Box::pin(write), Peer::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                let cred = match socket.peer_cred() {
+                    Ok(cred) => Some(super::PeerCred {
+                        uid: cred.uid(),
+                        gid: cred.gid(),
+                        pid: cred.pid(),
+                    }),
+                    Err(err) => {
+                        tracing::warn!("failed to look up the credentials of a peer: {err:?}");
+                        None
+                    }
+                };
+                let (read, write) = socket.into_split();
+                Ok((Box::pin(read), Box::pin(write), Peer::Unix(cred)))
+            }
+        }
+    }
+}
 
 /// `tokio` async 9p server.
 pub struct AsyncServer<FilesystemT>
@@ -36,10 +183,18 @@ where
     FilesystemT: Send,
     FilesystemT: 'static,
 {
-    listener: TcpListener,
+    listener: Listener,
+    #[cfg(feature = "websocket")]
+    websocket: bool,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tokio_rustls::rustls::ServerConfig>>,
     msize: u32,
+    options: Options,
+    handle: ServerHandle,
+    connection_limit: ConnectionLimit,
 
-    filesystems: Arc<Mutex<HashMap<String, FilesystemT>>>,
+    filesystems: Mounts<FilesystemT>,
+    router: Option<Router<FilesystemT>>,
 }
 
 /// Server context about the connected peer, instantiated Filesystem,
@@ -51,12 +206,56 @@ where
     FilesystemT: 'static,
 {
     // pub(super) join_set: JoinSet,
-    pub(super) msize: u32,
-    pub(super) version: Version,
-    pub(super) peer: SocketAddr,
+    /// Largest msize to agree to; the msize actually used is negotiated
+    /// during the handshake, and handed to each request in its
+    /// [super::MessageContext].
+    pub(super) max_msize: u32,
+    pub(super) peer: Peer,
     pub(super) handles: FileHandles<FilesystemT::File>,
     pub(super) requests: Requests,
-    pub(super) filesystems: Arc<Mutex<HashMap<String, FilesystemT>>>,
+    pub(super) filesystems: Mounts<FilesystemT>,
+    pub(super) router: Option<Router<FilesystemT>>,
+    pub(super) options: Options,
+    pub(super) admin: Option<Registration>,
+}
+
+impl<FilesystemT> Context<FilesystemT>
+where
+    FilesystemT: Filesystem,
+    FilesystemT: Send,
+    FilesystemT: 'static,
+{
+    /// Create a new Context for a freshly connected peer.
+    pub(crate) fn new(
+        peer: Peer,
+        max_msize: u32,
+        filesystems: Mounts<FilesystemT>,
+        options: Options,
+    ) -> Self {
+        Self {
+            peer,
+            max_msize,
+            handles: FileHandles::<FilesystemT::File>::new(),
+            requests: Requests::new(),
+            filesystems,
+            router: None,
+            options,
+            admin: None,
+        }
+    }
+
+    /// Consult `router` before the [Mounts] on attach.
+    pub(crate) fn with_router(mut self, router: Option<Router<FilesystemT>>) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// Accept admin requests from the [ServerHandle] this connection was
+    /// registered with.
+    pub(crate) fn with_admin(mut self, admin: Registration) -> Self {
+        self.admin = Some(admin);
+        self
+    }
 }
 
 impl<FilesystemT> AsyncServer<FilesystemT>
@@ -70,39 +269,118 @@ where
         AsyncServerBuilder::new()
     }
 
-    /// Listen on the configured port, and serve 9p requests.
+    /// Create a new [AsyncServerBuilder] for an [AsyncServer] which accepts
+    /// connections on the socket passed in by systemd socket activation
+    /// (see sd_listen_fds(3)), rather than binding one itself. If systemd
+    /// passed more than one socket, only the first is used. Building the
+    /// server fails if this process was not socket activated.
+    #[cfg(feature = "systemd")]
+    pub fn from_systemd() -> AsyncServerBuilder<FilesystemT> {
+        let mut builder = AsyncServerBuilder::new();
+        builder.systemd = true;
+        builder
+    }
+
+    /// Handle to inspect and manage the connections of this server. This may
+    /// be called (and the handle cloned) before calling
+    /// [AsyncServer::serve].
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Address this server is accepting TCP connections on, which is how to
+    /// find the port picked when listening on port 0. None when listening
+    /// on a UNIX socket.
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// Number of accepted connections being served right now, which never
+    /// goes over the limit set by [AsyncServerBuilder::with_max_connections].
+    pub fn connections(&self) -> usize {
+        self.connection_limit.active()
+    }
+
+    /// T message types this server implements, as opposed to rejecting
+    /// with an error. Tauth is only listed when there is an
+    /// [Authenticator] to handle it, and the 9P2000.L messages only when
+    /// 9P2000.L is offered (see [AsyncServerBuilder::with_linux_dialect]).
+    pub fn supported_messages(&self) -> Vec<Type> {
+        SUPPORTED_MESSAGES
+            .iter()
+            .copied()
+            .filter(|ty| *ty != TYPE_TAUTH || self.options.authenticator.is_some())
+            .filter(|ty| !linux_only(*ty) || self.options.linux)
+            .collect()
+    }
+
+    /// Listen on the configured port, and serve 9p requests. Once the accept
+    /// loop is running, [ServerHandle::ready] resolves. This returns once
+    /// [ServerHandle::shutdown] is called, or a Filesystem returns a
+    /// [crate::server::FileError::fatal] error.
     pub async fn serve(&self) -> Result<()> {
         let mut join_set = JoinSet::new();
+        let mut peers = TaskPeers::new();
+        self.handle.set_ready();
 
         loop {
-            match self.listener.accept().await {
-                Ok((socket, addr)) => {
-                    socket.set_nodelay(true)?;
-                    tracing::info!("new connection: {:?}", addr);
-                    let (read, write) = socket.into_split();
-                    let tr = TReader::new(Box::pin(read), self.msize);
-                    let rw = RWriter::new(Box::pin(write), self.msize);
-                    let ctx = Context {
-                        // join_set: JoinSet::new(),
-                        peer: addr,
-                        version: "9P2000.u".parse().unwrap(),
-                        msize: self.msize,
-                        handles: FileHandles::<FilesystemT::File>::new(),
-                        requests: Requests::new(),
-                        filesystems: self.filesystems.clone(),
-                    };
+            // finished connections are reaped as we go, rather than piling
+            // up in the JoinSet for the life of the server.
+            let accepted = match select(
+                self.accept(),
+                select(
+                    reap(&mut join_set, &mut peers, &self.handle),
+                    self.handle.shutdown_requested(),
+                ),
+            )
+            .await
+            {
+                Either::Left(accepted) => accepted,
+                Either::Right(Either::Left(())) => {
+                    self.handle.set_tasks(join_set.len());
+                    continue;
+                }
+                Either::Right(Either::Right(())) => {
+                    // dropping the JoinSet hangs up on everyone.
+                    tracing::info!("shutting down; dropping {} connections", join_set.len());
+                    return Ok(());
+                }
+            };
 
-                    let _ = join_set
+            match accepted {
+                Ok((read, write, peer, permit)) => {
+                    let Some(permit) = permit else {
+                        tracing::warn!(
+                            "hanging up on {peer}; already serving {} connections",
+                            self.connection_limit.max()
+                        );
+                        continue;
+                    };
+                    let connection = self.connection(read, write, peer.clone());
+                    let task_peer = peer.clone();
+                    let spawned = join_set
                         .build_task()
-                        .name(&format!("connection [{addr}]"))
+                        .name(&format!("connection [{peer}]"))
                         .spawn(async move {
-                            tracing::debug!("task started [{addr}]");
-                            let tr = tr;
-                            let rw = rw;
-                            if let Err(e) = connection_handler(ctx, rw, tr).await {
-                                tracing::warn!("task [{addr}] failed with {e:?}");
+                            // the connection counts against the limit until
+                            // the task is done with it.
+                            let _permit = permit;
+                            tracing::debug!("task started [{peer}]");
+                            if let Err(e) = connection.await {
+                                tracing::warn!("task [{peer}] failed with {e:?}");
                             }
                         });
+                    match spawned {
+                        Ok(task) => {
+                            peers.insert(task.id(), task_peer);
+                        }
+                        Err(e) => tracing::warn!("failed to spawn task [{task_peer}]: {e}"),
+                    }
+                    self.handle.set_tasks(join_set.len());
                 }
                 Err(e) => {
                     tracing::warn!("failed to establish: {}", e);
@@ -111,6 +389,155 @@ where
             }
         }
     }
+
+    /// Accept the next connection, along with the permit to serve it, if
+    /// there's room for it. With [ConnectionLimitPolicy::Wait], nothing is
+    /// accepted until there's room.
+    async fn accept(
+        &self,
+    ) -> std::io::Result<(AsyncRead, AsyncWrite, Peer, Option<OwnedSemaphorePermit>)> {
+        let limit = &self.connection_limit;
+        let permit = match limit.policy {
+            ConnectionLimitPolicy::Wait => Some(limit.acquire().await),
+            ConnectionLimitPolicy::Reject => None,
+        };
+        let (read, write, peer) = self.listener.accept().await?;
+        let permit = permit.or_else(|| limit.try_acquire());
+        Ok((read, write, peer, permit))
+    }
+
+    /// Serve a single connection over `stream`, which need not have come
+    /// from the listener, or be a socket at all: anything that can be read
+    /// from and written to will do, such as a compressed or encrypted
+    /// tunnel wrapped around some other stream. The connection is attributed
+    /// to `peer`, and is known to the [ServerHandle] like any other. This
+    /// returns once the connection is closed.
+    pub async fn serve_connection<S>(&self, stream: S, peer: Peer) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+    {
+        let (read, write) = tokio::io::split(stream);
+        self.serve_split(Box::pin(read), Box::pin(write), peer)
+            .await
+    }
+
+    /// Serve a single connection over a transport which has already been
+    /// split into its reading and writing halves, as with
+    /// [AsyncServer::serve_connection]. Connections the server accepts
+    /// itself are served the same way.
+    pub async fn serve_split(&self, read: AsyncRead, write: AsyncWrite, peer: Peer) -> Result<()> {
+        self.connection(read, write, peer).await
+    }
+
+    /// Register a new connection from `peer` with the [ServerHandle], and
+    /// return the future serving it, which does not borrow the server.
+    fn connection(
+        &self,
+        read: AsyncRead,
+        write: AsyncWrite,
+        peer: Peer,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        let registration = self.handle.register(peer.clone());
+        tracing::info!("new connection {}: {}", registration.id, peer);
+        let msize = self.msize;
+        #[cfg(feature = "websocket")]
+        let websocket = self.websocket.then(|| self.options.clock());
+        #[cfg(feature = "tls")]
+        let tls = self.tls.clone();
+        #[cfg(feature = "tls")]
+        let (clock, idle_timeout) = (self.options.clock(), self.options.idle_timeout);
+        let ctx = Context::new(
+            peer,
+            self.msize,
+            self.filesystems.clone(),
+            self.options.clone(),
+        )
+        .with_router(self.router.clone())
+        .with_admin(registration);
+
+        async move {
+            // a client which fails the handshake only loses its own
+            // connection; the accept loop carries on.
+            #[cfg(feature = "tls")]
+            let (read, write) = match tls {
+                Some(config) => {
+                    super::tls::accept(config, &*clock, idle_timeout, read, write).await?
+                }
+                None => (read, write),
+            };
+            #[cfg(feature = "websocket")]
+            let (read, write) = match websocket {
+                Some(clock) => super::websocket::accept(clock, read, write).await?,
+                None => (read, write),
+            };
+            let tr = TReader::new(read, msize);
+            let rw = RWriter::new(write, msize);
+            connection_handler(ctx, rw, tr).await
+        }
+    }
+}
+
+/// Peer on the other end of each connection task, by task id, so that a
+/// task which panics can be blamed on someone.
+type TaskPeers = HashMap<tokio::task::Id, Peer>;
+
+/// Wait for the next connection task in `join_set` to finish, logging (and
+/// counting) it if it panicked. This never completes while there are no
+/// tasks.
+async fn reap(join_set: &mut JoinSet, peers: &mut TaskPeers, handle: &ServerHandle) {
+    let (id, result) = match join_set.join_next_with_id().await {
+        Some(Ok((id, ()))) => (id, Ok(())),
+        Some(Err(e)) => (e.id(), Err(e)),
+        None => return std::future::pending().await,
+    };
+    let peer = match peers.remove(&id) {
+        Some(peer) => peer.to_string(),
+        None => format!("task {id}"),
+    };
+
+    match result {
+        Ok(()) => {}
+        Err(e) if e.is_panic() => {
+            let panic = e.into_panic();
+            let message = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+                (Some(message), _) => message,
+                (_, Some(message)) => message.as_str(),
+                _ => "(not a string)",
+            };
+            tracing::error!("connection task [{peer}] panicked: {message}");
+            handle.count_panic();
+        }
+        Err(e) => tracing::warn!("connection task [{peer}] did not finish cleanly: {e}"),
+    }
+}
+
+/// Listen on `addr`, with the provided socket buffer sizes, if any.
+async fn bind_tcp(
+    addr: &str,
+    recv_buffer_size: Option<u32>,
+    send_buffer_size: Option<u32>,
+) -> std::io::Result<TcpListener> {
+    if recv_buffer_size.is_none() && send_buffer_size.is_none() {
+        return TcpListener::bind(addr).await;
+    }
+
+    let addr = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to listen on")
+    })?;
+    let socket = match addr {
+        std::net::SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        std::net::SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    // as TcpListener::bind does.
+    socket.set_reuseaddr(true)?;
+    if let Some(size) = recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    socket.bind(addr)?;
+    socket.listen(1024)
 }
 
 /// Builder-pattern struct to create an [AsyncServer].
@@ -121,8 +548,24 @@ where
     FilesystemT: 'static,
 {
     tcp_listen_address: Option<String>,
+    recv_buffer_size: Option<u32>,
+    send_buffer_size: Option<u32>,
+    #[cfg(unix)]
+    unix_listen_address: Option<std::path::PathBuf>,
+    #[cfg(feature = "systemd")]
+    systemd: bool,
+    #[cfg(feature = "websocket")]
+    websocket: bool,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tokio_rustls::rustls::ServerConfig>>,
     msize: Option<u32>,
-    filesystems: HashMap<String, FilesystemT>,
+    max_connections: Option<usize>,
+    connection_limit_policy: ConnectionLimitPolicy,
+    rate_limit: Option<u32>,
+    rate_limit_policy: RateLimitPolicy,
+    options: Options,
+    filesystems: HashMap<String, Mount<FilesystemT>>,
+    router: Option<Router<FilesystemT>>,
 }
 
 impl<FilesystemT> AsyncServerBuilder<FilesystemT>
@@ -135,8 +578,24 @@ where
     fn new() -> Self {
         Self {
             filesystems: HashMap::new(),
+            router: None,
             msize: None,
+            max_connections: None,
+            connection_limit_policy: ConnectionLimitPolicy::Wait,
+            rate_limit: None,
+            rate_limit_policy: RateLimitPolicy::Wait,
+            options: Options::default(),
             tcp_listen_address: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            #[cfg(unix)]
+            unix_listen_address: None,
+            #[cfg(feature = "systemd")]
+            systemd: false,
+            #[cfg(feature = "websocket")]
+            websocket: false,
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 
@@ -147,30 +606,1041 @@ where
         self
     }
 
+    /// Serve at most `max` accepted connections at once. While at the limit,
+    /// the server stops accepting new connections, unless told otherwise by
+    /// [AsyncServerBuilder::with_connection_limit_policy]. By default,
+    /// connections are not limited.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Set what to do with connections over the limit set by
+    /// [AsyncServerBuilder::with_max_connections].
+    pub fn with_connection_limit_policy(mut self, policy: ConnectionLimitPolicy) -> Self {
+        self.connection_limit_policy = policy;
+        self
+    }
+
+    /// Limit each connection to `requests_per_sec` requests per second,
+    /// holding any requests over the limit until the connection is back
+    /// under budget. By default, connections are not limited. A limit of 0
+    /// would never let a request through, and is refused by
+    /// [AsyncServerBuilder::build].
+    pub fn with_rate_limit(mut self, requests_per_sec: u32) -> Self {
+        self.rate_limit = Some(requests_per_sec);
+        self
+    }
+
+    /// Set what to do with requests over the limit set by
+    /// [AsyncServerBuilder::with_rate_limit], which may be called before or
+    /// after this. By default, they wait.
+    pub fn with_rate_limit_policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.rate_limit_policy = policy;
+        self
+    }
+
+    /// Check the chain of qids returned by every Filesystem walk for internal
+    /// consistency (every step but the last is a directory, and the last
+    /// step is the file that was walked to), replying EINVAL rather than
+    /// passing along a bogus chain. This is intended as a debugging aid for
+    /// Filesystem implementors, and is off by default.
+    pub fn with_walk_validation(mut self, validate: bool) -> Self {
+        self.options.validate_walk = validate;
+        self
+    }
+
+    /// Batch replies into a single write of up to `limit` bytes, flushing
+    /// early whenever there are no further requests from the client waiting
+    /// to be handled. This cuts down on syscalls when many small replies are
+    /// ready at once, at the cost of a copy. Off by default.
+    pub fn with_write_coalescing(mut self, limit: usize) -> Self {
+        self.options.coalesce_writes = Some(limit);
+        self
+    }
+
+    /// Treat a walk that fails partway through as an error (ENOENT), rather
+    /// than replying with the qids of the elements that were walked, as the
+    /// spec requires. This is handy for clients that don't bother checking
+    /// the length of the reply. Off by default.
+    pub fn with_strict_walk(mut self, strict: bool) -> Self {
+        self.options.strict_walk = strict;
+        self
+    }
+
+    /// Offer 9P2000.L to clients that ask for it, rather than downgrading
+    /// them to 9P2000.u. Over 9P2000.L, errors go out as Rlerror, and the
+    /// 9P2000.L messages arigato implements (Tmkdir, Tunlinkat, Tsymlink and
+    /// the like) are answered; they are refused over any other dialect.
+    /// Anything else is still done with 9P2000 messages, such as Topen and
+    /// Tstat, which the Linux kernel client doesn't fall back to. Off by
+    /// default.
+    pub fn with_linux_dialect(mut self, linux: bool) -> Self {
+        self.options.linux = linux;
+        self
+    }
+
+    /// Refuse every request that could change a Filesystem -- Twrite,
+    /// Tcreate, Tremove, Twstat, an Topen for writing, and their 9P2000.L
+    /// counterparts -- with EROFS, before it reaches the Filesystem. This
+    /// holds no matter what the Filesystem itself would allow. Off by
+    /// default.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// Close connections which have gone `timeout` without sending a
+    /// request. This holds from the moment a connection is accepted, so a
+    /// client which never finishes a TLS handshake, or never sends a
+    /// Tversion, is hung up on too. By default, idle connections are kept
+    /// open indefinitely.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.options.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on any request which a Filesystem has taken longer than
+    /// `timeout` to handle, replying ETIMEDOUT. The Filesystem's work on it
+    /// is dropped where it stands, as with a Tflush. By default, requests
+    /// may take as long as they take.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.options.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Let each connection have at most `max` requests running or waiting
+    /// their turn. Once a connection is at the limit, no more of its
+    /// requests are read (a Tflush included) until one is answered, leaving
+    /// the client to back up behind the socket. By default, the limit is 64.
+    /// A limit of 0 would never let a request through, and is refused by
+    /// [AsyncServerBuilder::build].
+    pub fn with_max_requests(mut self, max: usize) -> Self {
+        self.options.max_requests = Some(max);
+        self
+    }
+
+    /// Hang up on connections which send more than `bytes` bytes (counting
+    /// the Tversion itself) before successfully negotiating a version. This
+    /// keeps a peer from streaming data at a server that is expecting a
+    /// handshake. By default, there is no limit beyond the msize.
+    pub fn with_handshake_byte_budget(mut self, bytes: u32) -> Self {
+        self.options.handshake_byte_budget = Some(bytes);
+        self
+    }
+
+    /// Use the provided [Clock] for timeouts, rather than the
+    /// [SystemClock].
+    pub fn with_clock<ClockT: Clock>(mut self, clock: ClockT) -> Self {
+        self.options.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Pass the path of every walk through the provided [PathPolicy] before
+    /// handing it to the Filesystem. By default, paths are walked as sent.
+    pub fn with_path_policy<PolicyT: PathPolicy>(mut self, policy: PolicyT) -> Self {
+        self.options.path_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Require every client to authenticate with `authenticator`, by way
+    /// of a Tauth, before it may attach. By default, Tauth is refused, and
+    /// any afid sent along with a Tattach is ignored.
+    pub fn with_authenticator<AuthenticatorT: Authenticator>(
+        mut self,
+        authenticator: AuthenticatorT,
+    ) -> Self {
+        self.options.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Tell `observer` about every connection, request and reply, for
+    /// keeping metrics. By default, nobody is told anything.
+    pub fn with_observer<ObserverT: Observer>(mut self, observer: ObserverT) -> Self {
+        self.options.observer = Some(Arc::new(observer));
+        self
+    }
+
     /// Set the IP address and port to listen on.
     pub fn with_tcp_listen_address(mut self, addr: &str) -> Self {
         self.tcp_listen_address = Some(addr.to_owned());
         self
     }
 
+    /// Set the size, in bytes, of the kernel receive buffer (SO_RCVBUF) of
+    /// every TCP connection. This is set on the listening socket before it
+    /// starts listening, so that the TCP window of each connection accepted
+    /// from it can grow to match. The kernel may round or cap the size (on
+    /// Linux, it is doubled). By default, the size is left to the kernel.
+    pub fn with_recv_buffer_size(mut self, size: u32) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Set the size, in bytes, of the kernel send buffer (SO_SNDBUF) of
+    /// every TCP connection, as with
+    /// [AsyncServerBuilder::with_recv_buffer_size].
+    pub fn with_send_buffer_size(mut self, size: u32) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Set the path of a UNIX socket to listen on, rather than listening on
+    /// TCP. Peers connecting over a UNIX socket have their kernel-reported
+    /// credentials passed along to [Filesystem::attach_with_context].
+    #[cfg(unix)]
+    pub fn with_unix_listen_address(mut self, path: &std::path::Path) -> Self {
+        self.unix_listen_address = Some(path.to_owned());
+        self
+    }
+
+    /// Expect every connection to open with a WebSocket handshake, and
+    /// speak 9p over binary WebSocket messages from then on. Connections
+    /// which fail the handshake, or which take longer than ten seconds (by
+    /// the server's [Clock]) over it, are dropped.
+    #[cfg(feature = "websocket")]
+    pub fn with_websocket(mut self, websocket: bool) -> Self {
+        self.websocket = websocket;
+        self
+    }
+
+    /// Expect every connection to open with a TLS handshake, and speak 9p
+    /// over TLS, as configured by `config`, from then on. When also serving
+    /// WebSockets, the WebSocket handshake happens inside the TLS session.
+    /// Connections which fail the handshake are logged and dropped.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: tokio_rustls::rustls::ServerConfig) -> Self {
+        self.tls = Some(Arc::new(config));
+        self
+    }
+
+    /// Trust the kernel-reported credentials of peers connected over a UNIX
+    /// socket over whatever uname and n_uname the client sends on attach:
+    /// both are replaced with the peer's uid. Peers without credentials
+    /// (such as those connected over TCP, or whose credentials could not be
+    /// looked up) are refused with EACCES.
+    pub fn with_peer_cred_identity(mut self, trust: bool) -> Self {
+        self.options.peer_cred_identity = trust;
+        self
+    }
+
     /// Use the provided Filesystem for the specified filesystem name
     /// (aname).
     pub fn with_filesystem(mut self, name: &str, fs: FilesystemT) -> Self {
-        self.filesystems.insert(name.to_owned(), fs);
+        self.filesystems.insert(
+            name.to_owned(),
+            Mount {
+                filesystem: fs,
+                max_read: None,
+            },
+        );
+        self
+    }
+
+    /// Use the provided Filesystem for the specified filesystem name
+    /// (aname), capping any single read against it to `max_read` bytes.
+    ///
+    /// The msize is negotiated once per connection, but some filesystems
+    /// (device nodes, for instance) have a much smaller useful transfer size
+    /// than others.
+    pub fn with_filesystem_max_read(mut self, name: &str, fs: FilesystemT, max_read: u32) -> Self {
+        self.filesystems.insert(
+            name.to_owned(),
+            Mount {
+                filesystem: fs,
+                max_read: Some(max_read),
+            },
+        );
         self
     }
 
-    /// Build an [AsyncServer].
-    pub async fn build(self) -> Result<AsyncServer<FilesystemT>> {
-        let listen_address = self.tcp_listen_address.unwrap();
-        let listener = TcpListener::bind(listen_address).await?;
+    /// Pick the Filesystem to attach to by calling `router` with the uname
+    /// and aname of each Tattach, rather than by aname alone. When the
+    /// router returns None, the aname is looked up among those registered
+    /// with [AsyncServerBuilder::with_filesystem] as usual.
+    ///
+    /// The router hands back a Filesystem of its own for every attach, so
+    /// it's best used with Filesystems that are cheap to clone.
+    pub fn with_filesystem_router<F>(mut self, router: F) -> Self
+    where
+        F: Fn(&str, &str) -> Option<FilesystemT> + Send + Sync + 'static,
+    {
+        self.router = Some(Arc::new(router));
+        self
+    }
+
+    /// Build an [AsyncServer]. The socket is bound and listening once this
+    /// returns, but connections are not accepted until
+    /// [AsyncServer::serve] is called.
+    pub async fn build(mut self) -> Result<AsyncServer<FilesystemT>> {
+        self.options.rate_limit = match self.rate_limit {
+            Some(0) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "rate limit of 0 requests per second",
+                )
+                .into())
+            }
+            Some(requests_per_sec) => Some(RateLimit {
+                requests_per_sec,
+                policy: self.rate_limit_policy,
+            }),
+            None => None,
+        };
+        if self.options.max_requests == Some(0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "limit of 0 requests per connection",
+            )
+            .into());
+        }
+
+        #[cfg(feature = "systemd")]
+        let listener = if self.systemd {
+            Some(super::systemd::listener()?)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "systemd"))]
+        let listener = None;
+
+        #[cfg(unix)]
+        let listener = match (listener, self.unix_listen_address) {
+            (None, Some(path)) => Some(Listener::Unix(tokio::net::UnixListener::bind(path)?)),
+            (listener, _) => listener,
+        };
+
+        let listener = match listener {
+            Some(listener) => listener,
+            None => {
+                let listen_address = self.tcp_listen_address.unwrap();
+                Listener::Tcp(
+                    bind_tcp(
+                        &listen_address,
+                        self.recv_buffer_size,
+                        self.send_buffer_size,
+                    )
+                    .await?,
+                )
+            }
+        };
 
         Ok(AsyncServer {
             listener,
+            #[cfg(feature = "websocket")]
+            websocket: self.websocket,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
             msize: self.msize.unwrap_or(0xFFFFFF00),
+            options: self.options,
+            handle: ServerHandle::default(),
+            connection_limit: match self.max_connections {
+                Some(max) => ConnectionLimit::new(max, self.connection_limit_policy),
+                None => ConnectionLimit::unlimited(),
+            },
             filesystems: Arc::new(Mutex::new(self.filesystems)),
+            router: self.router,
         })
     }
 }
 
+// the tests talk over UNIX sockets.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{AsyncServer, ConnectionLimitPolicy, Listener};
+    use crate::{
+        raw::{R, T},
+        server::{
+            testing::{block_on, block_on_logged, socket_path, TestFile, TestFs},
+            AttachContext, AuthFile, AuthFuture, Authenticator, FileError, Filesystem,
+            FilesystemResult, Peer, PeerCred, RReader, RateLimit, RateLimitPolicy, TWriter,
+        },
+    };
+    use std::{
+        os::unix::fs::MetadataExt,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+        net::{TcpSocket, TcpStream, UnixStream},
+    };
+
+    type Attached = Arc<Mutex<Vec<(String, u32, Option<PeerCred>)>>>;
+
+    /// Filesystem that remembers who attached to it.
+    struct Recording(Attached, TestFs);
+
+    impl Filesystem for Recording {
+        type File = TestFile;
+
+        async fn attach(
+            &self,
+            aname: &str,
+            uname: &str,
+            nuname: u32,
+        ) -> FilesystemResult<TestFile> {
+            self.1.attach(aname, uname, nuname).await
+        }
+
+        async fn attach_with_context(&self, ctx: &AttachContext<'_>) -> FilesystemResult<TestFile> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((ctx.uname.to_owned(), ctx.nuname, ctx.peer_cred()));
+            self.attach(ctx.aname, ctx.uname, ctx.nuname).await
+        }
+    }
+
+    #[test]
+    fn unix_peer_cred() {
+        block_on(async {
+            let path = socket_path("peercred.sock");
+            let uid = std::fs::metadata(path.parent().unwrap()).unwrap().uid();
+
+            let seen = Arc::new(Mutex::new(vec![]));
+            let srv = AsyncServer::builder()
+                .with_unix_listen_address(&path)
+                .with_peer_cred_identity(true)
+                .with_filesystem("", Recording(seen.clone(), TestFs::new(&[])))
+                .build()
+                .await
+                .unwrap();
+            let supported = srv.supported_messages();
+            for ty in [100, 104, 110, 112, 116, 118, 120, 124] {
+                assert!(supported.contains(&ty), "{ty} missing");
+            }
+            assert!(!supported.contains(&102));
+            tokio::spawn(async move { srv.serve().await });
+
+            let (mut tw, mut rr) = handshake(&path).await.unwrap();
+            tw.send(T::Attach(1, 1, !0, "mallory".to_owned(), "".to_owned(), 0))
+                .await
+                .unwrap();
+            assert!(matches!(rr.next().await.unwrap(), R::Attach(1, _)));
+
+            let seen = seen.lock().unwrap();
+            let (uname, nuname, cred) = seen[0].clone();
+            assert_eq!(uid, cred.unwrap().uid);
+            assert_eq!(uid, nuname);
+            assert_eq!(uid.to_string(), uname);
+
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    #[test]
+    fn ready() {
+        block_on(async {
+            let path = socket_path("ready.sock");
+
+            let srv = AsyncServer::builder()
+                .with_unix_listen_address(&path)
+                .with_filesystem("", TestFs::new(&[]))
+                .build()
+                .await
+                .unwrap();
+            let handle = srv.handle();
+            tokio::spawn(async move { srv.serve().await });
+            handle.ready().await;
+
+            let _peer = handshake(&path).await.unwrap();
+            assert_eq!(1, handle.connection_count());
+
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    /// Stream transform standing in for compression or encryption: every
+    /// byte is XORed with a key on the way in and on the way out.
+    struct Scrambled(DuplexStream, u8);
+
+    impl AsyncRead for Scrambled {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let start = buf.filled().len();
+            let key = self.1;
+            let polled = Pin::new(&mut self.0).poll_read(cx, buf);
+            for byte in &mut buf.filled_mut()[start..] {
+                *byte ^= key;
+            }
+            polled
+        }
+    }
+
+    impl AsyncWrite for Scrambled {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let scrambled: Vec<u8> = buf.iter().map(|byte| byte ^ self.1).collect();
+            Pin::new(&mut self.0).poll_write(cx, &scrambled)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    #[test]
+    fn serve_wrapped_stream() {
+        block_on(async {
+            let path = socket_path("wrapped.sock");
+
+            let srv = Arc::new(
+                AsyncServer::builder()
+                    .with_unix_listen_address(&path)
+                    .with_filesystem("", TestFs::new(&[("motd", b"hello")]))
+                    .build()
+                    .await
+                    .unwrap(),
+            );
+            let (client, server) = tokio::io::duplex(8192);
+            let serving = srv.clone();
+            let task = tokio::spawn(async move {
+                serving
+                    .serve_connection(Scrambled(server, 0x5A), Peer::Unix(None))
+                    .await
+            });
+
+            let (read, write) = tokio::io::split(Scrambled(client, 0x5A));
+            let (mut tw, mut rr) = version(read, write).await;
+            assert!(matches!(
+                rr.next().await.unwrap(),
+                R::Version(0xFFFF, 8192, _)
+            ));
+            tw.send(T::Attach(1, 1, !0, "user".to_owned(), "".to_owned(), 0))
+                .await
+                .unwrap();
+            assert!(matches!(rr.next().await.unwrap(), R::Attach(1, _)));
+            tw.send(T::Walk(2, 1, 2, vec!["motd".to_owned()]))
+                .await
+                .unwrap();
+            assert!(matches!(rr.next().await.unwrap(), R::Walk(2, _)));
+            tw.send(T::Open(3, 2, 0.into())).await.unwrap();
+            assert!(matches!(rr.next().await.unwrap(), R::Open(3, _, _)));
+            tw.send(T::Read(4, 2, 0, 100)).await.unwrap();
+            assert_eq!(R::Read(4, b"hello".to_vec()), rr.next().await.unwrap());
+            assert_eq!(1, srv.handle().connection_count());
+
+            // hanging up finishes serving the connection (with an EOF).
+            drop(tw);
+            drop(rr);
+            let _ = task.await.unwrap();
+            assert_eq!(0, srv.handle().connection_count());
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    #[test]
+    fn serve_split_duplex() {
+        block_on(async {
+            let path = socket_path("split.sock");
+
+            let srv = Arc::new(
+                AsyncServer::builder()
+                    .with_unix_listen_address(&path)
+                    .with_filesystem("", TestFs::new(&[("motd", b"hello")]))
+                    .build()
+                    .await
+                    .unwrap(),
+            );
+            let (client, server) = tokio::io::duplex(8192);
+            let (sr, sw) = tokio::io::split(server);
+            let serving = srv.clone();
+            let task = tokio::spawn(async move {
+                serving
+                    .serve_split(
+                        Box::pin(sr),
+                        Box::pin(sw),
+                        Peer::Custom("duplex".to_owned()),
+                    )
+                    .await
+            });
+
+            let (read, write) = tokio::io::split(client);
+            let (mut tw, mut rr) = version(read, write).await;
+            assert!(matches!(
+                rr.next().await.unwrap(),
+                R::Version(0xFFFF, 8192, _)
+            ));
+            tw.send(T::Attach(1, 1, !0, "user".to_owned(), "".to_owned(), 0))
+                .await
+                .unwrap();
+            assert!(matches!(rr.next().await.unwrap(), R::Attach(1, _)));
+            tw.send(T::Walk(2, 1, 2, vec!["motd".to_owned()]))
+                .await
+                .unwrap();
+            assert!(matches!(rr.next().await.unwrap(), R::Walk(2, _)));
+            tw.send(T::Open(3, 2, 0.into())).await.unwrap();
+            assert!(matches!(rr.next().await.unwrap(), R::Open(3, _, _)));
+            tw.send(T::Read(4, 2, 0, 100)).await.unwrap();
+            assert_eq!(R::Read(4, b"hello".to_vec()), rr.next().await.unwrap());
+
+            drop(tw);
+            drop(rr);
+            let _ = task.await.unwrap();
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    #[test]
+    fn peer_label_logged() {
+        let ((), logs) = block_on_logged(async {
+            let path = socket_path("label.sock");
+
+            let srv = Arc::new(
+                AsyncServer::builder()
+                    .with_unix_listen_address(&path)
+                    .with_filesystem("", TestFs::new(&[]))
+                    .build()
+                    .await
+                    .unwrap(),
+            );
+            let (client, server) = tokio::io::duplex(8192);
+            let serving = srv.clone();
+            let task = tokio::spawn(async move {
+                serving
+                    .serve_connection(server, Peer::Custom("test".to_owned()))
+                    .await
+            });
+
+            let (read, write) = tokio::io::split(client);
+            let (tw, mut rr) = version(read, write).await;
+            assert!(matches!(rr.next().await.unwrap(), R::Version(..)));
+
+            drop(tw);
+            drop(rr);
+            let _ = task.await.unwrap();
+            let _ = std::fs::remove_file(&path);
+        });
+        assert!(
+            logs.iter().any(
+                |line| line == "connection established with test; version 9P2000.u, msize 8192"
+            ),
+            "{:?}",
+            logs
+        );
+    }
+
+    #[test]
+    fn pipelined_attach() {
+        block_on(async {
+            let path = socket_path("pipelined.sock");
+
+            let srv = AsyncServer::builder()
+                .with_unix_listen_address(&path)
+                .with_filesystem("", TestFs::new(&[]))
+                .build()
+                .await
+                .unwrap();
+            tokio::spawn(async move { srv.serve().await });
+
+            // hold both messages back, so that they go out in one write.
+            let (read, write) = UnixStream::connect(&path).await.unwrap().into_split();
+            let mut tw = TWriter::new(Box::pin(write), 8192);
+            let mut rr = RReader::new(Box::pin(read), 8192);
+            tw.set_coalescing(Some(8192));
+            tw.send(T::Version(0xFFFF, 8192, "9P2000.u".parse().unwrap()))
+                .await
+                .unwrap();
+            tw.send(T::Attach(1, 1, !0, "user".to_owned(), "".to_owned(), 0))
+                .await
+                .unwrap();
+            tw.flush().await.unwrap();
+
+            let r = rr.next().await.unwrap();
+            assert!(matches!(r, R::Version(0xFFFF, 8192, _)), "{:?}", r);
+            let r = rr.next().await.unwrap();
+            assert!(matches!(r, R::Attach(1, _)), "{:?}", r);
+
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    /// Filesystem with a bug in it.
+    struct Panics;
+
+    impl Filesystem for Panics {
+        type File = TestFile;
+
+        async fn attach(&self, _: &str, _: &str, _: u32) -> FilesystemResult<TestFile> {
+            panic!("this should never happen")
+        }
+    }
+
+    #[test]
+    fn connection_panic() {
+        block_on(async {
+            let path = socket_path("panic.sock");
+
+            let srv = AsyncServer::builder()
+                .with_unix_listen_address(&path)
+                .with_filesystem("", Panics)
+                .build()
+                .await
+                .unwrap();
+            let handle = srv.handle();
+            let serve = tokio::spawn(async move { srv.serve().await });
+            handle.ready().await;
+
+            let (mut tw, mut rr) = handshake(&path).await.unwrap();
+            tw.send(T::Attach(1, 1, !0, "user".to_owned(), "".to_owned(), 0))
+                .await
+                .unwrap();
+            assert!(rr.next().await.is_err());
+            for _ in 0..10_000 {
+                if handle.panics() == 1 {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(1, handle.panics());
+
+            // everyone else is unaffected.
+            assert!(handshake(&path).await.is_some());
+            assert!(!serve.is_finished());
+
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    /// Filesystem whose backing store has gone away.
+    struct Gone;
+
+    impl Filesystem for Gone {
+        type File = TestFile;
+
+        async fn attach(&self, _: &str, _: &str, _: u32) -> FilesystemResult<TestFile> {
+            Err(FileError(5, "EIO".to_owned()).fatal())
+        }
+    }
+
+    #[test]
+    fn fatal_error_shuts_down() {
+        block_on(async {
+            let path = socket_path("fatal.sock");
+
+            let srv = AsyncServer::builder()
+                .with_unix_listen_address(&path)
+                .with_filesystem("", Gone)
+                .build()
+                .await
+                .unwrap();
+            let handle = srv.handle();
+            let serve = tokio::spawn(async move { srv.serve().await });
+            handle.ready().await;
+
+            let mut peers = vec![];
+            for _ in 0..2 {
+                peers.push(handshake(&path).await.unwrap());
+            }
+
+            // the peer that hit the error still hears about it.
+            let (tw, rr) = &mut peers[0];
+            tw.send(T::Attach(1, 1, !0, "user".to_owned(), "".to_owned(), 0))
+                .await
+                .unwrap();
+            assert_eq!(R::Error(1, "EIO".to_owned(), 5), rr.next().await.unwrap());
+
+            assert!(serve.await.unwrap().is_ok());
+            for (_, rr) in &mut peers {
+                assert!(rr.next().await.is_err());
+            }
+
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    #[test]
+    fn finished_connections_reaped() {
+        block_on(async {
+            let path = socket_path("reap.sock");
+
+            let srv = AsyncServer::builder()
+                .with_unix_listen_address(&path)
+                .with_filesystem("", TestFs::new(&[]))
+                .build()
+                .await
+                .unwrap();
+            let handle = srv.handle();
+            tokio::spawn(async move { srv.serve().await });
+            handle.ready().await;
+
+            for _ in 0..50 {
+                assert!(handshake(&path).await.is_some());
+            }
+
+            // every connection has hung up, so every task is reaped.
+            for _ in 0..10_000 {
+                if handle.tasks() == 0 {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(0, handle.tasks());
+            assert_eq!(0, handle.connection_count());
+
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    /// Speak 9P over `read` and `write`, starting with a Tversion for
+    /// 9P2000.u, whose reply is left for the caller to read.
+    async fn version<ReadT, WriteT>(read: ReadT, write: WriteT) -> (TWriter, RReader)
+    where
+        ReadT: AsyncRead + Send + Sync + 'static,
+        WriteT: AsyncWrite + Send + Sync + 'static,
+    {
+        let mut tw = TWriter::new(Box::pin(write), 8192);
+        let rr = RReader::new(Box::pin(read), 8192);
+        tw.send(T::Version(0xFFFF, 8192, "9P2000.u".parse().unwrap()))
+            .await
+            .unwrap();
+        (tw, rr)
+    }
+
+    /// Connect to the server listening on `path`, and negotiate a version,
+    /// giving up if there's no reply in a little while.
+    async fn handshake(path: &std::path::Path) -> Option<(TWriter, RReader)> {
+        let (read, write) = UnixStream::connect(path).await.unwrap().into_split();
+        let (tw, mut rr) = version(read, write).await;
+        match tokio::time::timeout(Duration::from_millis(100), rr.next()).await {
+            Ok(Ok(R::Version(..))) => Some((tw, rr)),
+            _ => None,
+        }
+    }
+
+    /// Serve a [TestFs] on a fresh UNIX socket named `name`, with at most one
+    /// connection at a time, under `policy`.
+    async fn serve_one_at_a_time(
+        name: &str,
+        policy: ConnectionLimitPolicy,
+    ) -> (Arc<AsyncServer<TestFs>>, std::path::PathBuf) {
+        let path = socket_path(name);
+
+        let srv = AsyncServer::builder()
+            .with_unix_listen_address(&path)
+            .with_max_connections(1)
+            .with_connection_limit_policy(policy)
+            .with_filesystem("", TestFs::new(&[]))
+            .build()
+            .await
+            .unwrap();
+        let srv = Arc::new(srv);
+        let serving = srv.clone();
+        tokio::spawn(async move { serving.serve().await });
+        srv.handle().ready().await;
+        (srv, path)
+    }
+
+    #[test]
+    fn max_connections_wait() {
+        block_on(async {
+            let (srv, path) =
+                serve_one_at_a_time("max-wait.sock", ConnectionLimitPolicy::Wait).await;
+
+            let first = handshake(&path).await.unwrap();
+            assert_eq!(1, srv.connections());
+
+            // the second connection isn't accepted while the first is open...
+            let (read, write) = UnixStream::connect(&path).await.unwrap().into_split();
+            let (_tw, mut rr) = version(read, write).await;
+            let waited = tokio::time::timeout(Duration::from_millis(100), rr.next()).await;
+            assert!(waited.is_err(), "{:?}", waited);
+            assert_eq!(1, srv.connections());
+
+            // ...but is once the first hangs up.
+            drop(first);
+            assert!(matches!(rr.next().await.unwrap(), R::Version(..)));
+            assert_eq!(1, srv.connections());
+
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    #[test]
+    fn max_connections_reject() {
+        block_on(async {
+            let (srv, path) =
+                serve_one_at_a_time("max-reject.sock", ConnectionLimitPolicy::Reject).await;
+
+            let first = handshake(&path).await.unwrap();
+            assert_eq!(1, srv.connections());
+
+            // the second connection is hung up on...
+            let (read, write) = UnixStream::connect(&path).await.unwrap().into_split();
+            let mut tw = TWriter::new(Box::pin(write), 8192);
+            let mut rr = RReader::new(Box::pin(read), 8192);
+            let _ = tw
+                .send(T::Version(0xFFFF, 8192, "9P2000.u".parse().unwrap()))
+                .await;
+            assert!(rr.next().await.is_err());
+            assert_eq!(1, srv.connections());
+
+            // ...but there's room again once the first is done.
+            drop(first);
+            for _ in 0..10_000 {
+                if srv.connections() == 0 {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+            assert!(handshake(&path).await.is_some());
+
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    /// Authenticator which lets nobody in.
+    struct Nobody;
+
+    impl Authenticator for Nobody {
+        fn auth<'a>(&'a self, _: &'a str, _: &'a str, _: u32) -> AuthFuture<'a, Box<dyn AuthFile>> {
+            Box::pin(async { Err(FileError(13, "EACCES".to_owned())) })
+        }
+
+        fn check<'a>(&'a self, _: &'a dyn AuthFile, _: &'a str, _: &'a str) -> AuthFuture<'a, ()> {
+            Box::pin(async { Err(FileError(13, "EACCES".to_owned())) })
+        }
+    }
+
+    #[test]
+    fn supported_messages() {
+        block_on(async {
+            let builder = || {
+                AsyncServer::builder()
+                    .with_tcp_listen_address("127.0.0.1:0")
+                    .with_filesystem("", TestFs::new(&[]))
+            };
+
+            // Tauth (102) needs an Authenticator, and Tmkdir (72) and
+            // Tstatfs (8) need 9P2000.L; Twalk (110) needs nothing.
+            let srv = builder().build().await.unwrap();
+            let supported = srv.supported_messages();
+            assert!(supported.contains(&110));
+            for ty in [102, 72, 8] {
+                assert!(!supported.contains(&ty), "{ty} listed");
+            }
+
+            let srv = builder()
+                .with_authenticator(Nobody)
+                .with_linux_dialect(true)
+                .build()
+                .await
+                .unwrap();
+            let supported = srv.supported_messages();
+            for ty in [110, 102, 72, 8] {
+                assert!(supported.contains(&ty), "{ty} missing");
+            }
+        });
+    }
+
+    #[test]
+    fn rate_limit_builder() {
+        block_on(async {
+            // the policy sticks whichever order it's set in.
+            let srv = AsyncServer::builder()
+                .with_tcp_listen_address("127.0.0.1:0")
+                .with_rate_limit_policy(RateLimitPolicy::Reject)
+                .with_rate_limit(5)
+                .with_filesystem("", TestFs::new(&[]))
+                .build()
+                .await
+                .unwrap();
+            let expected = RateLimit {
+                requests_per_sec: 5,
+                policy: RateLimitPolicy::Reject,
+            };
+            assert_eq!(Some(expected), srv.options.rate_limit);
+
+            // a policy alone doesn't limit anything...
+            let srv = AsyncServer::builder()
+                .with_tcp_listen_address("127.0.0.1:0")
+                .with_rate_limit_policy(RateLimitPolicy::Reject)
+                .with_filesystem("", TestFs::new(&[]))
+                .build()
+                .await
+                .unwrap();
+            assert_eq!(None, srv.options.rate_limit);
+
+            // ...and nor would a limit of 0, were it allowed.
+            let built = AsyncServer::builder()
+                .with_tcp_listen_address("127.0.0.1:0")
+                .with_rate_limit(0)
+                .with_filesystem("", TestFs::new(&[]))
+                .build()
+                .await;
+            assert!(built.is_err());
+        });
+    }
+
+    #[test]
+    fn max_requests_builder() {
+        block_on(async {
+            let build = |max: Option<usize>| {
+                let builder = AsyncServer::builder()
+                    .with_tcp_listen_address("127.0.0.1:0")
+                    .with_filesystem("", TestFs::new(&[]));
+                match max {
+                    Some(max) => builder.with_max_requests(max),
+                    None => builder,
+                }
+                .build()
+            };
+            assert_eq!(64, build(None).await.unwrap().options.max_requests());
+            assert_eq!(8, build(Some(8)).await.unwrap().options.max_requests());
+            assert!(build(Some(0)).await.is_err());
+        });
+    }
+
+    #[test]
+    fn socket_buffer_sizes() {
+        block_on(async {
+            let srv = AsyncServer::builder()
+                .with_tcp_listen_address("127.0.0.1:0")
+                .with_recv_buffer_size(8192)
+                .with_send_buffer_size(16384)
+                .with_filesystem("", TestFs::new(&[]))
+                .build()
+                .await
+                .unwrap();
+            let Listener::Tcp(listener) = &srv.listener else {
+                panic!("not listening on TCP");
+            };
+
+            let _client = TcpStream::connect(srv.local_addr().unwrap()).await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            let accepted = TcpSocket::from_std_stream(accepted.into_std().unwrap());
+
+            // whatever rounding the kernel does to a size, it does the same
+            // here.
+            let expected = TcpSocket::new_v4().unwrap();
+            expected.set_recv_buffer_size(8192).unwrap();
+            expected.set_send_buffer_size(16384).unwrap();
+            assert_eq!(
+                expected.recv_buffer_size().unwrap(),
+                accepted.recv_buffer_size().unwrap()
+            );
+            assert_eq!(
+                expected.send_buffer_size().unwrap(),
+                accepted.send_buffer_size().unwrap()
+            );
+        });
+    }
+}
+
 // vim: foldmethod=marker
@@ -0,0 +1,84 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Authentication of clients before they attach: a Tauth opens an auth fid
+//! (afid), which the client reads and writes to prove who it is, and then
+//! names in its Tattach.
+
+use super::FileResult;
+use crate::raw::Qid;
+use std::{any::Any, fmt, future::Future, pin::Pin};
+
+/// Future returned by the methods of an [Authenticator] or [AuthFile].
+pub type AuthFuture<'a, RetT> = Pin<Box<dyn Future<Output = FileResult<RetT>> + Send + 'a>>;
+
+/// Server end of the conversation a client carries out over an afid. Reads
+/// and writes of the afid are handed here as they come, with no regard for
+/// their offset.
+///
+/// An AuthFile is [Any], so that [Authenticator::check] can downcast it back
+/// to whatever [Authenticator::auth] returned.
+pub trait AuthFile: Any + Send + Sync {
+    /// Qid of the afid, which should be of type [crate::raw::FileType::Auth].
+    fn qid(&self) -> Qid;
+
+    /// Read the next part of the conversation into `buf`, returning how
+    /// many bytes were read.
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> AuthFuture<'a, u32>;
+
+    /// Take the next part of the conversation from `buf`, returning how
+    /// many bytes were taken.
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> AuthFuture<'a, u32>;
+}
+
+/// Hook into every Tauth and Tattach, which requires clients to
+/// authenticate before they may attach. Once set, a Tattach must name an
+/// afid on which the client has proven itself to be the uname it is
+/// attaching as.
+pub trait Authenticator: Send + Sync + 'static {
+    /// Begin authenticating `uname` (or `nuname`) to attach to `aname`, as
+    /// asked for by a Tauth, returning the [AuthFile] the client is to read
+    /// and write over the afid. Returning an Error refuses the Tauth with
+    /// that errno.
+    fn auth<'a>(
+        &'a self,
+        uname: &'a str,
+        aname: &'a str,
+        nuname: u32,
+    ) -> AuthFuture<'a, Box<dyn AuthFile>>;
+
+    /// Check that the conversation over `afile` proved the client to be
+    /// `uname`, and that it may attach to `aname`. Returning an Error
+    /// refuses the Tattach with that errno.
+    fn check<'a>(
+        &'a self,
+        afile: &'a dyn AuthFile,
+        uname: &'a str,
+        aname: &'a str,
+    ) -> AuthFuture<'a, ()>;
+}
+
+impl fmt::Debug for dyn Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authenticator").finish_non_exhaustive()
+    }
+}
+
+// vim: foldmethod=marker
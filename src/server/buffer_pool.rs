@@ -0,0 +1,106 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Reusable buffers for the data of Rread replies.
+
+use std::sync::{Arc, Mutex};
+
+/// Number of buffers kept around by a connection's [BufferPool].
+pub(crate) const DEFAULT_POOL_BUFFERS: usize = 4;
+
+/// Pool of byte buffers, shared between the code filling Rread replies and
+/// the [crate::server::RWriter] sending them, so that a connection doing
+/// large sequential reads allocates its read buffer once rather than once
+/// per request.
+#[derive(Clone, Debug)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    max_buffers: usize,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_POOL_BUFFERS)
+    }
+}
+
+impl BufferPool {
+    /// Create a new, empty, BufferPool, which will hold on to at most
+    /// `max_buffers` buffers that have been given back to it.
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            buffers: Arc::new(Mutex::new(vec![])),
+            max_buffers,
+        }
+    }
+
+    /// Take a zeroed buffer of `len` bytes from the pool, allocating a new
+    /// one if the pool is empty.
+    pub fn take(&self, len: usize) -> Vec<u8> {
+        let mut buf = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        buf.resize(len, 0);
+        buf
+    }
+
+    /// Give a buffer back to the pool once it's no longer needed. Its
+    /// contents are cleared, but its allocation is kept for the next
+    /// [BufferPool::take].
+    pub fn give(&self, mut buf: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buf.clear();
+            buffers.push(buf);
+        }
+    }
+
+    /// Number of buffers currently waiting in the pool.
+    pub fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    /// Check if there are no buffers waiting in the pool.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::new(1);
+        let mut buf = pool.take(16);
+        assert_eq!(vec![0; 16], buf);
+        buf[0] = 0xFF;
+        let ptr = buf.as_ptr();
+        pool.give(buf);
+        pool.give(vec![1, 2, 3]);
+        assert_eq!(1, pool.len());
+
+        let buf = pool.take(8);
+        assert_eq!(ptr, buf.as_ptr());
+        assert_eq!(vec![0; 8], buf);
+        assert!(pool.is_empty());
+    }
+}
+
+// vim: foldmethod=marker
@@ -0,0 +1,162 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+
+/// Future returned by [Clock::sleep].
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Source of time for the server (idle timeouts and the like), which
+/// Filesystems may also use for stat times. Swapping in a [MockClock] makes
+/// anything time-dependent deterministic under test.
+pub trait Clock: Send + Sync + 'static {
+    /// Current wall-clock time.
+    fn now(&self) -> SystemTime;
+
+    /// Return a future that completes once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> Sleep;
+
+    /// Current wall-clock time as seconds since the epoch, as used by the
+    /// atime and mtime fields of a Stat.
+    fn now_unix(&self) -> u32 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0)
+    }
+}
+
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Clock").field("now", &self.now()).finish()
+    }
+}
+
+/// Complete once `timeout` has passed by `clock`, or never, if there is no
+/// timeout.
+pub(crate) async fn expire(clock: &dyn Clock, timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => clock.sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// [Clock] backed by the system clock and tokio's timer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// [Clock] which only moves when told to, via [MockClock::advance]. Clones
+/// share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<watch::Sender<SystemTime>>,
+}
+
+impl MockClock {
+    /// Create a new MockClock, stopped at `now`.
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Arc::new(watch::Sender::new(now)),
+        }
+    }
+
+    /// Move the clock forward by `duration`, waking any sleepers whose time
+    /// has come.
+    pub fn advance(&self, duration: Duration) {
+        self.now.send_modify(|now| *now += duration);
+    }
+
+    /// Number of [Clock::sleep] futures currently waiting on this clock.
+    /// Tests can use this to know that the code under test has started
+    /// waiting before advancing the clock.
+    pub fn sleepers(&self) -> usize {
+        self.now.receiver_count()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new(UNIX_EPOCH)
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let deadline = self.now() + duration;
+        let mut rx = self.now.subscribe();
+        Box::pin(async move {
+            // An error here means the clock is gone, and will never get to
+            // the deadline.
+            if rx.wait_for(|now| *now >= deadline).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::block_on;
+
+    #[test]
+    fn mock_clock_sleep() {
+        block_on(async {
+            let clock = MockClock::default();
+            assert_eq!(0, clock.now_unix());
+
+            let mut sleep = clock.sleep(Duration::from_secs(10));
+            assert_eq!(1, clock.sleepers());
+            clock.advance(Duration::from_secs(9));
+            assert!(poll_once(&mut sleep).is_pending());
+            clock.advance(Duration::from_secs(1));
+            assert!(poll_once(&mut sleep).is_ready());
+            assert_eq!(10, clock.now_unix());
+        });
+    }
+
+    fn poll_once(f: &mut Sleep) -> std::task::Poll<()> {
+        let waker = std::task::Waker::noop();
+        f.as_mut().poll(&mut std::task::Context::from_waker(waker))
+    }
+}
+
+// vim: foldmethod=marker
@@ -19,51 +19,139 @@
 // THE SOFTWARE. }}}
 
 use super::{
+    admin::{AdminRequest, Registration},
     aio::{RWriter, TReader},
-    message_handler, Context, Result, ServerError,
+    async_server::{Mounts, Options, Router},
+    clock::expire,
+    dispatch::{Dispatcher, Flushed},
+    rate_limit::TokenBucket,
+    select::{select, Either},
+    BufferPool, Clock, Context, Observer, Peer, RateLimitPolicy, Result, ServerError,
 };
 use crate::{
-    raw::{Version, R, T},
-    server::{FileError, FileHandles, Filesystem, Requests},
+    raw::{Dialect, RError, TError, Tag, Version, VersionError, NOTAG, R, T},
+    server::{FileHandles, Filesystem, Requests, RequestsError},
 };
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::sync::Mutex;
+use std::{cmp::Ordering, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, task::JoinSet, time::Instant};
 
 struct ConnectionParams {
     msize: u32,
     version: Version,
 }
 
+/// Settle on the parameters of a connection, given what the client asked
+/// for in its Tversion, and the versions we speak (`offered`), the first of
+/// which is what we fall back to.
+fn negotiate(
+    max_msize: u32,
+    offered: &[Version],
+    client_msize: u32,
+    client_version: &Version,
+) -> std::result::Result<ConnectionParams, VersionError> {
+    let version = match offered
+        .iter()
+        .map(|ours| client_version.try_negotiate(ours))
+        .find(|agreed| !matches!(agreed, Err(VersionError::MismatchedVariant)))
+    {
+        Some(agreed) => agreed?,
+        // a client asking for a dialect we don't speak (such as 9P2000.L,
+        // when we speak 9P2000.u) is offered ours instead, as a downgrade;
+        // it is free to hang up.
+        None => offered[0].clone(),
+    };
+    Ok(ConnectionParams {
+        msize: max_msize.min(client_msize),
+        version,
+    })
+}
+
+/// Dialect spoken over a connection which negotiated `version`. Versions we
+/// know nothing about are spoken as 9P2000.u.
+pub(crate) fn dialect(version: &Version) -> Dialect {
+    Dialect::of(version).unwrap_or(Dialect::Unix)
+}
+
+/// Apply negotiated parameters to both halves of the connection. Every
+/// negotiation, be it the handshake or a later session reset, goes through
+/// here, so that the reader and writer never disagree about the msize, or
+/// about how a Stat is laid out.
+fn apply_params(params: &ConnectionParams, rw: &mut RWriter, tr: &mut TReader) {
+    let dialect = dialect(&params.version);
+    rw.set_msize(params.msize);
+    tr.set_msize(params.msize);
+    rw.set_dialect(dialect);
+    tr.set_dialect(dialect);
+}
+
+/// Wait for the client to negotiate a version. A client which goes
+/// `idle_timeout` (by `clock`) without sending anything is hung up on, as
+/// it would be once the connection is established.
 async fn handshake(
     msize: u32,
-    version: &Version,
+    offered: &[Version],
+    byte_budget: Option<u32>,
+    clock: &dyn Clock,
+    idle_timeout: Option<Duration>,
     rw: &mut RWriter,
     tr: &mut TReader,
 ) -> Result<ConnectionParams> {
+    let mut consumed: u32 = 0;
     loop {
-        let t = tr.next().await?;
+        // Capping the msize at what's left of the budget means an oversized
+        // frame is refused before we read (or allocate) any of it.
+        if let Some(budget) = byte_budget {
+            tr.set_msize(msize.min(budget.saturating_sub(consumed)));
+        }
+        let t = match select(tr.next(), expire(clock, idle_timeout)).await {
+            Either::Left(t) => t,
+            Either::Right(()) => {
+                tracing::info!("peer idle for {idle_timeout:?} without negotiating; hanging up");
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "no Tversion before the idle timeout",
+                )
+                .into());
+            }
+        };
+        let t = match t {
+            Ok(t) => t,
+            Err(TError::TooLong) if byte_budget.is_some() => {
+                tracing::warn!(
+                    "peer sent {consumed} bytes and a {} byte frame without negotiating",
+                    tr.last_frame_size()
+                );
+                return Err(ServerError::HandshakeBudgetExceeded);
+            }
+            // a read cut short by a signal is worth another go, rather than
+            // losing the connection before it has even started.
+            Err(TError::IoError(e)) if e.kind() == std::io::ErrorKind::Interrupted => {
+                tracing::debug!("handshake read interrupted; retrying");
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        consumed = consumed.saturating_add(tr.last_frame_size());
         let tag = t.tag();
         match t {
+            T::Version(tag, _, _) if tag != NOTAG => {
+                tracing::warn!("rejecting Tversion with tag={tag} rather than NOTAG");
+                rw.send(R::Error(tag, "EINVAL".to_owned(), 22)).await?;
+            }
             T::Version(tag, client_msize, client_version) => {
                 tracing::debug!("client version {client_msize} {client_version}");
-                let conn_msize = msize.min(client_msize);
-
-                match version.try_negotiate(&client_version) {
-                    Ok(conn_version) => {
-                        rw.set_msize(conn_msize);
-                        tr.set_msize(conn_msize);
 
-                        rw.send(R::Version(tag, conn_msize, conn_version.clone()))
+                match negotiate(msize, offered, client_msize, &client_version) {
+                    Ok(params) => {
+                        apply_params(&params, rw, tr);
+                        rw.send(R::Version(tag, params.msize, params.version.clone()))
                             .await?;
-
-                        return Ok(ConnectionParams {
-                            version: conn_version,
-                            msize: conn_msize,
-                        });
+                        return Ok(params);
                     }
                     Err(e) => {
-                        rw.send(R::Error(tag, format!("{:?}", e), 0xFFFFFFFF))
-                            .await?;
+                        tracing::warn!("failed to negotiate {client_version}: {e:?}");
+                        let (ename, errno) = ServerError::FailedToNegotiate.errno();
+                        rw.send(R::Error(tag, ename, errno)).await?;
                         return Err(ServerError::FailedToNegotiate);
                     }
                 };
@@ -82,11 +170,163 @@ where
     FilesystemT: Send,
     FilesystemT: 'static,
 {
-    pub(super) peer: SocketAddr,
-    pub(super) requests: &'a mut Requests,
+    pub(super) peer: &'a Peer,
     pub(super) handles: &'a mut FileHandles<FilesystemT::File>,
-    pub(super) filesystems: Arc<Mutex<HashMap<String, FilesystemT>>>,
+    pub(super) filesystems: Mounts<FilesystemT>,
+    pub(super) router: Option<&'a Router<FilesystemT>>,
     pub(super) msize: u32,
+    pub(super) version: &'a Version,
+    pub(super) options: &'a Options,
+    pub(super) pool: &'a BufferPool,
+}
+
+impl<FilesystemT> MessageContext<'_, FilesystemT>
+where
+    FilesystemT: Filesystem,
+    FilesystemT: Send,
+    FilesystemT: 'static,
+{
+    /// Peer which sent the request.
+    pub fn peer(&self) -> &Peer {
+        self.peer
+    }
+
+    /// msize negotiated with the peer during the handshake.
+    pub fn msize(&self) -> u32 {
+        self.msize
+    }
+
+    /// Version negotiated with the peer during the handshake.
+    pub fn version(&self) -> &Version {
+        self.version
+    }
+
+    /// Dialect spoken over this connection, which follows from its version.
+    pub fn dialect(&self) -> Dialect {
+        dialect(self.version)
+    }
+
+    /// Largest iounit a file opened over this connection may report: the
+    /// msize, less [crate::raw::IOHDRSZ] for the header of the Rread or
+    /// Twrite. Files whose [crate::server::OpenFile::iounit] is 0 are
+    /// reported with this one.
+    pub fn iounit(&self) -> u32 {
+        default_iounit(self.msize, self.dialect())
+    }
+}
+
+/// Largest iounit which fits a whole Rread or Twrite in `msize`.
+pub(crate) fn default_iounit(msize: u32, dialect: Dialect) -> u32 {
+    msize.saturating_sub(dialect.limits().io_header_size)
+}
+
+/// Sending half of the channel the reader task hands requests over on.
+type Incoming = mpsc::Sender<std::result::Result<T, TError>>;
+
+/// Token bucket requests wait on before they are read, if any.
+type Pace = Option<TokenBucket>;
+
+/// Everything [read_messages] needs to pick up where it left off.
+type Reader = (TReader, Incoming, Pace);
+
+/// Read T messages off the wire and hand them to the connection loop. This
+/// runs in its own task so that a disconnect is noticed even while a request
+/// is still being handled; the channel is only one deep, so at most one
+/// request is read ahead.
+///
+/// After a Tversion, this stops reading and hands the TReader back, so that
+/// the connection loop can apply the new msize before anything else is read.
+///
+/// Under [RateLimitPolicy::Wait], every request but a Tversion waits on the
+/// `pace` bucket for a token before it's handed over. Only the client's
+/// requests are held up; the connection loop carries on sending replies
+/// and answering admin requests in the meantime.
+async fn read_messages(mut tr: TReader, tx: Incoming, mut pace: Pace) -> Option<Reader> {
+    loop {
+        let t = tr.next().await;
+        let done = t.is_err();
+        let version = matches!(t, Ok(T::Version(..)));
+        if let (Some(bucket), false, false) = (&mut pace, done, version) {
+            bucket.take().await;
+        }
+        if tx.send(t).await.is_err() || done {
+            return None;
+        }
+        if version {
+            return Some((tr, tx, pace));
+        }
+    }
+}
+
+/// Wait for the next request from the [ServerHandle] this connection is
+/// registered with, if any.
+async fn recv_admin(admin: &mut Option<Registration>) -> AdminRequest {
+    if let Some(admin) = admin {
+        if let Some(request) = admin.rx.recv().await {
+            return request;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Turn the outcome of handling the request tagged `tag` into the reply to
+/// send, and whether the connection should be hung up on after sending it.
+fn reply(peer: &Peer, tag: Tag, result: Result<R>) -> (R, bool) {
+    match result {
+        Ok(r) => (r, false),
+        Err(ServerError::FileError(fe)) => {
+            if let Some(source) = std::error::Error::source(&fe) {
+                tracing::debug!("tag={tag} failed with {fe}, caused by {source}");
+            }
+            let fatal = fe.is_fatal();
+            if fatal {
+                tracing::error!("tag={tag} from {peer} failed fatally: {fe}");
+            }
+            let (ename, errno) = ServerError::FileError(fe).errno();
+            (R::Error(tag, ename, errno), fatal)
+        }
+        Err(err) => {
+            tracing::debug!("tag={tag} from {peer} failed with {err:?}");
+            let (ename, errno) = err.errno();
+            (R::Error(tag, ename, errno), false)
+        }
+    }
+}
+
+/// Tell `observer` about `reply`, to a request read at `received`, and hand
+/// it back to be sent.
+fn observed(observer: &dyn Observer, reply: R, received: Instant) -> R {
+    observer.on_reply(&reply, received.elapsed());
+    reply
+}
+
+/// Tell `observer` about any change in the number of `open` fids since it
+/// was last `told`.
+fn observe_fids(observer: &dyn Observer, told: &mut usize, open: usize) {
+    match open.cmp(told) {
+        Ordering::Greater => observer.on_fids_opened(open - *told),
+        Ordering::Less => observer.on_fids_clunked(*told - open),
+        Ordering::Equal => {}
+    }
+    *told = open;
+}
+
+/// Send `reply`, or if it does not fit in the msize, an Rerror saying so.
+async fn send_reply(rw: &mut RWriter, msize: u32, reply: R) -> Result<()> {
+    let tag = reply.tag();
+    tracing::debug!("reply tag={tag}: {:?}", reply);
+    match rw.send(reply).await {
+        Ok(_) => Ok(()),
+        Err(RError::TooLong) => {
+            // nothing has been written yet, so we can still tell the
+            // client what happened to this tag rather than dropping the
+            // connection on the floor.
+            tracing::warn!("reply tag={tag} does not fit in msize {msize}");
+            rw.send(R::Error(tag, "EMSGSIZE".to_owned(), 90)).await?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
 }
 
 /// Handler to manage the reading/writing of R/T messages, and dispatch
@@ -103,24 +343,221 @@ where
 {
     let Context {
         peer,
-        msize,
-        version,
-        mut handles,
+        max_msize,
+        handles,
         mut requests,
         filesystems,
+        router,
+        options,
+        mut admin,
     } = ctx;
 
-    let ConnectionParams { msize, version } = handshake(msize, &version, &mut rw, &mut tr).await?;
+    let mut offered: Vec<Version> = vec!["9P2000.u".parse().unwrap()];
+    if options.linux {
+        offered.push("9P2000.L".parse().unwrap());
+    }
+    let clock = options.clock();
+    let ConnectionParams { mut msize, version } = handshake(
+        max_msize,
+        &offered,
+        options.handshake_byte_budget,
+        &*clock,
+        options.idle_timeout,
+        &mut rw,
+        &mut tr,
+    )
+    .await?;
 
     tracing::info!("connection established with {peer}; version {version}, msize {msize}");
+    let observer = options.observer();
+    observer.on_connection_open(&peer);
+    let mut fids = 0;
+    rw.set_coalescing(options.coalesce_writes);
+    let pool = BufferPool::default();
+    rw.set_pool(Some(pool.clone()));
+    let max_requests = options.max_requests();
+    let options = Arc::new(options);
 
-    loop {
-        let t = tr.next().await?;
-        let tag = t.tag();
+    // requests over a Wait limit are held back by the reader, and requests
+    // over a Reject limit are turned away here.
+    let (pace, mut reject) = match &options.rate_limit {
+        Some(limit) if limit.policy == RateLimitPolicy::Wait => {
+            (Some(TokenBucket::new(limit, clock.clone())), None)
+        }
+        Some(limit) => (None, Some(TokenBucket::new(limit, clock.clone()))),
+        None => (None, None),
+    };
+
+    // Requests are each handled on their own task, so that a slow request
+    // doesn't hold up any other. Requests on the same fid are still run one
+    // at a time, in the order they were sent; in particular, a Tclunk (or
+    // Tremove) of a fid with a request in flight waits for that request to
+    // finish before the handle is removed out from under it.
+    let mut dispatcher = Dispatcher::new(
+        peer.clone(),
+        handles,
+        filesystems,
+        router,
+        options.clone(),
+        pool,
+        msize,
+        version,
+    );
+
+    // The reader task is aborted when this JoinSet is dropped, which is to
+    // say whenever we return.
+    let (tx, mut rx) = mpsc::channel(1);
+    let mut tasks = JoinSet::new();
+    tasks.spawn(read_messages(tr, tx, pace));
+
+    let result = async {
+        loop {
+            // With too many requests on the go, the client isn't heard from
+            // until one of them is done; the reader backs up behind the
+            // channel, and the client behind the socket.
+            let full = dispatcher.outstanding() >= max_requests;
+
+            // Nothing else is queued up (or nothing we'll take), so don't
+            // sit on any pending replies while we wait.
+            if rx.is_empty() || full {
+                rw.flush().await?;
+            }
+
+            // Admin requests take precedence over replies, which take
+            // precedence over the client, which in turn takes precedence
+            // over the idle timer. The timer only runs while there is
+            // nothing left to do.
+            let idle_timeout = options.idle_timeout.filter(|_| dispatcher.is_idle());
+            let idle = expire(&*clock, idle_timeout);
+            let incoming = async {
+                match full {
+                    false => rx.recv().await,
+                    true => std::future::pending().await,
+                }
+            };
+            let t = match select(
+                recv_admin(&mut admin),
+                select(dispatcher.next(), select(incoming, idle)),
+            )
+            .await
+            {
+                Either::Left(AdminRequest::ResetSession(reply)) => {
+                    let clunked = dispatcher.reset();
+                    observe_fids(&*observer, &mut fids, dispatcher.open_fids());
+                    tracing::info!("reset session of {peer}; clunked {clunked} fids");
+                    let _ = reply.send(clunked);
+                    continue;
+                }
+                Either::Right(Either::Left(finished)) => {
+                    let tag = finished.tag;
+                    let request = requests.remove(tag);
+                    let (result, flushes) = dispatcher.finish(finished);
+                    observe_fids(&*observer, &mut fids, dispatcher.open_fids());
+                    let mut fatal = false;
+                    match (result, request) {
+                        (Some(result), request) => {
+                            let mut reply;
+                            (reply, fatal) = self::reply(&peer, tag, result);
+                            if let Ok(request) = request {
+                                reply = observed(&*observer, reply, request.received);
+                            }
+                            send_reply(&mut rw, msize, reply).await?;
+                        }
+                        (None, _) => {
+                            tracing::debug!("request tag={tag} flushed before it was done")
+                        }
+                    }
+                    for tag in flushes {
+                        let mut reply = R::Flush(tag);
+                        if let Ok(request) = requests.remove(tag) {
+                            reply = observed(&*observer, reply, request.received);
+                        }
+                        rw.send(reply).await?;
+                    }
+                    if fatal {
+                        rw.flush().await?;
+                        if let Some(admin) = &admin {
+                            admin.shutdown();
+                        }
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Either::Right(Either::Right(Either::Left(t))) => match t {
+                    Some(t) => t?,
+                    None => return Ok(()),
+                },
+                Either::Right(Either::Right(Either::Right(()))) => {
+                    tracing::info!("{peer} idle for {:?}; hanging up", options.idle_timeout);
+                    return Ok(());
+                }
+            };
+            let tag = t.tag();
+            let received = Instant::now();
+            observer.on_request(&t);
+
+            if let T::Version(tag, client_msize, client_version) = t {
+                // the reader task has stopped, and is waiting to be handed
+                // the renegotiated msize.
+                let (mut tr, tx, pace) = match tasks.join_next().await {
+                    Some(Ok(Some(reader))) => reader,
+                    _ => return Ok(()),
+                };
+                if tag != NOTAG {
+                    tracing::warn!("rejecting Tversion with tag={tag} rather than NOTAG");
+                    let reply = R::Error(tag, "EINVAL".to_owned(), 22);
+                    rw.send(observed(&*observer, reply, received)).await?;
+                } else {
+                    tracing::debug!("client version {client_msize} {client_version}");
+                    match negotiate(max_msize, &offered, client_msize, &client_version) {
+                        Ok(params) => {
+                            // per version(5), this aborts all outstanding I/O
+                            // and clunks every fid.
+                            let clunked =
+                                dispatcher.abort(params.msize, params.version.clone()).await;
+                            observe_fids(&*observer, &mut fids, dispatcher.open_fids());
+                            requests = Requests::new();
+                            tracing::info!(
+                                "{peer} reset the session; version {}, msize {}, clunked {clunked} fids",
+                                params.version,
+                                params.msize
+                            );
+                            apply_params(&params, &mut rw, &mut tr);
+                            let reply = R::Version(tag, params.msize, params.version);
+                            rw.send(observed(&*observer, reply, received)).await?;
+                            msize = params.msize;
+                        }
+                        Err(e) => {
+                            tracing::warn!("{peer} failed to renegotiate: {e:?}");
+                            let (ename, errno) = ServerError::FailedToNegotiate.errno();
+                            let reply = R::Error(tag, ename, errno);
+                            rw.send(observed(&*observer, reply, received)).await?;
+                            rw.flush().await?;
+                            return Err(ServerError::FailedToNegotiate);
+                        }
+                    }
+                }
+                tasks.spawn(read_messages(tr, tx, pace));
+                continue;
+            }
+
+            if let Some(ref mut bucket) = reject {
+                if bucket.try_take().is_err() {
+                    tracing::debug!("request tag={tag} from {peer} over rate limit");
+                    let reply = R::Error(tag, "EAGAIN".to_owned(), 11);
+                    rw.send(observed(&*observer, reply, received)).await?;
+                    continue;
+                }
+            }
 
-        {
             match requests.insert(tag, t.clone()) {
                 Ok(_) => {}
+                Err(RequestsError::ReservedTag) => {
+                    tracing::warn!("request from {peer} used NOTAG");
+                    let reply = R::Error(tag, "EINVAL".to_owned(), 22);
+                    rw.send(observed(&*observer, reply, received)).await?;
+                    continue;
+                }
                 Err(_) => {
                     // what do here? treat it as a flush on the old and send
                     // an error in reply to this?
@@ -128,32 +565,937 @@ where
                 }
             };
 
-            let mctx = MessageContext::<FilesystemT> {
-                peer,
-                requests: &mut requests,
-                handles: &mut handles,
-                filesystems: filesystems.clone(),
-                msize,
+            if let T::Flush(tag, oldtag) = t {
+                tracing::debug!("flush request (peer={peer}, tag={tag}, oldtag={oldtag})");
+                if let Ok(req) = requests.get(oldtag) {
+                    tracing::debug!("  flush (peer={peer}, tag={tag}, t={:?})", req.t);
+                }
+                match dispatcher.flush(tag, oldtag) {
+                    Flushed::Cancelling => continue,
+                    Flushed::Dequeued => {
+                        let _ = requests.remove(oldtag);
+                    }
+                    Flushed::Unknown => {}
+                }
+                let _ = requests.remove(tag);
+                rw.send(observed(&*observer, R::Flush(tag), received))
+                    .await?;
+                continue;
+            }
+            dispatcher.submit(t);
+        }
+    }
+    .await;
+
+    // If the client goes away while we're working on its requests, drop
+    // them on the floor rather than finishing them for nobody.
+    dispatcher.shutdown().await;
+    observe_fids(&*observer, &mut fids, 0);
+    observer.on_connection_close(&peer);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        raw::{
+            messages_t::{TYPE_TREAD, TYPE_TWALK},
+            Dialect, Stat, NOTAG, R, T,
+        },
+        server::{
+            async_server::Options,
+            connection_handler,
+            testing::{block_on, mount, mounts, TestConnection, TestFs},
+            Context, CountingObserver, FileError, MockClock, Peer, RReader, RWriter, RateLimit,
+            RateLimitPolicy, ServerError, ServerHandle, TReader, TWriter,
+        },
+    };
+    use std::{
+        io::ErrorKind,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context as TaskContext, Poll},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadBuf},
+        sync::{Notify, Semaphore},
+    };
+
+    #[test]
+    fn empty_description_from_errno() {
+        let peer = Peer::Tcp("127.0.0.1:564".parse().unwrap());
+        let (r, _) = super::reply(&peer, 1, Err(FileError(2, "".to_owned()).into()));
+        assert_eq!(R::Error(1, "ENOENT".to_owned(), 2), r);
+
+        // descriptions the handler did give are left alone...
+        let (r, _) = super::reply(&peer, 2, Err(FileError(2, "gone".to_owned()).into()));
+        assert_eq!(R::Error(2, "gone".to_owned(), 2), r);
+
+        // ...as are errnos with no name.
+        let (r, _) = super::reply(&peer, 3, Err(FileError(0, "".to_owned()).into()));
+        assert_eq!(R::Error(3, "".to_owned(), 0), r);
+    }
+
+    #[test]
+    fn version_reset_msize() {
+        block_on(async {
+            let name = "n".repeat(220);
+            let mounts = mounts(vec![("", mount(TestFs::new(&[(&name, b"")])))]);
+            let mut conn = TestConnection::serve(8192, mounts);
+            conn.attach(8192, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, vec![name.clone()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+            let r = conn.rpc(T::Stat(3, 2)).await;
+            assert!(matches!(r, R::Stat(3, _)), "{:?}", r);
+
+            // the reset clunks everything, and brings the msize down.
+            let r = conn.version(256).await;
+            assert!(matches!(r, R::Version(NOTAG, 256, _)), "{:?}", r);
+            let r = conn.rpc(T::Stat(4, 1)).await;
+            assert_eq!(R::Error(4, "EBADF".to_owned(), 9), r);
+
+            // the writer refuses to send a reply that no longer fits...
+            let r = conn
+                .rpc(T::Attach(5, 1, !0, "user".to_owned(), "".to_owned(), 0))
+                .await;
+            assert!(matches!(r, R::Attach(5, _)), "{:?}", r);
+            let r = conn.rpc(T::Walk(6, 1, 2, vec![name.clone()])).await;
+            assert!(matches!(r, R::Walk(6, _)), "{:?}", r);
+            let r = conn.rpc(T::Stat(7, 2)).await;
+            assert_eq!(R::Error(7, "EMSGSIZE".to_owned(), 90), r);
+
+            // ...and the reader a request that no longer fits.
+            conn.tw.send(T::Write(8, 2, 0, vec![0; 300])).await.unwrap();
+            assert!(conn.task.await.unwrap().is_err());
+        });
+    }
+
+    #[test]
+    fn linux_negotiation() {
+        block_on(async {
+            let agreed = |r: R| match r {
+                R::Version(NOTAG, _, v) => v.to_string(),
+                r => panic!("unexpected reply {:?}", r),
             };
-            let reply = match message_handler(mctx, t).await {
-                Ok(r) => r,
-                Err(err) => match err {
-                    ServerError::FileError(FileError(errno, desc)) => R::Error(tag, desc, errno),
-                    _ => R::Error(tag, format!("{:?}", err), 0xFFFFFFFF),
-                },
+
+            // 9P2000.L is only agreed when it's offered...
+            let mounts = mounts(vec![("", mount(TestFs::new(&[])))]);
+            let mut conn = TestConnection::serve(8192, mounts.clone());
+            assert_eq!("9P2000.u", agreed(conn.version_as(8192, "9P2000.L").await));
+            let mut conn = TestConnection::serve_linux(8192, mounts);
+            assert_eq!("9P2000.L", agreed(conn.version_as(8192, "9P2000.L").await));
+
+            // ...and offering it takes nothing away from 9P2000.u.
+            assert_eq!("9P2000.u", agreed(conn.version_as(8192, "9P2000.u").await));
+        });
+    }
+
+    fn rate_limited(requests_per_sec: u32, policy: RateLimitPolicy, clock: &MockClock) -> Options {
+        Options {
+            rate_limit: Some(RateLimit {
+                requests_per_sec,
+                policy,
+            }),
+            clock: Some(Arc::new(clock.clone())),
+            ..Default::default()
+        }
+    }
+
+    /// Wait for whatever's running on the connection to start sleeping on
+    /// `clock`.
+    async fn asleep(clock: &MockClock) {
+        while clock.sleepers() == 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn rate_limit_wait() {
+        block_on(async {
+            let clock = MockClock::default();
+            let mounts = mounts(vec![("", mount(TestFs::new(&[])))]);
+            let options = rate_limited(10, RateLimitPolicy::Wait, &clock);
+            let mut conn = TestConnection::serve_with_options(1024, mounts, options);
+            conn.attach(1024, 1, "").await;
+
+            // the attach used one token; the rest of the burst is free...
+            for tag in 0..9 {
+                let r = conn.rpc(T::Stat(tag, 1)).await;
+                assert!(matches!(r, R::Stat(_, _)), "{:?}", r);
+            }
+
+            // ...and the next 10 each wait on the clock for a token.
+            for tag in 9..19 {
+                conn.tw.send(T::Stat(tag, 1)).await.unwrap();
+                asleep(&clock).await;
+                clock.advance(Duration::from_millis(100));
+                let r = conn.rr.next().await.unwrap();
+                assert!(matches!(r, R::Stat(_, _)), "{:?}", r);
+            }
+        });
+    }
+
+    #[test]
+    fn rate_limit_wait_holds_only_requests() {
+        block_on(async {
+            let clock = MockClock::default();
+            let handle = ServerHandle::default();
+            let peer = Peer::Tcp("127.0.0.1:564".parse().unwrap());
+            let options = rate_limited(1, RateLimitPolicy::Wait, &clock);
+            let mounts = mounts(vec![("", mount(TestFs::new(&[])))]);
+            let ctx =
+                Context::new(peer.clone(), 1024, mounts, options).with_admin(handle.register(peer));
+            let mut conn = TestConnection::new(ctx);
+            conn.attach(1024, 1, "").await;
+
+            // the attach used the only token, so this stat waits a second
+            // for another; the admin request doesn't wait with it.
+            conn.tw.send(T::Stat(2, 1)).await.unwrap();
+            asleep(&clock).await;
+            let (id, _) = handle.connections()[0];
+            assert_eq!(1, handle.reset_session(id).await.unwrap());
+
+            clock.advance(Duration::from_secs(1));
+            let r = conn.rr.next().await.unwrap();
+            assert_eq!(R::Error(2, "EBADF".to_owned(), 9), r);
+        });
+    }
+
+    #[test]
+    fn rate_limit_reject() {
+        block_on(async {
+            let clock = MockClock::default();
+            let mounts = mounts(vec![("", mount(TestFs::new(&[])))]);
+            let options = rate_limited(10, RateLimitPolicy::Reject, &clock);
+            let mut conn = TestConnection::serve_with_options(1024, mounts, options);
+            conn.attach(1024, 1, "").await;
+
+            for tag in 0..9 {
+                let r = conn.rpc(T::Stat(tag, 1)).await;
+                assert!(matches!(r, R::Stat(_, _)), "{:?}", r);
+            }
+            let r = conn.rpc(T::Stat(10, 1)).await;
+            assert_eq!(R::Error(10, "EAGAIN".to_owned(), 11), r);
+
+            clock.advance(Duration::from_millis(100));
+            let r = conn.rpc(T::Stat(11, 1)).await;
+            assert!(matches!(r, R::Stat(11, _)), "{:?}", r);
+        });
+    }
+
+    #[test]
+    fn handshake_byte_budget() {
+        block_on(async {
+            let options = Options {
+                handshake_byte_budget: Some(256),
+                ..Default::default()
+            };
+            let fs = mounts(vec![("", mount(TestFs::new(&[])))]);
+            let mut conn = TestConnection::serve_with_options(8192, fs, options);
+
+            // 64 bytes apiece; four of these fit, but a fifth does not.
+            for _ in 0..5 {
+                conn.tw
+                    .send(T::Unknown(0xFF, 0, vec![0xAA; 57]))
+                    .await
+                    .unwrap();
+            }
+            assert!(matches!(
+                conn.task.await.unwrap(),
+                Err(ServerError::HandshakeBudgetExceeded)
+            ));
+            assert!(conn.rr.next().await.is_err());
+
+            // a handshake within budget goes through as usual.
+            let options = Options {
+                handshake_byte_budget: Some(256),
+                ..Default::default()
+            };
+            let fs = mounts(vec![("", mount(TestFs::new(&[])))]);
+            let mut conn = TestConnection::serve_with_options(8192, fs, options);
+            conn.tw
+                .send(T::Unknown(0xFF, 0, vec![0xAA; 57]))
+                .await
+                .unwrap();
+            let r = conn.attach(8192, 1, "").await;
+            assert!(matches!(r, R::Attach(_, _)), "{:?}", r);
+        });
+    }
+
+    /// AsyncRead which fails its first read with Interrupted, and then
+    /// reads from the wrapped AsyncRead.
+    struct InterruptOnce<ReadT>(bool, ReadT);
+
+    impl<ReadT: AsyncRead + Unpin> AsyncRead for InterruptOnce<ReadT> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut TaskContext<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            if !self.0 {
+                self.0 = true;
+                return Poll::Ready(Err(std::io::ErrorKind::Interrupted.into()));
+            }
+            Pin::new(&mut self.1).poll_read(cx, buf)
+        }
+    }
+
+    #[test]
+    fn handshake_retries_interrupted_read() {
+        block_on(async {
+            let (client, server) = tokio::io::duplex(8192);
+            let (sr, sw) = tokio::io::split(server);
+            let (cr, cw) = tokio::io::split(client);
+            let ctx = Context::new(
+                Peer::Tcp("127.0.0.1:564".parse().unwrap()),
+                8192,
+                mounts(vec![("", mount(TestFs::new(&[])))]),
+                Options::default(),
+            );
+            let task = tokio::spawn(connection_handler(
+                ctx,
+                RWriter::new(Box::pin(sw), 8192),
+                TReader::new(Box::pin(InterruptOnce(false, sr)), 8192),
+            ));
+            let mut conn = TestConnection {
+                tw: TWriter::new(Box::pin(cw), 8192),
+                rr: RReader::new(Box::pin(cr), 8192),
+                task,
             };
 
-            tracing::debug!("reply tag={tag}: {:?}", reply);
-            match requests.remove(tag) {
-                Ok(_request) => {
-                    rw.send(reply).await?;
+            let r = conn.attach(8192, 1, "").await;
+            assert!(matches!(r, R::Attach(_, _)), "{:?}", r);
+        });
+    }
+
+    /// Frame of type `ty` and tag `tag` around `body`, as sent on the wire.
+    fn frame(ty: u8, tag: u16, body: &[u8]) -> Vec<u8> {
+        let size = (7 + body.len()) as u32;
+        let mut b = size.to_le_bytes().to_vec();
+        b.push(ty);
+        b.extend_from_slice(&tag.to_le_bytes());
+        b.extend_from_slice(body);
+        b
+    }
+
+    /// Send a frame, and read back the type, tag and body of the reply.
+    async fn raw_rpc(io: &mut DuplexStream, t: Vec<u8>) -> (u8, u16, Vec<u8>) {
+        io.write_all(&t).await.unwrap();
+        let mut size = [0; 4];
+        io.read_exact(&mut size).await.unwrap();
+        let mut r = vec![0; u32::from_le_bytes(size) as usize - 4];
+        io.read_exact(&mut r).await.unwrap();
+        (r[0], u16::from_le_bytes([r[1], r[2]]), r[3..].to_vec())
+    }
+
+    /// 9P string: size[2] followed by the bytes.
+    fn s(v: &str) -> Vec<u8> {
+        let mut b = (v.len() as u16).to_le_bytes().to_vec();
+        b.extend_from_slice(v.as_bytes());
+        b
+    }
+
+    #[test]
+    fn base_wire_format() {
+        block_on(async {
+            let (mut client, server) = tokio::io::duplex(8192);
+            let (sr, sw) = tokio::io::split(server);
+            let ctx = Context::new(
+                Peer::Tcp("127.0.0.1:564".parse().unwrap()),
+                8192,
+                mounts(vec![("", mount(TestFs::new(&[("motd", b"hello")])))]),
+                Options::default(),
+            );
+            tokio::spawn(connection_handler(
+                ctx,
+                RWriter::new(Box::pin(sw), 8192),
+                TReader::new(Box::pin(sr), 8192),
+            ));
+            let client = &mut client;
+
+            // Tversion msize[4] version[s]
+            let body = [8192u32.to_le_bytes().to_vec(), s("9P2000")].concat();
+            let (ty, _, body) = raw_rpc(client, frame(100, NOTAG, &body)).await;
+            assert_eq!(101, ty);
+            assert_eq!(s("9P2000"), body[4..]);
+
+            // Tattach fid[4] afid[4] uname[s] aname[s], with no n_uname[4].
+            let body = [
+                1u32.to_le_bytes().to_vec(),
+                (!0u32).to_le_bytes().to_vec(),
+                s("glenda"),
+                s(""),
+            ]
+            .concat();
+            let (ty, tag, _) = raw_rpc(client, frame(104, 1, &body)).await;
+            assert_eq!((105, 1), (ty, tag));
+
+            // Twalk fid[4] newfid[4] nwname[2] wname[s]; the Rerror is
+            // ename[s] alone, with no errno[4] after it.
+            let body = [
+                1u32.to_le_bytes().to_vec(),
+                2u32.to_le_bytes().to_vec(),
+                1u16.to_le_bytes().to_vec(),
+                s("nope"),
+            ]
+            .concat();
+            let (ty, tag, body) = raw_rpc(client, frame(110, 2, &body)).await;
+            assert_eq!((107, 2), (ty, tag));
+            assert_eq!(s("ENOENT"), body);
+
+            // Topen fid[4] mode[1], then Tread fid[4] offset[8] count[4]
+            let body = [1u32.to_le_bytes().to_vec(), vec![0]].concat();
+            let (ty, _, _) = raw_rpc(client, frame(112, 3, &body)).await;
+            assert_eq!(113, ty);
+            let body = [
+                1u32.to_le_bytes().to_vec(),
+                0u64.to_le_bytes().to_vec(),
+                8192u32.to_le_bytes().to_vec(),
+            ]
+            .concat();
+            let (ty, _, body) = raw_rpc(client, frame(116, 4, &body)).await;
+            assert_eq!(117, ty);
+
+            // Rread count[4] data[count]: a single 9P2000 stat, which
+            // stops at muid[s], with no extension[s] or n*id[4].
+            let data = &body[4..];
+            assert_eq!(
+                data.len() as u32,
+                u32::from_le_bytes(body[..4].try_into().unwrap())
+            );
+            let size = u16::from_le_bytes([data[0], data[1]]) as usize;
+            assert_eq!(data.len(), size + 2);
+            let mut c = std::io::Cursor::new(data);
+            let stat = Stat::hydrate_as(&mut c, Dialect::Base).unwrap();
+            assert_eq!("motd", stat.name);
+            assert_eq!(data.len() as u64, c.position());
+        });
+    }
+
+    #[test]
+    fn idle_timeout() {
+        block_on(async {
+            let clock = MockClock::default();
+            let options = Options {
+                idle_timeout: Some(Duration::from_secs(30)),
+                clock: Some(Arc::new(clock.clone())),
+                ..Default::default()
+            };
+            let mounts = mounts(vec![("", mount(TestFs::new(&[])))]);
+            let mut conn = TestConnection::serve_with_options(1024, mounts, options);
+            let r = conn.attach(1024, 1, "").await;
+            assert!(matches!(r, R::Attach(_, _)), "{:?}", r);
+
+            let idle = || async {
+                while clock.sleepers() == 0 {
+                    tokio::task::yield_now().await;
                 }
-                Err(_) => {
-                    tracing::trace!("reply tag={tag} not sent; was it flushed?");
+            };
+
+            // a request resets the idle timer.
+            idle().await;
+            clock.advance(Duration::from_secs(29));
+            let r = conn.rpc(T::Stat(2, 1)).await;
+            assert!(matches!(r, R::Stat(2, _)), "{:?}", r);
+
+            idle().await;
+            clock.advance(Duration::from_secs(29));
+            tokio::task::yield_now().await;
+            assert!(!conn.task.is_finished());
+            clock.advance(Duration::from_secs(1));
+            assert!(conn.task.await.unwrap().is_ok());
+        });
+    }
+
+    #[test]
+    fn idle_timeout_before_handshake() {
+        block_on(async {
+            let clock = MockClock::default();
+            let options = Options {
+                idle_timeout: Some(Duration::from_secs(30)),
+                clock: Some(Arc::new(clock.clone())),
+                ..Default::default()
+            };
+            let mounts = mounts(vec![("", mount(TestFs::new(&[])))]);
+            let conn = TestConnection::serve_with_options(1024, mounts, options);
+
+            // a client that connects and never says a word is hung up on.
+            asleep(&clock).await;
+            clock.advance(Duration::from_secs(29));
+            tokio::task::yield_now().await;
+            assert!(!conn.task.is_finished());
+            clock.advance(Duration::from_secs(1));
+            match conn.task.await.unwrap() {
+                Err(ServerError::IoError(e)) => assert_eq!(ErrorKind::TimedOut, e.kind()),
+                r => panic!("unexpected result {:?}", r),
+            }
+        });
+    }
+
+    #[test]
+    fn request_timeout() {
+        block_on(async {
+            let clock = MockClock::default();
+            let options = Options {
+                request_timeout: Some(Duration::from_secs(5)),
+                clock: Some(Arc::new(clock.clone())),
+                ..Default::default()
+            };
+            let (fs, release) = slow_reads(&[("slow", b"data")]);
+            let mut conn =
+                TestConnection::serve_with_options(1024, mounts(vec![("", mount(fs))]), options);
+            conn.attach(1024, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["slow".to_owned()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+            let r = conn.rpc(T::Open(3, 2, 0.into())).await;
+            assert!(matches!(r, R::Open(3, _, _)), "{:?}", r);
+
+            conn.tw.send(T::Read(4, 2, 0, 128)).await.unwrap();
+            while clock.sleepers() == 0 {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_secs(5));
+            assert_eq!(
+                R::Error(4, "ETIMEDOUT".to_owned(), 110),
+                conn.rr.next().await.unwrap()
+            );
+
+            // the fid is still good, and requests that are quick enough
+            // are answered as usual.
+            release.add_permits(1);
+            let r = conn.rpc(T::Read(5, 2, 0, 128)).await;
+            assert_eq!(R::Read(5, b"data".to_vec()), r);
+        });
+    }
+
+    #[test]
+    fn clunk_during_read() {
+        block_on(async {
+            let release = Arc::new(Semaphore::new(0));
+            let fs = {
+                let release = release.clone();
+                TestFs::new(&[("slow", b"data")]).with_read_hook(move |_| {
+                    let release = release.clone();
+                    async move { release.acquire().await.unwrap().forget() }
+                })
+            };
+            let mut conn = TestConnection::serve(1024, mounts(vec![("", mount(fs))]));
+            conn.attach(1024, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["slow".to_owned()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+            let r = conn.rpc(T::Open(3, 2, 0.into())).await;
+            assert!(matches!(r, R::Open(3, _, _)), "{:?}", r);
+
+            conn.tw.send(T::Read(4, 2, 0, 128)).await.unwrap();
+            conn.tw.send(T::Clunk(5, 2)).await.unwrap();
+            tokio::task::yield_now().await;
+            release.add_permits(1);
+
+            // the read finishes against the still-open fid, and only then is
+            // the fid clunked.
+            assert_eq!(R::Read(4, b"data".to_vec()), conn.rr.next().await.unwrap());
+            assert_eq!(R::Clunk(5), conn.rr.next().await.unwrap());
+            let r = conn.rpc(T::Read(6, 2, 0, 128)).await;
+            assert_eq!(R::Error(6, "EBADF".to_owned(), 9), r);
+        });
+    }
+
+    #[test]
+    fn clunk_after_write() {
+        block_on(async {
+            let release = Arc::new(Semaphore::new(0));
+            let fs = {
+                let release = release.clone();
+                TestFs::new(&[("new", b"")]).with_write_hook(move |_| {
+                    let release = release.clone();
+                    async move { release.acquire().await.unwrap().forget() }
+                })
+            };
+            let mut conn = TestConnection::serve(1024, mounts(vec![("", mount(fs.clone()))]));
+            conn.attach(1024, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["new".to_owned()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+            let r = conn.rpc(T::Open(3, 2, 1.into())).await;
+            assert!(matches!(r, R::Open(3, _, _)), "{:?}", r);
+
+            // write and close, without waiting on the write.
+            conn.tw
+                .send(T::Write(4, 2, 0, b"hello".to_vec()))
+                .await
+                .unwrap();
+            conn.tw.send(T::Clunk(5, 2)).await.unwrap();
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(Some(vec![]), fs.contents("new"));
+            release.add_permits(1);
+
+            // the clunk isn't answered until the write has landed.
+            assert_eq!(R::Write(4, 5), conn.rr.next().await.unwrap());
+            assert_eq!(R::Clunk(5), conn.rr.next().await.unwrap());
+            assert_eq!(Some(b"hello".to_vec()), fs.contents("new"));
+        });
+    }
+
+    /// TestFs where reads of "slow" wait for a permit from the returned
+    /// Semaphore.
+    fn slow_reads(files: &[(&str, &[u8])]) -> (TestFs, Arc<Semaphore>) {
+        let release = Arc::new(Semaphore::new(0));
+        let fs = {
+            let release = release.clone();
+            TestFs::new(files).with_read_hook(move |name| {
+                let (release, slow) = (release.clone(), name == "slow");
+                async move {
+                    if slow {
+                        release.acquire().await.unwrap().forget()
+                    }
                 }
+            })
+        };
+        (fs, release)
+    }
+
+    #[test]
+    fn concurrent_reads() {
+        block_on(async {
+            let (fs, release) = slow_reads(&[("slow", b"tortoise"), ("fast", b"hare")]);
+            let mut conn = TestConnection::serve(1024, mounts(vec![("", mount(fs))]));
+            conn.attach(1024, 1, "").await;
+            for (fid, name) in [(2, "slow"), (3, "fast")] {
+                let r = conn.rpc(T::Walk(2, 1, fid, vec![name.to_owned()])).await;
+                assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+                let r = conn.rpc(T::Open(3, fid, 0.into())).await;
+                assert!(matches!(r, R::Open(3, _, _)), "{:?}", r);
+            }
+
+            // the second read doesn't wait on the first.
+            conn.tw.send(T::Read(4, 2, 0, 128)).await.unwrap();
+            conn.tw.send(T::Read(5, 3, 0, 128)).await.unwrap();
+            assert_eq!(R::Read(5, b"hare".to_vec()), conn.rr.next().await.unwrap());
+
+            release.add_permits(1);
+            assert_eq!(
+                R::Read(4, b"tortoise".to_vec()),
+                conn.rr.next().await.unwrap()
+            );
+        });
+    }
+
+    #[test]
+    fn max_requests() {
+        block_on(async {
+            let observer = CountingObserver::new();
+            let options = Options {
+                max_requests: Some(2),
+                observer: Some(Arc::new(observer.clone())),
+                ..Default::default()
+            };
+            let (fs, release) = slow_reads(&[("slow", b"data")]);
+            let mut conn =
+                TestConnection::serve_with_options(1024, mounts(vec![("", mount(fs))]), options);
+            conn.attach(1024, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["slow".to_owned()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+            let r = conn.rpc(T::Open(3, 2, 0.into())).await;
+            assert!(matches!(r, R::Open(3, _, _)), "{:?}", r);
+            let seen = observer.total_requests();
+
+            // one read runs, one waits behind it, and the rest go unread.
+            for tag in 4..8 {
+                conn.tw.send(T::Read(tag, 2, 0, 128)).await.unwrap();
+            }
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(seen + 2, observer.total_requests());
+
+            // answering one makes room for one more.
+            release.add_permits(1);
+            assert_eq!(R::Read(4, b"data".to_vec()), conn.rr.next().await.unwrap());
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(seen + 3, observer.total_requests());
+
+            release.add_permits(3);
+            for tag in 5..8 {
+                assert_eq!(
+                    R::Read(tag, b"data".to_vec()),
+                    conn.rr.next().await.unwrap()
+                );
             }
+            assert_eq!(seen + 4, observer.total_requests());
+        });
+    }
+
+    #[test]
+    fn flush_cancels_read() {
+        block_on(async {
+            let (fs, release) = slow_reads(&[("slow", b"data")]);
+            let mut conn = TestConnection::serve(1024, mounts(vec![("", mount(fs))]));
+            conn.attach(1024, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["slow".to_owned()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+            let r = conn.rpc(T::Open(3, 2, 0.into())).await;
+            assert!(matches!(r, R::Open(3, _, _)), "{:?}", r);
+
+            // the read is waiting on the Filesystem, and the stat behind it
+            // on the read.
+            conn.tw.send(T::Read(4, 2, 0, 128)).await.unwrap();
+            conn.tw.send(T::Stat(5, 2)).await.unwrap();
+            conn.tw.send(T::Flush(6, 5)).await.unwrap();
+            assert_eq!(R::Flush(6), conn.rr.next().await.unwrap());
+            conn.tw.send(T::Flush(7, 4)).await.unwrap();
+            assert_eq!(R::Flush(7), conn.rr.next().await.unwrap());
+
+            // neither is answered, and the fid is still good.
+            release.add_permits(1);
+            let r = conn.rpc(T::Read(8, 2, 0, 128)).await;
+            assert_eq!(R::Read(8, b"data".to_vec()), r);
+        });
+    }
+
+    #[test]
+    fn same_fid_reads_serialize() {
+        block_on(async {
+            let release = Arc::new(Semaphore::new(0));
+            let active = Arc::new(AtomicUsize::new(0));
+            let overlapped = Arc::new(AtomicBool::new(false));
+            let fs = {
+                let (release, active, overlapped) =
+                    (release.clone(), active.clone(), overlapped.clone());
+                TestFs::new(&[("file", b"data")]).with_read_hook(move |_| {
+                    let (release, active, overlapped) =
+                        (release.clone(), active.clone(), overlapped.clone());
+                    async move {
+                        if active.fetch_add(1, Ordering::SeqCst) != 0 {
+                            overlapped.store(true, Ordering::SeqCst);
+                        }
+                        release.acquire().await.unwrap().forget();
+                        active.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            };
+            let mut conn = TestConnection::serve(1024, mounts(vec![("", mount(fs))]));
+            conn.attach(1024, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["file".to_owned()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+            let r = conn.rpc(T::Open(3, 2, 0.into())).await;
+            assert!(matches!(r, R::Open(3, _, _)), "{:?}", r);
+
+            conn.tw.send(T::Read(4, 2, 0, 2)).await.unwrap();
+            conn.tw.send(T::Read(5, 2, 2, 2)).await.unwrap();
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(1, active.load(Ordering::SeqCst));
+            release.add_permits(2);
+
+            assert_eq!(R::Read(4, b"da".to_vec()), conn.rr.next().await.unwrap());
+            assert_eq!(R::Read(5, b"ta".to_vec()), conn.rr.next().await.unwrap());
+            assert!(!overlapped.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn notag() {
+        block_on(async {
+            let mounts = mounts(vec![("", mount(TestFs::new(&[])))]);
+            let mut conn = TestConnection::serve(1024, mounts);
+
+            let r = conn
+                .rpc(T::Version(1, 1024, "9P2000.u".parse().unwrap()))
+                .await;
+            assert_eq!(R::Error(1, "EINVAL".to_owned(), 22), r);
+
+            // NOTAG gets us through the handshake, but not any further.
+            let r = conn.attach(1024, 1, "").await;
+            assert!(matches!(r, R::Attach(_, _)), "{:?}", r);
+            let r = conn.rpc(T::Stat(NOTAG, 1)).await;
+            assert_eq!(R::Error(NOTAG, "EINVAL".to_owned(), 22), r);
+            let r = conn.rpc(T::Stat(2, 1)).await;
+            assert!(matches!(r, R::Stat(2, _)), "{:?}", r);
+        });
+    }
+
+    #[test]
+    fn coalesced_replies() {
+        block_on(async {
+            let mounts = mounts(vec![("", mount(TestFs::new(&[])))]);
+            let options = Options {
+                coalesce_writes: Some(4096),
+                ..Default::default()
+            };
+            let mut conn = TestConnection::serve_with_options(1024, mounts, options);
+            let r = conn.attach(1024, 1, "").await;
+            assert!(matches!(r, R::Attach(_, _)), "{:?}", r);
+
+            // pipelined requests all get answered, in order, even though
+            // none of them fill up the buffer.
+            for tag in 2..10 {
+                conn.tw.send(T::Stat(tag, 1)).await.unwrap();
+            }
+            for tag in 2..10 {
+                let r = conn.rr.next().await.unwrap();
+                assert!(matches!(r, R::Stat(t, _) if t == tag), "{:?}", r);
+            }
+        });
+    }
+
+    #[test]
+    fn oversized_reply_is_rerror() {
+        block_on(async {
+            let name = "n".repeat(1000);
+            let data = vec![0xAAu8; 2048];
+            let mounts = mounts(vec![("", mount(TestFs::new(&[(&name, &data)])))]);
+            let mut conn = TestConnection::serve(1024, mounts);
+
+            let r = conn.attach(1024, 1, "").await;
+            assert!(matches!(r, R::Attach(_, _)), "{:?}", r);
+            let r = conn.rpc(T::Walk(2, 1, 2, vec![name.clone()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+
+            // the name fits in a Twalk, but not once it's in a Stat.
+            let r = conn.rpc(T::Stat(3, 2)).await;
+            assert_eq!(R::Error(3, "EMSGSIZE".to_owned(), 90), r);
+
+            // and the connection is still alive afterwards.
+            let r = conn.rpc(T::Open(4, 2, 0.into())).await;
+            assert!(matches!(r, R::Open(4, _, _)), "{:?}", r);
+            let r = conn.rpc(T::Read(5, 2, 0, 512)).await;
+            assert_eq!(R::Read(5, vec![0xAAu8; 512]), r);
+        });
+    }
+
+    /// Sets the flag when dropped.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
         }
     }
+
+    #[test]
+    fn disconnect_drops_inflight_read() {
+        block_on(async {
+            let started = Arc::new(Notify::new());
+            let dropped = Arc::new(AtomicBool::new(false));
+            let fs = {
+                let (started, dropped) = (started.clone(), dropped.clone());
+                TestFs::new(&[("slow", b"never")]).with_read_hook(move |_| {
+                    let (started, dropped) = (started.clone(), dropped.clone());
+                    async move {
+                        let _guard = DropFlag(dropped);
+                        started.notify_one();
+                        std::future::pending::<()>().await;
+                    }
+                })
+            };
+            let mut conn = TestConnection::serve(1024, mounts(vec![("", mount(fs))]));
+
+            let r = conn.attach(1024, 1, "").await;
+            assert!(matches!(r, R::Attach(_, _)), "{:?}", r);
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["slow".to_owned()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+            let r = conn.rpc(T::Open(3, 2, 0.into())).await;
+            assert!(matches!(r, R::Open(3, _, _)), "{:?}", r);
+
+            conn.tw.send(T::Read(4, 2, 0, 128)).await.unwrap();
+            started.notified().await;
+            assert!(!dropped.load(Ordering::SeqCst));
+
+            let TestConnection { tw, rr, task } = conn;
+            drop((tw, rr));
+
+            assert!(task.await.unwrap().is_err());
+            assert!(dropped.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn flush_drops_inflight_read() {
+        block_on(async {
+            let started = Arc::new(Notify::new());
+            let dropped = Arc::new(AtomicBool::new(false));
+            let fs = {
+                let (started, dropped) = (started.clone(), dropped.clone());
+                TestFs::new(&[("slow", b"never")]).with_read_hook(move |_| {
+                    let (started, dropped) = (started.clone(), dropped.clone());
+                    async move {
+                        let _guard = DropFlag(dropped);
+                        started.notify_one();
+                        std::future::pending::<()>().await;
+                    }
+                })
+            };
+            let mut conn = TestConnection::serve(1024, mounts(vec![("", mount(fs))]));
+            conn.attach(1024, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["slow".to_owned()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+            let r = conn.rpc(T::Open(3, 2, 0.into())).await;
+            assert!(matches!(r, R::Open(3, _, _)), "{:?}", r);
+
+            conn.tw.send(T::Read(4, 2, 0, 128)).await.unwrap();
+            started.notified().await;
+
+            // the read is gone by the time the Rflush goes out...
+            conn.tw.send(T::Flush(5, 4)).await.unwrap();
+            assert_eq!(R::Flush(5), conn.rr.next().await.unwrap());
+            assert!(dropped.load(Ordering::SeqCst));
+
+            // ...and it is never answered.
+            let r = conn.rpc(T::Clunk(6, 2)).await;
+            assert_eq!(R::Clunk(6), r);
+        });
+    }
+
+    #[test]
+    fn observer_counts() {
+        block_on(async {
+            let observer = CountingObserver::new();
+            let options = Options {
+                observer: Some(Arc::new(observer.clone())),
+                ..Default::default()
+            };
+            let fs = TestFs::new(&[("file", b"hello")]);
+            let mut conn =
+                TestConnection::serve_with_options(8192, mounts(vec![("", mount(fs))]), options);
+            let r = conn.attach(8192, 1, "").await;
+            assert!(matches!(r, R::Attach(_, _)), "{:?}", r);
+            assert_eq!(1, observer.connections());
+
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["file".to_owned()])).await;
+            assert!(matches!(r, R::Walk(2, _)), "{:?}", r);
+            let r = conn.rpc(T::Walk(3, 1, 3, vec!["missing".to_owned()])).await;
+            assert!(matches!(r, R::Error(3, _, _)), "{:?}", r);
+            let r = conn.rpc(T::Open(4, 2, 0.into())).await;
+            assert!(matches!(r, R::Open(4, _, _)), "{:?}", r);
+            let r = conn.rpc(T::Read(5, 2, 0, 1024)).await;
+            assert_eq!(R::Read(5, b"hello".to_vec()), r);
+            assert_eq!(2, observer.fids());
+            assert_eq!(R::Clunk(6), conn.rpc(T::Clunk(6, 2)).await);
+
+            assert_eq!(6, observer.total_requests());
+            assert_eq!(2, observer.requests(TYPE_TWALK));
+            assert_eq!(1, observer.requests(TYPE_TREAD));
+            assert_eq!(1, observer.errors());
+            assert_eq!(5, observer.bytes_read());
+            assert_eq!(0, observer.bytes_written());
+            assert_eq!(1, observer.fids());
+
+            // hanging up clunks whatever is left.
+            let TestConnection { tw, rr, task } = conn;
+            drop((tw, rr));
+            let _ = task.await.unwrap();
+            assert_eq!(0, observer.connections());
+            assert_eq!(0, observer.fids());
+        });
+    }
 }
 
 // vim: foldmethod=marker
@@ -0,0 +1,82 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// What to do with a connection that arrives while the server is already
+/// serving as many connections as it's allowed to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionLimitPolicy {
+    /// Stop accepting connections until one of the current ones closes.
+    Wait,
+
+    /// Accept the connection, and hang up on it right away.
+    Reject,
+}
+
+/// Limit on the number of connections a server will serve at once. Each
+/// connection holds a permit for as long as it's being served.
+#[derive(Debug)]
+pub(crate) struct ConnectionLimit {
+    max: usize,
+    pub(crate) policy: ConnectionLimitPolicy,
+    permits: Arc<Semaphore>,
+}
+
+impl ConnectionLimit {
+    /// Create a new ConnectionLimit allowing `max` connections at once.
+    pub(crate) fn new(max: usize, policy: ConnectionLimitPolicy) -> Self {
+        let max = max.min(Semaphore::MAX_PERMITS);
+        Self {
+            max,
+            policy,
+            permits: Arc::new(Semaphore::new(max)),
+        }
+    }
+
+    /// ConnectionLimit which never turns a connection away.
+    pub(crate) fn unlimited() -> Self {
+        Self::new(Semaphore::MAX_PERMITS, ConnectionLimitPolicy::Wait)
+    }
+
+    /// Wait for a connection to be allowed.
+    pub(crate) async fn acquire(&self) -> OwnedSemaphorePermit {
+        // the semaphore is never closed.
+        self.permits.clone().acquire_owned().await.unwrap()
+    }
+
+    /// Allow a connection if there is room for it right now.
+    pub(crate) fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.permits.clone().try_acquire_owned().ok()
+    }
+
+    /// Largest number of connections allowed at once.
+    pub(crate) fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Number of connections holding a permit.
+    pub(crate) fn active(&self) -> usize {
+        self.max - self.permits.available_permits()
+    }
+}
+
+// vim: foldmethod=marker
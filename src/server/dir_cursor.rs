@@ -0,0 +1,145 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use super::{FileError, FileResult};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Source of per-cursor salts, so that a cookie handed out by one listing
+/// is never mistaken for a position in another.
+static NEXT_SALT: AtomicU32 = AtomicU32::new(1);
+
+/// DirCursor manages the opaque offsets ("cookies") used to resume a
+/// directory read, where each entry carries a cookie the client echoes back
+/// to continue on from that entry.
+///
+/// Implementors only need to produce their entries in a stable order; the
+/// DirCursor takes care of handing out cookies, and of turning a cookie back
+/// into a position in that order. A cookie of 0 always means "from the
+/// start".
+#[derive(Debug)]
+pub struct DirCursor {
+    salt: u32,
+    issued: usize,
+}
+
+impl Default for DirCursor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DirCursor {
+    /// Create a new DirCursor for a fresh directory listing.
+    pub fn new() -> Self {
+        Self {
+            salt: NEXT_SALT.fetch_add(1, Ordering::Relaxed),
+            issued: 0,
+        }
+    }
+
+    /// Return the cookie for the entry at `position`. Resuming from this
+    /// cookie will continue with the entry at `position + 1`.
+    pub fn cookie(&mut self, position: usize) -> u64 {
+        let next = position + 1;
+        self.issued = self.issued.max(next);
+        ((self.salt as u64) << 32) | (next as u64 & 0xFFFFFFFF)
+    }
+
+    /// Turn a cookie sent by the client back into the position of the next
+    /// entry to return. Cookies which were never handed out by this
+    /// DirCursor (including ones from an older listing) are rejected with
+    /// EINVAL.
+    pub fn resume(&self, cookie: u64) -> FileResult<usize> {
+        if cookie == 0 {
+            return Ok(0);
+        }
+
+        let salt = (cookie >> 32) as u32;
+        let position = (cookie & 0xFFFFFFFF) as usize;
+        if salt != self.salt || position == 0 || position > self.issued {
+            return Err(FileError(22, "EINVAL".to_owned()));
+        }
+        Ok(position)
+    }
+
+    /// Resume from `cookie` over the full, ordered, set of `entries`,
+    /// returning each remaining entry along with its cookie.
+    pub fn page<'a, EntryT, IterT>(
+        &'a mut self,
+        cookie: u64,
+        entries: IterT,
+    ) -> FileResult<impl Iterator<Item = (u64, EntryT)> + 'a>
+    where
+        IterT: IntoIterator<Item = EntryT>,
+        IterT::IntoIter: 'a,
+    {
+        let start = self.resume(cookie)?;
+        Ok(entries
+            .into_iter()
+            .enumerate()
+            .skip(start)
+            .map(|(position, entry)| (self.cookie(position), entry)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DirCursor;
+    use crate::server::FileError;
+
+    const ENTRIES: [&str; 5] = ["a", "b", "c", "d", "e"];
+
+    #[test]
+    fn resume_mid_directory() {
+        let mut cursor = DirCursor::new();
+
+        let first: Vec<(u64, &str)> = cursor.page(0, ENTRIES).unwrap().take(2).collect();
+        assert_eq!(
+            vec!["a", "b"],
+            first.iter().map(|x| x.1).collect::<Vec<_>>()
+        );
+
+        let (cookie, _) = first[1];
+        let rest: Vec<&str> = cursor.page(cookie, ENTRIES).unwrap().map(|x| x.1).collect();
+        assert_eq!(vec!["c", "d", "e"], rest);
+
+        // resuming from the first entry again is fine too.
+        let (cookie, _) = first[0];
+        let rest: Vec<&str> = cursor.page(cookie, ENTRIES).unwrap().map(|x| x.1).collect();
+        assert_eq!(vec!["b", "c", "d", "e"], rest);
+    }
+
+    #[test]
+    fn resume_stale_cookie() {
+        let mut old = DirCursor::new();
+        let (cookie, _) = old.page(0, ENTRIES).unwrap().next().unwrap();
+
+        let cursor = DirCursor::new();
+        match cursor.resume(cookie) {
+            Err(FileError { errno: 22, .. }) => {}
+            v => panic!("unexpected {:?}", v),
+        }
+
+        // never-issued positions from the right listing are bogus too.
+        assert!(old.resume(cookie + 10).is_err());
+    }
+}
+
+// vim: foldmethod=marker
@@ -0,0 +1,418 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Run the requests of a single connection concurrently, each on its own
+//! task, while keeping requests which act on the same fid in order.
+
+use super::{
+    async_server::{Mounts, Options, Router},
+    message_handler,
+    select::{select, Either},
+    BufferPool, MessageContext, Peer, Result,
+};
+use crate::{
+    raw::{Fid, Tag, Version, NOFID, R, T},
+    server::{FileHandles, Filesystem},
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+use tokio::{sync::oneshot, task::JoinSet};
+
+/// fids that `t` acts on, which no other request may touch until it is
+/// done. This includes fids a request only creates (the newfid of a walk,
+/// the fid of an attach), so that a request which creates a fid is done
+/// before the next one uses it.
+fn fids(t: &T) -> Vec<Fid> {
+    match t {
+        T::Attach(_, fid, afid, ..) if *afid != NOFID && afid != fid => vec![*fid, *afid],
+        T::Auth(_, fid, ..)
+        | T::Attach(_, fid, ..)
+        | T::Open(_, fid, _)
+        | T::Create(_, fid, ..)
+        | T::Read(_, fid, ..)
+        | T::Write(_, fid, ..)
+        | T::Clunk(_, fid)
+        | T::Remove(_, fid)
+        | T::Stat(_, fid)
+        | T::WStat(_, fid, _)
+        | T::Mkdir(_, fid, ..)
+        | T::UnlinkAt(_, fid, ..)
+        | T::Symlink(_, fid, ..)
+        | T::Fsync(_, fid, _)
+        | T::StatFs(_, fid)
+        | T::GetAttr(_, fid, _)
+        | T::XattrCreate(_, fid, ..) => vec![*fid],
+        T::Walk(_, fid, other, _) | T::Link(_, fid, other, _) | T::XattrWalk(_, fid, other, _)
+            if fid == other =>
+        {
+            vec![*fid]
+        }
+        T::Walk(_, fid, other, _) | T::Link(_, fid, other, _) | T::XattrWalk(_, fid, other, _) => {
+            vec![*fid, *other]
+        }
+        _ => vec![],
+    }
+}
+
+/// Everything a request task needs which is the same for every request of
+/// the connection.
+struct Shared<FilesystemT>
+where
+    FilesystemT: Filesystem,
+    FilesystemT: Send,
+    FilesystemT: 'static,
+{
+    peer: Peer,
+    filesystems: Mounts<FilesystemT>,
+    router: Option<Router<FilesystemT>>,
+    options: Arc<Options>,
+    pool: BufferPool,
+}
+
+/// Request which has been handed to a task, and is now done.
+pub(crate) struct Finished<FilesystemT>
+where
+    FilesystemT: Filesystem,
+    FilesystemT: Send,
+    FilesystemT: 'static,
+{
+    /// Tag of the request.
+    pub(crate) tag: Tag,
+
+    /// Outcome of handling the request, or None if it was flushed before
+    /// it was done.
+    pub(crate) result: Option<Result<R>>,
+
+    handles: FileHandles<FilesystemT::File>,
+    fids: Vec<Fid>,
+    taken: usize,
+    generation: u64,
+}
+
+/// What became of the request a Tflush named.
+pub(crate) enum Flushed {
+    /// There was no such request (or it was already answered), so the
+    /// Rflush may be sent right away.
+    Unknown,
+
+    /// The request had not yet been started, and never will be. The Rflush
+    /// may be sent right away.
+    Dequeued,
+
+    /// The request is running, and has been told to stop. The Rflush is
+    /// handed back along with it once it has, by [Dispatcher::finish].
+    Cancelling,
+}
+
+/// Requests of a connection which are running, or waiting their turn.
+///
+/// The handles of the connection live here while no request is using them.
+/// A request is handed just the handles of the fids it names, and a request
+/// which names a fid that is already in use waits until every request ahead
+/// of it on that fid is done.
+pub(crate) struct Dispatcher<FilesystemT>
+where
+    FilesystemT: Filesystem,
+    FilesystemT: Send,
+    FilesystemT: 'static,
+{
+    shared: Arc<Shared<FilesystemT>>,
+    msize: u32,
+    version: Version,
+
+    handles: FileHandles<FilesystemT::File>,
+    busy: HashSet<Fid>,
+
+    /// Number of fids the client has open, counting those split off to
+    /// requests which are running.
+    open: usize,
+
+    waiting: VecDeque<(T, Vec<Fid>)>,
+
+    /// Way to stop each running request, by tag.
+    running: HashMap<Tag, oneshot::Sender<()>>,
+
+    /// Tflushes waiting on each request being cancelled, by the tag of the
+    /// request.
+    flushes: HashMap<Tag, Vec<Tag>>,
+
+    /// Session the handles were split off from. Handles which come back
+    /// from a request after a reset are for fids the client no longer has,
+    /// and are dropped.
+    generation: u64,
+
+    tasks: JoinSet<Finished<FilesystemT>>,
+}
+
+impl<FilesystemT> Dispatcher<FilesystemT>
+where
+    FilesystemT: Filesystem,
+    FilesystemT: Send,
+    FilesystemT: 'static,
+{
+    /// Create a new Dispatcher, taking ownership of the handles of the
+    /// connection.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        peer: Peer,
+        handles: FileHandles<FilesystemT::File>,
+        filesystems: Mounts<FilesystemT>,
+        router: Option<Router<FilesystemT>>,
+        options: Arc<Options>,
+        pool: BufferPool,
+        msize: u32,
+        version: Version,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                peer,
+                filesystems,
+                router,
+                options,
+                pool,
+            }),
+            msize,
+            version,
+            open: handles.len(),
+            handles,
+            busy: HashSet::new(),
+            waiting: VecDeque::new(),
+            running: HashMap::new(),
+            flushes: HashMap::new(),
+            generation: 0,
+            tasks: JoinSet::new(),
+        }
+    }
+
+    /// Check if there are no requests running, or waiting to be.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.tasks.is_empty() && self.waiting.is_empty()
+    }
+
+    /// Number of requests running, or waiting to be.
+    pub(crate) fn outstanding(&self) -> usize {
+        self.tasks.len() + self.waiting.len()
+    }
+
+    /// Number of fids the client has open.
+    pub(crate) fn open_fids(&self) -> usize {
+        self.open
+    }
+
+    /// Run `t` as soon as no request ahead of it is using any of its fids.
+    pub(crate) fn submit(&mut self, t: T) {
+        let fids = fids(&t);
+        let blocked = fids.iter().any(|fid| {
+            self.busy.contains(fid) || self.waiting.iter().any(|(_, other)| other.contains(fid))
+        });
+        if blocked {
+            tracing::trace!("request tag={} waiting on fids {fids:?}", t.tag());
+            self.waiting.push_back((t, fids));
+        } else {
+            self.start(t, fids);
+        }
+    }
+
+    fn start(&mut self, t: T, fids: Vec<Fid>) {
+        let tag = t.tag();
+        let mut handles = self.handles.split_off(&fids);
+        let taken = handles.len();
+        self.busy.extend(fids.iter().copied());
+
+        let (cancel, cancelled) = oneshot::channel();
+        self.running.insert(tag, cancel);
+
+        let shared = self.shared.clone();
+        let (msize, version, generation) = (self.msize, self.version.clone(), self.generation);
+        self.tasks.spawn(async move {
+            let result = {
+                let mctx = MessageContext::<FilesystemT> {
+                    peer: &shared.peer,
+                    handles: &mut handles,
+                    filesystems: shared.filesystems.clone(),
+                    router: shared.router.as_ref(),
+                    msize,
+                    version: &version,
+                    options: &shared.options,
+                    pool: &shared.pool,
+                };
+                let timeout = async {
+                    match shared.options.request_timeout {
+                        Some(timeout) => shared.options.clock().sleep(timeout).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                // Dropping the handler future stops it where it is, but
+                // the handles it was using are left behind to go back.
+                match select(message_handler(mctx, t), select(cancelled, timeout)).await {
+                    Either::Left(result) => Some(result),
+                    Either::Right(Either::Left(_)) => None,
+                    Either::Right(Either::Right(())) => {
+                        tracing::warn!("request tag={tag} from {} timed out", shared.peer);
+                        Some(Ok(R::Error(tag, "ETIMEDOUT".to_owned(), 110)))
+                    }
+                }
+            };
+            Finished {
+                tag,
+                result,
+                handles,
+                fids,
+                taken,
+                generation,
+            }
+        });
+    }
+
+    /// Start every waiting request which no longer shares a fid with one
+    /// running, or with one waiting ahead of it.
+    fn schedule(&mut self) {
+        let mut held = HashSet::new();
+        let mut waiting = std::mem::take(&mut self.waiting);
+        while let Some((t, fids)) = waiting.pop_front() {
+            if fids
+                .iter()
+                .any(|fid| self.busy.contains(fid) || held.contains(fid))
+            {
+                held.extend(fids.iter().copied());
+                self.waiting.push_back((t, fids));
+            } else {
+                self.start(t, fids);
+            }
+        }
+    }
+
+    /// Wait for the next request to be done. If there are none running,
+    /// this never resolves.
+    pub(crate) async fn next(&mut self) -> Finished<FilesystemT> {
+        match self.tasks.join_next().await {
+            Some(Ok(finished)) => finished,
+            Some(Err(e)) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // tasks are only ever aborted by Dispatcher::abort, which
+            // waits on them itself.
+            _ => std::future::pending().await,
+        }
+    }
+
+    /// Take back the handles of a request that is done, and start whatever
+    /// was waiting on them. Returns the outcome of the request, and the
+    /// tags of any Tflushes which were waiting on it. A request that used
+    /// fids which were clunked by a reset while it ran is answered with
+    /// EBADF, whatever it did, since none of it sticks.
+    pub(crate) fn finish(
+        &mut self,
+        finished: Finished<FilesystemT>,
+    ) -> (Option<Result<R>>, Vec<Tag>) {
+        let Finished {
+            tag,
+            result,
+            handles,
+            fids,
+            taken,
+            generation,
+        } = finished;
+
+        self.running.remove(&tag);
+        for fid in &fids {
+            self.busy.remove(fid);
+        }
+        let result = if generation == self.generation {
+            self.open = self.open - taken + handles.len();
+            self.handles.merge(handles);
+            result
+        } else if fids.is_empty() {
+            result
+        } else {
+            result.map(|_| Ok(R::Error(tag, "EBADF".to_owned(), 9)))
+        };
+        self.schedule();
+        (result, self.flushes.remove(&tag).unwrap_or_default())
+    }
+
+    /// Handle a Tflush (tagged `tag`) of the request tagged `oldtag`.
+    pub(crate) fn flush(&mut self, tag: Tag, oldtag: Tag) -> Flushed {
+        if let Some(idx) = self.waiting.iter().position(|(t, _)| t.tag() == oldtag) {
+            self.waiting.remove(idx);
+            self.schedule();
+            return Flushed::Dequeued;
+        }
+        if let Some(cancel) = self.running.remove(&oldtag) {
+            let _ = cancel.send(());
+        } else if !self.flushes.contains_key(&oldtag) {
+            return Flushed::Unknown;
+        }
+        self.flushes.entry(oldtag).or_default().push(tag);
+        Flushed::Cancelling
+    }
+
+    /// Clunk every fid, returning how many there were. Requests still
+    /// running carry on, but any fids they were using are clunked once they
+    /// are done, and they're answered with EBADF; fids they have only
+    /// locked (the newfid of a walk, say) are not counted, since they don't
+    /// exist yet.
+    pub(crate) fn reset(&mut self) -> usize {
+        self.generation += 1;
+        self.handles.drain().for_each(drop);
+        self.handles.drain_auth();
+        std::mem::take(&mut self.open)
+    }
+
+    /// Stop every request, running or waiting, and clunk every fid,
+    /// returning how many there were. Once this returns, `msize` and
+    /// `version` apply to every request that follows.
+    pub(crate) async fn abort(&mut self, msize: u32, version: Version) -> usize {
+        self.tasks.shutdown().await;
+        let clunked = self.reset();
+        self.busy.clear();
+        self.waiting.clear();
+        self.running.clear();
+        self.flushes.clear();
+        self.msize = msize;
+        self.version = version;
+        clunked
+    }
+
+    /// Stop every request, and wait for them to be dropped.
+    pub(crate) async fn shutdown(&mut self) {
+        self.tasks.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fids;
+    use crate::raw::{NOFID, T};
+
+    #[test]
+    fn request_fids() {
+        assert_eq!(vec![1], fids(&T::Clunk(0, 1)));
+        assert_eq!(vec![1, 2], fids(&T::Walk(0, 1, 2, vec![])));
+        assert_eq!(vec![1], fids(&T::Walk(0, 1, 1, vec![])));
+        assert_eq!(vec![3, 4], fids(&T::Link(0, 3, 4, "name".to_owned())));
+        assert!(fids(&T::Flush(0, 1)).is_empty());
+        let attach = |afid| T::Attach(0, 1, afid, "user".to_owned(), "".to_owned(), 0);
+        assert_eq!(vec![1, 2], fids(&attach(2)));
+        assert_eq!(vec![1], fids(&attach(NOFID)));
+    }
+}
+
+// vim: foldmethod=marker
@@ -64,7 +64,7 @@ macro_rules! gen_file_enum {
                 }
             }
 
-            async fn walk(&self, path: &[&str]) -> $crate::server::FileResult<(Option<Self>, Vec<Self>)> {
+            async fn walk(&self, path: &[&str]) -> $crate::server::FileResult<($crate::server::FileResult<Self>, Vec<Self>)> {
                 match self {
                     $(
                         Self::$child(slf) => {
@@ -78,6 +78,14 @@ macro_rules! gen_file_enum {
                 }
             }
 
+            async fn exists_child(&self, name: &str) -> $crate::server::FileResult<bool> {
+                match self {
+                    $(
+                        Self::$child(slf) => slf.exists_child(name).await
+                    )+
+                }
+            }
+
             async fn unlink(&mut self) -> $crate::server::FileResult<()> {
                 match self {
                     $(
@@ -109,6 +117,120 @@ macro_rules! gen_file_enum {
                 }
             }
 
+            async fn create_with_context(
+                &mut self,
+                ctx: &$crate::server::OpContext<'_>,
+                name: &str,
+                perm: u16,
+                ty: FileType,
+                mode: OpenMode,
+                extension: &str,
+            ) -> $crate::server::FileResult<Self> {
+                match self {
+                    $(
+                        Self::$child(slf) => Ok(Self::$child(
+                            slf.create_with_context(ctx, name, perm, ty, mode, extension).await?
+                        ))
+                    )+
+                }
+            }
+
+            async fn open_with_context(
+                &mut self,
+                ctx: &$crate::server::OpContext<'_>,
+                mode: OpenMode,
+            ) -> $crate::server::FileResult<$open_file_name> {
+                match self {
+                    $(
+                        Self::$child(slf) => Ok($open_file_name::$child(
+                            slf.open_with_context(ctx, mode).await?
+                        ))
+                    )+
+                }
+            }
+
+            async fn readdir(&self) -> $crate::server::FileResult<Vec<$crate::raw::Stat>> {
+                match self {
+                    $(
+                        Self::$child(slf) => slf.readdir().await
+                    )+
+                }
+            }
+
+            async fn readdir_stream(&self) -> $crate::server::FileResult<$crate::server::DirEntries> {
+                match self {
+                    $(
+                        Self::$child(slf) => slf.readdir_stream().await
+                    )+
+                }
+            }
+
+            async fn mkdir(
+                &mut self,
+                name: &str,
+                mode: u32,
+                gid: u32,
+            ) -> $crate::server::FileResult<Qid> {
+                match self {
+                    $(
+                        Self::$child(slf) => slf.mkdir(name, mode, gid).await
+                    )+
+                }
+            }
+
+            async fn unlink_at(&mut self, name: &str, flags: u32) -> $crate::server::FileResult<()> {
+                match self {
+                    $(
+                        Self::$child(slf) => slf.unlink_at(name, flags).await
+                    )+
+                }
+            }
+
+            async fn symlink(
+                &mut self,
+                name: &str,
+                target: &str,
+                gid: u32,
+            ) -> $crate::server::FileResult<Qid> {
+                match self {
+                    $(
+                        Self::$child(slf) => slf.symlink(name, target, gid).await
+                    )+
+                }
+            }
+
+            async fn link(&mut self, target: &Self, name: &str) -> $crate::server::FileResult<()> {
+                #[allow(unreachable_patterns)]
+                match (self, target) {
+                    $(
+                        (Self::$child(slf), Self::$child(target)) => slf.link(target, name).await,
+                    )+
+                    // no linking across filesystems.
+                    _ => Err($crate::server::FileError(18, "EXDEV".to_owned())),
+                }
+            }
+
+            async fn xattr_get(&self, name: &str) -> $crate::server::FileResult<Vec<u8>> {
+                match self {
+                    $(
+                        Self::$child(slf) => slf.xattr_get(name).await
+                    )+
+                }
+            }
+
+            async fn xattr_set(
+                &mut self,
+                name: &str,
+                value: &[u8],
+                flags: u32,
+            ) -> $crate::server::FileResult<()> {
+                match self {
+                    $(
+                        Self::$child(slf) => slf.xattr_set(name, value, flags).await
+                    )+
+                }
+            }
+
             fn qid(&self) -> Qid {
                 match self {
                     $(
@@ -143,6 +265,35 @@ macro_rules! gen_file_enum {
                     )+
                 }
            }
+
+           async fn write_at_with_context(
+               &mut self,
+               ctx: &$crate::server::OpContext<'_>,
+               buf: &mut [u8],
+               off: u64,
+           ) -> $crate::server::FileResult<u32> {
+                match self {
+                    $(
+                        Self::$child(slf) => slf.write_at_with_context(ctx, buf, off).await
+                    )+
+                }
+           }
+
+           async fn stat_hint(&self) -> $crate::server::FileResult<Option<$crate::raw::Stat>> {
+                match self {
+                    $(
+                        Self::$child(slf) => slf.stat_hint().await
+                    )+
+                }
+           }
+
+           async fn sync(&mut self) -> $crate::server::FileResult<()> {
+                match self {
+                    $(
+                        Self::$child(slf) => slf.sync().await
+                    )+
+                }
+           }
         }
 
     };
@@ -20,10 +20,139 @@
 
 use super::{MessageContext, Result};
 use crate::{
-    raw::{FileType, OpenMode, Qid, R, T},
-    server::{File, Filesystem, OpenFile, ServerError, Session},
+    raw::{
+        messages_t::{
+            TYPE_TATTACH, TYPE_TAUTH, TYPE_TCLUNK, TYPE_TCREATE, TYPE_TFLUSH, TYPE_TFSYNC,
+            TYPE_TGETATTR, TYPE_TLINK, TYPE_TMKDIR, TYPE_TOPEN, TYPE_TREAD, TYPE_TREMOVE,
+            TYPE_TSTAT, TYPE_TSTATFS, TYPE_TSYMLINK, TYPE_TUNLINKAT, TYPE_TVERSION, TYPE_TWALK,
+            TYPE_TWRITE, TYPE_TWSTAT, TYPE_TXATTRCREATE, TYPE_TXATTRWALK,
+        },
+        Attr, CreatePerm, Dialect, FileType, IoDirection, OpenMode, Qid, Stat, Type, NOFID, R, T,
+    },
+    server::{
+        state::Xattr, AttachContext, File, FileError, FileHandle, Filesystem, OpContext, OpenFile,
+        ServerError, Session,
+    },
 };
 
+/// Check that a chain of walked qids hangs together: every step but the last
+/// must be a directory (we had to walk through it), and if the walk made it
+/// all the way, the last step must be the file we ended up at.
+fn walk_is_consistent<FileT: File>(qids: &[Qid], file: Option<&FileT>) -> bool {
+    if let Some((_, steps)) = qids.split_last() {
+        if steps.iter().any(|qid| qid.ty != FileType::Dir) {
+            return false;
+        }
+    }
+
+    match (file, qids.last()) {
+        (Some(file), Some(last)) => file.qid() == *last,
+        _ => true,
+    }
+}
+
+/// Stat of the file behind `handle`: the one its Tattach set aside, or
+/// whatever its open file knows, before asking the File itself.
+async fn stat_of<FileT: File + Send>(handle: &mut FileHandle<FileT>) -> Result<Stat> {
+    if let Some(stat) = handle.stat.take() {
+        return Ok(stat);
+    }
+    if let Some(of) = &handle.of {
+        if let Some(stat) = of.stat_hint().await? {
+            return Ok(stat);
+        }
+    }
+    Ok(handle.file.stat().await?)
+}
+
+/// Check if `ty` is a 9P2000.L message type, which is only answered over a
+/// connection which negotiated 9P2000.L.
+pub(crate) fn linux_only(ty: Type) -> bool {
+    matches!(
+        ty,
+        TYPE_TMKDIR
+            | TYPE_TUNLINKAT
+            | TYPE_TSYMLINK
+            | TYPE_TFSYNC
+            | TYPE_TLINK
+            | TYPE_TSTATFS
+            | TYPE_TGETATTR
+            | TYPE_TXATTRWALK
+            | TYPE_TXATTRCREATE
+    )
+}
+
+/// Check if handling `t` could change the Filesystem, which a read-only
+/// server refuses to do. Tremove is left to its handler, since the fid is
+/// clunked even when the remove is refused.
+fn mutates(t: &T) -> bool {
+    match t {
+        T::Open(_, _, mode) => {
+            !matches!(mode.direction(), IoDirection::Read) || mode.truncate() || mode.remove()
+        }
+        T::Create(..)
+        | T::Write(..)
+        | T::WStat(..)
+        | T::Mkdir(..)
+        | T::UnlinkAt(..)
+        | T::Symlink(..)
+        | T::Link(..)
+        | T::XattrCreate(..) => true,
+        _ => false,
+    }
+}
+
+/// iounit to report for a file which advertised `advertised`: `max`, the
+/// default for the connection, if the file has no preference (0), and never
+/// more than that, so that a whole Rread or Twrite of one iounit always fits.
+fn iounit(advertised: u32, max: u32) -> u32 {
+    match advertised {
+        0 => max,
+        advertised => advertised.min(max),
+    }
+}
+
+/// Trim the qids of a walk of `walked` elements down to the `requested`
+/// elements the client actually asked for, in case a
+/// [crate::server::PathPolicy] added a prefix to the path.
+fn client_qids(mut qids: Vec<Qid>, walked: usize, requested: usize) -> Vec<Qid> {
+    let prefix = walked.saturating_sub(requested).min(qids.len());
+    qids.drain(..prefix);
+    qids
+}
+
+/// Largest extended attribute a Txattrcreate may set, as Linux caps them.
+const XATTR_SIZE_MAX: u64 = 65536;
+
+/// T message types which [message_handler] actually implements, rather than
+/// replying with an error. Keep this in sync with the match below. Tauth
+/// needs an [crate::server::Authenticator], and the 9P2000.L messages (see
+/// [linux_only]) need 9P2000.L to have been offered.
+pub(crate) const SUPPORTED_MESSAGES: &[Type] = &[
+    TYPE_TVERSION,
+    TYPE_TAUTH,
+    TYPE_TATTACH,
+    TYPE_TFLUSH,
+    TYPE_TWALK,
+    TYPE_TOPEN,
+    TYPE_TCREATE,
+    TYPE_TREAD,
+    TYPE_TWRITE,
+    TYPE_TCLUNK,
+    TYPE_TREMOVE,
+    TYPE_TSTAT,
+    TYPE_TWSTAT,
+    TYPE_TMKDIR,
+    TYPE_TUNLINKAT,
+    TYPE_TSYMLINK,
+    TYPE_TFSYNC,
+    TYPE_TLINK,
+    TYPE_TSTATFS,
+    TYPE_TGETATTR,
+    TYPE_TXATTRWALK,
+    TYPE_TXATTRCREATE,
+];
+
 /// common method to handle the processing of an incoming message of type T (9p
 /// T type), returning an R type (9p R type).
 pub async fn message_handler<FilesystemT>(mctx: MessageContext<'_, FilesystemT>, t: T) -> Result<R>
@@ -32,13 +161,34 @@ where
     FilesystemT: Send,
     FilesystemT: 'static,
 {
+    let dialect = mctx.dialect();
+    let max_iounit = mctx.iounit();
     let MessageContext {
         peer,
         msize,
+        version,
         handles,
-        requests,
         filesystems,
+        router,
+        options,
+        pool,
     } = mctx;
+    let clock = options.clock();
+
+    if linux_only(t.ty()) && dialect != Dialect::Linux {
+        let tag = t.tag();
+        tracing::warn!("9P2000.L message from {peer} over {version}; tag={tag}");
+        return Ok(R::Error(tag, "ENOSYS".to_owned(), 38));
+    }
+
+    // writes to an afid carry on the authentication conversation, and
+    // leave every Filesystem be.
+    let auth_write = matches!(t, T::Write(_, fid, ..) if handles.get_auth_mut(fid).is_some());
+    if options.read_only && mutates(&t) && !auth_write {
+        let tag = t.tag();
+        tracing::debug!("refusing to modify a read-only server (peer={peer}, tag={tag})");
+        return Ok(R::Error(tag, "EROFS".to_owned(), 30));
+    }
 
     match t {
         T::Version(tag, _, _) => {
@@ -47,39 +197,90 @@ where
             );
             Ok(R::Error(tag, "EALREADY".to_owned(), 114))
         }
-        T::Auth(tag, _, _, _, _) => {
-            tracing::debug!("auth request (peer={peer}, tag={tag})");
-            Ok(R::Error(tag, "ECONNREFUSED".to_owned(), 111))
+        T::Auth(tag, afid, uname, aname, nuname) => {
+            tracing::debug!(
+                "auth request (peer={peer}, tag={tag}, afid={afid}, uname={uname}, aname={aname})"
+            );
+            let Some(authenticator) = &options.authenticator else {
+                return Ok(R::Error(tag, "ECONNREFUSED".to_owned(), 111));
+            };
+            if afid == NOFID {
+                return Ok(R::Error(tag, "EBADF".to_owned(), 9));
+            }
+            let afile = authenticator.auth(&uname, &aname, nuname).await?;
+            let qid = afile.qid();
+            handles.insert_auth(afid, afile)?;
+            Ok(R::Auth(tag, qid))
         }
-        T::Attach(tag, fid, _afid, uname, aname, nuname) => {
+        T::Attach(tag, fid, afid, uname, aname, nuname) => {
             tracing::debug!(
-                "attach request (peer={peer}, tag={tag}, fid={fid}, uname={uname}, aname={aname}, nuname={nuname})"
+                "attach request (peer={peer}, tag={tag}, fid={fid}, afid={afid}, uname={uname}, aname={aname}, nuname={nuname})"
             );
 
+            if let Some(authenticator) = &options.authenticator {
+                let Some(afile) = handles.get_auth_mut(afid) else {
+                    tracing::debug!("attach request (peer={peer}, tag={tag}) without an afid");
+                    return Ok(R::Error(tag, "EACCES".to_owned(), 13));
+                };
+                authenticator.check(afile, &uname, &aname).await?;
+            }
+
+            let (uname, nuname) = match (options.peer_cred_identity, peer.cred()) {
+                (true, Some(cred)) => (cred.uid.to_string(), cred.uid),
+                (true, None) => {
+                    // with nobody to vouch for the peer, the uname it sent
+                    // counts for nothing.
+                    tracing::warn!("attach request (peer={peer}, tag={tag}) without credentials");
+                    return Ok(R::Error(tag, "EACCES".to_owned(), 13));
+                }
+                (false, _) => (uname, nuname),
+            };
+
+            let routed = router.and_then(|route| route(&uname, &aname));
             let filesystems = filesystems.lock().await;
-            let fs = match filesystems.get(&aname) {
-                Some(fs) => fs,
-                None => return Err(ServerError::NoSuchFilesystem),
+            let (filesystem, max_read) = match (&routed, filesystems.get(&aname)) {
+                (Some(filesystem), _) => (filesystem, None),
+                (None, Some(mount)) => (&mount.filesystem, mount.max_read),
+                (None, None) => return Err(ServerError::NoSuchFilesystem),
+            };
+
+            let actx = AttachContext {
+                aname: &aname,
+                uname: &uname,
+                nuname,
+                peer,
+                msize,
+                version,
+                clock: &*clock,
             };
-            let file = fs.attach(&uname, &aname, nuname).await?;
+            let (file, stat) = filesystem.attach_with_stat(&actx).await?;
             let qid = file.qid();
-            let session = Session::new(uname.clone(), aname.clone());
+            let session = Session::new(uname.clone(), aname.clone()).with_max_read(max_read);
             handles.insert(fid, session, file)?;
+            handles.get_mut(fid)?.stat = stat;
             Ok(R::Attach(tag, qid))
         }
         T::Flush(tag, oldtag) => {
+            // connection_handler answers a Tflush itself, as it is the one
+            // with the requests in flight to cancel; on its own, a request
+            // has nothing to flush.
             tracing::debug!("flush request (peer={peer}, tag={tag}, oldtag={oldtag})");
-            if let Ok(req) = requests.remove(oldtag) {
-                tracing::debug!(
-                    "  flush (peer={peer}, tag={tag}, oldtag={oldtag}, t={:?})",
-                    req.t
-                );
-            }
-
             Ok(R::Flush(tag))
         }
         T::Walk(tag, fid, newfid, path) => {
             tracing::debug!("walk request (peer={peer}, tag={tag} from fid={fid}, store to newfid={newfid}, path={path:?})");
+            if path.len() > dialect.limits().max_walk_elements {
+                tracing::warn!(
+                    "walk request (peer={peer}, tag={tag}) of {} elements is over MAXWELEM",
+                    path.len()
+                );
+                return Ok(R::Error(tag, "EINVAL".to_owned(), 22));
+            }
+            if newfid == fid && path.is_empty() {
+                // walking a fid onto itself, nowhere at all.
+                handles.get(fid)?;
+                return Ok(R::Walk(tag, vec![]));
+            }
             {
                 let handle = handles.get(fid)?;
                 let session = handle.session.clone();
@@ -90,46 +291,96 @@ where
                     session.uname
                 );
 
+                let requested = path.len();
+                let path = match &options.path_policy {
+                    Some(policy) => policy.walk(&path)?,
+                    None => path,
+                };
                 let path: Vec<&str> = path.iter().map(|x| x.as_ref()).collect();
-                let (file, files) = handle.file.walk(path.as_slice()).await?;
+                // only the first element can be asked about, since the
+                // directories holding the rest aren't there until walked.
+                let exists = match path.first() {
+                    Some(first) => handle.file.exists_child(first).await?,
+                    None => true,
+                };
+                // we can't hold on to the handle over the await above.
+                let handle = handles.get(fid)?;
+                let (file, files) = match exists {
+                    true => handle.file.walk(path.as_slice()).await?,
+                    false => (Err(FileError(2, "ENOENT".to_owned())), vec![]),
+                };
+                if files.len() > path.len() {
+                    tracing::warn!(
+                        "walk returned {} files for {} path elements",
+                        files.len(),
+                        path.len()
+                    );
+                    return Ok(R::Error(tag, "EINVAL".to_owned(), 22));
+                }
                 let qids: Vec<Qid> = files.iter().map(|x| x.qid()).collect();
 
+                if options.validate_walk && !walk_is_consistent(&qids, file.as_ref().ok()) {
+                    tracing::warn!("walk returned an inconsistent qid chain: {qids:?}");
+                    return Ok(R::Error(tag, "EINVAL".to_owned(), 22));
+                }
+
                 match file {
-                    None => {
+                    Err(err) => {
                         // failed to walk to the file
                         tracing::warn!(
-                            "walk failed! file len={} path len={}",
+                            "walk failed! file len={} path len={} error={:?}",
                             files.len(),
-                            path.len()
+                            path.len(),
+                            err
                         );
 
-                        if files.len() == path.len() {
-                            return Ok(R::Error(tag, "ENOENT".to_owned(), 2));
-                        } else {
-                            return Ok(R::Walk(tag, qids));
+                        // the client can only tell a partial walk apart from a
+                        // complete one if it's short.
+                        if files.is_empty() || files.len() == path.len() || options.strict_walk {
+                            return Err(err.into());
                         }
+                        // nor if it stopped in a prefix the client never
+                        // asked for, which leaves it nothing to go on.
+                        let qids = client_qids(qids, path.len(), requested);
+                        if qids.is_empty() && requested > 0 {
+                            return Err(err.into());
+                        }
+                        return Ok(R::Walk(tag, qids));
                     }
-                    Some(file) => {
+                    Ok(file) => {
                         if files.len() != path.len() {
                             tracing::warn!("walk failed but was reported as a success!");
                             return Ok(R::Error(tag, "EINVAL".to_owned(), 22));
                         }
                         tracing::info!("target {:?} is now newfid {}", file.qid(), newfid);
-                        handles.insert(newfid, session, file)?;
+                        if newfid == fid {
+                            // the fid is walked in place, keeping its session.
+                            let handle = handles.get_mut(fid)?;
+                            handle.file = file;
+                            handle.stat = None;
+                        } else {
+                            handles.insert(newfid, session, file)?;
+                        }
                     }
                 }
 
-                Ok(R::Walk(tag, qids))
+                Ok(R::Walk(tag, client_qids(qids, path.len(), requested)))
             }
         }
         T::Open(tag, fid, mode) => {
             tracing::debug!("open request (peer={peer}, tag={tag}, fid={fid}, mode={mode:?})");
             let handle = handles.get_mut(fid)?;
 
+            let ctx = OpContext {
+                session: &handle.session,
+                peer,
+                dialect,
+                clock: &*clock,
+            };
             let file = &mut handle.file;
-            let of = file.open(mode).await?;
+            let of = file.open_with_context(&ctx, mode).await?;
 
-            let iounit = of.iounit();
+            let iounit = iounit(of.iounit(), max_iounit);
             let qid = file.qid();
             handle.of = Some(of);
 
@@ -139,34 +390,69 @@ where
             tracing::debug!("create request (peer={peer}, tag={tag}, fid={fid}, name={name})");
 
             let handle = handles.get_mut(fid)?;
+            let ctx = OpContext {
+                session: &handle.session,
+                peer,
+                dialect,
+                clock: &*clock,
+            };
             let file = &mut handle.file;
 
             let mode: OpenMode = mode.into();
-            let ty: FileType = perm.into();
-            let perm: u16 = (perm & 0o777) as u16;
+            let perm = CreatePerm::from(perm);
+            let ty = perm.file_type();
+            let perm = perm.unix_mode();
 
             tracing::debug!("  tag={tag}, name={name}, ty={ty:?}, mode={mode:?}, perm={perm})");
 
-            let mut f = file.create(&name, perm, ty, mode, &extension).await?;
-            let of = f.open(mode).await?;
+            let mut f = file
+                .create_with_context(&ctx, &name, perm, ty, mode, &extension)
+                .await?;
+            let of = f.open_with_context(&ctx, mode).await?;
+            let iounit = iounit(of.iounit(), max_iounit);
             handle.of = Some(of);
 
-            Ok(R::Create(tag, f.qid(), 0))
+            Ok(R::Create(tag, f.qid(), iounit))
         }
         T::Read(tag, fid, offset, size) => {
             tracing::debug!(
                 "read request (peer={peer}, tag={tag}, fid={fid}, offset={offset}, size={size})"
             );
+            if let Some(afile) = handles.get_auth_mut(fid) {
+                let mut buf = vec![0; size.min(max_iounit) as usize];
+                let n = afile.read(&mut buf).await?;
+                buf.truncate(n as usize);
+                return Ok(R::Read(tag, buf));
+            }
             let handle = handles.get_mut(fid)?;
+            match &handle.xattr {
+                Some(Xattr::Read(value)) => {
+                    let start = offset.min(value.len() as u64) as usize;
+                    let n = (value.len() - start).min(size.min(max_iounit) as usize);
+                    return Ok(R::Read(tag, value[start..start + n].to_vec()));
+                }
+                Some(Xattr::Write { .. }) => return Ok(R::Error(tag, "EBADFD".to_owned(), 77)),
+                None => {}
+            }
 
-            // msize here is wrong, buttttt, fine. This is just to cap
-            // the upper bound not prevent errors from broken client
-            // requests :)
-            let mut buf = vec![0u8; size.min(msize) as usize];
+            let size = size.min(max_iounit);
+            let size = match handle.session.max_read {
+                Some(max_read) => size.min(max_read),
+                None => size,
+            };
             match &mut handle.of {
                 Some(ref mut of) => {
-                    let n = of.read_at(&mut buf, offset).await? as usize;
-                    buf.resize(n, 0u8);
+                    // the buffer goes back to the pool once the reply has
+                    // been sent.
+                    let mut buf = pool.take(size as usize);
+                    let n = match of.read_at(&mut buf, offset).await {
+                        Ok(n) => n as usize,
+                        Err(e) => {
+                            pool.give(buf);
+                            return Err(e.into());
+                        }
+                    };
+                    buf.truncate(n);
                     Ok(R::Read(tag, buf))
                 }
                 None => Ok(R::Error(tag, "EBADFD".to_owned(), 77)),
@@ -177,11 +463,37 @@ where
                 "write request (peer={peer}, tag={tag}, fid={fid}, offset={offset}, size={})",
                 buf.len(),
             );
+            if let Some(afile) = handles.get_auth_mut(fid) {
+                let n = afile.write(&buf).await?;
+                return Ok(R::Write(tag, n));
+            }
             let handle = handles.get_mut(fid)?;
+            match &mut handle.xattr {
+                Some(Xattr::Write { size, value, .. }) => {
+                    let end = offset.saturating_add(buf.len() as u64);
+                    if end > *size {
+                        return Ok(R::Error(tag, "ENOSPC".to_owned(), 28));
+                    }
+                    let (start, end) = (offset as usize, end as usize);
+                    if value.len() < end {
+                        value.resize(end, 0);
+                    }
+                    value[start..end].copy_from_slice(&buf);
+                    return Ok(R::Write(tag, buf.len() as u32));
+                }
+                Some(Xattr::Read(_)) => return Ok(R::Error(tag, "EBADFD".to_owned(), 77)),
+                None => {}
+            }
+            let ctx = OpContext {
+                session: &handle.session,
+                peer,
+                dialect,
+                clock: &*clock,
+            };
 
             match &mut handle.of {
                 Some(ref mut of) => {
-                    let n = of.write_at(&mut buf, offset).await?;
+                    let n = of.write_at_with_context(&ctx, &mut buf, offset).await?;
                     Ok(R::Write(tag, n))
                 }
                 None => Ok(R::Error(tag, "EBADFD".to_owned(), 77)),
@@ -189,27 +501,152 @@ where
         }
         T::Clunk(tag, fid) => {
             tracing::debug!("clunk request (peer={peer}, tag={tag}, fid={fid})");
-            let _handle = handles.remove(fid)?;
+            if handles.remove_auth(fid).is_some() {
+                return Ok(R::Clunk(tag));
+            }
+            let mut handle = handles.remove(fid)?;
+            if let Some(Xattr::Write {
+                name,
+                size,
+                flags,
+                value,
+            }) = handle.xattr.take()
+            {
+                // the fid is gone either way, but the extended attribute is
+                // only set if the client wrote every byte it said it would.
+                if value.len() as u64 != size {
+                    return Ok(R::Error(tag, "EINVAL".to_owned(), 22));
+                }
+                handle.file.xattr_set(&name, &value, flags).await?;
+            }
             Ok(R::Clunk(tag))
         }
         T::Remove(tag, fid) => {
             tracing::debug!("remove request (peer={peer}, tag={tag}, fid={fid})");
             let mut handle = handles.remove(fid)?;
+            if options.read_only {
+                return Ok(R::Error(tag, "EROFS".to_owned(), 30));
+            }
             handle.file.unlink().await?;
             Ok(R::Remove(tag))
         }
         T::Stat(tag, fid) => {
             tracing::debug!("stat request (peer={peer}, tag={tag}, fid={fid})");
-            let handle = handles.get(fid)?;
-            let stat = handle.file.stat().await?;
+            let stat = stat_of(handles.get_mut(fid)?).await?;
             Ok(R::Stat(tag, stat))
         }
+        T::GetAttr(tag, fid, mask) => {
+            tracing::debug!("getattr request (peer={peer}, tag={tag}, fid={fid}, mask={mask:#x})");
+            let stat = stat_of(handles.get_mut(fid)?).await?;
+            Ok(R::GetAttr(tag, Attr::from(&stat)))
+        }
         T::WStat(tag, fid, stat) => {
             tracing::debug!("wstat request (peer={peer}, tag={tag}, fid={fid}, stat={stat:?})");
             let handle = handles.get_mut(fid)?;
+            // A wstat that touches nothing asks for the file to be committed
+            // to stable storage.
+            if stat.is_dont_touch() {
+                if let Some(of) = &mut handle.of {
+                    of.sync().await?;
+                    return Ok(R::WStat(tag));
+                }
+            }
+            handle.stat = None;
             handle.file.wstat(&stat).await?;
             Ok(R::WStat(tag))
         }
+        T::Mkdir(tag, fid, name, mode, gid) => {
+            tracing::debug!("mkdir request (peer={peer}, tag={tag}, fid={fid}, name={name}, mode={mode:o}, gid={gid})");
+            let handle = handles.get_mut(fid)?;
+            let qid = handle.file.mkdir(&name, mode, gid).await?;
+            Ok(R::Mkdir(tag, qid))
+        }
+        T::UnlinkAt(tag, fid, name, flags) => {
+            tracing::debug!(
+                "unlinkat request (peer={peer}, tag={tag}, fid={fid}, name={name}, flags={flags:#x})"
+            );
+            let handle = handles.get_mut(fid)?;
+            handle.file.unlink_at(&name, flags).await?;
+            Ok(R::UnlinkAt(tag))
+        }
+        T::Symlink(tag, fid, name, target, gid) => {
+            tracing::debug!("symlink request (peer={peer}, tag={tag}, fid={fid}, name={name}, target={target}, gid={gid})");
+            let handle = handles.get_mut(fid)?;
+            let qid = handle.file.symlink(&name, &target, gid).await?;
+            Ok(R::Symlink(tag, qid))
+        }
+        T::Fsync(tag, fid, datasync) => {
+            tracing::debug!(
+                "fsync request (peer={peer}, tag={tag}, fid={fid}, datasync={datasync})"
+            );
+            let handle = handles.get_mut(fid)?;
+            match &mut handle.of {
+                Some(ref mut of) => {
+                    of.sync().await?;
+                    Ok(R::Fsync(tag))
+                }
+                None => Ok(R::Error(tag, "EBADFD".to_owned(), 77)),
+            }
+        }
+        T::Link(tag, dfid, fid, name) => {
+            tracing::debug!(
+                "link request (peer={peer}, tag={tag}, dfid={dfid}, fid={fid}, name={name})"
+            );
+            if dfid == fid {
+                // a directory can't be hard linked, let alone into itself.
+                return Ok(R::Error(tag, "EPERM".to_owned(), 1));
+            }
+            let link = {
+                let (dir, target) = handles.get_pair_mut(dfid, fid)?;
+                dir.file.link(&target.file, &name)
+            };
+            link.await?;
+            Ok(R::Link(tag))
+        }
+        T::StatFs(tag, fid) => {
+            tracing::debug!("statfs request (peer={peer}, tag={tag}, fid={fid})");
+            let session = handles.get(fid)?.session.clone();
+
+            // the fid doesn't know which Filesystem it came from, but its
+            // Session does, just as the Tattach found it.
+            let routed = router.and_then(|route| route(&session.uname, &session.aname));
+            let filesystems = filesystems.lock().await;
+            let filesystem = match (&routed, filesystems.get(&session.aname)) {
+                (Some(filesystem), _) => filesystem,
+                (None, Some(mount)) => &mount.filesystem,
+                (None, None) => return Err(ServerError::NoSuchFilesystem),
+            };
+            Ok(R::StatFs(tag, filesystem.statfs().await?))
+        }
+        T::XattrWalk(tag, fid, newfid, name) => {
+            tracing::debug!("xattrwalk request (peer={peer}, tag={tag}, fid={fid}, newfid={newfid}, name={name})");
+            let handle = handles.get(fid)?;
+            let session = handle.session.clone();
+            let value = handle.file.xattr_get(&name).await?;
+
+            // the new fid is a clone of the old one, which reads back the
+            // extended attribute rather than the File.
+            let handle = handles.get(fid)?;
+            let (file, _) = handle.file.walk(&[]).await?;
+            let size = value.len() as u64;
+            handles.insert(newfid, session, file?)?;
+            handles.get_mut(newfid)?.xattr = Some(Xattr::Read(value));
+            Ok(R::XattrWalk(tag, size))
+        }
+        T::XattrCreate(tag, fid, name, size, flags) => {
+            tracing::debug!("xattrcreate request (peer={peer}, tag={tag}, fid={fid}, name={name}, size={size}, flags={flags:#x})");
+            if size > XATTR_SIZE_MAX {
+                return Ok(R::Error(tag, "E2BIG".to_owned(), 7));
+            }
+            let handle = handles.get_mut(fid)?;
+            handle.xattr = Some(Xattr::Write {
+                name,
+                size,
+                flags,
+                value: vec![],
+            });
+            Ok(R::XattrCreate(tag))
+        }
         T::Unknown(ty, tag, _) => {
             tracing::warn!("unknown message from {peer}; ty={ty}, tag={tag}");
             Ok(R::Error(tag, "ENOSYS".to_owned(), 38))
@@ -217,4 +654,1380 @@ where
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::raw::{FileType, Qid, Stat, StatFs, GETATTR_ALL, GETATTR_BASIC};
+    use crate::{
+        fs::{create_dir_all, MemFile, MemFilesystem},
+        raw::{IOHDRSZ, NOFID, R, T},
+        server::{
+            async_server::{Context, Mount, Mounts, Options, Router},
+            testing::{block_on, mount, mounts, ScriptedFs, TestConnection, TestFile, TestFs},
+            AttachContext, AuthFile, AuthFuture, Authenticator, File, FileError, FileResult,
+            Filesystem, FilesystemResult, MockClock, PathPolicy, Peer,
+        },
+    };
+    use std::{
+        any::Any,
+        sync::Arc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    /// Connection to a server which offers 9P2000.L, that settles on
+    /// 9P2000.u instead. Offering it doesn't make a 9P2000.L message any
+    /// less of one, so they're all refused with ENOSYS.
+    async fn dotu<FilesystemT>(mounts: Mounts<FilesystemT>) -> TestConnection
+    where
+        FilesystemT: Filesystem,
+        FilesystemT: Send,
+        FilesystemT: 'static,
+    {
+        let mut conn = TestConnection::serve_linux(8192, mounts);
+        conn.attach(8192, 1, "").await;
+        conn
+    }
+
+    fn bogus_walk() -> ScriptedFs {
+        ScriptedFs::new(|_| {
+            // "a" is a regular file, but was walked through.
+            Ok((
+                Ok(Qid::new(FileType::File, 0, 3)),
+                vec![
+                    Qid::new(FileType::File, 0, 2),
+                    Qid::new(FileType::File, 0, 3),
+                ],
+            ))
+        })
+    }
+
+    /// AuthFile which takes a password, written to it by the client.
+    struct Password {
+        uname: String,
+        written: Vec<u8>,
+    }
+
+    impl AuthFile for Password {
+        fn qid(&self) -> Qid {
+            Qid::new(FileType::Auth, 0, 0)
+        }
+
+        fn read<'a>(&'a mut self, _: &'a mut [u8]) -> AuthFuture<'a, u32> {
+            Box::pin(async { Ok(0) })
+        }
+
+        fn write<'a>(&'a mut self, buf: &'a [u8]) -> AuthFuture<'a, u32> {
+            self.written.extend_from_slice(buf);
+            Box::pin(async move { Ok(buf.len() as u32) })
+        }
+    }
+
+    /// Authenticator which lets in anyone who knows the password.
+    struct SharedSecret(&'static [u8]);
+
+    impl Authenticator for SharedSecret {
+        fn auth<'a>(
+            &'a self,
+            uname: &'a str,
+            _: &'a str,
+            _: u32,
+        ) -> AuthFuture<'a, Box<dyn AuthFile>> {
+            let afile = Password {
+                uname: uname.to_owned(),
+                written: vec![],
+            };
+            Box::pin(async { Ok(Box::new(afile) as Box<dyn AuthFile>) })
+        }
+
+        fn check<'a>(
+            &'a self,
+            afile: &'a dyn AuthFile,
+            uname: &'a str,
+            _: &'a str,
+        ) -> AuthFuture<'a, ()> {
+            let afile = (afile as &dyn Any).downcast_ref::<Password>().unwrap();
+            let ok = afile.uname == uname && afile.written == self.0;
+            Box::pin(async move {
+                match ok {
+                    true => Ok(()),
+                    false => Err(FileError(13, "EACCES".to_owned())),
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn authenticate() {
+        block_on(async {
+            let options = Options {
+                authenticator: Some(Arc::new(SharedSecret(b"hunter2"))),
+                ..Default::default()
+            };
+            let mut conn = TestConnection::serve_one(TestFs::new(&[]), options);
+            conn.version(8192).await;
+            let attach = |tag, fid, afid, uname: &str| {
+                T::Attach(tag, fid, afid, uname.to_owned(), "".to_owned(), 0)
+            };
+
+            // attaching without authenticating is refused...
+            let r = conn.rpc(attach(1, 1, NOFID, "user")).await;
+            assert_eq!(R::Error(1, "EACCES".to_owned(), 13), r);
+
+            // ...as is attaching before proving anything...
+            let r = conn
+                .rpc(T::Auth(2, 10, "user".to_owned(), "".to_owned(), 0))
+                .await;
+            assert_eq!(R::Auth(2, Qid::new(FileType::Auth, 0, 0)), r);
+            let r = conn.rpc(attach(3, 1, 10, "user")).await;
+            assert_eq!(R::Error(3, "EACCES".to_owned(), 13), r);
+
+            // ...but with the password, the attach goes through, and only
+            // for the user who authenticated.
+            let r = conn.rpc(T::Write(4, 10, 0, b"hunter2".to_vec())).await;
+            assert_eq!(R::Write(4, 7), r);
+            let r = conn.rpc(attach(5, 1, 10, "mallory")).await;
+            assert_eq!(R::Error(5, "EACCES".to_owned(), 13), r);
+            let r = conn.rpc(attach(6, 1, 10, "user")).await;
+            assert!(matches!(r, R::Attach(6, _)), "{:?}", r);
+
+            // the afid is clunked like any other fid.
+            assert_eq!(R::Clunk(7), conn.rpc(T::Clunk(7, 10)).await);
+            let r = conn.rpc(T::Clunk(8, 10)).await;
+            assert_eq!(R::Error(8, "EBADF".to_owned(), 9), r);
+        });
+    }
+
+    #[test]
+    fn auth_refused_by_default() {
+        block_on(async {
+            let fs = TestFs::new(&[]);
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(fs))]));
+            conn.version(8192).await;
+            let r = conn
+                .rpc(T::Auth(1, 10, "user".to_owned(), "".to_owned(), 0))
+                .await;
+            assert_eq!(R::Error(1, "ECONNREFUSED".to_owned(), 111), r);
+        });
+    }
+
+    #[test]
+    fn walk_strict() {
+        block_on(async {
+            // "a" exists, but "missing" does not.
+            let short_walk = || {
+                ScriptedFs::new(|_| {
+                    Ok((
+                        Err(FileError(2, "ENOENT".to_owned())),
+                        vec![Qid::new(FileType::Dir, 0, 2)],
+                    ))
+                })
+            };
+            let path = vec!["a".to_owned(), "missing".to_owned()];
+
+            // by default, the client gets the partial chain.
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(short_walk()))]));
+            conn.attach(8192, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, path.clone())).await;
+            assert_eq!(R::Walk(2, vec![Qid::new(FileType::Dir, 0, 2)]), r);
+
+            let mut conn = TestConnection::serve_one(
+                short_walk(),
+                Options {
+                    strict_walk: true,
+                    ..Default::default()
+                },
+            );
+            conn.attach(8192, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, path.clone())).await;
+            assert_eq!(R::Error(2, "ENOENT".to_owned(), 2), r);
+        });
+    }
+
+    /// "a" is a directory, "secret" may not be searched, and nothing else
+    /// exists.
+    fn guarded_walk() -> ScriptedFs {
+        ScriptedFs::new(|path| {
+            let mut qids = vec![];
+            for name in path {
+                match *name {
+                    "a" => qids.push(Qid::new(FileType::Dir, 0, 2)),
+                    "secret" => return Ok((Err(FileError(13, "EACCES".to_owned())), qids)),
+                    _ => return Ok((Err(FileError(2, "ENOENT".to_owned())), qids)),
+                }
+            }
+            Ok((
+                qids.last()
+                    .cloned()
+                    .ok_or(FileError(22, "EINVAL".to_owned())),
+                qids,
+            ))
+        })
+    }
+
+    #[test]
+    fn walk_maxwelem() {
+        block_on(async {
+            // every element is another directory down.
+            let fs = ScriptedFs::new(|path| {
+                let qids: Vec<Qid> = (0..path.len())
+                    .map(|depth| Qid::new(FileType::Dir, 0, depth as u64 + 2))
+                    .collect();
+                Ok((
+                    qids.last()
+                        .cloned()
+                        .ok_or(FileError(22, "EINVAL".to_owned())),
+                    qids,
+                ))
+            });
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(fs))]));
+            conn.attach(8192, 1, "").await;
+
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["a".to_owned(); 16])).await;
+            assert!(
+                matches!(r, R::Walk(2, ref qids) if qids.len() == 16),
+                "{:?}",
+                r
+            );
+            let r = conn.rpc(T::Walk(3, 1, 3, vec!["a".to_owned(); 17])).await;
+            assert_eq!(R::Error(3, "EINVAL".to_owned(), 22), r);
+        });
+    }
+
+    #[test]
+    fn walk_errors() {
+        block_on(async {
+            let walk = |tag, path: &[&str]| {
+                T::Walk(tag, 1, 2, path.iter().map(|x| x.to_string()).collect())
+            };
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(guarded_walk()))]));
+            conn.attach(8192, 1, "").await;
+
+            // no progress at all, so the client hears why.
+            let r = conn.rpc(walk(2, &["missing"])).await;
+            assert_eq!(R::Error(2, "ENOENT".to_owned(), 2), r);
+            let r = conn.rpc(walk(3, &["secret"])).await;
+            assert_eq!(R::Error(3, "EACCES".to_owned(), 13), r);
+
+            // otherwise, it gets as far as the walk went.
+            let r = conn.rpc(walk(4, &["a", "secret"])).await;
+            assert_eq!(R::Walk(4, vec![Qid::new(FileType::Dir, 0, 2)]), r);
+            let r = conn.rpc(walk(5, &["a", "missing"])).await;
+            assert_eq!(R::Walk(5, vec![Qid::new(FileType::Dir, 0, 2)]), r);
+
+            // unless the server is being strict about it.
+            let mut conn = TestConnection::serve_one(
+                guarded_walk(),
+                Options {
+                    strict_walk: true,
+                    ..Default::default()
+                },
+            );
+            conn.attach(8192, 1, "").await;
+            let r = conn.rpc(walk(6, &["a", "secret"])).await;
+            assert_eq!(R::Error(6, "EACCES".to_owned(), 13), r);
+        });
+    }
+
+    /// Only `public/...` may be walked, and it's found under `srv/export`.
+    struct PublicOnly;
+
+    impl PathPolicy for PublicOnly {
+        fn walk(&self, path: &[String]) -> FileResult<Vec<String>> {
+            match path.split_first() {
+                None => Ok(vec![]),
+                Some((first, rest)) if first == "public" => {
+                    let mut path = vec!["srv".to_owned(), "export".to_owned()];
+                    path.extend_from_slice(rest);
+                    Ok(path)
+                }
+                Some(_) => Err(FileError(13, "EACCES".to_owned())),
+            }
+        }
+    }
+
+    #[test]
+    fn walk_path_policy() {
+        block_on(async {
+            let fs = MemFilesystem::new();
+            let root = fs.attach("", "", 0).await.unwrap();
+            let (_, qids) = create_dir_all(&root, &["srv", "export", "docs"], 0o755)
+                .await
+                .unwrap();
+
+            let mut conn = TestConnection::serve_one(
+                fs,
+                Options {
+                    path_policy: Some(Arc::new(PublicOnly)),
+                    ..Default::default()
+                },
+            );
+            conn.attach(8192, 1, "").await;
+
+            // one qid per element the client asked for.
+            let path = vec!["public".to_owned(), "docs".to_owned()];
+            let r = conn.rpc(T::Walk(2, 1, 2, path)).await;
+            assert_eq!(R::Walk(2, qids[1..].to_vec()), r);
+            match conn.rpc(T::Stat(3, 2)).await {
+                R::Stat(3, stat) => assert_eq!("docs", stat.name),
+                r => panic!("unexpected reply {:?}", r),
+            }
+
+            let r = conn.rpc(T::Walk(4, 1, 3, vec!["srv".to_owned()])).await;
+            assert_eq!(R::Error(4, "EACCES".to_owned(), 13), r);
+            assert_eq!(R::Walk(5, vec![]), conn.rpc(T::Walk(5, 1, 3, vec![])).await);
+        });
+    }
+
+    #[test]
+    fn walk_path_policy_stops_in_prefix() {
+        block_on(async {
+            let fs = MemFilesystem::new();
+            let root = fs.attach("", "", 0).await.unwrap();
+            create_dir_all(&root, &["srv"], 0o755).await.unwrap();
+
+            let mut conn = TestConnection::serve_one(
+                fs,
+                Options {
+                    path_policy: Some(Arc::new(PublicOnly)),
+                    ..Default::default()
+                },
+            );
+            conn.attach(8192, 1, "").await;
+
+            // the walk gets as far as srv, which the client never asked
+            // for, so all it's told is why it went no further.
+            let path = vec!["public".to_owned(), "docs".to_owned()];
+            let r = conn.rpc(T::Walk(2, 1, 2, path)).await;
+            assert_eq!(R::Error(2, "ENOENT".to_owned(), 2), r);
+            let r = conn.rpc(T::Stat(3, 2)).await;
+            assert_eq!(R::Error(3, "EBADF".to_owned(), 9), r);
+        });
+    }
+
+    #[test]
+    fn walk_exists_fast_path() {
+        block_on(async {
+            let missing = vec!["missing".to_owned(), "a".to_owned(), "b".to_owned()];
+            for fast in [false, true] {
+                let fs = TestFs::new(&[("file", b"")]);
+                let fs = if fast { fs.with_exists_fast_path() } else { fs };
+                let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(fs.clone()))]));
+                conn.attach(8192, 1, "").await;
+
+                let r = conn.rpc(T::Walk(2, 1, 2, missing.clone())).await;
+                assert_eq!(R::Error(2, "ENOENT".to_owned(), 2), r);
+                assert_eq!(
+                    if fast { (1, 0) } else { (0, 1) },
+                    (fs.exists_checks(), fs.lookups())
+                );
+
+                // anything that does exist is still walked to.
+                let r = conn.rpc(T::Walk(3, 1, 3, vec!["file".to_owned()])).await;
+                assert_eq!(R::Walk(3, vec![Qid::new(FileType::File, 0, 2)]), r);
+                assert_eq!(
+                    if fast { (2, 1) } else { (0, 2) },
+                    (fs.exists_checks(), fs.lookups())
+                );
+
+                // and past the first element, it's all up to the walk.
+                let r = conn
+                    .rpc(T::Walk(
+                        4,
+                        1,
+                        4,
+                        vec!["file".to_owned(), "missing".to_owned()],
+                    ))
+                    .await;
+                assert_eq!(R::Error(4, "ENOENT".to_owned(), 2), r);
+                assert_eq!(if fast { 3 } else { 0 }, fs.exists_checks());
+            }
+        });
+    }
+
+    #[test]
+    fn walk_in_place() {
+        block_on(async {
+            let fs = TestFs::new(&[("sub", b"hello")]);
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(fs))]));
+            conn.attach(8192, 1, "").await;
+
+            // nowhere at all leaves the fid where it was.
+            assert_eq!(R::Walk(2, vec![]), conn.rpc(T::Walk(2, 1, 1, vec![])).await);
+            match conn.rpc(T::Stat(3, 1)).await {
+                R::Stat(3, stat) => assert_eq!(Qid::new(FileType::Dir, 0, 1), stat.qid),
+                r => panic!("unexpected reply {:?}", r),
+            }
+
+            let r = conn.rpc(T::Walk(4, 1, 1, vec!["sub".to_owned()])).await;
+            assert_eq!(R::Walk(4, vec![Qid::new(FileType::File, 0, 2)]), r);
+            match conn.rpc(T::Stat(5, 1)).await {
+                R::Stat(5, stat) => assert_eq!("sub", stat.name),
+                r => panic!("unexpected reply {:?}", r),
+            }
+            assert!(matches!(
+                conn.rpc(T::Open(6, 1, 0.into())).await,
+                R::Open(6, ..)
+            ));
+            assert_eq!(
+                R::Read(7, b"hello".to_vec()),
+                conn.rpc(T::Read(7, 1, 0, 64)).await
+            );
+
+            // a failed walk leaves the fid alone.
+            let r = conn.rpc(T::Walk(8, 1, 1, vec!["missing".to_owned()])).await;
+            assert_eq!(R::Error(8, "ENOENT".to_owned(), 2), r);
+        });
+    }
+
+    #[test]
+    fn walk_over_return() {
+        block_on(async {
+            // walking "a" somehow went through three files.
+            let fs = ScriptedFs::new(|_| {
+                Ok((
+                    Err(FileError(2, "ENOENT".to_owned())),
+                    (2..5)
+                        .map(|path| Qid::new(FileType::Dir, 0, path))
+                        .collect(),
+                ))
+            });
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(fs))]));
+            conn.attach(8192, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, vec!["a".to_owned()])).await;
+            assert_eq!(R::Error(2, "EINVAL".to_owned(), 22), r);
+        });
+    }
+
+    #[test]
+    fn walk_validation() {
+        block_on(async {
+            let path = vec!["a".to_owned(), "b".to_owned()];
+
+            // by default, this is passed along as-is.
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(bogus_walk()))]));
+            conn.attach(8192, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, path.clone())).await;
+            assert!(
+                matches!(r, R::Walk(2, ref qids) if qids.len() == 2),
+                "{:?}",
+                r
+            );
+
+            let mut conn = TestConnection::serve_one(
+                bogus_walk(),
+                Options {
+                    validate_walk: true,
+                    ..Default::default()
+                },
+            );
+            conn.attach(8192, 1, "").await;
+            let r = conn.rpc(T::Walk(2, 1, 2, path.clone())).await;
+            assert_eq!(R::Error(2, "EINVAL".to_owned(), 22), r);
+        });
+    }
+
+    /// Filesystem which only lets a single user attach.
+    struct OnlyUser(&'static str, TestFs);
+
+    impl Filesystem for OnlyUser {
+        type File = TestFile;
+
+        async fn attach(
+            &self,
+            aname: &str,
+            uname: &str,
+            nuname: u32,
+        ) -> FilesystemResult<TestFile> {
+            if uname != self.0 {
+                return Err(FileError(13, "EACCES".to_owned()));
+            }
+            self.1.attach(aname, uname, nuname).await
+        }
+    }
+
+    /// Filesystem which only allows attaching with an msize of 512, and the
+    /// 9P2000.u dialect.
+    struct Needs512(TestFs);
+
+    impl Filesystem for Needs512 {
+        type File = TestFile;
+
+        async fn attach(&self, _: &str, _: &str, _: u32) -> FilesystemResult<TestFile> {
+            unreachable!()
+        }
+
+        async fn attach_with_context(&self, ctx: &AttachContext<'_>) -> FilesystemResult<TestFile> {
+            if ctx.msize != 512 || ctx.version.to_string() != "9P2000.u" {
+                return Err(FileError(22, "EINVAL".to_owned()));
+            }
+            self.0.attach(ctx.aname, ctx.uname, ctx.nuname).await
+        }
+    }
+
+    #[test]
+    fn attach_sees_negotiated_msize() {
+        block_on(async {
+            let mounts = mounts(vec![("", mount(Needs512(TestFs::new(&[]))))]);
+
+            // the server would go as high as 8192, but the client wants less.
+            let mut conn = TestConnection::serve(8192, mounts.clone());
+            let r = conn.attach(512, 1, "").await;
+            assert!(matches!(r, R::Attach(1, _)), "{:?}", r);
+
+            let mut conn = TestConnection::serve(8192, mounts);
+            let r = conn.attach(8192, 1, "").await;
+            assert_eq!(R::Error(1, "EINVAL".to_owned(), 22), r);
+        });
+    }
+
+    /// Filesystem which can't be attached to until its server's clock says
+    /// it's open.
+    struct OpensAt(SystemTime, TestFs);
+
+    impl Filesystem for OpensAt {
+        type File = TestFile;
+
+        async fn attach(&self, _: &str, _: &str, _: u32) -> FilesystemResult<TestFile> {
+            unreachable!()
+        }
+
+        async fn attach_with_context(&self, ctx: &AttachContext<'_>) -> FilesystemResult<TestFile> {
+            if ctx.clock.now() < self.0 {
+                return Err(FileError(11, "EAGAIN".to_owned()));
+            }
+            self.1.attach(ctx.aname, ctx.uname, ctx.nuname).await
+        }
+    }
+
+    #[test]
+    fn attach_sees_clock() {
+        block_on(async {
+            let clock = MockClock::default();
+            let opens = UNIX_EPOCH + Duration::from_secs(60);
+            let mounts = mounts(vec![("", mount(OpensAt(opens, TestFs::new(&[]))))]);
+            let options = Options {
+                clock: Some(Arc::new(clock.clone())),
+                ..Default::default()
+            };
+            let mut conn = TestConnection::serve_with_options(8192, mounts, options);
+            let r = conn.attach(8192, 1, "").await;
+            assert_eq!(R::Error(1, "EAGAIN".to_owned(), 11), r);
+
+            clock.advance(Duration::from_secs(60));
+            let r = conn
+                .rpc(T::Attach(2, 1, !0, "user".to_owned(), "".to_owned(), 0))
+                .await;
+            assert!(matches!(r, R::Attach(2, _)), "{:?}", r);
+        });
+    }
+
+    #[test]
+    fn attach_denied() {
+        block_on(async {
+            let mounts = mounts(vec![
+                ("private", mount(OnlyUser("admin", TestFs::new(&[])))),
+                ("public", mount(OnlyUser("guest", TestFs::new(&[])))),
+            ]);
+            let mut conn = TestConnection::serve(8192, mounts);
+            conn.version(8192).await;
+
+            let r = conn
+                .rpc(T::Attach(
+                    1,
+                    1,
+                    !0,
+                    "guest".to_owned(),
+                    "private".to_owned(),
+                    0,
+                ))
+                .await;
+            assert_eq!(R::Error(1, "EACCES".to_owned(), 13), r);
+
+            let r = conn
+                .rpc(T::Attach(
+                    2,
+                    1,
+                    !0,
+                    "guest".to_owned(),
+                    "public".to_owned(),
+                    0,
+                ))
+                .await;
+            assert!(matches!(r, R::Attach(2, _)), "{:?}", r);
+        });
+    }
+
+    #[test]
+    fn attach_routed_by_uname() {
+        block_on(async {
+            let admin = TestFs::new(&[("secret", b"")]);
+            let mounts = mounts(vec![("data", mount(TestFs::new(&[("public", b"")])))]);
+            let route: Router<TestFs> = Arc::new(move |uname, aname| match (uname, aname) {
+                ("admin", "data") => Some(admin.clone()),
+                _ => None,
+            });
+
+            for (uname, file, other) in
+                [("admin", "secret", "public"), ("guest", "public", "secret")]
+            {
+                let ctx = Context::new(
+                    Peer::Tcp("127.0.0.1:564".parse().unwrap()),
+                    8192,
+                    mounts.clone(),
+                    Options::default(),
+                )
+                .with_router(Some(route.clone()));
+                let mut conn = TestConnection::new(ctx);
+                conn.version(8192).await;
+
+                let r = conn
+                    .rpc(T::Attach(1, 1, !0, uname.to_owned(), "data".to_owned(), 0))
+                    .await;
+                assert!(matches!(r, R::Attach(1, _)), "{:?}", r);
+                let r = conn.rpc(T::Walk(2, 1, 2, vec![file.to_owned()])).await;
+                assert!(
+                    matches!(r, R::Walk(2, ref qids) if qids.len() == 1),
+                    "{:?}",
+                    r
+                );
+                let r = conn.rpc(T::Walk(3, 1, 3, vec![other.to_owned()])).await;
+                assert_eq!(R::Error(3, "ENOENT".to_owned(), 2), r);
+            }
+        });
+    }
+
+    /// Filesystem which hands back the Stat of the root when attaching.
+    struct CachedRoot(TestFs);
+
+    impl Filesystem for CachedRoot {
+        type File = TestFile;
+
+        async fn attach(
+            &self,
+            aname: &str,
+            uname: &str,
+            nuname: u32,
+        ) -> FilesystemResult<TestFile> {
+            self.0.attach(aname, uname, nuname).await
+        }
+
+        async fn attach_with_stat(
+            &self,
+            ctx: &AttachContext<'_>,
+        ) -> FilesystemResult<(TestFile, Option<Stat>)> {
+            let root = self.0.attach_with_context(ctx).await?;
+            let stat = Stat::builder("/", root.qid()).with_mode(0o755).build();
+            Ok((root, Some(stat)))
+        }
+    }
+
+    #[test]
+    fn attach_cached_stat() {
+        block_on(async {
+            // without a cached Stat, the mount's stat goes to the File.
+            let fs = TestFs::new(&[]);
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(fs.clone()))]));
+            conn.attach(8192, 1, "").await;
+            let r = conn.rpc(T::Stat(2, 1)).await;
+            assert!(matches!(r, R::Stat(2, _)), "{:?}", r);
+            assert_eq!(1, fs.stats());
+
+            let fs = TestFs::new(&[]);
+            let mut conn =
+                TestConnection::serve(8192, mounts(vec![("", mount(CachedRoot(fs.clone())))]));
+            conn.attach(8192, 1, "").await;
+            let r = conn.rpc(T::Stat(2, 1)).await;
+            assert!(
+                matches!(r, R::Stat(2, ref stat) if stat.name == "/"),
+                "{:?}",
+                r
+            );
+            assert_eq!(0, fs.stats());
+
+            // only the first stat is answered from the cache.
+            let r = conn.rpc(T::Stat(3, 1)).await;
+            assert!(matches!(r, R::Stat(3, _)), "{:?}", r);
+            assert_eq!(1, fs.stats());
+        });
+    }
+
+    #[test]
+    fn peer_cred_identity_without_cred() {
+        block_on(async {
+            // a TCP peer has no credentials to trust in place of the uname.
+            let mut conn = TestConnection::serve_one(
+                TestFs::new(&[]),
+                Options {
+                    peer_cred_identity: true,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(
+                R::Error(1, "EACCES".to_owned(), 13),
+                conn.attach(8192, 1, "").await
+            );
+            assert_eq!(
+                R::Error(2, "EBADF".to_owned(), 9),
+                conn.rpc(T::Stat(2, 1)).await
+            );
+        });
+    }
+
+    /// Filesystem which hands each user their own home directory as the
+    /// root of the tree.
+    struct Homes(MemFilesystem);
+
+    impl Filesystem for Homes {
+        type File = MemFile;
+
+        async fn attach(&self, aname: &str, uname: &str, nuname: u32) -> FilesystemResult<MemFile> {
+            let root = self.0.attach(aname, uname, nuname).await?;
+            let (home, _) = root.walk(&[uname]).await?;
+            home
+        }
+    }
+
+    #[test]
+    fn attach_per_user_root() {
+        block_on(async {
+            let fs = MemFilesystem::new();
+            let root = fs.attach("", "", 0).await.unwrap();
+            let (alice, _) = create_dir_all(&root, &["alice"], 0o755).await.unwrap();
+            let (bob, _) = create_dir_all(&root, &["bob"], 0o755).await.unwrap();
+            assert_ne!(alice.qid(), bob.qid());
+
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(Homes(fs)))]));
+            conn.version(8192).await;
+            for (tag, fid, uname, home) in [(1, 1, "alice", &alice), (2, 2, "bob", &bob)] {
+                let r = conn
+                    .rpc(T::Attach(
+                        tag,
+                        fid,
+                        NOFID,
+                        uname.to_owned(),
+                        "".to_owned(),
+                        0,
+                    ))
+                    .await;
+                assert_eq!(R::Attach(tag, home.qid()), r);
+            }
+
+            // each fid stays at its own root.
+            for (tag, fid, name) in [(3, 1, "alice"), (4, 2, "bob")] {
+                match conn.rpc(T::Stat(tag, fid)).await {
+                    R::Stat(_, stat) => assert_eq!(name, stat.name),
+                    r => panic!("unexpected reply {:?}", r),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn stat_open_file() {
+        block_on(async {
+            let fs = TestFs::new(&[("log", b"")]);
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(fs.clone()))]));
+            conn.attach(8192, 1, "").await;
+            conn.walk(2, 1, 2, &["log"]).await;
+            conn.open(3, 2, 1).await;
+
+            for (tag, size) in [(4, 5), (6, 10)] {
+                let r = conn
+                    .rpc(T::Write(tag, 2, size - 5, b"hello".to_vec()))
+                    .await;
+                assert_eq!(R::Write(tag, 5), r);
+                let r = conn.rpc(T::Stat(tag + 1, 2)).await;
+                assert!(
+                    matches!(r, R::Stat(_, ref stat) if stat.length == size),
+                    "{:?}",
+                    r
+                );
+            }
+            // all of which came from the open file.
+            assert_eq!(0, fs.stats());
+        });
+    }
+
+    #[test]
+    fn wstat_sync() {
+        block_on(async {
+            let fs = TestFs::new(&[("log", b"")]);
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(fs.clone()))]));
+            conn.attach(8192, 1, "").await;
+            conn.walk(2, 1, 2, &["log"]).await;
+
+            // not yet open, so this goes to File::wstat (which refuses).
+            let r = conn.rpc(T::WStat(3, 2, Stat::dont_touch())).await;
+            assert_eq!(R::Error(3, "EPERM".to_owned(), 1), r);
+            assert_eq!(0, fs.syncs());
+
+            conn.open(4, 2, 1).await;
+            let r = conn.rpc(T::WStat(5, 2, Stat::dont_touch())).await;
+            assert_eq!(R::WStat(5), r);
+            assert_eq!(1, fs.syncs());
+
+            // anything else is still a wstat.
+            let stat = Stat::builder("log", Qid::new(FileType::File, 0, 2)).build();
+            let r = conn.rpc(T::WStat(6, 2, stat)).await;
+            assert_eq!(R::Error(6, "EPERM".to_owned(), 1), r);
+            assert_eq!(1, fs.syncs());
+        });
+    }
+
+    #[test]
+    fn fsync() {
+        block_on(async {
+            let fs = TestFs::new(&[("log", b"")]);
+            let mounts = mounts(vec![("", mount(fs.clone()))]);
+            let mut conn = TestConnection::serve_linux(8192, mounts.clone());
+            conn.attach_linux(8192, 1, "").await;
+            conn.walk(2, 1, 2, &["log"]).await;
+
+            let r = conn.rpc(T::Fsync(3, 2, 0)).await;
+            assert_eq!(R::LError(3, 77), r);
+            assert_eq!(0, fs.syncs());
+
+            conn.open(4, 2, 1).await;
+            let r = conn.rpc(T::Fsync(5, 2, 1)).await;
+            assert_eq!(R::Fsync(5), r);
+            assert_eq!(1, fs.syncs());
+
+            let mut conn = dotu(mounts).await;
+            conn.walk(2, 1, 2, &["log"]).await;
+            conn.open(3, 2, 1).await;
+            let r = conn.rpc(T::Fsync(4, 2, 1)).await;
+            assert_eq!(R::Error(4, "ENOSYS".to_owned(), 38), r);
+            assert_eq!(1, fs.syncs());
+        });
+    }
+
+    /// Filesystem which reports a fixed StatFs.
+    struct Reports(StatFs, TestFs);
+
+    impl Filesystem for Reports {
+        type File = TestFile;
+
+        async fn attach(
+            &self,
+            aname: &str,
+            uname: &str,
+            nuname: u32,
+        ) -> FilesystemResult<TestFile> {
+            self.1.attach(aname, uname, nuname).await
+        }
+
+        async fn statfs(&self) -> FilesystemResult<StatFs> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn statfs() {
+        block_on(async {
+            let configured = StatFs {
+                bsize: 512,
+                blocks: 1000,
+                bfree: 250,
+                bavail: 200,
+                fsid: 0xF00,
+                ..Default::default()
+            };
+            let mounts = mounts(vec![
+                (
+                    "",
+                    mount(Reports(configured.clone(), TestFs::new(&[("a", b"")]))),
+                ),
+                (
+                    "default",
+                    mount(Reports(StatFs::default(), TestFs::new(&[]))),
+                ),
+            ]);
+            let mut conn = TestConnection::serve_linux(8192, mounts.clone());
+            conn.attach_linux(8192, 1, "").await;
+
+            let r = conn.rpc(T::StatFs(2, 1)).await;
+            assert_eq!(R::StatFs(2, configured.clone()), r);
+
+            // any fid will do, not just the root.
+            conn.walk(3, 1, 2, &["a"]).await;
+            let r = conn.rpc(T::StatFs(4, 2)).await;
+            assert_eq!(R::StatFs(4, configured), r);
+
+            let r = conn.rpc(T::StatFs(5, 9)).await;
+            assert_eq!(R::LError(5, 9), r);
+
+            let mut conn = TestConnection::serve_linux(8192, mounts.clone());
+            conn.attach_linux(8192, 1, "default").await;
+            let r = conn.rpc(T::StatFs(2, 1)).await;
+            assert_eq!(R::StatFs(2, StatFs::default()), r);
+
+            let mut conn = dotu(mounts).await;
+            let r = conn.rpc(T::StatFs(2, 1)).await;
+            assert_eq!(R::Error(2, "ENOSYS".to_owned(), 38), r);
+        });
+    }
+
+    #[test]
+    fn getattr() {
+        block_on(async {
+            let fs = TestFs::new(&[("log", b"hello")]);
+            let mut conn = TestConnection::serve_linux(8192, mounts(vec![("", mount(fs.clone()))]));
+            conn.attach_linux(8192, 1, "").await;
+            conn.walk(2, 1, 2, &["log"]).await;
+
+            match conn.rpc(T::GetAttr(3, 2, GETATTR_ALL)).await {
+                R::GetAttr(3, attr) => {
+                    assert_eq!(GETATTR_BASIC, attr.valid);
+                    assert_eq!(0o100644, attr.mode);
+                    assert_eq!(5, attr.size);
+                }
+                r => panic!("unexpected reply {:?}", r),
+            }
+            match conn.rpc(T::GetAttr(4, 1, GETATTR_BASIC)).await {
+                R::GetAttr(4, attr) => assert_eq!(0o040755, attr.mode),
+                r => panic!("unexpected reply {:?}", r),
+            }
+            assert_eq!(
+                R::LError(5, 9),
+                conn.rpc(T::GetAttr(5, 9, GETATTR_BASIC)).await
+            );
+
+            let mut conn = dotu(mounts(vec![("", mount(fs))])).await;
+            let r = conn.rpc(T::GetAttr(2, 1, GETATTR_BASIC)).await;
+            assert_eq!(R::Error(2, "ENOSYS".to_owned(), 38), r);
+        });
+    }
+
+    #[test]
+    fn read_only() {
+        block_on(async {
+            let fs = TestFs::new(&[("log", b"hello")]);
+            let mut conn = TestConnection::serve_one(
+                fs.clone(),
+                Options {
+                    read_only: true,
+                    ..Default::default()
+                },
+            );
+            conn.attach(8192, 1, "").await;
+            conn.walk(2, 1, 2, &["log"]).await;
+
+            let erofs = |tag| R::Error(tag, "EROFS".to_owned(), 30);
+            // write, read-write, truncate and remove-on-clunk.
+            for (tag, mode) in [(3, 1), (4, 2), (5, 0x10), (6, 0x40)] {
+                assert_eq!(erofs(tag), conn.rpc(T::Open(tag, 2, mode.into())).await);
+            }
+            conn.open(7, 2, 0).await;
+
+            let refused = [
+                T::Write(8, 2, 0, b"bye".to_vec()),
+                T::Create(9, 1, "new".to_owned(), 0o644, 1, String::new()),
+                T::WStat(10, 2, Stat::dont_touch()),
+                T::Remove(15, 2),
+            ];
+            for t in refused {
+                let tag = t.tag();
+                assert_eq!(erofs(tag), conn.rpc(t).await);
+            }
+
+            // as are their 9P2000.L counterparts, over 9P2000.L.
+            let mut dotl = TestConnection::serve_one(
+                fs.clone(),
+                Options {
+                    read_only: true,
+                    linux: true,
+                    ..Default::default()
+                },
+            );
+            dotl.attach_linux(8192, 1, "").await;
+            dotl.walk(2, 1, 2, &["log"]).await;
+            let refused = [
+                T::Mkdir(11, 1, "dir".to_owned(), 0o755, 0),
+                T::UnlinkAt(12, 1, "log".to_owned(), 0),
+                T::Symlink(13, 1, "link".to_owned(), "log".to_owned(), 0),
+                T::Link(14, 1, 2, "hard".to_owned()),
+            ];
+            for t in refused {
+                let tag = t.tag();
+                assert_eq!(R::LError(tag, 30), dotl.rpc(t).await);
+            }
+
+            // the remove still clunked the fid.
+            let r = conn.rpc(T::Stat(16, 2)).await;
+            assert!(matches!(r, R::Error(16, _, _)), "{:?}", r);
+
+            // and nothing was written.
+            conn.walk(17, 1, 3, &["log"]).await;
+            conn.open(18, 3, 0).await;
+            let r = conn.rpc(T::Read(19, 3, 0, 64)).await;
+            assert_eq!(R::Read(19, b"hello".to_vec()), r);
+        });
+    }
+
+    #[test]
+    fn read_capped_at_iounit() {
+        block_on(async {
+            let data = vec![0xAAu8; 4096];
+            let mounts = mounts(vec![("", mount(TestFs::new(&[("data", &data)])))]);
+            let mut conn = TestConnection::serve(1024, mounts);
+            conn.attach(1024, 1, "").await;
+            conn.walk(2, 1, 2, &["data"]).await;
+            conn.open(3, 2, 0).await;
+
+            // a whole msize of data wouldn't fit in the Rread.
+            match conn.rpc(T::Read(4, 2, 0, 1024)).await {
+                R::Read(4, buf) => assert_eq!(1024 - IOHDRSZ as usize, buf.len()),
+                r => panic!("unexpected reply {:?}", r),
+            }
+        });
+    }
+
+    #[test]
+    fn read_capped_per_filesystem() {
+        block_on(async {
+            let data = vec![0xAAu8; 4096];
+            let mounts = mounts(vec![
+                (
+                    "small",
+                    Mount {
+                        filesystem: TestFs::new(&[("data", &data)]),
+                        max_read: Some(16),
+                    },
+                ),
+                (
+                    "large",
+                    Mount {
+                        filesystem: TestFs::new(&[("data", &data)]),
+                        max_read: Some(1024),
+                    },
+                ),
+            ]);
+
+            let mut conn = TestConnection::serve(8192, mounts);
+            conn.version(8192).await;
+
+            for (fid, aname, expected) in [(1, "small", 16), (3, "large", 1024)] {
+                let r = conn
+                    .rpc(T::Attach(
+                        1,
+                        fid,
+                        !0,
+                        "user".to_owned(),
+                        aname.to_owned(),
+                        0,
+                    ))
+                    .await;
+                assert!(matches!(r, R::Attach(1, _)), "{:?}", r);
+
+                conn.walk(2, fid, fid + 1, &["data"]).await;
+                conn.open(3, fid + 1, 0).await;
+
+                match conn.rpc(T::Read(4, fid + 1, 0, 4096)).await {
+                    R::Read(4, buf) => assert_eq!(expected, buf.len()),
+                    r => panic!("unexpected reply {:?}", r),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn dotl_mkdir_symlink() {
+        block_on(async {
+            let fs = MemFilesystem::new();
+            let mut conn = TestConnection::serve_linux(8192, mounts(vec![("", mount(fs.clone()))]));
+            conn.attach_linux(8192, 1, "").await;
+
+            let r = conn.rpc(T::Mkdir(2, 1, "dir".to_owned(), 0o755, 0)).await;
+            let dir = match r {
+                R::Mkdir(2, qid) => qid,
+                r => panic!("unexpected reply {:?}", r),
+            };
+            assert_eq!(FileType::Dir, dir.ty);
+
+            let r = conn
+                .rpc(T::Symlink(3, 1, "link".to_owned(), "dir".to_owned(), 0))
+                .await;
+            assert!(
+                matches!(
+                    r,
+                    R::Symlink(
+                        3,
+                        Qid {
+                            ty: FileType::Link,
+                            ..
+                        }
+                    )
+                ),
+                "{:?}",
+                r
+            );
+
+            // the target only shows up in a 9P2000.u Stat.
+            let mut dotu = TestConnection::serve(8192, mounts(vec![("", mount(fs))]));
+            dotu.attach(8192, 1, "").await;
+            dotu.walk(4, 1, 2, &["link"]).await;
+            match dotu.rpc(T::Stat(5, 2)).await {
+                R::Stat(5, stat) => assert_eq!("dir", stat.extension),
+                r => panic!("unexpected reply {:?}", r),
+            }
+
+            // a directory needs AT_REMOVEDIR, and a file must not have it;
+            // errors come back as Rlerror.
+            let r = conn.rpc(T::UnlinkAt(6, 1, "dir".to_owned(), 0)).await;
+            assert_eq!(R::LError(6, 21), r);
+            let r = conn.rpc(T::UnlinkAt(7, 1, "link".to_owned(), 0x200)).await;
+            assert_eq!(R::LError(7, 20), r);
+            assert_eq!(
+                R::UnlinkAt(8),
+                conn.rpc(T::UnlinkAt(8, 1, "dir".to_owned(), 0x200)).await
+            );
+            assert_eq!(
+                R::UnlinkAt(9),
+                conn.rpc(T::UnlinkAt(9, 1, "link".to_owned(), 0)).await
+            );
+            let r = conn.rpc(T::Walk(10, 1, 3, vec!["dir".to_owned()])).await;
+            assert_eq!(R::LError(10, 2), r);
+        });
+    }
+
+    #[test]
+    fn dotl_needs_linux() {
+        block_on(async {
+            let mut conn = dotu(mounts(vec![("", mount(MemFilesystem::new()))])).await;
+
+            let enosys = |tag| R::Error(tag, "ENOSYS".to_owned(), 38);
+            let r = conn.rpc(T::Mkdir(2, 1, "dir".to_owned(), 0o755, 0)).await;
+            assert_eq!(enosys(2), r);
+            let r = conn
+                .rpc(T::Symlink(3, 1, "link".to_owned(), "dir".to_owned(), 0))
+                .await;
+            assert_eq!(enosys(3), r);
+            let r = conn.rpc(T::UnlinkAt(4, 1, "dir".to_owned(), 0)).await;
+            assert_eq!(enosys(4), r);
+
+            // and nothing was made.
+            let r = conn.rpc(T::Walk(5, 1, 2, vec!["dir".to_owned()])).await;
+            assert_eq!(R::Error(5, "ENOENT".to_owned(), 2), r);
+        });
+    }
+
+    #[test]
+    fn write_authorized_by_session() {
+        block_on(async {
+            let fs = TestFs::new(&[("file", b"")]).with_writer("alice");
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(fs))]));
+            conn.attach(8192, 1, "").await;
+            let r = conn
+                .rpc(T::Attach(2, 2, !0, "alice".to_owned(), "".to_owned(), 0))
+                .await;
+            assert!(matches!(r, R::Attach(2, _)), "{:?}", r);
+
+            for (tag, root, fid) in [(3, 1, 3), (5, 2, 4)] {
+                conn.walk(tag, root, fid, &["file"]).await;
+                conn.open(tag + 1, fid, 2).await;
+            }
+
+            let r = conn.rpc(T::Write(7, 3, 0, b"user".to_vec())).await;
+            assert_eq!(R::Error(7, "EACCES".to_owned(), 13), r);
+            let r = conn.rpc(T::Write(8, 4, 0, b"alice".to_vec())).await;
+            assert_eq!(R::Write(8, 5), r);
+        });
+    }
+
+    #[test]
+    fn create_iounit() {
+        block_on(async {
+            let fs = TestFs::new(&[]).with_iounit(4096);
+            let mut conn = TestConnection::serve(8192, mounts(vec![("", mount(fs))]));
+            conn.attach(8192, 1, "").await;
+
+            let r = conn
+                .rpc(T::Create(2, 1, "file".to_owned(), 0o644, 1, "".to_owned()))
+                .await;
+            assert_eq!(R::Create(2, Qid::new(FileType::File, 0, 2), 4096), r);
+
+            // and Ropen agrees.
+            conn.walk(3, 1, 2, &["file"]).await;
+            let r = conn.rpc(T::Open(4, 2, 0.into())).await;
+            assert_eq!(R::Open(4, Qid::new(FileType::File, 0, 2), 4096), r);
+        });
+    }
+
+    #[test]
+    fn default_iounit() {
+        block_on(async {
+            // files with no preference get the most that fits in the msize,
+            // and files asking for more are brought down to it.
+            for (advertised, msize, expected) in [
+                (0, 8192, 8192 - 24),
+                (0, 256, 256 - 24),
+                (1 << 20, 8192, 8192 - 24),
+                (4096, 8192, 4096),
+            ] {
+                let fs = TestFs::new(&[("file", b"")]).with_iounit(advertised);
+                let mut conn = TestConnection::serve(msize, mounts(vec![("", mount(fs))]));
+                conn.attach(msize, 1, "").await;
+                conn.walk(2, 1, 2, &["file"]).await;
+                let r = conn.rpc(T::Open(3, 2, 0.into())).await;
+                let R::Open(3, _, iounit) = r else {
+                    panic!("{:?}", r);
+                };
+                assert_eq!(expected, iounit);
+                assert!(iounit <= msize - 24);
+            }
+        });
+    }
+
+    #[test]
+    fn dotl_link() {
+        block_on(async {
+            let fs = MemFilesystem::new();
+            let mounts = mounts(vec![("", mount(fs))]);
+            let mut conn = TestConnection::serve_linux(8192, mounts.clone());
+            conn.attach_linux(8192, 1, "").await;
+
+            let r = conn.rpc(T::Walk(2, 1, 2, vec![])).await;
+            assert_eq!(R::Walk(2, vec![]), r);
+            let r = conn
+                .rpc(T::Create(3, 2, "file".to_owned(), 0o644, 1, "".to_owned()))
+                .await;
+            assert!(matches!(r, R::Create(3, _, _)), "{:?}", r);
+            let r = conn.rpc(T::Write(4, 2, 0, b"hello".to_vec())).await;
+            assert_eq!(R::Write(4, 5), r);
+
+            conn.walk(5, 1, 3, &["file"]).await;
+            let r = conn.rpc(T::Link(6, 1, 3, "hardlink".to_owned())).await;
+            assert_eq!(R::Link(6), r);
+            let r = conn.rpc(T::Link(7, 1, 1, "root".to_owned())).await;
+            assert_eq!(R::LError(7, 1), r);
+
+            let mut qids = vec![];
+            for (fid, name) in [(10, "file"), (11, "hardlink")] {
+                match conn.rpc(T::Walk(8, 1, fid, vec![name.to_owned()])).await {
+                    R::Walk(_, walked) => qids.push(walked[0].clone()),
+                    r => panic!("unexpected reply {:?}", r),
+                }
+                conn.open(9, fid, 0).await;
+                let r = conn.rpc(T::Read(10, fid, 0, 1024)).await;
+                assert_eq!(R::Read(10, b"hello".to_vec()), r);
+            }
+            assert_eq!(qids[0], qids[1]);
+
+            let mut conn = dotu(mounts).await;
+            conn.walk(2, 1, 3, &["file"]).await;
+            let r = conn.rpc(T::Link(3, 1, 3, "another".to_owned())).await;
+            assert_eq!(R::Error(3, "ENOSYS".to_owned(), 38), r);
+            let r = conn.rpc(T::Walk(4, 1, 4, vec!["another".to_owned()])).await;
+            assert_eq!(R::Error(4, "ENOENT".to_owned(), 2), r);
+        });
+    }
+
+    #[test]
+    fn dotl_unsupported() {
+        block_on(async {
+            let mounts = mounts(vec![("", mount(TestFs::new(&[])))]);
+            let mut conn = TestConnection::serve_linux(8192, mounts);
+            conn.attach_linux(8192, 1, "").await;
+
+            let r = conn.rpc(T::Mkdir(2, 1, "dir".to_owned(), 0o755, 0)).await;
+            assert_eq!(R::LError(2, 1), r);
+            let r = conn
+                .rpc(T::Symlink(3, 1, "link".to_owned(), "dir".to_owned(), 0))
+                .await;
+            assert_eq!(R::LError(3, 1), r);
+            let r = conn.rpc(T::UnlinkAt(4, 1, "dir".to_owned(), 0)).await;
+            assert_eq!(R::LError(4, 1), r);
+        });
+    }
+
+    #[test]
+    fn xattr_walk() {
+        block_on(async {
+            let fs = TestFs::new(&[("file", b"hello")]);
+            let mounts = mounts(vec![("", mount(fs))]);
+            let mut conn = TestConnection::serve_linux(8192, mounts.clone());
+            conn.attach_linux(8192, 1, "").await;
+            conn.walk(2, 1, 2, &["file"]).await;
+
+            // the walked fid reads back the value, not the file...
+            let r = conn
+                .rpc(T::XattrWalk(3, 2, 3, "user.name".to_owned()))
+                .await;
+            assert_eq!(R::XattrWalk(3, 4), r);
+            let r = conn.rpc(T::Read(4, 3, 0, 1024)).await;
+            assert_eq!(R::Read(4, b"file".to_vec()), r);
+            let r = conn.rpc(T::Read(5, 3, 2, 1024)).await;
+            assert_eq!(R::Read(5, b"le".to_vec()), r);
+            let r = conn.rpc(T::Read(6, 3, 4, 1024)).await;
+            assert_eq!(R::Read(6, vec![]), r);
+            assert_eq!(R::Clunk(7), conn.rpc(T::Clunk(7, 3)).await);
+
+            // ...an empty name lists them...
+            let r = conn.rpc(T::XattrWalk(8, 2, 3, "".to_owned())).await;
+            assert_eq!(R::XattrWalk(8, 10), r);
+            let r = conn.rpc(T::Read(9, 3, 0, 1024)).await;
+            assert_eq!(R::Read(9, b"user.name\0".to_vec()), r);
+
+            // ...and one that isn't there is whatever error the File says.
+            let r = conn
+                .rpc(T::XattrWalk(10, 2, 4, "user.missing".to_owned()))
+                .await;
+            assert_eq!(R::LError(10, 61), r);
+            let r = conn.rpc(T::Clunk(11, 4)).await;
+            assert_eq!(R::LError(11, 9), r);
+
+            // the original fid is untouched.
+            conn.open(12, 2, 0).await;
+            let r = conn.rpc(T::Read(13, 2, 0, 1024)).await;
+            assert_eq!(R::Read(13, b"hello".to_vec()), r);
+
+            let mut conn = dotu(mounts).await;
+            let r = conn.rpc(T::XattrWalk(2, 1, 2, "".to_owned())).await;
+            assert_eq!(R::Error(2, "ENOSYS".to_owned(), 38), r);
+            let r = conn.rpc(T::Clunk(3, 2)).await;
+            assert_eq!(R::Error(3, "EBADF".to_owned(), 9), r);
+        });
+    }
+
+    #[test]
+    fn xattr_create() {
+        block_on(async {
+            let fs = TestFs::new(&[("file", b"hello")]);
+            let mounts = mounts(vec![("", mount(fs))]);
+            let mut conn = TestConnection::serve_linux(8192, mounts.clone());
+            conn.attach_linux(8192, 1, "").await;
+            let xattr = |tag, fid, size| T::XattrCreate(tag, fid, "user.tag".to_owned(), size, 0);
+            for fid in [2, 3, 4] {
+                conn.walk(1, 1, fid, &["file"]).await;
+            }
+
+            // writes go to the extended attribute, up to its size, and the
+            // File only gets to say no once it has all of it.
+            assert_eq!(R::XattrCreate(2), conn.rpc(xattr(2, 2, 4)).await);
+            let r = conn.rpc(T::Write(3, 2, 0, b"ab".to_vec())).await;
+            assert_eq!(R::Write(3, 2), r);
+            let r = conn.rpc(T::Write(4, 2, 2, b"cde".to_vec())).await;
+            assert_eq!(R::LError(4, 28), r);
+            let r = conn.rpc(T::Write(5, 2, 2, b"cd".to_vec())).await;
+            assert_eq!(R::Write(5, 2), r);
+            let r = conn.rpc(T::Clunk(6, 2)).await;
+            assert_eq!(R::LError(6, 95), r);
+
+            // a short write is never set.
+            assert_eq!(R::XattrCreate(7), conn.rpc(xattr(7, 3, 4)).await);
+            let r = conn.rpc(T::Write(8, 3, 0, b"ab".to_vec())).await;
+            assert_eq!(R::Write(8, 2), r);
+            let r = conn.rpc(T::Clunk(9, 3)).await;
+            assert_eq!(R::LError(9, 22), r);
+
+            let r = conn.rpc(xattr(10, 4, 1 << 20)).await;
+            assert_eq!(R::LError(10, 7), r);
+
+            let mut conn = dotu(mounts).await;
+            conn.walk(2, 1, 2, &["file"]).await;
+            let r = conn.rpc(xattr(3, 2, 4)).await;
+            assert_eq!(R::Error(3, "ENOSYS".to_owned(), 38), r);
+        });
+    }
+}
+
 // vim: foldmethod=marker
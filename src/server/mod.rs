@@ -20,22 +20,58 @@
 
 //! This module
 
+mod admin;
 mod aio;
 mod async_server;
+mod auth;
+mod buffer_pool;
+mod clock;
 mod connection_handler;
+mod connection_limit;
+mod dir_cursor;
+mod dispatch;
 mod macros;
 mod message_handler;
+mod observer;
+mod path_policy;
+mod peer;
+mod rate_limit;
+mod select;
 mod state;
+#[cfg(feature = "systemd")]
+mod systemd;
+#[cfg(feature = "tls")]
+mod tls;
 mod traits;
 
-pub use aio::{RReader, RWriter, TReader, TWriter};
-pub use traits::{File, FileError, FileResult, Filesystem, OpenFile};
+#[cfg(feature = "websocket")]
+mod websocket;
+
+#[cfg(test)]
+pub(crate) mod testing;
+
+pub use admin::{ConnectionId, ServerHandle};
+pub use aio::{FrameObserver, RReader, RWriter, TReader, TWriter};
+pub(crate) use traits::{errno_name, next_entry};
+pub use traits::{
+    AttachContext, DirEntries, DirStream, File, FileError, FileResult, Filesystem,
+    FilesystemResult, IterDirStream, OpContext, OpenFile,
+};
 
 use crate::raw::{RError, TError};
 
 pub use async_server::{AsyncServer, AsyncServerBuilder, Context};
+pub use auth::{AuthFile, AuthFuture, Authenticator};
+pub use buffer_pool::BufferPool;
+pub use clock::{Clock, MockClock, Sleep, SystemClock};
 pub use connection_handler::{connection_handler, MessageContext};
+pub use connection_limit::ConnectionLimitPolicy;
+pub use dir_cursor::DirCursor;
 pub use message_handler::message_handler;
+pub use observer::{CountingObserver, NoopObserver, Observer};
+pub use path_policy::PathPolicy;
+pub use peer::{Peer, PeerCred};
+pub use rate_limit::{RateLimit, RateLimitPolicy};
 pub use state::{
     FileHandle, FileHandles, FileHandlesError, Request, Requests, RequestsError, Session,
 };
@@ -51,9 +87,16 @@ pub enum ServerError {
     /// protocol to use.
     FailedToNegotiate,
 
+    /// The client sent more than the allowed number of bytes without
+    /// negotiating a version.
+    HandshakeBudgetExceeded,
+
     /// No filesystem by that name is known by this server.
     NoSuchFilesystem,
 
+    /// No connection by that id is connected to this server.
+    NoSuchConnection,
+
     /// Something happened below us. Dunno! Good luck!
     IoError(std::io::Error),
 
@@ -73,6 +116,44 @@ pub enum ServerError {
     FileError(FileError),
 }
 
+impl ServerError {
+    /// Name and (Linux) errno to answer a request which failed with this
+    /// error with, in an Rerror. The details of what went wrong are only
+    /// good for the log; clients go by the errno.
+    pub fn errno(&self) -> (String, u32) {
+        let named = |errno| match traits::errno_name(errno) {
+            Some(name) => (name.to_owned(), errno),
+            None => ("EIO".to_owned(), 5),
+        };
+        match self {
+            Self::FailedToNegotiate => named(93),
+            Self::HandshakeBudgetExceeded => named(71),
+            Self::NoSuchFilesystem | Self::NoSuchConnection => named(2),
+            Self::IoError(ioe) => named(ioe.raw_os_error().unwrap_or(5) as u32),
+            Self::TError(TError::TooLong) | Self::RError(RError::TooLong) => named(90),
+            Self::TError(TError::StringError(_)) | Self::RError(RError::StringError(_)) => {
+                named(84)
+            }
+            Self::TError(TError::IoError(_)) | Self::RError(RError::IoError(_)) => named(5),
+            Self::TError(_) | Self::RError(_) => named(22),
+            Self::RequestsError(RequestsError::TagAlreadyExists) => named(16),
+            Self::RequestsError(_) => named(22),
+            Self::FileHandlesError(FileHandlesError::FidAlreadyExists) => named(17),
+            Self::FileHandlesError(FileHandlesError::NoSuchFid) => named(9),
+            Self::FileHandlesError(FileHandlesError::SameFid) => named(22),
+            Self::FileError(fe) => {
+                // clients show the description, so never leave it empty
+                // when the errno can speak for itself.
+                let description = match traits::errno_name(fe.errno) {
+                    Some(name) if fe.description.is_empty() => name.to_owned(),
+                    _ => fe.description.clone(),
+                };
+                (description, fe.errno)
+            }
+        }
+    }
+}
+
 impl From<FileError> for ServerError {
     fn from(fe: FileError) -> Self {
         Self::FileError(fe)
@@ -115,4 +196,103 @@ impl From<std::io::Error> for ServerError {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{FileError, FileHandlesError, RequestsError, ServerError};
+    use crate::raw::{RError, StatError, StringError, TError, VersionError};
+
+    #[test]
+    fn errno() {
+        let errno = |err: ServerError| err.errno();
+        let named = |name: &str, errno: u32| (name.to_owned(), errno);
+
+        assert_eq!(
+            named("EPROTONOSUPPORT", 93),
+            errno(ServerError::FailedToNegotiate)
+        );
+        assert_eq!(
+            named("EPROTO", 71),
+            errno(ServerError::HandshakeBudgetExceeded)
+        );
+        assert_eq!(named("ENOENT", 2), errno(ServerError::NoSuchFilesystem));
+        assert_eq!(named("ENOENT", 2), errno(ServerError::NoSuchConnection));
+
+        let ioe = std::io::Error::from_raw_os_error(32);
+        assert_eq!(named("EPIPE", 32), errno(ServerError::IoError(ioe)));
+        let ioe = std::io::Error::other("who knows");
+        assert_eq!(named("EIO", 5), errno(ServerError::IoError(ioe)));
+        let ioe = std::io::Error::from_raw_os_error(4095);
+        assert_eq!(named("EIO", 5), errno(ServerError::IoError(ioe)));
+
+        assert_eq!(
+            named("EMSGSIZE", 90),
+            errno(ServerError::TError(TError::TooLong))
+        );
+        assert_eq!(
+            named("EMSGSIZE", 90),
+            errno(ServerError::RError(RError::TooLong))
+        );
+        let utf8 = || StringError::TooLarge;
+        assert_eq!(
+            named("EILSEQ", 84),
+            errno(ServerError::TError(TError::StringError(utf8())))
+        );
+        assert_eq!(
+            named("EILSEQ", 84),
+            errno(ServerError::RError(RError::StringError(utf8())))
+        );
+        let ioe = || std::io::Error::other("who knows");
+        assert_eq!(
+            named("EIO", 5),
+            errno(ServerError::TError(TError::IoError(ioe())))
+        );
+        assert_eq!(
+            named("EIO", 5),
+            errno(ServerError::RError(RError::IoError(ioe())))
+        );
+        for te in [
+            TError::TrailingBytes,
+            TError::VersionError(VersionError::MismatchedVariant),
+            TError::StatError(StatError::TooLarge),
+        ] {
+            assert_eq!(named("EINVAL", 22), errno(ServerError::TError(te)));
+        }
+        assert_eq!(
+            named("EINVAL", 22),
+            errno(ServerError::RError(RError::VersionError(
+                VersionError::MismatchedVariant
+            )))
+        );
+
+        assert_eq!(
+            named("EBUSY", 16),
+            errno(RequestsError::TagAlreadyExists.into())
+        );
+        assert_eq!(named("EINVAL", 22), errno(RequestsError::NoSuchTag.into()));
+        assert_eq!(
+            named("EINVAL", 22),
+            errno(RequestsError::ReservedTag.into())
+        );
+        assert_eq!(
+            named("EEXIST", 17),
+            errno(FileHandlesError::FidAlreadyExists.into())
+        );
+        assert_eq!(named("EBADF", 9), errno(FileHandlesError::NoSuchFid.into()));
+        assert_eq!(named("EINVAL", 22), errno(FileHandlesError::SameFid.into()));
+
+        assert_eq!(
+            named("no such file", 2),
+            errno(FileError(2, "no such file".to_owned()).into())
+        );
+        assert_eq!(
+            named("ENOENT", 2),
+            errno(FileError(2, "".to_owned()).into())
+        );
+        assert_eq!(
+            named("", 4095),
+            errno(FileError(4095, "".to_owned()).into())
+        );
+    }
+}
+
 // vim: foldmethod=marker
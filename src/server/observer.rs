@@ -0,0 +1,204 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use super::Peer;
+use crate::raw::{Type, R, T};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Hook into the life of every connection, for keeping metrics. Each method
+/// is called from the connection's own task, in between handling requests,
+/// so it should be quick about it. Every method does nothing by default.
+pub trait Observer: Send + Sync + 'static {
+    /// A connection from `peer` has negotiated a version.
+    fn on_connection_open(&self, _peer: &Peer) {}
+
+    /// The connection from `peer` is closed. Any fids it left open are
+    /// reported clunked just before this.
+    fn on_connection_close(&self, _peer: &Peer) {}
+
+    /// A request has been read from the client.
+    fn on_request(&self, _t: &T) {}
+
+    /// A reply is being sent, `latency` after the request it answers was
+    /// read.
+    fn on_reply(&self, _r: &R, _latency: Duration) {}
+
+    /// The client has `count` more fids than it did.
+    fn on_fids_opened(&self, _count: usize) {}
+
+    /// The client has `count` fewer fids than it did.
+    fn on_fids_clunked(&self, _count: usize) {}
+}
+
+impl fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Observer").finish_non_exhaustive()
+    }
+}
+
+/// [Observer] which pays no attention at all.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {}
+
+/// Counters kept by a [CountingObserver].
+#[derive(Debug)]
+struct Counters {
+    requests: [AtomicU64; 256],
+    errors: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    fids: AtomicU64,
+    connections: AtomicU64,
+    latency_us: AtomicU64,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self {
+            requests: std::array::from_fn(|_| AtomicU64::new(0)),
+            errors: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            fids: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            latency_us: AtomicU64::new(0),
+        }
+    }
+}
+
+/// [Observer] which keeps a running count of what every connection of the
+/// server has been up to. Clones share the same counts, so keep one to read
+/// them back while the server has the other.
+#[derive(Debug, Clone, Default)]
+pub struct CountingObserver {
+    counters: Arc<Counters>,
+}
+
+impl CountingObserver {
+    /// Create a new CountingObserver, with every count at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of requests read with the type byte `ty`.
+    pub fn requests(&self, ty: Type) -> u64 {
+        self.counters.requests[ty as usize].load(Ordering::Relaxed)
+    }
+
+    /// Number of requests read, of any type.
+    pub fn total_requests(&self) -> u64 {
+        self.counters
+            .requests
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Number of Rerror replies sent.
+    pub fn errors(&self) -> u64 {
+        self.counters.errors.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of file data sent to clients in Rread replies.
+    pub fn bytes_read(&self) -> u64 {
+        self.counters.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of file data written by clients, as reported by Rwrite
+    /// replies.
+    pub fn bytes_written(&self) -> u64 {
+        self.counters.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Number of fids open across every connection.
+    pub fn fids(&self) -> u64 {
+        self.counters.fids.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections open.
+    pub fn connections(&self) -> u64 {
+        self.counters.connections.load(Ordering::Relaxed)
+    }
+
+    /// Time spent on every request answered so far, all added up.
+    pub fn total_latency(&self) -> Duration {
+        Duration::from_micros(self.counters.latency_us.load(Ordering::Relaxed))
+    }
+}
+
+impl Observer for CountingObserver {
+    fn on_connection_open(&self, _: &Peer) {
+        self.counters.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_connection_close(&self, _: &Peer) {
+        self.counters.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn on_request(&self, t: &T) {
+        self.counters.requests[t.ty() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_reply(&self, r: &R, latency: Duration) {
+        let counters = &self.counters;
+        match r {
+            R::Read(_, data) => {
+                counters
+                    .bytes_read
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            R::Write(_, n) => {
+                counters
+                    .bytes_written
+                    .fetch_add(*n as u64, Ordering::Relaxed);
+            }
+            R::Error(..) => {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        counters
+            .latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn on_fids_opened(&self, count: usize) {
+        self.counters
+            .fids
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn on_fids_clunked(&self, count: usize) {
+        self.counters
+            .fids
+            .fetch_sub(count as u64, Ordering::Relaxed);
+    }
+}
+
+// vim: foldmethod=marker
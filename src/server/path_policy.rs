@@ -0,0 +1,47 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use super::FileResult;
+use std::fmt;
+
+/// Hook into every Twalk, ahead of the Filesystem: a PathPolicy sees the
+/// path components requested by the client and may hand back a different
+/// path to walk instead, or refuse the walk outright. This is enough to
+/// build a virtual root or a path-rewriting proxy over an existing
+/// Filesystem.
+///
+/// The client is sent the qids of the final `path.len()` elements walked,
+/// so a policy which adds a prefix (say, `a` becomes `internal/a`) is
+/// invisible to it. A policy which shortens the path will look like a
+/// partial walk.
+pub trait PathPolicy: Send + Sync + 'static {
+    /// Return the path to walk in place of `path`, which is relative to the
+    /// fid being walked from. Returning an Error replies with that errno,
+    /// and the Filesystem never sees the walk.
+    fn walk(&self, path: &[String]) -> FileResult<Vec<String>>;
+}
+
+impl fmt::Debug for dyn PathPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PathPolicy").finish_non_exhaustive()
+    }
+}
+
+// vim: foldmethod=marker
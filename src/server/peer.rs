@@ -0,0 +1,81 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use std::net::SocketAddr;
+
+/// Credentials of the process on the other end of a connection, as reported
+/// by the kernel (SO_PEERCRED). Unlike the uname sent in a Tattach, these
+/// can't be forged by the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerCred {
+    /// Effective user id of the peer.
+    pub uid: u32,
+
+    /// Effective group id of the peer.
+    pub gid: u32,
+
+    /// Process id of the peer, if known.
+    pub pid: Option<i32>,
+}
+
+/// Information about the connected peer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Peer {
+    /// Peer connected over TCP.
+    Tcp(SocketAddr),
+
+    /// Peer connected over a UNIX socket, along with its credentials if the
+    /// kernel was able to tell us.
+    Unix(Option<PeerCred>),
+
+    /// Peer connected over some other transport, handed to
+    /// [crate::server::AsyncServer::serve_split], along with a label to
+    /// know it by in the logs.
+    Custom(String),
+}
+
+impl Peer {
+    /// Kernel-reported credentials of the peer, if any.
+    pub fn cred(&self) -> Option<PeerCred> {
+        match self {
+            Self::Unix(cred) => *cred,
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(Some(cred)) => write!(f, "unix(uid={}, gid={})", cred.uid, cred.gid),
+            Self::Unix(None) => write!(f, "unix"),
+            Self::Custom(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+// vim: foldmethod=marker
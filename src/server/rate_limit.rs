@@ -0,0 +1,122 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+use super::Clock;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// What to do with a request that arrives after the connection has used up
+/// its request budget.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitPolicy {
+    /// Hold the request until the connection is back under the limit.
+    Wait,
+
+    /// Reply to the request with EAGAIN without dispatching it.
+    Reject,
+}
+
+/// Limit on the number of requests a single connection may make per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Sustained number of requests allowed per second. This is also the
+    /// size of the largest allowed burst.
+    pub requests_per_sec: u32,
+
+    /// What to do with requests over the limit.
+    pub policy: RateLimitPolicy,
+}
+
+/// Token bucket used to enforce a [RateLimit] on a single connection, as
+/// time passes by the server's [Clock].
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    last: SystemTime,
+    clock: Arc<dyn Clock>,
+}
+
+impl TokenBucket {
+    /// Create a new, full, TokenBucket for the provided [RateLimit], which
+    /// must allow at least one request per second.
+    pub(crate) fn new(limit: &RateLimit, clock: Arc<dyn Clock>) -> Self {
+        let capacity = limit.requests_per_sec as f64;
+        Self {
+            capacity,
+            tokens: capacity,
+            last: clock.now(),
+            clock,
+        }
+    }
+
+    fn refill(&mut self, now: SystemTime) {
+        // a clock that steps backwards refills nothing.
+        let elapsed = now.duration_since(self.last).unwrap_or_default();
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.capacity).min(self.capacity);
+        self.last = self.last.max(now);
+    }
+
+    /// Try to take a token from the bucket. If the bucket is empty, this
+    /// returns how long until the next token is available.
+    pub(crate) fn try_take(&mut self) -> Result<(), Duration> {
+        self.refill(self.clock.now());
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.capacity))
+    }
+
+    /// Take a token from the bucket, waiting for one if needed.
+    pub(crate) async fn take(&mut self) {
+        while let Err(wait) = self.try_take() {
+            self.clock.sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateLimit, RateLimitPolicy, TokenBucket};
+    use crate::server::MockClock;
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn bucket_refills() {
+        let clock = MockClock::default();
+        let limit = RateLimit {
+            requests_per_sec: 2,
+            policy: RateLimitPolicy::Wait,
+        };
+        let mut bucket = TokenBucket::new(&limit, Arc::new(clock.clone()));
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_ok());
+        let wait = bucket.try_take().unwrap_err();
+        assert!(wait <= Duration::from_millis(500));
+
+        clock.advance(Duration::from_millis(500));
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_err());
+    }
+}
+
+// vim: foldmethod=marker
@@ -19,10 +19,11 @@
 // THE SOFTWARE. }}}
 
 use crate::{
-    raw::{Fid, Tag, T},
-    server::File,
+    raw::{Fid, Stat, Tag, NOTAG, T},
+    server::{AuthFile, File},
 };
 use std::collections::HashMap;
+use tokio::time::Instant;
 
 /// Session being requested. This contains internal state about the connecting
 /// user and filesystem requested.
@@ -30,12 +31,33 @@ use std::collections::HashMap;
 pub struct Session {
     pub(super) uname: String,
     pub(super) aname: String,
+    pub(super) max_read: Option<u32>,
 }
 
 impl Session {
     /// Create a new Session.
     pub fn new(uname: String, aname: String) -> Self {
-        Self { uname, aname }
+        Self {
+            uname,
+            aname,
+            max_read: None,
+        }
+    }
+
+    /// Name of the user this Session was attached as.
+    pub fn uname(&self) -> &str {
+        &self.uname
+    }
+
+    /// Name of the filesystem this Session is attached to.
+    pub fn aname(&self) -> &str {
+        &self.aname
+    }
+
+    /// Cap reads made during this Session to `max_read` bytes.
+    pub fn with_max_read(mut self, max_read: Option<u32>) -> Self {
+        self.max_read = max_read;
+        self
     }
 }
 
@@ -50,15 +72,47 @@ where
     pub(super) session: Session,
     pub(super) file: FileT,
     pub(super) of: Option<FileT::OpenFile>,
+
+    /// Stat to answer the next Tstat with, rather than asking the File.
+    pub(super) stat: Option<Stat>,
+
+    /// Extended attribute this fid holds, rather than the File's contents.
+    pub(super) xattr: Option<Xattr>,
+}
+
+/// Extended attribute a fid was turned into by a Txattrwalk or
+/// Txattrcreate, which the fid reads or writes in place of its File.
+#[derive(Debug, Clone)]
+pub(crate) enum Xattr {
+    /// Value of the extended attribute (or the list of names), as it was
+    /// walked to.
+    Read(Vec<u8>),
+
+    /// Value being written, to be set on the File with the setxattr(2)
+    /// flags once the fid is clunked.
+    Write {
+        name: String,
+        size: u64,
+        flags: u32,
+        value: Vec<u8>,
+    },
 }
 
 /// Map of all open Files (wrapped in their FileHandle) by file descriptor.
+///
+/// Each request is handed a FileHandles of just the fids it names, split off
+/// from those of the connection for as long as it runs, so two operations on
+/// the same fid never overlap; a second request against a busy fid waits its
+/// turn.
 pub struct FileHandles<FileT>
 where
     FileT: File,
     FileT: Send,
 {
     handles: HashMap<Fid, FileHandle<FileT>>,
+
+    /// Auth fids, opened by a Tauth rather than naming a FileT.
+    auths: HashMap<Fid, Box<dyn AuthFile>>,
 }
 
 /// Errors which the FileHandles manager may return.
@@ -70,6 +124,10 @@ pub enum FileHandlesError {
     /// No such file descriptor has been defined yet, or has been
     /// clunked.
     NoSuchFid,
+
+    /// The same file descriptor was given where two different ones were
+    /// needed.
+    SameFid,
 }
 
 impl<FileT> Default for FileHandles<FileT>
@@ -91,6 +149,7 @@ where
     pub fn new() -> Self {
         Self {
             handles: HashMap::new(),
+            auths: HashMap::new(),
         }
     }
 
@@ -106,9 +165,11 @@ where
             session,
             file,
             of: None,
+            stat: None,
+            xattr: None,
         };
 
-        if self.handles.contains_key(&fid) {
+        if self.handles.contains_key(&fid) || self.auths.contains_key(&fid) {
             return Err(FileHandlesError::FidAlreadyExists);
         }
         self.handles.insert(fid, fh);
@@ -116,6 +177,73 @@ where
         Ok(self.handles.get(&fid).unwrap())
     }
 
+    /// Add a new auth fid, as opened by a Tauth.
+    pub fn insert_auth(
+        &mut self,
+        fid: Fid,
+        afile: Box<dyn AuthFile>,
+    ) -> Result<(), FileHandlesError> {
+        if self.handles.contains_key(&fid) || self.auths.contains_key(&fid) {
+            return Err(FileHandlesError::FidAlreadyExists);
+        }
+        self.auths.insert(fid, afile);
+        Ok(())
+    }
+
+    /// Number of fids known, auth fids included.
+    pub fn len(&self) -> usize {
+        self.handles.len() + self.auths.len()
+    }
+
+    /// Check if there are no fids known at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove every FileT, returning an iterator over them and the file
+    /// descriptors they were known by.
+    pub fn drain(&mut self) -> impl Iterator<Item = (Fid, FileHandle<FileT>)> + '_ {
+        self.handles.drain()
+    }
+
+    /// Remove every auth fid, returning how many there were.
+    pub(crate) fn drain_auth(&mut self) -> usize {
+        self.auths.drain().count()
+    }
+
+    /// Move the FileTs (and auth fids) known by any of `fids` out into a
+    /// FileHandles of their own. Any of `fids` which are not known are
+    /// skipped.
+    pub(crate) fn split_off(&mut self, fids: &[Fid]) -> Self {
+        Self {
+            handles: fids
+                .iter()
+                .filter_map(|fid| self.handles.remove_entry(fid))
+                .collect(),
+            auths: fids
+                .iter()
+                .filter_map(|fid| self.auths.remove_entry(fid))
+                .collect(),
+        }
+    }
+
+    /// Move every FileT (and auth fid) of `other` back in, as split off by
+    /// [FileHandles::split_off].
+    pub(crate) fn merge(&mut self, other: Self) {
+        self.handles.extend(other.handles);
+        self.auths.extend(other.auths);
+    }
+
+    /// Remove the auth fid known by `fid`, if it is one.
+    pub fn remove_auth(&mut self, fid: Fid) -> Option<Box<dyn AuthFile>> {
+        self.auths.remove(&fid)
+    }
+
+    /// Get the auth fid known by `fid`, if it is one.
+    pub fn get_auth_mut(&mut self, fid: Fid) -> Option<&mut (dyn AuthFile + 'static)> {
+        self.auths.get_mut(&fid).map(|afile| afile.as_mut())
+    }
+
     /// Remove the FileT, known by the provided file descriptor.
     pub fn remove(&mut self, fid: Fid) -> Result<FileHandle<FileT>, FileHandlesError> {
         match self.handles.remove(&fid) {
@@ -132,6 +260,33 @@ where
         }
     }
 
+    /// Get the FileT known by `fid` mutably, along with the FileT known by
+    /// some `other` file descriptor. If the two are the same, there is no
+    /// other, and this returns [FileHandlesError::SameFid].
+    pub fn get_pair_mut(
+        &mut self,
+        fid: Fid,
+        other: Fid,
+    ) -> Result<(&mut FileHandle<FileT>, &FileHandle<FileT>), FileHandlesError> {
+        if fid == other {
+            return Err(FileHandlesError::SameFid);
+        }
+        // a request only holds the handles of the fids it names, so this
+        // is no more than a couple of steps.
+        let (mut found, mut found_other) = (None, None);
+        for (key, fh) in self.handles.iter_mut() {
+            if *key == fid {
+                found = Some(fh);
+            } else if *key == other {
+                found_other = Some(&*fh);
+            }
+        }
+        match (found, found_other) {
+            (Some(fh), Some(other)) => Ok((fh, other)),
+            _ => Err(FileHandlesError::NoSuchFid),
+        }
+    }
+
     /// Get the FileT, known by the provided file descriptor.
     pub fn get_mut(&mut self, fid: Fid) -> Result<&mut FileHandle<FileT>, FileHandlesError> {
         match self.handles.get_mut(&fid) {
@@ -144,6 +299,9 @@ where
 /// Request type -- opaque handle containing a T type message.
 pub struct Request {
     pub(super) t: T,
+
+    /// When the request was read from the client.
+    pub(super) received: Instant,
 }
 
 /// Possible Errors from the state code when resolving a tag during a session.
@@ -154,6 +312,9 @@ pub enum RequestsError {
 
     /// No such tag exists by that name anymore.
     NoSuchTag,
+
+    /// That tag is NOTAG, which is reserved for Tversion.
+    ReservedTag,
 }
 
 /// All pending requests known to the server.
@@ -177,10 +338,19 @@ impl Requests {
 
     /// Insert a new T message under the tag T.
     pub fn insert(&mut self, tag: Tag, t: T) -> Result<(), RequestsError> {
+        if tag == NOTAG {
+            return Err(RequestsError::ReservedTag);
+        }
         if self.requests.contains_key(&tag) {
             return Err(RequestsError::TagAlreadyExists);
         }
-        self.requests.insert(tag, Request { t });
+        self.requests.insert(
+            tag,
+            Request {
+                t,
+                received: Instant::now(),
+            },
+        );
         Ok(())
     }
 
@@ -201,4 +371,23 @@ impl Requests {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notag_reserved() {
+        let mut requests = Requests::new();
+        assert!(matches!(
+            requests.insert(NOTAG, T::Clunk(NOTAG, 1)),
+            Err(RequestsError::ReservedTag)
+        ));
+        requests.insert(1, T::Clunk(1, 1)).unwrap();
+        assert!(matches!(
+            requests.insert(1, T::Clunk(1, 1)),
+            Err(RequestsError::TagAlreadyExists)
+        ));
+    }
+}
+
 // vim: foldmethod=marker
@@ -0,0 +1,119 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! systemd socket activation: picking up a listening socket which was
+//! opened on our behalf, rather than binding one ourselves.
+
+use super::async_server::Listener;
+use std::{
+    io::{Error, ErrorKind, Result},
+    os::fd::{FromRawFd, OwnedFd, RawFd},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tokio::net::{TcpListener, UnixListener};
+
+/// First fd passed along by systemd; see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Set once the inherited socket has been taken ownership of, so that it is
+/// never owned (and closed) twice.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Take the listening socket systemd passed to this process, as described
+/// by the `LISTEN_PID` and `LISTEN_FDS` environment variables. Only the
+/// first socket is used.
+pub(crate) fn listener() -> Result<Listener> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    listener_from(pid.as_deref(), fds.as_deref(), SD_LISTEN_FDS_START)
+}
+
+fn listener_from(pid: Option<&str>, fds: Option<&str>, start: RawFd) -> Result<Listener> {
+    let not_activated = || Error::new(ErrorKind::NotFound, "not socket activated by systemd");
+
+    // the variables are inherited by our children too, so they only count
+    // if they were meant for us.
+    let pid: u32 = pid.and_then(|p| p.parse().ok()).ok_or_else(not_activated)?;
+    if pid != std::process::id() {
+        return Err(not_activated());
+    }
+    let fds: RawFd = fds
+        .and_then(|n| n.parse().ok())
+        .filter(|n| *n > 0)
+        .ok_or_else(not_activated)?;
+    if fds > 1 {
+        tracing::warn!("systemd passed {fds} sockets; only serving the first");
+    }
+
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            "systemd socket already taken",
+        ));
+    }
+    // SAFETY: LISTEN_PID names this process, so the fd was handed to us to
+    // own, and TAKEN makes sure that happens only once.
+    let fd = unsafe { OwnedFd::from_raw_fd(start) };
+
+    // a UNIX socket has no address a TcpListener can make sense of.
+    let tcp = std::net::TcpListener::from(fd);
+    if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true)?;
+        return Ok(Listener::Tcp(TcpListener::from_std(tcp)?));
+    }
+    let unix = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp));
+    unix.local_addr()?;
+    unix.set_nonblocking(true)?;
+    Ok(Listener::Unix(UnixListener::from_std(unix)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{listener_from, Listener};
+    use crate::server::testing::{block_on, socket_path};
+    use std::os::fd::IntoRawFd;
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn inherited_unix_socket() {
+        block_on(async {
+            let path = socket_path("systemd.sock");
+            let fd = std::os::unix::net::UnixListener::bind(&path)
+                .unwrap()
+                .into_raw_fd();
+
+            let pid = std::process::id().to_string();
+            assert!(listener_from(None, Some("1"), fd).is_err());
+            assert!(listener_from(Some("1"), Some("1"), fd).is_err());
+            assert!(listener_from(Some(&pid), Some("0"), fd).is_err());
+
+            let listener = listener_from(Some(&pid), Some("1"), fd).unwrap();
+            assert!(matches!(listener, Listener::Unix(_)));
+            let _client = UnixStream::connect(&path).await.unwrap();
+            listener.accept().await.unwrap();
+
+            // and it is only ever handed out once.
+            assert!(listener_from(Some(&pid), Some("1"), fd).is_err());
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+}
+
+// vim: foldmethod=marker
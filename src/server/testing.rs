@@ -0,0 +1,799 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Shared fixtures for the server tests: an in-memory transport wired to
+//! [connection_handler], and a tiny flat in-memory Filesystem.
+
+// Not every test uses every fixture.
+#![allow(dead_code)]
+
+use super::{
+    async_server::{Mount, Mounts, Options},
+    connection_handler, Context, Peer, RReader, Result, TWriter,
+};
+use crate::{
+    raw::{Dialect, Fid, FileType, IoDirection, OpenMode, Qid, Stat, Tag, NOTAG, R, T},
+    server::{File, FileError, FileResult, Filesystem, OpContext, OpenFile},
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::Cursor,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{sync::Mutex as AsyncMutex, task::JoinHandle};
+
+/// Run the provided future to completion on a fresh single-threaded runtime.
+pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(f)
+}
+
+/// Log lines recorded by a [LogCapture].
+pub(crate) type Logs = Arc<Mutex<Vec<String>>>;
+
+/// tracing Subscriber which keeps the message of every event, so that tests
+/// can check what was logged. Spans are ignored.
+struct LogCapture(Logs);
+
+/// Pulls the message out of an event.
+struct MessageVisitor<'a>(&'a mut String);
+
+impl tracing::field::Visit for MessageVisitor<'_> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            *self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+impl tracing::Subscriber for LogCapture {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        tracing::span::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+    fn event(&self, event: &tracing::Event<'_>) {
+        let mut message = String::new();
+        event.record(&mut MessageVisitor(&mut message));
+        self.0.lock().unwrap().push(message);
+    }
+
+    fn enter(&self, _: &tracing::span::Id) {}
+
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+/// Run `f` to completion as [block_on] does, recording everything logged
+/// along the way (on this thread, which is every task of the runtime).
+pub(crate) fn block_on_logged<F: Future>(f: F) -> (F::Output, Vec<String>) {
+    let logs = Logs::default();
+    let output = tracing::subscriber::with_default(LogCapture(logs.clone()), || block_on(f));
+    let logs = logs.lock().unwrap().clone();
+    (output, logs)
+}
+
+/// Path of a UNIX socket named `name`, in a directory of our own, with
+/// whatever an earlier run left there cleared out of the way.
+#[cfg(unix)]
+pub(crate) fn socket_path(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("arigato-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Build the shared Mounts map from a list of (aname, Mount) pairs.
+pub(crate) fn mounts<FilesystemT>(mounts: Vec<(&str, Mount<FilesystemT>)>) -> Mounts<FilesystemT> {
+    Arc::new(AsyncMutex::new(
+        mounts
+            .into_iter()
+            .map(|(name, mount)| (name.to_owned(), mount))
+            .collect::<HashMap<_, _>>(),
+    ))
+}
+
+/// Wrap a Filesystem in a Mount with no special tuning.
+pub(crate) fn mount<FilesystemT>(filesystem: FilesystemT) -> Mount<FilesystemT> {
+    Mount {
+        filesystem,
+        max_read: None,
+    }
+}
+
+/// Client end of an in-memory connection being served by
+/// [connection_handler].
+pub(crate) struct TestConnection {
+    pub(crate) tw: TWriter,
+    pub(crate) rr: RReader,
+    pub(crate) task: JoinHandle<Result<()>>,
+}
+
+impl TestConnection {
+    /// Spawn a [connection_handler] serving the provided Context over an
+    /// in-memory duplex pipe.
+    pub(crate) fn new<FilesystemT>(ctx: Context<FilesystemT>) -> Self
+    where
+        FilesystemT: Filesystem,
+        FilesystemT: Send,
+        FilesystemT: 'static,
+    {
+        let msize = ctx.max_msize;
+        let (client, server) = tokio::io::duplex(msize as usize * 2);
+        let (sr, sw) = tokio::io::split(server);
+        let (cr, cw) = tokio::io::split(client);
+
+        let task = tokio::spawn(connection_handler(
+            ctx,
+            super::RWriter::new(Box::pin(sw), msize),
+            super::TReader::new(Box::pin(sr), msize),
+        ));
+
+        Self {
+            tw: TWriter::new(Box::pin(cw), msize),
+            rr: RReader::new(Box::pin(cr), msize),
+            task,
+        }
+    }
+
+    /// Spawn a [connection_handler] serving the provided Mounts.
+    pub(crate) fn serve<FilesystemT>(msize: u32, mounts: Mounts<FilesystemT>) -> Self
+    where
+        FilesystemT: Filesystem,
+        FilesystemT: Send,
+        FilesystemT: 'static,
+    {
+        Self::serve_with_options(msize, mounts, Options::default())
+    }
+
+    /// Spawn a [connection_handler] serving the provided Mounts, with some
+    /// non-default connection Options.
+    pub(crate) fn serve_with_options<FilesystemT>(
+        msize: u32,
+        mounts: Mounts<FilesystemT>,
+        options: Options,
+    ) -> Self
+    where
+        FilesystemT: Filesystem,
+        FilesystemT: Send,
+        FilesystemT: 'static,
+    {
+        Self::new(Context::new(
+            Peer::Tcp("127.0.0.1:564".parse().unwrap()),
+            msize,
+            mounts,
+            options,
+        ))
+    }
+
+    /// Spawn a [connection_handler] serving `filesystem` as the only
+    /// (unnamed) mount, with some non-default connection Options.
+    pub(crate) fn serve_one<FilesystemT>(filesystem: FilesystemT, options: Options) -> Self
+    where
+        FilesystemT: Filesystem,
+        FilesystemT: Send,
+        FilesystemT: 'static,
+    {
+        Self::serve_with_options(8192, mounts(vec![("", mount(filesystem))]), options)
+    }
+
+    /// Send a T message, and wait for the next R message.
+    pub(crate) async fn rpc(&mut self, t: T) -> R {
+        self.tw.send(t).await.unwrap();
+        self.rr.next().await.unwrap()
+    }
+
+    /// Spawn a [connection_handler] serving the provided Mounts, which
+    /// offers 9P2000.L as well as 9P2000.u.
+    pub(crate) fn serve_linux<FilesystemT>(msize: u32, mounts: Mounts<FilesystemT>) -> Self
+    where
+        FilesystemT: Filesystem,
+        FilesystemT: Send,
+        FilesystemT: 'static,
+    {
+        let options = Options {
+            linux: true,
+            ..Default::default()
+        };
+        Self::serve_with_options(msize, mounts, options)
+    }
+
+    /// Negotiate 9P2000.u with the server.
+    pub(crate) async fn version(&mut self, msize: u32) -> R {
+        self.version_as(msize, "9P2000.u").await
+    }
+
+    /// Negotiate `version` with the server, speaking whatever dialect it
+    /// agrees to from then on.
+    pub(crate) async fn version_as(&mut self, msize: u32, version: &str) -> R {
+        let r = self
+            .rpc(T::Version(NOTAG, msize, version.parse().unwrap()))
+            .await;
+        if let R::Version(_, _, agreed) = &r {
+            let dialect = Dialect::of(agreed).unwrap_or(Dialect::Unix);
+            self.tw.set_dialect(dialect);
+            self.rr.set_dialect(dialect);
+        }
+        r
+    }
+
+    /// Negotiate, then attach `fid` to the root of `aname`.
+    pub(crate) async fn attach(&mut self, msize: u32, fid: u32, aname: &str) -> R {
+        self.version(msize).await;
+        self.attach_only(fid, aname).await
+    }
+
+    /// Negotiate 9P2000.L, then attach `fid` to the root of `aname`.
+    pub(crate) async fn attach_linux(&mut self, msize: u32, fid: u32, aname: &str) -> R {
+        let r = self.version_as(msize, "9P2000.L").await;
+        assert!(
+            matches!(&r, R::Version(_, _, v) if v.to_string() == "9P2000.L"),
+            "{:?}",
+            r
+        );
+        self.attach_only(fid, aname).await
+    }
+
+    /// Walk `fid` to `newfid` by way of `path`, which must succeed.
+    pub(crate) async fn walk(&mut self, tag: Tag, fid: Fid, newfid: Fid, path: &[&str]) {
+        let path = path.iter().map(|name| name.to_string()).collect();
+        let r = self.rpc(T::Walk(tag, fid, newfid, path)).await;
+        assert!(matches!(r, R::Walk(rtag, _) if rtag == tag), "{:?}", r);
+    }
+
+    /// Open `fid` with `mode`, which must succeed.
+    pub(crate) async fn open(&mut self, tag: Tag, fid: Fid, mode: u8) {
+        let r = self.rpc(T::Open(tag, fid, mode.into())).await;
+        assert!(matches!(r, R::Open(rtag, _, _) if rtag == tag), "{:?}", r);
+    }
+
+    /// Attach `fid` to the root of `aname`, over an already negotiated
+    /// connection.
+    async fn attach_only(&mut self, fid: u32, aname: &str) -> R {
+        self.rpc(T::Attach(
+            1,
+            fid,
+            !0,
+            "user".to_owned(),
+            aname.to_owned(),
+            0,
+        ))
+        .await
+    }
+}
+
+/// Contents of a [TestFs], by file name.
+type TestFiles = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+/// Called with the file name before every read (or write) of a regular
+/// file, or walk to one, so that tests can stall or observe them.
+type IoHook = Arc<dyn Fn(&str) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Flat in-memory Filesystem: a root directory containing regular files.
+#[derive(Clone)]
+pub(crate) struct TestFs {
+    files: TestFiles,
+    read_hook: Option<IoHook>,
+    write_hook: Option<IoHook>,
+    walk_hook: Option<IoHook>,
+    stats: Arc<AtomicUsize>,
+    syncs: Arc<AtomicUsize>,
+    iounit: u32,
+    writer: Option<String>,
+    walks: Arc<AtomicUsize>,
+    lookups: Arc<AtomicUsize>,
+    exists_checks: Arc<AtomicUsize>,
+    exists_fast_path: bool,
+}
+
+impl TestFs {
+    /// Create a new TestFs containing the provided files.
+    pub(crate) fn new(files: &[(&str, &[u8])]) -> Self {
+        Self {
+            files: Arc::new(Mutex::new(
+                files
+                    .iter()
+                    .map(|(name, data)| (name.to_string(), data.to_vec()))
+                    .collect(),
+            )),
+            read_hook: None,
+            write_hook: None,
+            walk_hook: None,
+            stats: Arc::new(AtomicUsize::new(0)),
+            syncs: Arc::new(AtomicUsize::new(0)),
+            iounit: 0,
+            writer: None,
+            walks: Arc::new(AtomicUsize::new(0)),
+            lookups: Arc::new(AtomicUsize::new(0)),
+            exists_checks: Arc::new(AtomicUsize::new(0)),
+            exists_fast_path: false,
+        }
+    }
+
+    /// Number of times [File::stat] has been called on any file in this
+    /// TestFs.
+    pub(crate) fn stats(&self) -> usize {
+        self.stats.load(Ordering::SeqCst)
+    }
+
+    /// Number of times [File::walk] has been called on any file in this
+    /// TestFs.
+    pub(crate) fn walks(&self) -> usize {
+        self.walks.load(Ordering::SeqCst)
+    }
+
+    /// Number of times [File::walk] has gone looking for a path in the
+    /// list of files, rather than staying put.
+    pub(crate) fn lookups(&self) -> usize {
+        self.lookups.load(Ordering::SeqCst)
+    }
+
+    /// Number of names [File::exists_child] has checked against the list
+    /// of files, which it only does with [TestFs::with_exists_fast_path].
+    pub(crate) fn exists_checks(&self) -> usize {
+        self.exists_checks.load(Ordering::SeqCst)
+    }
+
+    /// Answer [File::exists_child] from the list of files, rather than
+    /// leaving it to the walk.
+    pub(crate) fn with_exists_fast_path(mut self) -> Self {
+        self.exists_fast_path = true;
+        self
+    }
+
+    /// Number of times [OpenFile::sync] has been called on any regular file
+    /// in this TestFs.
+    pub(crate) fn syncs(&self) -> usize {
+        self.syncs.load(Ordering::SeqCst)
+    }
+
+    /// Await the future returned by `hook` before every read of a regular
+    /// file.
+    pub(crate) fn with_read_hook<F, FutureT>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str) -> FutureT + Send + Sync + 'static,
+        FutureT: Future<Output = ()> + Send + 'static,
+    {
+        self.read_hook = Some(Arc::new(move |name| Box::pin(hook(name))));
+        self
+    }
+
+    /// Await the future returned by `hook` before every write to a regular
+    /// file.
+    pub(crate) fn with_write_hook<F, FutureT>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str) -> FutureT + Send + Sync + 'static,
+        FutureT: Future<Output = ()> + Send + 'static,
+    {
+        self.write_hook = Some(Arc::new(move |name| Box::pin(hook(name))));
+        self
+    }
+
+    /// Await the future returned by `hook` before every walk to a regular
+    /// file.
+    pub(crate) fn with_walk_hook<F, FutureT>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str) -> FutureT + Send + Sync + 'static,
+        FutureT: Future<Output = ()> + Send + 'static,
+    {
+        self.walk_hook = Some(Arc::new(move |name| Box::pin(hook(name))));
+        self
+    }
+
+    /// Current contents of the file `name`.
+    pub(crate) fn contents(&self, name: &str) -> Option<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        files
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, data)| data.clone())
+    }
+
+    /// Only allow the user `uname` to write to files in this TestFs; anyone
+    /// else gets EACCES.
+    pub(crate) fn with_writer(mut self, uname: &str) -> Self {
+        self.writer = Some(uname.to_owned());
+        self
+    }
+
+    /// Report `iounit` from every regular file opened in this TestFs.
+    pub(crate) fn with_iounit(mut self, iounit: u32) -> Self {
+        self.iounit = iounit;
+        self
+    }
+}
+
+impl Filesystem for TestFs {
+    type File = TestFile;
+
+    async fn attach(&self, _: &str, _: &str, _: u32) -> FileResult<TestFile> {
+        Ok(TestFile {
+            files: self.files.clone(),
+            read_hook: self.read_hook.clone(),
+            write_hook: self.write_hook.clone(),
+            walk_hook: self.walk_hook.clone(),
+            stats: self.stats.clone(),
+            syncs: self.syncs.clone(),
+            iounit: self.iounit,
+            writer: self.writer.clone(),
+            walks: self.walks.clone(),
+            lookups: self.lookups.clone(),
+            exists_checks: self.exists_checks.clone(),
+            exists_fast_path: self.exists_fast_path,
+            idx: None,
+        })
+    }
+}
+
+/// File within a [TestFs]; `idx` of None is the root directory.
+#[derive(Clone)]
+pub(crate) struct TestFile {
+    files: TestFiles,
+    read_hook: Option<IoHook>,
+    write_hook: Option<IoHook>,
+    walk_hook: Option<IoHook>,
+    stats: Arc<AtomicUsize>,
+    syncs: Arc<AtomicUsize>,
+    iounit: u32,
+    writer: Option<String>,
+    walks: Arc<AtomicUsize>,
+    lookups: Arc<AtomicUsize>,
+    exists_checks: Arc<AtomicUsize>,
+    exists_fast_path: bool,
+    idx: Option<usize>,
+}
+
+impl TestFile {
+    /// The regular file at `idx`.
+    fn child(&self, idx: usize) -> Self {
+        Self {
+            idx: Some(idx),
+            ..self.clone()
+        }
+    }
+
+    /// Open the file, listing the root in the provided [Dialect].
+    async fn open_as(&mut self, mode: OpenMode, dialect: Dialect) -> FileResult<TestOpenFile> {
+        match self.idx {
+            Some(idx) => Ok(TestOpenFile::File(self.clone(), idx)),
+            None => {
+                match mode.direction() {
+                    IoDirection::Read => {}
+                    _ => return Err(FileError(21, "EISDIR".to_owned())),
+                }
+                let len = self.files.lock().unwrap().len();
+                let mut ent = Cursor::new(vec![]);
+                for idx in 0..len {
+                    let stat = self.child(idx).stat().await?;
+                    stat.dehydrate_as(&mut ent, dialect).unwrap();
+                }
+                Ok(TestOpenFile::Dir(ent.into_inner()))
+            }
+        }
+    }
+}
+
+impl File for TestFile {
+    type OpenFile = TestOpenFile;
+
+    async fn stat(&self) -> FileResult<Stat> {
+        self.stats.fetch_add(1, Ordering::SeqCst);
+        let files = self.files.lock().unwrap();
+        Ok(match self.idx {
+            None => Stat::builder("/", self.qid()).with_mode(0o755).build(),
+            Some(idx) => Stat::builder(&files[idx].0, self.qid())
+                .with_mode(0o644)
+                .with_size(files[idx].1.len() as u64)
+                .build(),
+        })
+    }
+
+    async fn wstat(&mut self, _: &Stat) -> FileResult<()> {
+        Err(FileError(1, "EPERM".to_owned()))
+    }
+
+    async fn walk(&self, path: &[&str]) -> FileResult<(FileResult<Self>, Vec<Self>)> {
+        self.walks.fetch_add(1, Ordering::SeqCst);
+        if path.is_empty() {
+            return Ok((Ok(self.clone()), vec![]));
+        }
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        if self.idx.is_some() || path.len() != 1 {
+            return Ok((Err(enoent()), vec![]));
+        }
+        if let Some(hook) = &self.walk_hook {
+            hook(path[0]).await;
+        }
+
+        let files = self.files.lock().unwrap();
+        match files.iter().position(|(name, _)| name == path[0]) {
+            Some(idx) => {
+                let file = self.child(idx);
+                Ok((Ok(file.clone()), vec![file]))
+            }
+            None => Ok((Err(enoent()), vec![])),
+        }
+    }
+
+    async fn exists_child(&self, name: &str) -> FileResult<bool> {
+        if !self.exists_fast_path || self.idx.is_some() {
+            return Ok(true);
+        }
+        self.exists_checks.fetch_add(1, Ordering::SeqCst);
+        let files = self.files.lock().unwrap();
+        Ok(files.iter().any(|(existing, _)| existing == name))
+    }
+
+    async fn unlink(&mut self) -> FileResult<()> {
+        Err(FileError(1, "EPERM".to_owned()))
+    }
+
+    async fn create(
+        &mut self,
+        name: &str,
+        _: u16,
+        ty: FileType,
+        _: OpenMode,
+        _: &str,
+    ) -> FileResult<Self> {
+        if self.idx.is_some() || ty != FileType::File {
+            return Err(FileError(1, "EPERM".to_owned()));
+        }
+        let mut files = self.files.lock().unwrap();
+        if files.iter().any(|(existing, _)| existing == name) {
+            return Err(FileError(17, "EEXIST".to_owned()));
+        }
+        files.push((name.to_owned(), vec![]));
+        Ok(self.child(files.len() - 1))
+    }
+
+    async fn open(&mut self, mode: OpenMode) -> FileResult<TestOpenFile> {
+        self.open_as(mode, Dialect::Unix).await
+    }
+
+    async fn open_with_context(
+        &mut self,
+        ctx: &OpContext<'_>,
+        mode: OpenMode,
+    ) -> FileResult<TestOpenFile> {
+        self.open_as(mode, ctx.dialect).await
+    }
+
+    async fn xattr_get(&self, name: &str) -> FileResult<Vec<u8>> {
+        // files have a single, synthetic, "user.name" extended attribute.
+        let Some(idx) = self.idx else {
+            return Err(FileError(61, "ENODATA".to_owned()));
+        };
+        match name {
+            "" => Ok(b"user.name\0".to_vec()),
+            "user.name" => Ok(self.files.lock().unwrap()[idx].0.as_bytes().to_vec()),
+            _ => Err(FileError(61, "ENODATA".to_owned())),
+        }
+    }
+
+    fn qid(&self) -> Qid {
+        match self.idx {
+            None => Qid::new(FileType::Dir, 0, 1),
+            Some(idx) => Qid::new(FileType::File, 0, 2 + idx as u64),
+        }
+    }
+}
+
+/// Open handle to a [TestFile].
+pub(crate) enum TestOpenFile {
+    /// Serialized directory listing.
+    Dir(Vec<u8>),
+
+    /// Regular file, by index.
+    File(TestFile, usize),
+}
+
+fn enoent() -> FileError {
+    FileError(2, "ENOENT".to_owned())
+}
+
+fn read_from(data: &[u8], buf: &mut [u8], offset: u64) -> u32 {
+    let offset = (offset as usize).min(data.len());
+    let n = buf.len().min(data.len() - offset);
+    buf[..n].copy_from_slice(&data[offset..offset + n]);
+    n as u32
+}
+
+impl OpenFile for TestOpenFile {
+    fn iounit(&self) -> u32 {
+        match self {
+            Self::Dir(_) => 0,
+            Self::File(file, _) => file.iounit,
+        }
+    }
+
+    async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> FileResult<u32> {
+        match self {
+            Self::Dir(data) => Ok(read_from(data, buf, offset)),
+            Self::File(file, idx) => {
+                if let Some(hook) = &file.read_hook {
+                    let name = file.files.lock().unwrap()[*idx].0.clone();
+                    hook(&name).await;
+                }
+                Ok(read_from(&file.files.lock().unwrap()[*idx].1, buf, offset))
+            }
+        }
+    }
+
+    async fn stat_hint(&self) -> FileResult<Option<Stat>> {
+        Ok(match self {
+            Self::Dir(_) => None,
+            Self::File(file, idx) => {
+                let files = file.files.lock().unwrap();
+                let (name, data) = &files[*idx];
+                let qid = Qid::new(FileType::File, 0, 2 + *idx as u64);
+                Some(
+                    Stat::builder(name, qid)
+                        .with_mode(0o644)
+                        .with_size(data.len() as u64)
+                        .build(),
+                )
+            }
+        })
+    }
+
+    async fn write_at(&mut self, buf: &mut [u8], offset: u64) -> FileResult<u32> {
+        match self {
+            Self::Dir(_) => Err(FileError(21, "EISDIR".to_owned())),
+            Self::File(file, idx) => {
+                if let Some(hook) = &file.write_hook {
+                    let name = file.files.lock().unwrap()[*idx].0.clone();
+                    hook(&name).await;
+                }
+                let mut files = file.files.lock().unwrap();
+                let data = &mut files[*idx].1;
+                let end = offset as usize + buf.len();
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[offset as usize..end].copy_from_slice(buf);
+                Ok(buf.len() as u32)
+            }
+        }
+    }
+
+    async fn write_at_with_context(
+        &mut self,
+        ctx: &OpContext<'_>,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> FileResult<u32> {
+        if let Self::File(file, _) = self {
+            if matches!(&file.writer, Some(writer) if writer != ctx.session.uname()) {
+                return Err(FileError(13, "EACCES".to_owned()));
+            }
+        }
+        self.write_at(buf, offset).await
+    }
+
+    async fn sync(&mut self) -> FileResult<()> {
+        if let Self::File(file, _) = self {
+            file.syncs.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+/// Walk behavior for a [ScriptedFs]: given the requested path, return the
+/// qids of the final file and of each file traversed.
+type WalkScript = Arc<dyn Fn(&[&str]) -> FileResult<(FileResult<Qid>, Vec<Qid>)> + Send + Sync>;
+
+/// Filesystem whose walk results are scripted by the test, for exercising
+/// how the server copes with misbehaving filesystems.
+#[derive(Clone)]
+pub(crate) struct ScriptedFs {
+    walk: WalkScript,
+}
+
+impl ScriptedFs {
+    /// Create a new ScriptedFs which walks using the provided closure.
+    pub(crate) fn new<F>(walk: F) -> Self
+    where
+        F: Fn(&[&str]) -> FileResult<(FileResult<Qid>, Vec<Qid>)> + Send + Sync + 'static,
+    {
+        Self {
+            walk: Arc::new(walk),
+        }
+    }
+}
+
+impl Filesystem for ScriptedFs {
+    type File = ScriptedFile;
+
+    async fn attach(&self, _: &str, _: &str, _: u32) -> FileResult<ScriptedFile> {
+        Ok(ScriptedFile {
+            walk: self.walk.clone(),
+            qid: Qid::new(FileType::Dir, 0, 1),
+        })
+    }
+}
+
+/// File within a [ScriptedFs], which is nothing more than its Qid.
+#[derive(Clone)]
+pub(crate) struct ScriptedFile {
+    walk: WalkScript,
+    qid: Qid,
+}
+
+impl File for ScriptedFile {
+    type OpenFile = TestOpenFile;
+
+    async fn stat(&self) -> FileResult<Stat> {
+        Ok(Stat::builder("scripted", self.qid()).build())
+    }
+
+    async fn wstat(&mut self, _: &Stat) -> FileResult<()> {
+        Err(FileError(1, "EPERM".to_owned()))
+    }
+
+    async fn walk(&self, path: &[&str]) -> FileResult<(FileResult<Self>, Vec<Self>)> {
+        let (file, files) = (self.walk)(path)?;
+        let wrap = |qid| Self {
+            walk: self.walk.clone(),
+            qid,
+        };
+        Ok((file.map(wrap), files.into_iter().map(wrap).collect()))
+    }
+
+    async fn unlink(&mut self) -> FileResult<()> {
+        Err(FileError(1, "EPERM".to_owned()))
+    }
+
+    async fn create(
+        &mut self,
+        _: &str,
+        _: u16,
+        _: FileType,
+        _: OpenMode,
+        _: &str,
+    ) -> FileResult<Self> {
+        Err(FileError(1, "EPERM".to_owned()))
+    }
+
+    async fn open(&mut self, _: OpenMode) -> FileResult<TestOpenFile> {
+        Ok(TestOpenFile::Dir(vec![]))
+    }
+
+    fn qid(&self) -> Qid {
+        self.qid.clone()
+    }
+}
+
+// vim: foldmethod=marker
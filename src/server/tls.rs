@@ -0,0 +1,120 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! TLS around every connection, so that a 9p export can be served across a
+//! network that isn't trusted.
+
+use super::{
+    aio::{AsyncRead, AsyncWrite},
+    clock::expire,
+    select::{select, Either},
+    Clock,
+};
+use std::{
+    io::{Error, ErrorKind, Result},
+    sync::Arc,
+    time::Duration,
+};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+/// Complete the TLS handshake with the client on `read` and `write`,
+/// returning the two halves of the decrypted stream to run the 9p
+/// connection over. A client which hasn't finished the handshake within
+/// `timeout` (by `clock`) is hung up on.
+pub(crate) async fn accept(
+    config: Arc<ServerConfig>,
+    clock: &dyn Clock,
+    timeout: Option<Duration>,
+    read: AsyncRead,
+    write: AsyncWrite,
+) -> Result<(AsyncRead, AsyncWrite)> {
+    let handshake = TlsAcceptor::from(config).accept(tokio::io::join(read, write));
+    let stream = match select(handshake, expire(clock, timeout)).await {
+        Either::Left(stream) => stream?,
+        Either::Right(()) => {
+            return Err(Error::new(ErrorKind::TimedOut, "tls: handshake timed out"))
+        }
+    };
+    let (read, write) = tokio::io::split(stream);
+    Ok((Box::pin(read), Box::pin(write)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::accept;
+    use crate::server::{testing::block_on, MockClock};
+    use std::{io::ErrorKind, sync::Arc, time::Duration};
+    use tokio_rustls::rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    };
+
+    /// Resolver with no certificate to offer; the handshakes under test
+    /// never get as far as needing one.
+    #[derive(Debug)]
+    struct NoCert;
+
+    impl ResolvesServerCert for NoCert {
+        fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            None
+        }
+    }
+
+    #[test]
+    fn handshake_deadline() {
+        block_on(async {
+            let clock = MockClock::default();
+            let config = ServerConfig::builder()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(NoCert));
+
+            // a client that never sends a ClientHello is hung up on.
+            let (_client, server) = tokio::io::duplex(1024);
+            let (read, write) = tokio::io::split(server);
+            let accepting = tokio::spawn({
+                let clock = clock.clone();
+                async move {
+                    let timeout = Some(Duration::from_secs(30));
+                    accept(
+                        Arc::new(config),
+                        &clock,
+                        timeout,
+                        Box::pin(read),
+                        Box::pin(write),
+                    )
+                    .await
+                    .map(|_| ())
+                }
+            });
+            while clock.sleepers() == 0 {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_secs(29));
+            tokio::task::yield_now().await;
+            assert!(!accepting.is_finished());
+            clock.advance(Duration::from_secs(1));
+            let err = accepting.await.unwrap().unwrap_err();
+            assert_eq!(ErrorKind::TimedOut, err.kind());
+        });
+    }
+}
+
+// vim: foldmethod=marker
@@ -18,26 +18,182 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE. }}}
 
-use crate::raw::{FileType, OpenMode, Qid, Stat};
-use std::future::Future;
+use super::{Clock, Peer, PeerCred, Session};
+use crate::raw::{Dialect, FileType, OpenMode, Qid, Stat, StatFs, Version};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Underlying cause of a [FileError].
+type Source = Box<dyn std::error::Error + Send + Sync>;
 
 /// 9P Error, numerical code and description as defined by the
-/// 9P UNIX variant.
+/// 9P UNIX variant, along with whatever error caused it, if any. Only the
+/// code and description are sent to the client; the source is for logging.
+///
+/// These are created with [FileError()], as in
+/// `FileError(2, "ENOENT".to_owned())`.
 #[derive(Debug)]
-pub struct FileError(pub u32, pub String);
+pub struct FileError {
+    /// Numeric error code (errno).
+    pub errno: u32,
+
+    /// Description of the error, sent along with the errno.
+    pub description: String,
+
+    source: Option<Source>,
+    fatal: bool,
+}
+
+/// Create a new [FileError] with the provided errno and description, and no
+/// source.
+#[allow(non_snake_case)]
+pub fn FileError(errno: u32, description: String) -> FileError {
+    FileError {
+        errno,
+        description,
+        source: None,
+        fatal: false,
+    }
+}
+
+impl FileError {
+    /// Attach the error which caused this one, to be returned by
+    /// [std::error::Error::source].
+    pub fn with_source<ErrorT>(mut self, source: ErrorT) -> Self
+    where
+        ErrorT: Into<Source>,
+    {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Mark this error as fatal to the whole server, rather than to just the
+    /// one request: for when the backing store is gone, and there is no
+    /// point in carrying on. The error is still sent to the client, and
+    /// then the server stops accepting connections and hangs up on every
+    /// connected peer, as with [crate::server::ServerHandle::shutdown].
+    pub fn fatal(mut self) -> Self {
+        self.fatal = true;
+        self
+    }
+
+    /// Whether this error was marked with [FileError::fatal].
+    pub fn is_fatal(&self) -> bool {
+        self.fatal
+    }
+}
+
+/// Name of a (Linux) errno, such as "ENOENT" for 2, as used for the
+/// description of an Rerror which came without one.
+pub(crate) fn errno_name(errno: u32) -> Option<&'static str> {
+    Some(match errno {
+        1 => "EPERM",
+        2 => "ENOENT",
+        4 => "EINTR",
+        5 => "EIO",
+        6 => "ENXIO",
+        9 => "EBADF",
+        11 => "EAGAIN",
+        12 => "ENOMEM",
+        13 => "EACCES",
+        16 => "EBUSY",
+        17 => "EEXIST",
+        18 => "EXDEV",
+        19 => "ENODEV",
+        20 => "ENOTDIR",
+        21 => "EISDIR",
+        22 => "EINVAL",
+        23 => "ENFILE",
+        24 => "EMFILE",
+        26 => "ETXTBSY",
+        27 => "EFBIG",
+        28 => "ENOSPC",
+        29 => "ESPIPE",
+        30 => "EROFS",
+        31 => "EMLINK",
+        32 => "EPIPE",
+        34 => "ERANGE",
+        36 => "ENAMETOOLONG",
+        38 => "ENOSYS",
+        39 => "ENOTEMPTY",
+        40 => "ELOOP",
+        61 => "ENODATA",
+        71 => "EPROTO",
+        75 => "EOVERFLOW",
+        77 => "EBADFD",
+        84 => "EILSEQ",
+        90 => "EMSGSIZE",
+        93 => "EPROTONOSUPPORT",
+        95 => "EOPNOTSUPP",
+        110 => "ETIMEDOUT",
+        111 => "ECONNREFUSED",
+        114 => "EALREADY",
+        122 => "EDQUOT",
+        125 => "ECANCELED",
+        _ => return None,
+    })
+}
+
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (errno {})", self.description, self.errno)
+    }
+}
+
+impl std::error::Error for FileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_deref().map(|e| e as _)
+    }
+}
 
 impl From<std::io::Error> for FileError {
     fn from(e: std::io::Error) -> Self {
-        match e.raw_os_error() {
+        let fe = match e.raw_os_error() {
             Some(ose) => FileError(ose as u32, format!("{:?}", e)),
             None => FileError(0, "".to_owned()),
-        }
+        };
+        fe.with_source(e)
     }
 }
 
+/// Entries of a directory, produced as they are read rather than all at
+/// once. This is the usual Stream shape, spelled out here rather than
+/// pulling in a dependency for it.
+pub trait DirStream {
+    /// Poll for the next entry, returning `Ready(None)` once there are no
+    /// more.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<FileResult<Stat>>>;
+}
+
+/// Boxed [DirStream], as returned by [File::readdir_stream].
+pub type DirEntries = Pin<Box<dyn DirStream + Send>>;
+
+/// [DirStream] over entries which are already at hand.
+pub struct IterDirStream<IterT>(pub IterT);
+
+impl<IterT> DirStream for IterDirStream<IterT>
+where
+    IterT: Iterator<Item = FileResult<Stat>> + Unpin,
+{
+    fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<FileResult<Stat>>> {
+        Poll::Ready(self.0.next())
+    }
+}
+
+/// Wait for the next entry of a [DirStream].
+pub(crate) async fn next_entry(entries: &mut DirEntries) -> Option<FileResult<Stat>> {
+    std::future::poll_fn(|cx| entries.as_mut().poll_next(cx)).await
+}
+
 /// Handle to an open file.
 pub trait OpenFile {
-    /// Negotiated iounit.
+    /// Largest read or write which is guaranteed to be done in one go
+    /// (atomically). Returning 0 leaves it to the server, which reports the
+    /// msize less [crate::raw::IOHDRSZ]; anything larger than that is
+    /// brought down to it.
     fn iounit(&self) -> u32;
 
     /// Read the file at some particular offset.
@@ -53,6 +209,33 @@ pub trait OpenFile {
         buf: &mut [u8],
         offset: u64,
     ) -> impl Future<Output = FileResult<u32>> + Send;
+
+    /// Write the file at some particular offset, with information about who
+    /// is asking, so that each write can be authorized. This is what the
+    /// server calls; by default it calls [OpenFile::write_at].
+    fn write_at_with_context(
+        &mut self,
+        _ctx: &OpContext<'_>,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> impl Future<Output = FileResult<u32>> + Send {
+        self.write_at(buf, offset)
+    }
+
+    /// Metadata about the open file, if the OpenFile can provide it more
+    /// cheaply or more accurately than [File::stat] (the current size of a
+    /// growing file, for instance). A Tstat against an open fid uses this
+    /// when it returns Some; by default it returns None.
+    fn stat_hint(&self) -> impl Future<Output = FileResult<Option<Stat>>> + Send {
+        std::future::ready(Ok(None))
+    }
+
+    /// Flush any buffered state of the open file to stable storage. This is
+    /// requested by a Tfsync, or by a Twstat whose every field is "don't
+    /// touch" (see stat(5)); by default it does nothing.
+    fn sync(&mut self) -> impl Future<Output = FileResult<()>> + Send {
+        std::future::ready(Ok(()))
+    }
 }
 
 /// Trait to be implemented by a File returned by some Filesystem.
@@ -71,12 +254,32 @@ where
     fn wstat(&mut self, s: &Stat) -> impl Future<Output = FileResult<()>> + Send;
 
     /// Walk will navigate from self (must be a directory) to some specific
-    /// path in relation to `self`. This returns the ending file (if no Error
-    /// state was hit), as well as any files traversed along the way.
+    /// path in relation to `self`. This returns the ending file, or the
+    /// reason the walk stopped short of it (ENOENT for a missing component,
+    /// EACCES for one that may not be searched, and so on), as well as any
+    /// files traversed along the way.
+    ///
+    /// If the walk failed on the very first component, the server replies
+    /// with that error; otherwise the client gets the partial walk, as
+    /// walk(5) says it must. An `Err` from the outer result fails the whole
+    /// request.
     fn walk(
         &self,
         path: &[&str],
-    ) -> impl Future<Output = FileResult<(Option<Self>, Vec<Self>)>> + Send;
+    ) -> impl Future<Output = FileResult<(FileResult<Self>, Vec<Self>)>> + Send;
+
+    /// Check whether this directory might contain `name`, without the cost
+    /// of a [File::walk]. Before walking, the server asks about the first
+    /// element of the path, and when this returns false, replies as if the
+    /// walk had failed there without walking at all. Returning true only
+    /// means the walk goes ahead as usual, which is what the default does.
+    ///
+    /// Only the first element is ever checked: the directories holding the
+    /// rest of the path don't exist as Files until the walk gets to them,
+    /// so a missing name further along is left for the walk to find.
+    fn exists_child(&self, _name: &str) -> impl Future<Output = FileResult<bool>> + Send {
+        std::future::ready(Ok(true))
+    }
 
     /// remove the file
     fn unlink(&mut self) -> impl Future<Output = FileResult<()>> + Send;
@@ -94,6 +297,115 @@ where
     /// Open the file.
     fn open(&mut self, mode: OpenMode) -> impl Future<Output = FileResult<Self::OpenFile>> + Send;
 
+    /// Like [File::create], with information about who is asking. This is
+    /// what the server calls; by default it calls [File::create].
+    fn create_with_context(
+        &mut self,
+        _ctx: &OpContext<'_>,
+        name: &str,
+        perm: u16,
+        ty: FileType,
+        mode: OpenMode,
+        extension: &str,
+    ) -> impl Future<Output = FileResult<Self>> + Send {
+        self.create(name, perm, ty, mode, extension)
+    }
+
+    /// Like [File::open], with information about who is asking. This is
+    /// what the server calls; by default it calls [File::open]. Directories
+    /// should lay their listing out for [OpContext::dialect], since
+    /// 9P2000 peers can't parse the 9P2000.u Stat.
+    fn open_with_context(
+        &mut self,
+        _ctx: &OpContext<'_>,
+        mode: OpenMode,
+    ) -> impl Future<Output = FileResult<Self::OpenFile>> + Send {
+        self.open(mode)
+    }
+
+    /// List the entries of this directory, without having to open it and
+    /// parse what it reads back. Directory-backed files should implement
+    /// this; the Filesystems in [crate::fs] build the listing they return
+    /// from [File::open] out of it. By default, this fails with ENOTDIR.
+    fn readdir(&self) -> impl Future<Output = FileResult<Vec<Stat>>> + Send {
+        std::future::ready(Err(FileError(20, "ENOTDIR".to_owned())))
+    }
+
+    /// Like [File::readdir], but producing the entries one at a time, so
+    /// that neither the whole listing nor its serialized form needs to be
+    /// held in memory, as with [crate::fs::DirReader]. By default, this
+    /// streams the result of [File::readdir].
+    fn readdir_stream(&self) -> impl Future<Output = FileResult<DirEntries>> + Send {
+        let readdir = self.readdir();
+        async move {
+            let entries: DirEntries = Box::pin(IterDirStream(readdir.await?.into_iter().map(Ok)));
+            Ok(entries)
+        }
+    }
+
+    /// Create a directory named `name` within this directory, owned by
+    /// `gid`, as asked for by a 9P2000.L Tmkdir. By default, this is refused
+    /// with EPERM.
+    fn mkdir(
+        &mut self,
+        _name: &str,
+        _mode: u32,
+        _gid: u32,
+    ) -> impl Future<Output = FileResult<Qid>> + Send {
+        std::future::ready(Err(FileError(1, "EPERM".to_owned())))
+    }
+
+    /// Remove the file named `name` from this directory, as asked for by a
+    /// 9P2000.L Tunlinkat. `flags` may contain `AT_REMOVEDIR` (0x200). By
+    /// default, this is refused with EPERM.
+    fn unlink_at(
+        &mut self,
+        _name: &str,
+        _flags: u32,
+    ) -> impl Future<Output = FileResult<()>> + Send {
+        std::future::ready(Err(FileError(1, "EPERM".to_owned())))
+    }
+
+    /// Create a symlink named `name` pointing to `target` within this
+    /// directory, owned by `gid`, as asked for by a 9P2000.L Tsymlink. By
+    /// default, this is refused with EPERM.
+    fn symlink(
+        &mut self,
+        _name: &str,
+        _target: &str,
+        _gid: u32,
+    ) -> impl Future<Output = FileResult<Qid>> + Send {
+        std::future::ready(Err(FileError(1, "EPERM".to_owned())))
+    }
+
+    /// Create a hard link named `name` within this directory to `target`,
+    /// as asked for by a 9P2000.L Tlink. Both names should then refer to
+    /// the same file, with the same qid. By default, this is refused with
+    /// EPERM.
+    fn link(&mut self, _target: &Self, _name: &str) -> impl Future<Output = FileResult<()>> + Send {
+        std::future::ready(Err(FileError(1, "EPERM".to_owned())))
+    }
+
+    /// Get the value of the extended attribute `name`, as asked for by a
+    /// 9P2000.L Txattrwalk. An empty `name` asks for the names of every
+    /// extended attribute, each followed by a NUL. By default, this is
+    /// refused with ENOTSUP.
+    fn xattr_get(&self, _name: &str) -> impl Future<Output = FileResult<Vec<u8>>> + Send {
+        std::future::ready(Err(FileError(95, "ENOTSUP".to_owned())))
+    }
+
+    /// Set the extended attribute `name` to `value`, with the setxattr(2)
+    /// `flags`, once the client has written all of it to the fid of a
+    /// 9P2000.L Txattrcreate. By default, this is refused with ENOTSUP.
+    fn xattr_set(
+        &mut self,
+        _name: &str,
+        _value: &[u8],
+        _flags: u32,
+    ) -> impl Future<Output = FileResult<()>> + Send {
+        std::future::ready(Err(FileError(95, "ENOTSUP".to_owned())))
+    }
+
     /// sync (not async)
     fn qid(&self) -> Qid;
 }
@@ -104,6 +416,69 @@ pub type FileResult<RetT> = Result<RetT, FileError>;
 /// Result used by the Filesystem trait.
 pub type FilesystemResult<RetT> = Result<RetT, FileError>;
 
+/// Everything known about a peer at the time it attaches to a Filesystem.
+#[derive(Debug, Clone)]
+pub struct AttachContext<'a> {
+    /// Name of the filesystem being attached to.
+    pub aname: &'a str,
+
+    /// Name of the user attaching. If the server has been configured to
+    /// trust peer credentials, this is the peer's uid.
+    pub uname: &'a str,
+
+    /// Numerical id of the user attaching. If the server has been configured
+    /// to trust peer credentials, this is the peer's uid.
+    pub nuname: u32,
+
+    /// Peer making the request.
+    pub peer: &'a Peer,
+
+    /// msize negotiated with the peer.
+    pub msize: u32,
+
+    /// Version negotiated with the peer.
+    pub version: &'a Version,
+
+    /// Server's source of time. Filesystems should take atimes, mtimes and
+    /// the like from this, so that they agree with the server's timeouts
+    /// (and with a [crate::server::MockClock] under test).
+    pub clock: &'a dyn Clock,
+}
+
+impl AttachContext<'_> {
+    /// Kernel-reported credentials of the peer, if any. This is only
+    /// available for peers connected over a UNIX socket.
+    pub fn peer_cred(&self) -> Option<PeerCred> {
+        self.peer.cred()
+    }
+}
+
+/// Everything known about the peer on whose behalf an operation on an
+/// attached file is being made.
+#[derive(Debug, Clone)]
+pub struct OpContext<'a> {
+    /// Session the fid being operated on was attached under.
+    pub session: &'a Session,
+
+    /// Peer making the request.
+    pub peer: &'a Peer,
+
+    /// Dialect spoken over the connection, which decides how a directory
+    /// listing is laid out (see [crate::fs::DirReader::new_as]).
+    pub dialect: Dialect,
+
+    /// Server's source of time; see [AttachContext::clock].
+    pub clock: &'a dyn Clock,
+}
+
+impl OpContext<'_> {
+    /// Kernel-reported credentials of the peer, if any. This is only
+    /// available for peers connected over a UNIX socket.
+    pub fn peer_cred(&self) -> Option<PeerCred> {
+        self.peer.cred()
+    }
+}
+
 /// Filesystem represents a collection of files which may be accessed
 /// by some peer.
 pub trait Filesystem {
@@ -112,12 +487,74 @@ pub trait Filesystem {
 
     /// Create a new connection to this filesystem for some peer,
     /// returning an open file descriptor at the root directory.
+    ///
+    /// This is the place to do any access control on the tree as a whole;
+    /// returning an Err (such as `FileError(13, "EACCES".to_owned())`) will
+    /// send that error back to the client as an Rerror. The connection is
+    /// left intact, so the client is free to attach to some other tree.
+    ///
+    /// The root need not be the same File for everyone; the server only
+    /// ever asks the returned File for its qid, so handing each user their
+    /// own home directory (with its own qid) as the root works as expected.
     fn attach(
         &self,
         aname: &str,
         uname: &str,
         nuname: u32,
     ) -> impl Future<Output = FilesystemResult<Self::File>> + Send;
+
+    /// Create a new connection to this filesystem for some peer, with full
+    /// information about the peer, returning an open file descriptor at the
+    /// root directory. This is what the server calls; by default this calls
+    /// [Filesystem::attach].
+    fn attach_with_context(
+        &self,
+        ctx: &AttachContext<'_>,
+    ) -> impl Future<Output = FilesystemResult<Self::File>> + Send {
+        self.attach(ctx.aname, ctx.uname, ctx.nuname)
+    }
+
+    /// Like [Filesystem::attach_with_context], but may also return the Stat
+    /// of the root directory, for backends which learn it for free while
+    /// attaching. If provided, it is used to answer the first Tstat against
+    /// the attached fid, without calling [File::stat]. By default, no Stat
+    /// is returned.
+    fn attach_with_stat(
+        &self,
+        ctx: &AttachContext<'_>,
+    ) -> impl Future<Output = FilesystemResult<(Self::File, Option<Stat>)>> + Send {
+        let attach = self.attach_with_context(ctx);
+        async move { Ok((attach.await?, None)) }
+    }
+
+    /// Return information about the filesystem as a whole, as asked for by
+    /// a 9P2000.L Tstatfs against any fid attached to it. By default, this
+    /// is [StatFs::default], which describes a roomy, but entirely made up,
+    /// filesystem.
+    fn statfs(&self) -> impl Future<Output = FilesystemResult<StatFs>> + Send {
+        async { Ok(StatFs::default()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileError;
+    use std::{error::Error, io};
+
+    #[test]
+    fn file_error_source() {
+        let fe: FileError = io::Error::from_raw_os_error(2).into();
+        assert_eq!(2, fe.errno);
+        let source = fe.source().unwrap();
+        let io = source.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io::ErrorKind::NotFound, io.kind());
+
+        let fe = FileError(5, "EIO".to_owned());
+        assert!(fe.source().is_none());
+        assert_eq!("EIO (errno 5)", fe.to_string());
+        let fe = fe.with_source(io::Error::other("disk on fire"));
+        assert_eq!("disk on fire", fe.source().unwrap().to_string());
+    }
 }
 
 // vim: foldmethod=marker
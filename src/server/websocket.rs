@@ -0,0 +1,373 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! 9p over WebSocket (RFC 6455), for browser-based clients. Binary messages
+//! in either direction carry the 9p byte stream as-is: a message may hold a
+//! single 9p frame, several of them, or only part of one. The WebSocket
+//! protocol itself is left to tungstenite.
+
+use super::{
+    aio::{AsyncRead, AsyncWrite},
+    select::{select, Either},
+    Clock,
+};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use std::{
+    io::{Error, ErrorKind, Result},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, Join, ReadHalf, WriteHalf};
+use tokio_tungstenite::{
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error as WsError, Message,
+    },
+    WebSocketStream,
+};
+
+/// Longest a client may take over the opening handshake, before it has
+/// sent a single 9p message.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Size of the buffers used to move data between the WebSocket and the 9p
+/// connection.
+const BUFFER: usize = 8192;
+
+type Socket = WebSocketStream<Join<AsyncRead, AsyncWrite>>;
+
+/// Complete the WebSocket opening handshake on `read` and `write`, returning
+/// a plain byte stream to run the 9p connection over. A client which hasn't
+/// upgraded within [HANDSHAKE_TIMEOUT] (by `clock`) is hung up on. The
+/// WebSocket framing is handled by a task that runs until either side hangs
+/// up.
+pub(crate) async fn accept(
+    clock: Arc<dyn Clock>,
+    read: AsyncRead,
+    write: AsyncWrite,
+) -> Result<(AsyncRead, AsyncWrite)> {
+    let handshake = tokio_tungstenite::accept_async(tokio::io::join(read, write));
+    let socket = match select(handshake, clock.sleep(HANDSHAKE_TIMEOUT)).await {
+        Either::Left(socket) => socket.map_err(io_error)?,
+        Either::Right(()) => {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "websocket: handshake timed out",
+            ))
+        }
+    };
+
+    let (ours, theirs) = tokio::io::duplex(BUFFER);
+    let (from_server, to_server) = tokio::io::split(ours);
+    let (sink, stream) = socket.split();
+    tokio::spawn(async move {
+        let result = select(inbound(stream, to_server), outbound(from_server, sink)).await;
+        if let Either::Left(Err(e)) | Either::Right(Err(e)) = result {
+            tracing::debug!("websocket closed: {e}");
+        }
+    });
+
+    let (read, write) = tokio::io::split(theirs);
+    Ok((Box::pin(read), Box::pin(write)))
+}
+
+fn io_error(e: WsError) -> Error {
+    match e {
+        WsError::Io(e) => e,
+        e => Error::new(ErrorKind::InvalidData, format!("websocket: {e}")),
+    }
+}
+
+/// Move data from the client's binary messages to the 9p connection.
+/// Control frames are answered by tungstenite as the stream is polled, so
+/// this carries on until the closing handshake is done.
+async fn inbound(
+    mut stream: SplitStream<Socket>,
+    mut to_server: WriteHalf<DuplexStream>,
+) -> Result<()> {
+    while let Some(message) = stream.next().await {
+        match message.map_err(io_error)? {
+            Message::Binary(data) => to_server.write_all(&data).await?,
+            Message::Text(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "websocket: text message",
+                ))
+            }
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_) | Message::Frame(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Move replies from the 9p connection to the client, closing the
+/// WebSocket once the connection is done.
+async fn outbound(
+    mut from_server: ReadHalf<DuplexStream>,
+    mut sink: SplitSink<Socket, Message>,
+) -> Result<()> {
+    let mut buf = vec![0; BUFFER];
+    loop {
+        let n = from_server.read(&mut buf).await?;
+        if n == 0 {
+            let close = CloseFrame {
+                code: CloseCode::Normal,
+                reason: "".into(),
+            };
+            return sink
+                .send(Message::Close(Some(close)))
+                .await
+                .map_err(io_error);
+        }
+        sink.send(Message::Binary(buf[..n].to_vec()))
+            .await
+            .map_err(io_error)?;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::{
+        raw::{Dehydrate, R, T},
+        server::{
+            testing::{block_on, socket_path, TestFs},
+            AsyncServer, MockClock, RReader,
+        },
+    };
+    use std::{
+        io::Cursor,
+        path::PathBuf,
+        time::{Duration, UNIX_EPOCH},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+    };
+
+    const UPGRADE: &str = "GET /9p HTTP/1.1\r\n\
+                           Host: localhost\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: Upgrade\r\n\
+                           Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                           Sec-WebSocket-Version: 13\r\n\r\n";
+
+    /// Serve WebSockets on a fresh UNIX socket named for `name`, timing
+    /// handshakes out by `clock`.
+    async fn serve(name: &str, clock: MockClock) -> PathBuf {
+        let path = socket_path(&format!("websocket-{name}.sock"));
+        let srv = AsyncServer::builder()
+            .with_unix_listen_address(&path)
+            .with_websocket(true)
+            .with_clock(clock)
+            .with_filesystem("", TestFs::new(&[]))
+            .build()
+            .await
+            .unwrap();
+        tokio::spawn(async move { srv.serve().await });
+        path
+    }
+
+    /// Send `payload` to the server as a single masked frame.
+    async fn send_frame(stream: &mut UnixStream, head: u8, payload: &[u8]) {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![head, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        stream.write_all(&frame).await.unwrap();
+    }
+
+    /// Read a single frame from the server, returning its opcode and payload.
+    async fn recv_frame(stream: &mut UnixStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).await.unwrap();
+        assert_eq!(0, head[1] & 0x80);
+        let len = match head[1] {
+            126 => stream.read_u16().await.unwrap() as usize,
+            n => n as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    fn encode(t: T) -> Vec<u8> {
+        let mut frame = Cursor::new(vec![0; 4]);
+        frame.set_position(4);
+        t.dehydrate(&mut frame).unwrap();
+        let mut frame = frame.into_inner();
+        let size = frame.len() as u32;
+        frame[..4].copy_from_slice(&size.to_le_bytes());
+        frame
+    }
+
+    /// Read binary messages until a whole R message has arrived.
+    async fn recv(stream: &mut UnixStream) -> R {
+        let mut buf = vec![];
+        while buf.len() < 4 || buf.len() < u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize
+        {
+            let (opcode, payload) = recv_frame(stream).await;
+            assert_eq!(0x2, opcode);
+            buf.extend(payload);
+        }
+        RReader::new(Box::pin(Cursor::new(buf)), 8192)
+            .next()
+            .await
+            .unwrap()
+    }
+
+    async fn upgrade(stream: &mut UnixStream, request: &str) -> String {
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    /// Connect and upgrade to a WebSocket, ready for 9p.
+    async fn connect(path: &PathBuf) -> UnixStream {
+        let mut stream = UnixStream::connect(path).await.unwrap();
+        let response = upgrade(&mut stream, UPGRADE).await;
+        assert!(response.starts_with("HTTP/1.1 101 "), "{response}");
+        let accept = response.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("sec-websocket-accept")
+                .then(|| value.trim())
+        });
+        assert_eq!(Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), accept, "{response}");
+        stream
+    }
+
+    /// Read whatever the server still has to say, until it hangs up, and
+    /// return any binary messages among it.
+    async fn hang_up(stream: &mut UnixStream) -> Vec<u8> {
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).await.unwrap();
+        rest
+    }
+
+    #[test]
+    fn attach_and_stat() {
+        block_on(async {
+            let path = serve("attach", MockClock::new(UNIX_EPOCH)).await;
+            let mut stream = connect(&path).await;
+
+            // version and attach share a message.
+            let version = encode(T::Version(!0, 8192, "9P2000.u".parse().unwrap()));
+            let attach = encode(T::Attach(1, 1, !0, "user".to_owned(), "".to_owned(), 0));
+            send_frame(&mut stream, 0x82, &[version, attach].concat()).await;
+            let r = recv(&mut stream).await;
+            assert!(matches!(r, R::Version(_, 8192, _)), "{:?}", r);
+            let r = recv(&mut stream).await;
+            assert!(matches!(r, R::Attach(1, _)), "{:?}", r);
+
+            // pings are answered.
+            send_frame(&mut stream, 0x89, b"hi").await;
+            assert_eq!((0xA, b"hi".to_vec()), recv_frame(&mut stream).await);
+
+            // and a stat is split over two messages.
+            let stat = encode(T::Stat(2, 1));
+            send_frame(&mut stream, 0x02, &stat[..5]).await;
+            send_frame(&mut stream, 0x80, &stat[5..]).await;
+            let r = recv(&mut stream).await;
+            assert!(matches!(r, R::Stat(2, _)), "{:?}", r);
+
+            send_frame(&mut stream, 0x88, &1000u16.to_be_bytes()).await;
+            assert_eq!(
+                (0x8, 1000u16.to_be_bytes().to_vec()),
+                recv_frame(&mut stream).await
+            );
+            assert_eq!(Vec::<u8>::new(), hang_up(&mut stream).await);
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    #[test]
+    fn bad_handshakes() {
+        block_on(async {
+            let path = serve("handshakes", MockClock::new(UNIX_EPOCH)).await;
+
+            // not a WebSocket...
+            let mut stream = UnixStream::connect(&path).await.unwrap();
+            let request = "GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+            stream.write_all(request.as_bytes()).await.unwrap();
+            assert_eq!(Vec::<u8>::new(), hang_up(&mut stream).await);
+
+            // ...nor without asking for the connection to be upgraded.
+            let mut stream = UnixStream::connect(&path).await.unwrap();
+            let request = UPGRADE.replace("Connection: Upgrade\r\n", "");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            assert_eq!(Vec::<u8>::new(), hang_up(&mut stream).await);
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    #[test]
+    fn bad_frames() {
+        block_on(async {
+            let path = serve("frames", MockClock::new(UNIX_EPOCH)).await;
+            let version = encode(T::Version(!0, 8192, "9P2000.u".parse().unwrap()));
+
+            // reserved bits are refused, as are fragmented control frames
+            // and text; none of them make it to the 9p connection.
+            for head in [0xC2, 0x09, 0x81] {
+                let mut stream = connect(&path).await;
+                let payload = match head {
+                    0x81 => b"hi".to_vec(),
+                    _ => version.clone(),
+                };
+                send_frame(&mut stream, head, &payload).await;
+                let rest = hang_up(&mut stream).await;
+                assert!(
+                    rest.is_empty() || rest[0] & 0x0F == 0x8,
+                    "{head:x}: {rest:?}"
+                );
+            }
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    #[test]
+    fn handshake_deadline() {
+        block_on(async {
+            let clock = MockClock::new(UNIX_EPOCH);
+            let path = serve("deadline", clock.clone()).await;
+
+            // a client that never finishes its request is hung up on.
+            let mut stream = UnixStream::connect(&path).await.unwrap();
+            stream.write_all(&UPGRADE.as_bytes()[..20]).await.unwrap();
+            while clock.sleepers() == 0 {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_secs(9));
+            let mut byte = [0; 1];
+            let read = tokio::time::timeout(Duration::from_millis(50), stream.read(&mut byte));
+            assert!(read.await.is_err());
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(0, stream.read(&mut byte).await.unwrap());
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+}
+
+// vim: foldmethod=marker
@@ -0,0 +1,167 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Interop tests against the plan9port `9p` client.
+//!
+//! These need a `9p` binary (from plan9port) on the `PATH`, or pointed to by
+//! `ARIGATO_9P`, and so are `#[ignore]`d by default. Run them with:
+//!
+//! ```text
+//! $ cargo test --test plan9port -- --ignored
+//! ```
+//!
+//! If no `9p` binary can be found, each test logs that it was skipped and
+//! passes.
+
+#![cfg(unix)]
+
+use arigato::{
+    fs::{StaticEntry, StaticTree},
+    server::AsyncServer,
+};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(f)
+}
+
+/// Path to the `9p` binary under test.
+fn nine_p() -> PathBuf {
+    std::env::var_os("ARIGATO_9P")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("9p"))
+}
+
+/// Tree served to the client for every test here.
+fn tree() -> StaticTree {
+    StaticTree::new(BTreeMap::from([
+        ("hello".to_owned(), StaticEntry::file(b"Hello, World!\n")),
+        (
+            "dir".to_owned(),
+            StaticEntry::dir([("nested", StaticEntry::file(b"nested\n"))]),
+        ),
+    ]))
+}
+
+/// Serve [tree] on a fresh unix socket named `name`, and run the `9p` client
+/// against it with `args`. Returns None if the `9p` binary is missing.
+fn run_9p(name: &str, args: &[&str]) -> Option<Output> {
+    block_on(async {
+        let dir = std::env::temp_dir().join(format!("arigato-9p-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.sock", name));
+        let _ = std::fs::remove_file(&path);
+
+        let srv = AsyncServer::builder()
+            .with_unix_listen_address(&path)
+            .with_filesystem("", tree())
+            .build()
+            .await
+            .unwrap();
+        let handle = srv.handle();
+        tokio::spawn(async move { srv.serve().await });
+        handle.ready().await;
+
+        // the client blocks; keep it off of the thread driving the server.
+        let address = address(&path);
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let output = tokio::task::spawn_blocking(move || {
+            Command::new(nine_p())
+                .arg("-a")
+                .arg(address)
+                .args(args)
+                .output()
+        })
+        .await
+        .unwrap();
+
+        let _ = std::fs::remove_file(&path);
+        match output {
+            Ok(output) => Some(output),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!("skipping: no 9p binary found ({:?})", nine_p());
+                None
+            }
+            Err(err) => panic!("failed to run 9p: {:?}", err),
+        }
+    })
+}
+
+/// plan9port dial string for a unix socket.
+fn address(path: &Path) -> String {
+    format!("unix!{}", path.display())
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "9p failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+#[ignore]
+fn read_file() {
+    let Some(output) = run_9p("read", &["read", "hello"]) else {
+        return;
+    };
+    assert_eq!("Hello, World!\n", stdout(&output));
+}
+
+#[test]
+#[ignore]
+fn read_nested() {
+    let Some(output) = run_9p("nested", &["read", "dir/nested"]) else {
+        return;
+    };
+    assert_eq!("nested\n", stdout(&output));
+}
+
+#[test]
+#[ignore]
+fn ls_root() {
+    let Some(output) = run_9p("ls", &["ls", "/"]) else {
+        return;
+    };
+    let mut names: Vec<String> = stdout(&output).lines().map(str::to_owned).collect();
+    names.sort();
+    assert_eq!(vec!["dir".to_owned(), "hello".to_owned()], names);
+}
+
+#[test]
+#[ignore]
+fn read_missing() {
+    let Some(output) = run_9p("missing", &["read", "nope"]) else {
+        return;
+    };
+    assert!(!output.status.success());
+}
+
+// vim: foldmethod=marker
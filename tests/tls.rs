@@ -0,0 +1,137 @@
+// {{{ Copyright (c) Paul R. Tagliamonte <paultag@gmail.com>, 2023-2024
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE. }}}
+
+//! Serving over TLS, and reading back with the crate's own client.
+//!
+//! The certificate under `tests/data` is self-signed, for `localhost`.
+
+#![cfg(feature = "tls")]
+
+use arigato::{
+    client::{Client, Dir},
+    fs::{StaticEntry, StaticTree},
+    server::AsyncServer,
+};
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsConnector,
+};
+
+const CERT: &[u8] = include_bytes!("data/localhost.crt");
+const KEY: &[u8] = include_bytes!("data/localhost.key");
+
+fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(f)
+}
+
+fn server_config() -> ServerConfig {
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from_pem_slice(CERT).unwrap()],
+            PrivateKeyDer::from_pem_slice(KEY).unwrap(),
+        )
+        .unwrap()
+}
+
+/// Connector which trusts (only) the test certificate.
+fn connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_slice(CERT).unwrap())
+        .unwrap();
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Serve a tree with a single file over TLS on a local port, returning the
+/// address it is listening on.
+async fn serve() -> std::net::SocketAddr {
+    let srv = AsyncServer::builder()
+        .with_tcp_listen_address("127.0.0.1:0")
+        .with_tls(server_config())
+        .with_filesystem(
+            "",
+            StaticTree::new(BTreeMap::from([(
+                "hello".to_owned(),
+                StaticEntry::file(b"Hello, World!\n"),
+            )])),
+        )
+        .build()
+        .await
+        .unwrap();
+    let addr = srv.local_addr().unwrap();
+    let handle = srv.handle();
+    tokio::spawn(async move { srv.serve().await });
+    handle.ready().await;
+    addr
+}
+
+/// Read `/hello` over a TLS connection to `addr`.
+async fn read_hello(addr: std::net::SocketAddr) -> Vec<u8> {
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let stream = connector()
+        .connect(ServerName::try_from("localhost").unwrap(), socket)
+        .await
+        .unwrap();
+    let (read, write) = tokio::io::split(stream);
+    let client = Arc::new(Client::new(read, write, 8192).await.unwrap());
+    let root = Dir::attach(client, "user", "").await.unwrap();
+    let mut file = root.open("hello", 0.into()).await.unwrap();
+    file.read_to_end().await.unwrap()
+}
+
+#[test]
+fn read_over_tls() {
+    block_on(async {
+        let addr = serve().await;
+        assert_eq!(b"Hello, World!\n".to_vec(), read_hello(addr).await);
+    });
+}
+
+#[test]
+fn failed_handshake_drops_connection() {
+    block_on(async {
+        let addr = serve().await;
+
+        // a plaintext Tversion is no TLS ClientHello; the server hangs up...
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(b"\x13\x00\x00\x00\x64\xff\xff\x00\x20\x00\x00\x06\x009P2000")
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        let _ = tokio::io::AsyncReadExt::read_to_end(&mut socket, &mut buf).await;
+
+        // ...but carries on serving everyone else.
+        assert_eq!(b"Hello, World!\n".to_vec(), read_hello(addr).await);
+    });
+}